@@ -1,9 +1,9 @@
-use wal_rs::segment::{ChunkStartPosition, Segment};
+use wal_rs::{options::Options, wal::Wal};
 fn main() {
-    let path: std::path::PathBuf = std::path::PathBuf::from("/tmp/000001.log");
-    let mut wal = Segment::open(&path).unwrap();
+    let opts = Options::new("/tmp/wal-rs-example", 1024 * 1024 * 1024);
+    let mut wal = Wal::open(opts).unwrap();
     // One block
     let s = "A".repeat(2028);
-    let pos = wal.write(s.into_bytes()).unwrap();
-    wal.read(pos.block_number, pos.chunk_offset).unwrap();
+    let pos = wal.write(s.as_bytes()).unwrap();
+    wal.read(pos).unwrap();
 }
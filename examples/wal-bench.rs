@@ -0,0 +1,209 @@
+//! Throughput and latency benchmark for common workloads.
+//!
+//! cargo run --release --example wal-bench -- --threads 4 --sizes 64-4096 --sync every:64 --reads 0.2
+//!
+//! The Wal goes in a new `wal-bench-<pid>` directory inside `--dir`, which is
+//! removed afterwards; nothing else in `--dir` is touched.
+use std::{
+    sync::{Mutex, RwLock},
+    time::{Duration, Instant},
+};
+
+use wal_rs::{
+    options::Options,
+    wal::{ChunkPosition, Wal},
+};
+
+const USAGE: &str = "usage: wal-bench [--dir PATH] [--ops N] [--threads N] [--sizes N|MIN-MAX]
+                 [--sync never|every|every:N] [--reads RATIO] [--segment-size BYTES]";
+
+struct Config {
+    dir: std::path::PathBuf,
+    ops: usize,
+    threads: usize,
+    min_size: usize,
+    max_size: usize,
+    /// Sync after every `sync_every` writes of a thread, never if 0.
+    sync_every: usize,
+    read_ratio: f64,
+    segment_size: u64,
+}
+
+fn parse_args() -> Result<Config, String> {
+    let mut config = Config {
+        dir: std::env::temp_dir(),
+        ops: 100_000,
+        threads: 1,
+        min_size: 1024,
+        max_size: 1024,
+        sync_every: 0,
+        read_ratio: 0.0,
+        segment_size: 64 * 1024 * 1024,
+    };
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let value = args
+            .next()
+            .ok_or_else(|| format!("missing value for {}", arg))?;
+        let invalid = || format!("invalid value for {}: {}", arg, value);
+        match arg.as_str() {
+            "--dir" => config.dir = value.clone().into(),
+            "--ops" => config.ops = value.parse().map_err(|_| invalid())?,
+            "--threads" => config.threads = value.parse().map_err(|_| invalid())?,
+            "--sizes" => {
+                let (min, max) = value.split_once('-').unwrap_or((&value, &value));
+                config.min_size = min.parse().map_err(|_| invalid())?;
+                config.max_size = max.parse().map_err(|_| invalid())?;
+            }
+            "--sync" => {
+                config.sync_every = match value.as_str() {
+                    "never" => 0,
+                    "every" => 1,
+                    _ => value
+                        .strip_prefix("every:")
+                        .and_then(|n| n.parse().ok())
+                        .ok_or_else(invalid)?,
+                }
+            }
+            "--reads" => config.read_ratio = value.parse().map_err(|_| invalid())?,
+            "--segment-size" => config.segment_size = value.parse().map_err(|_| invalid())?,
+            _ => return Err(format!("unknown argument {}", arg)),
+        }
+    }
+    if config.threads == 0 || config.min_size > config.max_size {
+        return Err("invalid configuration".to_string());
+    }
+    Ok(config)
+}
+
+/// xorshift64
+fn next(state: &mut u64) -> u64 {
+    *state ^= *state << 13;
+    *state ^= *state >> 7;
+    *state ^= *state << 17;
+    *state
+}
+
+#[derive(Default)]
+struct Stats {
+    writes: Vec<Duration>,
+    reads: Vec<Duration>,
+    syncs: Vec<Duration>,
+    bytes_written: u64,
+}
+
+fn run_thread(
+    config: &Config,
+    wal: &RwLock<Wal>,
+    written: &Mutex<Vec<ChunkPosition>>,
+    seed: u64,
+) -> Stats {
+    let mut rng = seed;
+    let mut stats = Stats::default();
+    let data = vec![0xab; config.max_size];
+    for _ in 0..config.ops / config.threads {
+        let is_read = (next(&mut rng) % 1_000_000) as f64 / 1_000_000.0 < config.read_ratio;
+        let pos = if is_read {
+            let written = written.lock().unwrap();
+            (!written.is_empty()).then(|| written[next(&mut rng) as usize % written.len()])
+        } else {
+            None
+        };
+        if let Some(pos) = pos {
+            let start = Instant::now();
+            // Reads take `&self`, so they run concurrently with each other.
+            wal.read().unwrap().read(pos).unwrap();
+            stats.reads.push(start.elapsed());
+            continue;
+        }
+
+        let size =
+            config.min_size + next(&mut rng) as usize % (config.max_size - config.min_size + 1);
+        let start = Instant::now();
+        let pos = wal.write().unwrap().write(&data[..size]).unwrap();
+        stats.writes.push(start.elapsed());
+        stats.bytes_written += size as u64;
+        written.lock().unwrap().push(pos);
+
+        if config.sync_every > 0 && stats.writes.len() % config.sync_every == 0 {
+            let start = Instant::now();
+            wal.read().unwrap().sync_segment(pos.segment_id).unwrap();
+            stats.syncs.push(start.elapsed());
+        }
+    }
+    stats
+}
+
+fn print_latencies(name: &str, mut latencies: Vec<Duration>, elapsed: Duration) {
+    if latencies.is_empty() {
+        return;
+    }
+    latencies.sort();
+    let percentile = |p: f64| latencies[((latencies.len() - 1) as f64 * p) as usize];
+    println!(
+        "{:<6} {:>9} ops {:>11.0} ops/s   p50 {:>9.1?}  p90 {:>9.1?}  p99 {:>9.1?}  p99.9 {:>9.1?}  max {:>9.1?}",
+        name,
+        latencies.len(),
+        latencies.len() as f64 / elapsed.as_secs_f64(),
+        percentile(0.5),
+        percentile(0.9),
+        percentile(0.99),
+        percentile(0.999),
+        latencies[latencies.len() - 1],
+    );
+}
+
+fn main() {
+    let config = match parse_args() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}\n{}", e, USAGE);
+            std::process::exit(2);
+        }
+    };
+    // Never removes anything but the directory it created itself.
+    let dir = config.dir.join(format!("wal-bench-{}", std::process::id()));
+    if let Err(e) = std::fs::create_dir_all(&config.dir).and_then(|_| std::fs::create_dir(&dir)) {
+        eprintln!("can't create {}: {}", dir.display(), e);
+        std::process::exit(1);
+    }
+    let wal = RwLock::new(Wal::open(Options::new(&dir, config.segment_size)).unwrap());
+    let written = Mutex::new(Vec::new());
+
+    let start = Instant::now();
+    let stats: Vec<Stats> = std::thread::scope(|s| {
+        let handles: Vec<_> = (0..config.threads)
+            .map(|i| {
+                let (config, wal, written) = (&config, &wal, &written);
+                s.spawn(move || {
+                    run_thread(config, wal, written, 0x9e37_79b9_7f4a_7c15 ^ (i as u64 + 1))
+                })
+            })
+            .collect();
+        handles.into_iter().map(|h| h.join().unwrap()).collect()
+    });
+    let elapsed = start.elapsed();
+
+    let bytes_written: u64 = stats.iter().map(|s| s.bytes_written).sum();
+    println!(
+        "{} threads, {}-{} byte records, {:.2?}, {:.1} MB/s written",
+        config.threads,
+        config.min_size,
+        config.max_size,
+        elapsed,
+        bytes_written as f64 / elapsed.as_secs_f64() / (1024.0 * 1024.0)
+    );
+    let mut writes = Vec::new();
+    let mut reads = Vec::new();
+    let mut syncs = Vec::new();
+    for s in stats {
+        writes.extend(s.writes);
+        reads.extend(s.reads);
+        syncs.extend(s.syncs);
+    }
+    print_latencies("write", writes, elapsed);
+    print_latencies("read", reads, elapsed);
+    print_latencies("sync", syncs, elapsed);
+    drop(wal);
+    let _ = std::fs::remove_dir_all(&dir);
+}
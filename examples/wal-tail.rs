@@ -0,0 +1,213 @@
+//! Print the records of a Wal, e.g. to inspect a live log during an incident.
+//!
+//! cargo run --example wal-tail -- --dir PATH --follow --from 3:0:0 --grep ERROR --format json
+//!
+//! The Wal is opened read-only, so this never changes it. With `--follow`
+//! it is reopened every `--interval-ms` to pick up records written since.
+use std::{io::Write, time::Duration};
+
+use wal_rs::{
+    error::WalError,
+    options::Options,
+    wal::{ChunkPosition, ReadLimits, ReplayControl, Wal},
+};
+
+const USAGE: &str = "usage: wal-tail --dir PATH [--sealed-dir PATH] [--follow] [--interval-ms N]
+                [--from SEGMENT:BLOCK:OFFSET] [--grep BYTES] [--format text|json]";
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Format {
+    Text,
+    Json,
+}
+
+struct Config {
+    dir: Option<std::path::PathBuf>,
+    sealed_dir: Option<std::path::PathBuf>,
+    follow: bool,
+    interval: Duration,
+    from: Option<ChunkPosition>,
+    grep: Option<Vec<u8>>,
+    format: Format,
+}
+
+fn parse_position(value: &str) -> Option<ChunkPosition> {
+    let mut fields = value.split(':');
+    let pos = ChunkPosition {
+        segment_id: fields.next()?.parse().ok()?,
+        block_number: fields.next()?.parse().ok()?,
+        chunk_offset: fields.next()?.parse().ok()?,
+    };
+    fields.next().is_none().then_some(pos)
+}
+
+fn parse_args() -> Result<Config, String> {
+    let mut config = Config {
+        dir: None,
+        sealed_dir: None,
+        follow: false,
+        interval: Duration::from_millis(200),
+        from: None,
+        grep: None,
+        format: Format::Text,
+    };
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "--follow" {
+            config.follow = true;
+            continue;
+        }
+        let value = args
+            .next()
+            .ok_or_else(|| format!("missing value for {}", arg))?;
+        let invalid = || format!("invalid value for {}: {}", arg, value);
+        match arg.as_str() {
+            "--dir" => config.dir = Some(value.clone().into()),
+            "--sealed-dir" => config.sealed_dir = Some(value.clone().into()),
+            "--interval-ms" => {
+                config.interval = Duration::from_millis(value.parse().map_err(|_| invalid())?)
+            }
+            // Records have no sequence numbers or timestamps, only positions.
+            "--from" => config.from = Some(parse_position(&value).ok_or_else(invalid)?),
+            "--grep" => config.grep = Some(value.clone().into_bytes()),
+            "--format" => {
+                config.format = match value.as_str() {
+                    "text" => Format::Text,
+                    "json" => Format::Json,
+                    _ => return Err(invalid()),
+                }
+            }
+            _ => return Err(format!("unknown argument {}", arg)),
+        }
+    }
+    if config.dir.is_none() {
+        return Err("missing --dir".to_string());
+    }
+    Ok(config)
+}
+
+fn open(config: &Config) -> Result<Wal, WalError> {
+    let dir = config.dir.clone().unwrap_or_default();
+    // The segment size only matters for writing.
+    let mut options = Options::new(dir, u64::MAX).with_read_only(true);
+    if let Some(sealed_dir) = &config.sealed_dir {
+        options = options.with_sealed_dir(sealed_dir);
+    }
+    Wal::open(options)
+}
+
+fn json_string(data: &[u8]) -> String {
+    let mut out = String::with_capacity(data.len() + 2);
+    out.push('"');
+    for c in String::from_utf8_lossy(data).chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c.is_control() => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+fn print_record(config: &Config, out: &mut impl Write, pos: ChunkPosition, data: &[u8]) {
+    if let Some(grep) = &config.grep {
+        if !grep.is_empty() && !data.windows(grep.len()).any(|window| window == &grep[..]) {
+            return;
+        }
+    }
+    let written = match config.format {
+        Format::Text => writeln!(
+            out,
+            "{}:{}:{} {}",
+            pos.segment_id,
+            pos.block_number,
+            pos.chunk_offset,
+            String::from_utf8_lossy(data).escape_debug()
+        ),
+        Format::Json => writeln!(
+            out,
+            "{{\"segment_id\":{},\"block_number\":{},\"chunk_offset\":{},\"len\":{},\"data\":{}}}",
+            pos.segment_id,
+            pos.block_number,
+            pos.chunk_offset,
+            data.len(),
+            json_string(data)
+        ),
+    };
+    // E.g. piped into `head`.
+    if written.and_then(|_| out.flush()).is_err() {
+        std::process::exit(0);
+    }
+}
+
+/// Print the records of `wal` after `last`, or from `config.from` the first
+/// time, and return the position of the last one printed.
+fn print_new(
+    config: &Config,
+    wal: &Wal,
+    last: Option<ChunkPosition>,
+    out: &mut impl Write,
+) -> Result<Option<ChunkPosition>, WalError> {
+    let Some(start) = last.or(config.from) else {
+        let mut last = None;
+        wal.replay(|pos, data| {
+            print_record(config, out, pos, data);
+            last = Some(pos);
+            Ok(ReplayControl::Continue)
+        })?;
+        return Ok(last);
+    };
+    let end = ChunkPosition {
+        segment_id: u32::MAX,
+        block_number: u32::MAX,
+        chunk_offset: u64::MAX,
+    };
+    let limits = ReadLimits::default().with_max_records(1024);
+    let mut pos = Some(start);
+    let mut last = last;
+    while let Some(from) = pos {
+        let page = wal.read_range(from..=end, limits)?;
+        for (pos, data) in page.records {
+            // The last record printed is where the next poll starts.
+            if Some(pos) != last {
+                print_record(config, out, pos, &data);
+                last = Some(pos);
+            }
+        }
+        pos = page.next;
+    }
+    Ok(last)
+}
+
+fn main() {
+    let config = match parse_args() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}\n{}", e, USAGE);
+            std::process::exit(2);
+        }
+    };
+    let mut out = std::io::stdout().lock();
+    let mut last = None;
+    loop {
+        let printed = open(&config).and_then(|wal| print_new(&config, &wal, last, &mut out));
+        match printed {
+            Ok(printed) => last = printed.or(last),
+            Err(e) => {
+                eprintln!("wal-tail: {}", e);
+                if !config.follow {
+                    std::process::exit(1);
+                }
+            }
+        }
+        if !config.follow {
+            break;
+        }
+        std::thread::sleep(config.interval);
+    }
+}
@@ -0,0 +1,171 @@
+//! A single-file container of segments and their manifest, see
+//! [`crate::wal::Wal::export_archive`].
+//!
+//! The archive starts with [`MAGIC`], followed by entries of a kind byte, a
+//! segment id for segment entries (u32 LE), the length of the data (u64 LE)
+//! and the data. An entry of kind [`END`] ends it. Besides the manifest and
+//! the segments, the logical start and the entry index travel as the files
+//! they are stored in.
+
+use std::{
+    io::{Read, Write},
+    path::Path,
+};
+
+use crate::{
+    entry_index::ENTRY_INDEX_FILE_NAME,
+    error::{IoResultExt, WalError},
+    manifest::{MANIFEST_FILE_NAME, START_FILE_NAME},
+    platform, segment,
+};
+
+const MAGIC: &[u8; 8] = b"WALARCH1";
+const END: u8 = 0;
+const MANIFEST: u8 = 1;
+const SEGMENT: u8 = 2;
+const START: u8 = 3;
+const ENTRY_INDEX: u8 = 4;
+
+/// What an archive is called in errors, it has no path of its own.
+const ARCHIVE: &str = "<archive>";
+
+pub(crate) struct ArchiveWriter<W> {
+    writer: W,
+}
+
+impl<W: Write> ArchiveWriter<W> {
+    pub(crate) fn new(mut writer: W) -> Result<Self, WalError> {
+        writer.write_all(MAGIC).context("write", ARCHIVE)?;
+        Ok(Self { writer })
+    }
+
+    pub(crate) fn manifest(&mut self, contents: &str) -> Result<(), WalError> {
+        self.file(MANIFEST, contents)
+    }
+
+    /// Add the contents of the file recording the logical start.
+    pub(crate) fn start(&mut self, contents: &str) -> Result<(), WalError> {
+        self.file(START, contents)
+    }
+
+    /// Add the contents of the file persisting the entry index.
+    pub(crate) fn entry_index(&mut self, contents: &str) -> Result<(), WalError> {
+        self.file(ENTRY_INDEX, contents)
+    }
+
+    fn file(&mut self, kind: u8, contents: &str) -> Result<(), WalError> {
+        self.writer.write_all(&[kind]).context("write", ARCHIVE)?;
+        self.data(contents.len() as u64, contents.as_bytes())
+    }
+
+    /// Add the first `len` bytes of the segment file at `path`.
+    pub(crate) fn segment(&mut self, id: u32, path: &Path, len: u64) -> Result<(), WalError> {
+        let file = std::fs::File::open(path).context("open", path)?;
+        self.writer
+            .write_all(&[SEGMENT])
+            .context("write", ARCHIVE)?;
+        self.writer
+            .write_all(&id.to_le_bytes())
+            .context("write", ARCHIVE)?;
+        self.data(len, file.take(len))
+    }
+
+    pub(crate) fn finish(mut self) -> Result<(), WalError> {
+        self.writer.write_all(&[END]).context("write", ARCHIVE)?;
+        self.writer.flush().context("flush", ARCHIVE)
+    }
+
+    fn data(&mut self, len: u64, mut data: impl Read) -> Result<(), WalError> {
+        self.writer
+            .write_all(&len.to_le_bytes())
+            .context("write", ARCHIVE)?;
+        let copied = std::io::copy(&mut data, &mut self.writer).context("copy", ARCHIVE)?;
+        if copied != len {
+            return Err(WalError::InvalidArchive(format!(
+                "{} bytes of an entry of {} bytes",
+                copied, len
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Unpack an archive into `dir_path`, which must not hold a Wal yet. The
+/// manifest is written last, after the segments and other files are synced.
+pub(crate) fn unpack(mut reader: impl Read, dir_path: &Path) -> Result<(), WalError> {
+    let mut magic = [0; MAGIC.len()];
+    reader.read_exact(&mut magic).context("read", ARCHIVE)?;
+    if &magic != MAGIC {
+        return Err(WalError::InvalidArchive("not an archive".to_string()));
+    }
+    std::fs::create_dir_all(dir_path).context("create", dir_path)?;
+    let manifest_path = dir_path.join(MANIFEST_FILE_NAME);
+    if manifest_path.exists() {
+        return Err(WalError::InvalidArchive(format!(
+            "{} already holds a wal",
+            dir_path.display()
+        )));
+    }
+    let mut manifest = None;
+    loop {
+        let mut kind = [0];
+        reader.read_exact(&mut kind).context("read", ARCHIVE)?;
+        match kind[0] {
+            END => break,
+            MANIFEST => {
+                let mut contents = Vec::new();
+                read_data(&mut reader, &mut contents)?;
+                manifest = Some(contents);
+            }
+            SEGMENT => {
+                let mut id = [0; 4];
+                reader.read_exact(&mut id).context("read", ARCHIVE)?;
+                let path = segment::segment_file_path(dir_path, u32::from_le_bytes(id));
+                let mut file = std::fs::File::options()
+                    .write(true)
+                    .create_new(true)
+                    .open(&path)
+                    .context("create", &path)?;
+                read_data(&mut reader, &mut file)?;
+                file.sync_all().context("fsync", &path)?;
+            }
+            START | ENTRY_INDEX => {
+                let name = match kind[0] {
+                    START => START_FILE_NAME,
+                    _ => ENTRY_INDEX_FILE_NAME,
+                };
+                let path = dir_path.join(name);
+                let mut file = std::fs::File::create(&path).context("create", &path)?;
+                read_data(&mut reader, &mut file)?;
+                file.sync_all().context("fsync", &path)?;
+            }
+            kind => {
+                return Err(WalError::InvalidArchive(format!(
+                    "unknown entry kind {}",
+                    kind
+                )))
+            }
+        }
+    }
+    let Some(manifest) = manifest else {
+        return Err(WalError::InvalidArchive("no manifest".to_string()));
+    };
+    let tmp = manifest_path.with_extension("tmp");
+    let mut file = std::fs::File::create(&tmp).context("create", &tmp)?;
+    file.write_all(&manifest).context("write", &tmp)?;
+    file.sync_all().context("fsync", &tmp)?;
+    std::fs::rename(&tmp, &manifest_path).context("rename", &tmp)?;
+    platform::sync_dir(dir_path).context("fsync", dir_path)
+}
+
+/// Copy the data of an entry from `reader` to `writer`.
+fn read_data(reader: &mut impl Read, writer: &mut impl Write) -> Result<(), WalError> {
+    let mut len = [0; 8];
+    reader.read_exact(&mut len).context("read", ARCHIVE)?;
+    let len = u64::from_le_bytes(len);
+    let copied = std::io::copy(&mut reader.by_ref().take(len), writer).context("copy", ARCHIVE)?;
+    if copied != len {
+        return Err(WalError::InvalidArchive("truncated".to_string()));
+    }
+    Ok(())
+}
@@ -0,0 +1,269 @@
+//! Caches for repeated point reads: of decoded records, so records stored
+//! compressed or through a [`crate::transform::RecordTransform`] aren't
+//! decoded again on every read, and of blocks, so hot ones aren't read from
+//! the file again.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Arc,
+};
+
+use crate::segment::{BlockId, ChunkPosition};
+
+/// Records decoded by the transform or decompressed, by position, evicting
+/// the least recently read ones once their payloads exceed the budget.
+pub(crate) struct DecodedCache {
+    budget: usize,
+    used: usize,
+    tick: u64,
+    /// The segments before this one are gone, see
+    /// [`DecodedCache::forget_before`].
+    first_segment_id: u32,
+    /// Versions and records by position, with the tick they were last read at.
+    records: HashMap<ChunkPosition, (u8, Vec<u8>, u64)>,
+    /// Positions by the tick they were last read at.
+    by_use: BTreeMap<u64, ChunkPosition>,
+}
+
+impl DecodedCache {
+    pub(crate) fn new(budget: usize) -> Self {
+        Self {
+            budget,
+            used: 0,
+            tick: 0,
+            first_segment_id: 0,
+            records: HashMap::new(),
+            by_use: BTreeMap::new(),
+        }
+    }
+
+    /// The version and record at `pos`, if cached.
+    pub(crate) fn get(&mut self, pos: ChunkPosition) -> Option<(u8, &[u8])> {
+        self.tick += 1;
+        let (version, data, used_at) = self.records.get_mut(&pos)?;
+        self.by_use.remove(used_at);
+        *used_at = self.tick;
+        self.by_use.insert(self.tick, pos);
+        Some((*version, data))
+    }
+
+    /// Cache `data`, unless it alone exceeds the budget.
+    pub(crate) fn insert(&mut self, pos: ChunkPosition, version: u8, data: Vec<u8>) {
+        if data.len() > self.budget {
+            return;
+        }
+        self.remove(pos);
+        self.used += data.len();
+        while self.used > self.budget {
+            let Some((_, oldest)) = self.by_use.pop_first() else {
+                break;
+            };
+            if let Some((_, evicted, _)) = self.records.remove(&oldest) {
+                self.used -= evicted.len();
+            }
+        }
+        self.tick += 1;
+        self.by_use.insert(self.tick, pos);
+        self.records.insert(pos, (version, data, self.tick));
+    }
+
+    /// Drop the records after `pos`, e.g. once they are truncated.
+    pub(crate) fn forget_after(&mut self, pos: ChunkPosition) {
+        let after: Vec<ChunkPosition> =
+            self.records.keys().filter(|p| **p > pos).copied().collect();
+        for pos in after {
+            self.remove(pos);
+        }
+    }
+
+    /// Drop the records of the segments before `segment_id`, once they are
+    /// removed. Cheap if none were removed since the last call.
+    pub(crate) fn forget_before(&mut self, segment_id: u32) {
+        if segment_id <= self.first_segment_id {
+            return;
+        }
+        self.first_segment_id = segment_id;
+        let before: Vec<ChunkPosition> = self
+            .records
+            .keys()
+            .filter(|p| p.segment_id < segment_id)
+            .copied()
+            .collect();
+        for pos in before {
+            self.remove(pos);
+        }
+    }
+
+    fn remove(&mut self, pos: ChunkPosition) {
+        if let Some((_, data, used_at)) = self.records.remove(&pos) {
+            self.by_use.remove(&used_at);
+            self.used -= data.len();
+        }
+    }
+
+    #[cfg(test)]
+    fn used(&self) -> usize {
+        self.used
+    }
+}
+
+/// Complete blocks by id, shared by the segments of
+/// a Wal, evicting the least recently read ones once they exceed the budget.
+/// A block is only cached once the segment has been written past it, from
+/// then on it never changes.
+pub(crate) struct BlockCache {
+    budget: usize,
+    used: usize,
+    tick: u64,
+    /// Blocks dropped because they disagreed with their checksums or the
+    /// file, see [`BlockCache::forget_corrupt`].
+    repairs: u64,
+    /// Blocks with the tick they were last read at.
+    blocks: HashMap<BlockId, (Arc<[u8]>, u64)>,
+    /// Blocks by the tick they were last read at.
+    by_use: BTreeMap<u64, BlockId>,
+}
+
+impl BlockCache {
+    pub(crate) fn new(budget: usize) -> Self {
+        Self {
+            budget,
+            used: 0,
+            tick: 0,
+            repairs: 0,
+            blocks: HashMap::new(),
+            by_use: BTreeMap::new(),
+        }
+    }
+
+    pub(crate) fn get(&mut self, id: BlockId) -> Option<Arc<[u8]>> {
+        self.tick += 1;
+        let (block, used_at) = self.blocks.get_mut(&id)?;
+        self.by_use.remove(used_at);
+        *used_at = self.tick;
+        self.by_use.insert(self.tick, id);
+        Some(block.clone())
+    }
+
+    /// Cache `block`, unless it alone exceeds the budget.
+    pub(crate) fn insert(&mut self, id: BlockId, block: Arc<[u8]>) {
+        if block.len() > self.budget {
+            return;
+        }
+        if let Some((old, used_at)) = self.blocks.remove(&id) {
+            self.by_use.remove(&used_at);
+            self.used -= old.len();
+        }
+        self.used += block.len();
+        while self.used > self.budget {
+            let Some((_, oldest)) = self.by_use.pop_first() else {
+                break;
+            };
+            if let Some((evicted, _)) = self.blocks.remove(&oldest) {
+                self.used -= evicted.len();
+            }
+        }
+        self.tick += 1;
+        self.by_use.insert(self.tick, id);
+        self.blocks.insert(id, (block, self.tick));
+    }
+
+    /// Drop `id` once it turned out corrupt, in memory or in the file, so
+    /// the next read of it goes to the file, and count it if it was cached.
+    pub(crate) fn forget_corrupt(&mut self, id: BlockId) {
+        if let Some((block, used_at)) = self.blocks.remove(&id) {
+            self.by_use.remove(&used_at);
+            self.used -= block.len();
+            self.repairs += 1;
+        }
+    }
+
+    pub(crate) fn repairs(&self) -> u64 {
+        self.repairs
+    }
+
+    /// Drop `first` and the blocks after it, e.g. once they are truncated
+    /// and written anew.
+    pub(crate) fn forget_from(&mut self, first: BlockId) {
+        self.forget(|id| id >= first);
+    }
+
+    /// Drop `first` and the blocks after it in its segment, e.g. once the
+    /// segment is cut off there.
+    pub(crate) fn forget_segment_from(&mut self, first: BlockId) {
+        self.forget(|id| id.segment_id == first.segment_id && id >= first);
+    }
+
+    fn forget(&mut self, mut forget: impl FnMut(BlockId) -> bool) {
+        self.blocks.retain(|id, (block, used_at)| {
+            if !forget(*id) {
+                return true;
+            }
+            self.by_use.remove(used_at);
+            self.used -= block.len();
+            false
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pos(chunk_offset: u64) -> ChunkPosition {
+        ChunkPosition {
+            segment_id: 1,
+            block_number: 0,
+            chunk_offset,
+        }
+    }
+
+    #[test]
+    fn evicts_least_recently_read() {
+        let mut cache = DecodedCache::new(10);
+        cache.insert(pos(0), 0, vec![0; 4]);
+        cache.insert(pos(1), 0, vec![1; 4]);
+        assert_eq!(cache.get(pos(0)), Some((0, &[0; 4][..])));
+        cache.insert(pos(2), 0, vec![2; 4]);
+        assert_eq!(cache.get(pos(1)), None);
+        assert_eq!(cache.get(pos(0)), Some((0, &[0; 4][..])));
+        assert_eq!(cache.get(pos(2)), Some((0, &[2; 4][..])));
+        assert_eq!(cache.used(), 8);
+
+        // Too large to cache at all, nothing is evicted for it.
+        cache.insert(pos(3), 0, vec![3; 11]);
+        assert_eq!(cache.get(pos(3)), None);
+        assert_eq!(cache.used(), 8);
+
+        // Records of removed segments go.
+        let next = ChunkPosition {
+            segment_id: 2,
+            ..pos(0)
+        };
+        cache.insert(next, 0, vec![4; 2]);
+        cache.forget_before(2);
+        assert_eq!(cache.get(pos(0)), None);
+        assert_eq!(cache.get(next), Some((0, &[4; 2][..])));
+        assert_eq!(cache.used(), 2);
+    }
+
+    fn block(segment_id: u32, block_number: u32) -> BlockId {
+        BlockId {
+            segment_id,
+            block_number,
+        }
+    }
+
+    #[test]
+    fn blocks_of_all_segments_share_the_budget() {
+        let mut cache = BlockCache::new(8);
+        cache.insert(block(1, 0), vec![1; 4].into());
+        cache.insert(block(2, 0), vec![2; 4].into());
+        assert_eq!(cache.get(block(1, 0)).as_deref(), Some(&[1; 4][..]));
+        cache.insert(block(2, 1), vec![3; 4].into());
+        assert!(cache.get(block(2, 0)).is_none());
+        assert_eq!(cache.get(block(1, 0)).as_deref(), Some(&[1; 4][..]));
+        assert_eq!(cache.get(block(2, 1)).as_deref(), Some(&[3; 4][..]));
+        assert_eq!(cache.used, 8);
+    }
+}
@@ -0,0 +1,27 @@
+use std::time::{Instant, SystemTime};
+
+/// Where the Wal gets the time from, e.g. for intervals and ages.
+///
+/// Defaults to [`SystemClock`]; tests can swap in a clock they advance by
+/// hand, such as `wal_rs::testing::ManualClock`, instead of sleeping.
+pub trait Clock: Send + Sync {
+    /// Monotonic time, for measuring intervals.
+    fn now(&self) -> Instant;
+
+    /// Wall-clock time, for ages of things on disk.
+    fn system_time(&self) -> SystemTime;
+}
+
+/// The operating system's clocks.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn system_time(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
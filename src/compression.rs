@@ -0,0 +1,232 @@
+//! Compression of records as stored, see
+//! [`crate::options::Options::with_compression`].
+//!
+//! The chunks of a compressed record have `COMPRESSED` set in their type
+//! byte, and the record starts with the id of its codec. Reads go by the flag
+//! rather than the options, so records written without compression, e.g.
+//! before it was enabled, read as they are.
+
+use crate::error::WalError;
+
+/// Set in the type byte of every chunk of a compressed record.
+pub(crate) const COMPRESSED: u8 = 0x40;
+
+#[cfg(feature = "lz4")]
+const LZ4: u8 = 1;
+#[cfg(feature = "zstd")]
+const ZSTD: u8 = 2;
+
+/// How records are compressed before they are chunked.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    #[cfg(feature = "lz4")]
+    Lz4,
+    /// At a level from 1, the fastest, to 22.
+    #[cfg(feature = "zstd")]
+    Zstd(i32),
+}
+
+impl Compression {
+    /// `data` compressed, `None` if that doesn't make it smaller, in which case
+    /// the record is stored as it is.
+    #[cfg_attr(not(any(feature = "lz4", feature = "zstd")), allow(unused_variables))]
+    pub(crate) fn compress(self, data: &[u8]) -> Result<Option<Vec<u8>>, WalError> {
+        match self {
+            #[cfg(feature = "lz4")]
+            Compression::Lz4 => Ok(smaller(
+                LZ4,
+                lz4_flex::compress_prepend_size(data),
+                data.len(),
+            )),
+            #[cfg(feature = "zstd")]
+            Compression::Zstd(level) => {
+                let compressed = zstd::bulk::compress(data, level)
+                    .map_err(|e| WalError::CompressionFailed(e.to_string()))?;
+                Ok(smaller(ZSTD, compressed, data.len()))
+            }
+        }
+    }
+}
+
+#[cfg(any(feature = "lz4", feature = "zstd"))]
+fn smaller(codec: u8, compressed: Vec<u8>, len: usize) -> Option<Vec<u8>> {
+    (compressed.len() + 1 < len).then(|| [&[codec][..], &compressed].concat())
+}
+
+/// The record `data` was compressed from, failing if its codec isn't
+/// compiled in.
+#[cfg_attr(not(any(feature = "lz4", feature = "zstd")), allow(unused_variables))]
+pub(crate) fn decompress(data: &[u8]) -> Result<Vec<u8>, WalError> {
+    let Some((&codec, compressed)) = data.split_first() else {
+        return Err(WalError::CompressionFailed("empty record".to_string()));
+    };
+    match codec {
+        #[cfg(feature = "lz4")]
+        LZ4 => lz4_flex::decompress_size_prepended(compressed)
+            .map_err(|e| WalError::CompressionFailed(e.to_string())),
+        #[cfg(feature = "zstd")]
+        ZSTD => zstd::stream::decode_all(compressed)
+            .map_err(|e| WalError::CompressionFailed(e.to_string())),
+        // Ids 1 and 2 need the lz4 and zstd features.
+        _ => Err(WalError::CompressionFailed(format!(
+            "unsupported codec {}",
+            codec
+        ))),
+    }
+}
+
+#[cfg(all(test, any(feature = "lz4", feature = "zstd")))]
+mod tests {
+    use super::*;
+    use crate::{
+        options::Options,
+        segment::{ChunkPosition, BLOCK_SIZE},
+        testing,
+        wal::Wal,
+    };
+
+    fn json(i: usize, len: usize) -> Vec<u8> {
+        let mut record = format!("{{\"id\":{},\"items\":[", i);
+        while record.len() < len {
+            record.push_str("{\"name\":\"item\",\"count\":1},");
+        }
+        record.push_str("]}");
+        record.into_bytes()
+    }
+
+    #[test]
+    fn compressed_records_read_alongside_plain_ones() {
+        let codecs = [
+            #[cfg(feature = "lz4")]
+            Compression::Lz4,
+            #[cfg(feature = "zstd")]
+            Compression::Zstd(3),
+        ];
+        for compression in codecs {
+            let dir = testing::temp_dir("compression");
+            let opts = || Options::new(&dir, 64 * BLOCK_SIZE as u64);
+            let mut wal = Wal::open(opts()).unwrap();
+            let mut records = vec![(wal.write(&json(0, 100)).unwrap(), json(0, 100))];
+            drop(wal);
+
+            let mut wal = Wal::open(opts().with_compression(compression)).unwrap();
+            // Spanning blocks even compressed, and one that doesn't compress.
+            let mut incompressible = vec![0; 1000];
+            let mut x = 1u32;
+            for b in &mut incompressible {
+                x = x.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+                *b = (x >> 24) as u8;
+            }
+            for data in [
+                json(1, 3 * BLOCK_SIZE as usize),
+                json(2, 100),
+                incompressible,
+            ] {
+                records.push((wal.write(&data).unwrap(), data));
+            }
+            let raw: usize = records.iter().map(|(_, data)| data.len()).sum();
+            let stored = wal.segments().iter().map(|info| info.size).sum::<u64>();
+            assert!(stored * 4 < raw as u64);
+            for (pos, data) in &records {
+                assert_eq!(&wal.read(*pos).unwrap(), data);
+            }
+            let replayed: Vec<_> = wal.reader().map(|record| record.unwrap()).collect();
+            assert_eq!(replayed.len(), records.len());
+            let small = wal
+                .read_block_records(records[2].0.segment_id, records[2].0.block_number)
+                .unwrap();
+            assert!(small.contains(&records[2]));
+            drop(wal);
+
+            let wal = Wal::open(opts()).unwrap();
+            for (pos, data) in &records {
+                assert_eq!(&wal.read(*pos).unwrap(), data);
+            }
+            std::fs::remove_dir_all(dir).unwrap();
+        }
+    }
+
+    #[test]
+    fn decompressed_records_are_cached() {
+        #[cfg(feature = "lz4")]
+        let compression = Compression::Lz4;
+        #[cfg(not(feature = "lz4"))]
+        let compression = Compression::Zstd(3);
+        let dir = testing::temp_dir("compression_decoded_cache");
+        let opts = || Options::new(&dir, 64 * BLOCK_SIZE as u64).with_decoded_cache_bytes(1 << 20);
+        let mut wal = Wal::open(opts().with_compression(compression)).unwrap();
+        let compressed = wal.write(&json(0, 3 * BLOCK_SIZE as usize)).unwrap();
+        drop(wal);
+        // Also without the option, the chunks say the record is compressed.
+        let mut wal = Wal::open(opts()).unwrap();
+        let plain = wal.write(&json(1, 100)).unwrap();
+        let reads = |wal: &Wal| wal.read_stats()[0].reads;
+        for _ in 0..3 {
+            assert_eq!(
+                wal.read(compressed).unwrap(),
+                json(0, 3 * BLOCK_SIZE as usize)
+            );
+        }
+        assert_eq!(reads(&wal), 1);
+        // Records stored as written are read from the file every time.
+        for _ in 0..3 {
+            assert_eq!(wal.read(plain).unwrap(), json(1, 100));
+        }
+        assert_eq!(reads(&wal), 4);
+        drop(wal);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn codec_switches_at_rotation() {
+        let codecs = [
+            #[cfg(feature = "lz4")]
+            Compression::Lz4,
+            #[cfg(feature = "zstd")]
+            Compression::Zstd(3),
+        ];
+        let dir = testing::temp_dir("compression_rotation");
+        let mut wal = Wal::open(Options::new(&dir, 4 * BLOCK_SIZE as u64)).unwrap();
+        let mut records = Vec::new();
+        let mut write = |wal: &mut Wal, i| {
+            let pos = wal.write(&json(i, 20_000)).unwrap();
+            records.push((pos, json(i, 20_000)));
+            pos
+        };
+        let compressed = |wal: &Wal, pos: ChunkPosition| {
+            let block = wal.read_block(pos.block_id()).unwrap();
+            let chunk = block
+                .chunks
+                .iter()
+                .find(|c| c.offset as u64 == pos.chunk_offset);
+            chunk.unwrap().compressed
+        };
+        for (i, compression) in codecs.into_iter().map(Some).chain([None]).enumerate() {
+            let first = write(&mut wal, i * 1000);
+            wal.set_compression(compression);
+            // The active segment goes on as it was.
+            let same = write(&mut wal, i * 1000 + 1);
+            assert_eq!(same.segment_id, first.segment_id);
+            assert_eq!(compressed(&wal, same), compressed(&wal, first));
+            let mut next = same;
+            let mut j = 2;
+            while next.segment_id == first.segment_id {
+                next = write(&mut wal, i * 1000 + j);
+                j += 1;
+            }
+            assert_eq!(compressed(&wal, next), compression.is_some());
+            assert_eq!(wal.compression(), compression);
+        }
+        assert_eq!(wal.format_info().compression, "none");
+        for (pos, data) in &records {
+            assert_eq!(&wal.read(*pos).unwrap(), data);
+        }
+        drop(wal);
+        let wal = Wal::open(Options::new(&dir, 4 * BLOCK_SIZE as u64)).unwrap();
+        for (pos, data) in &records {
+            assert_eq!(&wal.read(*pos).unwrap(), data);
+        }
+        drop(wal);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,160 @@
+//! Encryption of chunk payloads at rest, see
+//! [`crate::options::Options::with_encryption_key`].
+//!
+//! Every chunk is sealed with XChaCha20-Poly1305 under a random nonce, stored
+//! in front of the ciphertext, and authenticated together with its position
+//! and type byte, so a chunk moved elsewhere or retyped fails to open. The
+//! chunks of an encrypted record have [`crate::segment::ENCRYPTED`] set in
+//! their type byte.
+
+use chacha20poly1305::{
+    aead::{Aead, AeadCore, KeyInit, OsRng, Payload},
+    XChaCha20Poly1305, XNonce,
+};
+
+use crate::error::WalError;
+
+const NONCE_SIZE: usize = 24;
+const TAG_SIZE: usize = 16;
+
+/// Bytes an encrypted chunk's payload is longer than the plain one.
+pub(crate) const OVERHEAD: u32 = (NONCE_SIZE + TAG_SIZE) as u32;
+
+pub(crate) struct Cipher(XChaCha20Poly1305);
+
+impl Cipher {
+    pub(crate) fn new(key: &[u8; 32]) -> Self {
+        Cipher(XChaCha20Poly1305::new(key.into()))
+    }
+
+    /// `payload` of the chunk `at` sealed, nonce in front.
+    pub(crate) fn seal(&self, at: ChunkAt, payload: &[u8]) -> Vec<u8> {
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let aad = at.aad();
+        let ciphertext = self
+            .0
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: payload,
+                    aad: &aad,
+                },
+            )
+            .expect("payloads of a chunk are far below the cipher's limit");
+        [&nonce[..], &ciphertext].concat()
+    }
+
+    /// The payload `sealed` was sealed from by [`Cipher::seal`] at `at`.
+    pub(crate) fn open(&self, at: ChunkAt, sealed: &[u8]) -> Result<Vec<u8>, WalError> {
+        let failed = || WalError::DecryptionFailed {
+            segment_id: at.segment_id,
+            block_number: at.block_number,
+            offset: at.chunk_offset,
+        };
+        if sealed.len() < OVERHEAD as usize {
+            return Err(failed());
+        }
+        let (nonce, ciphertext) = sealed.split_at(NONCE_SIZE);
+        let aad = at.aad();
+        self.0
+            .decrypt(
+                XNonce::from_slice(nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad: &aad,
+                },
+            )
+            .map_err(|_| failed())
+    }
+}
+
+/// Where a chunk is and how it is typed, which its encryption is bound to.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct ChunkAt {
+    pub(crate) segment_id: u32,
+    pub(crate) block_number: u32,
+    pub(crate) chunk_offset: u64,
+    pub(crate) type_byte: u8,
+}
+
+impl ChunkAt {
+    fn aad(&self) -> [u8; 17] {
+        let mut aad = [0; 17];
+        aad[0..4].copy_from_slice(&self.segment_id.to_le_bytes());
+        aad[4..8].copy_from_slice(&self.block_number.to_le_bytes());
+        aad[8..16].copy_from_slice(&self.chunk_offset.to_le_bytes());
+        aad[16] = self.type_byte;
+        aad
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        error::WalError,
+        options::Options,
+        segment::{BLOCK_SIZE, CHUNK_HEADER_SIZE},
+        testing,
+        wal::{ChunkType, Wal},
+    };
+
+    #[test]
+    fn chunks_are_encrypted_at_rest() {
+        let dir = testing::temp_dir("encryption");
+        let opts = || Options::new(&dir, 64 * BLOCK_SIZE as u64);
+        let key = [7; 32];
+        let mut wal = Wal::open(opts()).unwrap();
+        let plain = wal.write(b"written in the clear").unwrap();
+        drop(wal);
+
+        let mut wal = Wal::open(opts().with_encryption_key(key)).unwrap();
+        let mut records = vec![(plain, b"written in the clear".to_vec())];
+        // Leave less room in the block than a chunk grows by, so the record
+        // after starts with empty chunks in the clear.
+        let used = wal.visible_position().unwrap().chunk_offset as u32;
+        let filler = BLOCK_SIZE - used - 2 * CHUNK_HEADER_SIZE - super::OVERHEAD - 30;
+        for data in [
+            b"secret".repeat(filler as usize / 6),
+            b"secret".repeat(BLOCK_SIZE as usize / 2),
+            Vec::new(),
+        ] {
+            records.push((wal.write(&data).unwrap(), data));
+        }
+        let block = wal.read_block(records[2].0.block_id()).unwrap();
+        let first = block
+            .chunks
+            .iter()
+            .position(|chunk| chunk.offset as u64 == records[2].0.chunk_offset)
+            .unwrap();
+        assert_eq!(block.chunks[first].chunk_type, ChunkType::First);
+        assert!(block.chunks[first..]
+            .iter()
+            .all(|chunk| chunk.data.is_empty()));
+        for (pos, data) in &records {
+            assert_eq!(&wal.read(*pos).unwrap(), data);
+        }
+        let replayed: Vec<_> = wal.reader().map(|record| record.unwrap()).collect();
+        assert_eq!(replayed.len(), records.len());
+        let block = wal.read_block_records(records[1].0.segment_id, 0).unwrap();
+        assert!(block.contains(&records[1]));
+        drop(wal);
+
+        let mut on_disk = Vec::new();
+        for entry in std::fs::read_dir(&dir).unwrap() {
+            on_disk.extend(std::fs::read(entry.unwrap().path()).unwrap());
+        }
+        assert!(!on_disk.windows(6).any(|w| w == b"secret"));
+
+        for opts in [opts(), opts().with_encryption_key([8; 32])] {
+            let wal = Wal::open(opts).unwrap();
+            assert_eq!(wal.read(plain).unwrap(), records[0].1);
+            assert!(matches!(
+                wal.read(records[1].0),
+                Err(WalError::DecryptionFailed { .. })
+            ));
+        }
+        let wal = Wal::open(opts().with_encryption_key(key)).unwrap();
+        assert_eq!(wal.read(records[2].0).unwrap(), records[2].1);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,139 @@
+//! Monotonic indexes of the records, see
+//! [`crate::options::Options::with_entry_index`].
+//!
+//! Every segment's records are numbered on from the index of its first
+//! record, which is persisted per segment, so numbers stay the same once the
+//! segments before are removed. The positions themselves are rebuilt by
+//! reading the segments when the Wal is opened.
+
+use std::{collections::BTreeMap, io::Write, path::Path};
+
+use crate::{
+    error::{IoResultExt, WalError},
+    segment::ChunkPosition,
+};
+
+pub(crate) const ENTRY_INDEX_FILE_NAME: &str = "ENTRY_INDEX";
+
+/// The positions of the records by index.
+#[derive(Debug, Default)]
+pub(crate) struct EntryIndex {
+    /// By segment id, the index of the segment's first record and the
+    /// positions of its records in order.
+    segments: BTreeMap<u32, (u64, Vec<ChunkPosition>)>,
+}
+
+impl EntryIndex {
+    /// Start indexing the segment `segment_id`, numbering its records from
+    /// `first`, or on from the segments before it.
+    pub(crate) fn add_segment(&mut self, segment_id: u32, first: Option<u64>) {
+        let first = first.unwrap_or_else(|| self.next());
+        self.segments.insert(segment_id, (first, Vec::new()));
+    }
+
+    /// Index a record appended after every indexed one, and return whether
+    /// it started a segment.
+    pub(crate) fn push(&mut self, pos: ChunkPosition) -> bool {
+        let started = !self.segments.contains_key(&pos.segment_id);
+        if started {
+            self.add_segment(pos.segment_id, None);
+        }
+        self.segments.get_mut(&pos.segment_id).unwrap().1.push(pos);
+        started
+    }
+
+    /// The index the next record gets.
+    pub(crate) fn next(&self) -> u64 {
+        self.segments
+            .values()
+            .next_back()
+            .map_or(0, |(first, positions)| first + positions.len() as u64)
+    }
+
+    /// The index of the last record, `None` if there is none.
+    pub(crate) fn last(&self) -> Option<u64> {
+        self.segments
+            .values()
+            .rev()
+            .find(|(_, positions)| !positions.is_empty())
+            .map(|(first, positions)| first + positions.len() as u64 - 1)
+    }
+
+    pub(crate) fn position(&self, index: u64) -> Option<ChunkPosition> {
+        let (first, positions) = self
+            .segments
+            .values()
+            .rev()
+            .find(|(first, _)| *first <= index)?;
+        positions.get(usize::try_from(index - first).ok()?).copied()
+    }
+
+    /// The index of the record at `pos`, `None` if no record starts there.
+    pub(crate) fn index_of(&self, pos: ChunkPosition) -> Option<u64> {
+        let (first, positions) = self.segments.get(&pos.segment_id)?;
+        let i = positions.binary_search(&pos).ok()?;
+        Some(first + i as u64)
+    }
+
+    /// Drop the segments before `segment_id`, and return whether there were
+    /// any.
+    pub(crate) fn forget_before(&mut self, segment_id: u32) -> bool {
+        let kept = self.segments.split_off(&segment_id);
+        let forgot = !self.segments.is_empty();
+        self.segments = kept;
+        forgot
+    }
+
+    /// Drop the records after `pos`.
+    pub(crate) fn forget_after(&mut self, pos: ChunkPosition) {
+        self.segments.split_off(&(pos.segment_id + 1));
+        if let Some((_, positions)) = self.segments.get_mut(&pos.segment_id) {
+            positions.retain(|p| *p <= pos);
+        }
+    }
+}
+
+/// The index of the first record of every segment persisted in `dir_path`,
+/// by segment id.
+pub(crate) fn load(dir_path: impl AsRef<Path>) -> Result<BTreeMap<u32, u64>, WalError> {
+    let path = dir_path.as_ref().join(ENTRY_INDEX_FILE_NAME);
+    let content = match std::fs::read_to_string(&path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(BTreeMap::new()),
+        Err(e) => return Err(e).context("read", &path),
+    };
+    content
+        .lines()
+        .map(|line| {
+            let mut fields = line.split(' ');
+            let parsed = (|| {
+                let id = fields.next()?.parse().ok()?;
+                let first = fields.next()?.parse().ok()?;
+                fields.next().is_none().then_some((id, first))
+            })();
+            parsed.ok_or_else(|| WalError::InvalidManifest(format!("invalid entry index {line}")))
+        })
+        .collect()
+}
+
+/// The contents of the file persisting the first indexes of `index`.
+pub(crate) fn contents(index: &EntryIndex) -> String {
+    index
+        .segments
+        .iter()
+        .map(|(id, (first, _))| format!("{id} {first}\n"))
+        .collect()
+}
+
+/// Atomically replace the first indexes persisted in `dir_path` with the
+/// ones of `index`.
+pub(crate) fn store(dir_path: impl AsRef<Path>, index: &EntryIndex) -> Result<(), WalError> {
+    let path = dir_path.as_ref().join(ENTRY_INDEX_FILE_NAME);
+    let tmp = path.with_extension("tmp");
+    let mut file = std::fs::File::create(&tmp).context("create", &tmp)?;
+    file.write_all(contents(index).as_bytes())
+        .context("write", &tmp)?;
+    file.sync_all().context("fsync", &tmp)?;
+    std::fs::rename(&tmp, &path).context("rename", &tmp)?;
+    Ok(())
+}
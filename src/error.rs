@@ -1,9 +1,16 @@
+use std::path::{Path, PathBuf};
+
 use thiserror::Error;
 
 #[derive(Error, Debug)]
 pub enum WalError {
-    #[error("Open file failed")]
-    OpenFileFailed(#[from] std::io::Error),
+    #[error("{op} {} failed: {source}", path.display())]
+    Io {
+        op: &'static str,
+        path: PathBuf,
+        #[source]
+        source: std::io::Error,
+    },
 
     #[error("OsString to String failed")]
     FileNameCovertFailed,
@@ -13,4 +20,214 @@ pub enum WalError {
 
     #[error("Segment file not found")]
     SegmentFileNotFound,
+
+    #[error("Wal is frozen")]
+    Frozen,
+
+    #[error("Record transform failed")]
+    TransformFailed(Box<dyn std::error::Error + Send + Sync>),
+
+    #[error("Invalid crc at segment {segment_id}, block {block_number}, offset {offset}")]
+    InvalidCrc {
+        segment_id: u32,
+        block_number: u32,
+        offset: u64,
+    },
+
+    #[error("Invalid manifest: {0}")]
+    InvalidManifest(String),
+
+    #[error("Option {field} is {requested}, but the wal was created with {on_disk}")]
+    OptionsMismatch {
+        field: &'static str,
+        on_disk: String,
+        requested: String,
+    },
+
+    #[error("Rotating to segment {segment_id} failed")]
+    RotationFailed {
+        segment_id: u32,
+        #[source]
+        source: Box<WalError>,
+    },
+
+    #[error("Record written at segment {segment_id}, block {block_number}, offset {offset} reads back different")]
+    WriteVerificationFailed {
+        segment_id: u32,
+        block_number: u32,
+        offset: u64,
+    },
+
+    #[error("A thread panicked while holding the wal lock")]
+    Poisoned,
+
+    #[error("Record at segment {segment_id}, block {block_number}, offset {offset} is incomplete")]
+    IncompleteRecord {
+        segment_id: u32,
+        block_number: u32,
+        offset: u64,
+    },
+
+    #[error("The wal was opened read-only")]
+    ReadOnly,
+
+    #[error("Invalid chunk type {chunk_type} at segment {segment_id}, block {block_number}, offset {offset}")]
+    InvalidChunkType {
+        segment_id: u32,
+        block_number: u32,
+        offset: u64,
+        chunk_type: u8,
+    },
+
+    /// A bug in the wal, see `Options::with_debug_assertions_as_errors`.
+    #[error("Internal invariant violated: {0}")]
+    InvariantViolated(&'static str),
+
+    #[error("Record versions need Options::with_record_versions")]
+    RecordVersionsDisabled,
+
+    /// The position is in the padding at the end of a block, or otherwise
+    /// not where a record starts.
+    #[error("No record starts at segment {segment_id}, block {block_number}, offset {offset}")]
+    InvalidPosition {
+        segment_id: u32,
+        block_number: u32,
+        offset: u64,
+    },
+
+    #[error("Invalid archive: {0}")]
+    InvalidArchive(String),
+
+    /// Found by [`crate::options::Options::with_tail_check`].
+    #[error("Segment {segment_id} of {len} bytes doesn't end on a chunk boundary, the last is at offset {offset}")]
+    MisalignedTail {
+        segment_id: u32,
+        offset: u64,
+        len: u64,
+    },
+
+    #[error("Invalid options: {0}")]
+    InvalidOptions(String),
+
+    /// A write reached a segment the Wal has already rotated past.
+    #[error("Segment {segment_id} is sealed")]
+    SegmentSealed { segment_id: u32 },
+
+    /// See [`crate::options::Options::with_compression`].
+    #[error("Compression failed: {0}")]
+    CompressionFailed(String),
+
+    /// The chunk failed to authenticate under the key, or there is no key,
+    /// see [`crate::options::Options::with_encryption_key`].
+    #[error(
+        "Can't decrypt the chunk at segment {segment_id}, block {block_number}, offset {offset}"
+    )]
+    DecryptionFailed {
+        segment_id: u32,
+        block_number: u32,
+        offset: u64,
+    },
+
+    /// See [`crate::options::Options::with_content_index`].
+    #[error("The content index is disabled")]
+    ContentIndexDisabled,
+
+    /// Truncating after the record would cut its batch short, see
+    /// [`crate::wal::Wal::truncate_after`].
+    #[error(
+        "The record at segment {segment_id}, block {block_number}, offset {offset} doesn't end its batch"
+    )]
+    TruncateInBatch {
+        segment_id: u32,
+        block_number: u32,
+        offset: u64,
+    },
+
+    /// A file named like a segment has no valid id, see
+    /// [`crate::options::Options::with_invalid_segment_names`].
+    #[error("Invalid segment file name {}", name.display())]
+    InvalidSegmentFileName { name: PathBuf },
+
+    /// A chunk header claims more data than its block holds, it is corrupted.
+    #[error("Chunk at segment {segment_id}, block {block_number}, offset {offset} claims {length} bytes, past the end of its block")]
+    InvalidChunkLength {
+        segment_id: u32,
+        block_number: u32,
+        offset: u64,
+        length: u16,
+    },
+
+    /// See [`crate::options::Options::with_entry_index`].
+    #[error("The entry index is disabled")]
+    EntryIndexDisabled,
+
+    /// No record has the index, it was removed or not written yet.
+    #[error("No record has index {index}")]
+    NoSuchEntry { index: u64 },
+
+    /// See [`crate::segment::ChunkPosition::decode`].
+    #[error("Invalid encoded position: {0}")]
+    InvalidEncodedPosition(String),
+
+    /// A Wal opened with [`crate::wal::Wal::open_file`] has no room left for
+    /// the record, and can't rotate to another segment.
+    #[error("The log file is full")]
+    LogFull,
+
+    /// A Wal opened with [`crate::wal::Wal::open_file`] has no directory to
+    /// do this in.
+    #[error("{op} needs a Wal directory")]
+    NoDirectory { op: &'static str },
+
+    /// Another Wal is open for writing in the directory, in this process or
+    /// another one.
+    #[error("Wal directory {path:?} is locked by another writer")]
+    DirectoryLocked { path: std::path::PathBuf },
+
+    /// The shared async Wal was closed.
+    #[error("The Wal is closed")]
+    Closed,
+
+    /// A batch holds more bytes than `Options::with_max_batch_bytes` allows.
+    #[error("Batch of {bytes} bytes is over the maximum of {max}")]
+    BatchTooLarge { bytes: u64, max: u64 },
+
+    /// A part of a batch split by `OversizedBatch::Split` failed after the
+    /// parts before it, whose positions are in `written`, were written.
+    #[error("Split batch failed after {} of its records were written", written.len())]
+    BatchPartlyWritten {
+        written: Vec<crate::segment::ChunkPosition>,
+        #[source]
+        source: Box<WalError>,
+    },
+
+    /// The record comes before the start of the log, it was discarded by
+    /// [`crate::wal::Wal::truncate_before`].
+    #[error("The record at segment {segment_id}, block {block_number}, offset {offset} is before the start of the log")]
+    BeforeStart {
+        segment_id: u32,
+        block_number: u32,
+        offset: u64,
+    },
+
+    /// [`crate::wal::Wal::open_file`] was given something other than a
+    /// regular file, e.g. a block device, whose length doesn't tell where
+    /// the log ends.
+    #[error("{path:?} is not a regular file")]
+    NotARegularFile { path: std::path::PathBuf },
+}
+
+/// Attach the operation and file involved to an io error.
+pub(crate) trait IoResultExt<T> {
+    fn context(self, op: &'static str, path: impl AsRef<Path>) -> Result<T, WalError>;
+}
+
+impl<T> IoResultExt<T> for std::io::Result<T> {
+    fn context(self, op: &'static str, path: impl AsRef<Path>) -> Result<T, WalError> {
+        self.map_err(|source| WalError::Io {
+            op,
+            path: path.as_ref().to_path_buf(),
+            source,
+        })
+    }
 }
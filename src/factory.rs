@@ -0,0 +1,96 @@
+use std::path::Path;
+
+use crate::{
+    error::{IoResultExt, WalError},
+    platform,
+};
+
+/// Creates new segment files, for deployments whose filesystems need more
+/// than a plain create to make a new file durable or atomically visible.
+///
+/// `create` is called for every segment file the Wal creates, before the Wal
+/// opens it for appending; it is never called for files which already exist,
+/// nor in read-only mode. By default the Wal creates files itself.
+pub trait SegmentFactory: Send + Sync {
+    /// Create an empty file at `path`, which doesn't exist yet.
+    fn create(&self, path: &Path) -> Result<(), WalError>;
+}
+
+/// Create a segment under a temporary name, fsync it, rename it into place
+/// and fsync the directory, so a crash never leaves a segment file which
+/// isn't durable.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TempRename;
+
+impl SegmentFactory for TempRename {
+    fn create(&self, path: &Path) -> Result<(), WalError> {
+        let tmp = path.with_extension("tmp");
+        let file = std::fs::File::create(&tmp).context("create", &tmp)?;
+        file.sync_all().context("fsync", &tmp)?;
+        std::fs::rename(&tmp, path).context("rename", &tmp)?;
+        if let Some(dir) = path.parent() {
+            platform::sync_dir(dir).context("fsync", dir)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::{options::Options, segment::BLOCK_SIZE, testing, wal::Wal};
+
+    /// Records the files it creates.
+    #[derive(Clone, Default)]
+    struct Recording(Arc<Mutex<Vec<std::path::PathBuf>>>);
+
+    impl SegmentFactory for Recording {
+        fn create(&self, path: &Path) -> Result<(), WalError> {
+            self.0.lock().unwrap().push(path.to_path_buf());
+            TempRename.create(path)
+        }
+    }
+
+    #[test]
+    fn creates_new_segments() {
+        let dir = testing::temp_dir("segment_factory");
+        let factory = Recording::default();
+        let opts = Options::new(&dir, BLOCK_SIZE as u64).with_segment_factory(factory.clone());
+        let mut wal = Wal::open(opts.clone()).unwrap();
+        let first = wal.write(&[1; 100]).unwrap();
+        let second = wal.write(&[2; BLOCK_SIZE as usize]).unwrap();
+        assert_ne!(first.segment_id, second.segment_id);
+        drop(wal);
+
+        let wal = Wal::open(opts).unwrap();
+        assert_eq!(wal.read(second).unwrap(), vec![2; BLOCK_SIZE as usize]);
+        let created = factory.0.lock().unwrap().clone();
+        assert!(created.len() >= 2);
+        assert!(created.iter().all(|path| path.is_file()));
+        assert!(!created
+            .iter()
+            .any(|path| path.with_extension("tmp").exists()));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    struct Failing;
+
+    impl SegmentFactory for Failing {
+        fn create(&self, _: &Path) -> Result<(), WalError> {
+            Err(WalError::InvariantViolated("no segments here"))
+        }
+    }
+
+    #[test]
+    fn failures_fail_the_open() {
+        let dir = testing::temp_dir("segment_factory_failing");
+        let opts = Options::new(&dir, 1024 * 1024).with_segment_factory(Failing);
+        assert!(matches!(
+            Wal::open(opts),
+            Err(WalError::InvariantViolated(_))
+        ));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,60 @@
+//! Crash injection points, compiled into the library with the `failpoints` feature.
+//!
+//! A failpoint is armed through the `WAL_RS_FAILPOINT` environment variable as
+//! `<name>=<n>`: the process aborts the `n`-th time the named point is reached.
+
+/// Abort the process here when the named failpoint is armed.
+///
+/// The optional block runs right before the abort, e.g. to leave a torn write behind.
+macro_rules! fail_point {
+    ($name:expr) => {
+        fail_point!($name, {})
+    };
+    ($name:expr, $before_crash:block) => {
+        #[cfg(feature = "failpoints")]
+        if $crate::failpoint::triggered($name) {
+            $before_crash;
+            std::process::abort();
+        }
+    };
+}
+
+#[cfg(feature = "failpoints")]
+pub(crate) const FAILPOINT_ENV: &str = "WAL_RS_FAILPOINT";
+
+/// Every failpoint in the crate.
+#[cfg(feature = "failpoints")]
+pub const FAILPOINTS: &[&str] = &[
+    "segment::before_chunk",
+    "segment::torn_chunk",
+    "segment::before_padding",
+    "wal::before_rotate",
+    "wal::after_rotate",
+    "wal::before_batch_sync",
+    "wal::before_truncate_cut",
+    "wal::before_truncate_remove",
+    "wal::before_truncate_purge",
+    "manifest::before_rename",
+];
+
+#[cfg(feature = "failpoints")]
+pub(crate) fn triggered(name: &str) -> bool {
+    use std::sync::{
+        atomic::{AtomicU64, Ordering},
+        OnceLock,
+    };
+    static ARMED: OnceLock<Option<(String, u64)>> = OnceLock::new();
+    static HITS: AtomicU64 = AtomicU64::new(0);
+
+    let armed = ARMED.get_or_init(|| {
+        let value = std::env::var(FAILPOINT_ENV).ok()?;
+        let (name, n) = value.split_once('=')?;
+        Some((name.to_string(), n.parse().ok()?))
+    });
+    match armed {
+        Some((armed_name, n)) if armed_name == name => {
+            HITS.fetch_add(1, Ordering::SeqCst) + 1 == *n
+        }
+        _ => false,
+    }
+}
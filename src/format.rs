@@ -0,0 +1,32 @@
+//! Constants of the on-disk format, and the format of an opened Wal for
+//! tooling, logging and compatibility checks, see [`crate::wal::Wal::format_info`].
+
+pub use crate::{
+    manifest::FORMAT_VERSION,
+    segment::{
+        BLOCK_SIZE, BLOCK_TRAILER_SIZE, CHUNK_HEADER_SIZE, MAX_BLOCK_SIZE, MIN_BLOCK_SIZE,
+        SEGMENT_FILE_SUFFIX,
+    },
+};
+
+/// The format a Wal directory was created with.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FormatInfo {
+    pub version: u32,
+    pub block_size: u32,
+    pub chunk_header_size: u32,
+    /// Checksum of every chunk, e.g. `crc32`.
+    pub checksum: String,
+    /// Compression of the segments as a whole, a legacy field which is always
+    /// `none`: records are compressed one by one, each naming its codec. The
+    /// one in use is [`crate::wal::Wal::compression`].
+    pub compression: String,
+    pub block_trailers: bool,
+    /// Whether every record starts with a version byte.
+    pub record_versions: bool,
+    /// Chunks start at multiples of this many bytes into their block.
+    pub chunk_alignment: u32,
+    /// How the active segment is preallocated, see
+    /// [`crate::options::Options::with_preallocation`].
+    pub preallocation: crate::options::Preallocation,
+}
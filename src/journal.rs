@@ -0,0 +1,192 @@
+//! A journal interface independent of this crate, see [`Journal`], so code
+//! built on a write-ahead log, such as an embedded KV store, can take any
+//! implementation of one, e.g. to compare them in benchmarks.
+
+use std::collections::VecDeque;
+
+use crate::{
+    error::WalError,
+    segment::ChunkPosition,
+    wal::{ReplayControl, Wal},
+};
+
+/// An append-only log of records, addressed by the positions appending
+/// returns.
+pub trait Journal {
+    /// Where a record is, ordered the same way the records were appended.
+    type Position: Copy + Ord + std::fmt::Debug;
+    type Error: std::error::Error + Send + Sync + 'static;
+
+    fn append(&mut self, record: &[u8]) -> Result<Self::Position, Self::Error>;
+
+    /// Append the records as a unit where the journal supports it, one at a
+    /// time otherwise.
+    fn append_batch(&mut self, records: &[&[u8]]) -> Result<Vec<Self::Position>, Self::Error> {
+        records.iter().map(|record| self.append(record)).collect()
+    }
+
+    fn read(&self, pos: Self::Position) -> Result<Vec<u8>, Self::Error>;
+
+    /// Make every appended record durable.
+    fn sync(&mut self) -> Result<(), Self::Error>;
+
+    /// Feed every record to `apply` in order, until it returns `false`.
+    fn replay(
+        &self,
+        apply: &mut dyn FnMut(Self::Position, &[u8]) -> bool,
+    ) -> Result<(), Self::Error>;
+
+    /// Discard records before `pos`, once they are no longer needed. A
+    /// journal may keep some of them, e.g. the rest of a segment.
+    fn discard_before(&mut self, pos: Self::Position) -> Result<(), Self::Error>;
+}
+
+impl Journal for Wal {
+    type Position = ChunkPosition;
+    type Error = WalError;
+
+    fn append(&mut self, record: &[u8]) -> Result<ChunkPosition, WalError> {
+        self.write(record)
+    }
+
+    fn append_batch(&mut self, records: &[&[u8]]) -> Result<Vec<ChunkPosition>, WalError> {
+        self.write_batch(records)
+    }
+
+    fn read(&self, pos: ChunkPosition) -> Result<Vec<u8>, WalError> {
+        Wal::read(self, pos)
+    }
+
+    fn sync(&mut self) -> Result<(), WalError> {
+        Wal::sync(self)
+    }
+
+    fn replay(&self, apply: &mut dyn FnMut(ChunkPosition, &[u8]) -> bool) -> Result<(), WalError> {
+        Wal::replay(self, |pos, data| {
+            Ok(match apply(pos, data) {
+                true => ReplayControl::Continue,
+                false => ReplayControl::Stop,
+            })
+        })
+    }
+
+    /// Starts the log at `pos`, see [`Wal::truncate_before`].
+    fn discard_before(&mut self, pos: ChunkPosition) -> Result<(), WalError> {
+        self.truncate_before(pos)
+    }
+}
+
+/// A [`Journal`] held in memory, e.g. as a baseline in benchmarks or a
+/// stand-in in tests. Records are numbered from 0 and never durable.
+#[derive(Debug, Default)]
+pub struct MemoryJournal {
+    records: VecDeque<Vec<u8>>,
+    /// Number of the first record in `records`.
+    first: u64,
+}
+
+impl MemoryJournal {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+/// A record of a [`MemoryJournal`] which was discarded or not appended yet.
+#[derive(Debug, thiserror::Error)]
+#[error("No record {0}")]
+pub struct NoRecord(pub u64);
+
+impl Journal for MemoryJournal {
+    type Position = u64;
+    type Error = NoRecord;
+
+    fn append(&mut self, record: &[u8]) -> Result<u64, NoRecord> {
+        self.records.push_back(record.to_vec());
+        Ok(self.first + self.records.len() as u64 - 1)
+    }
+
+    fn read(&self, pos: u64) -> Result<Vec<u8>, NoRecord> {
+        pos.checked_sub(self.first)
+            .and_then(|i| self.records.get(i as usize))
+            .cloned()
+            .ok_or(NoRecord(pos))
+    }
+
+    fn sync(&mut self) -> Result<(), NoRecord> {
+        Ok(())
+    }
+
+    fn replay(&self, apply: &mut dyn FnMut(u64, &[u8]) -> bool) -> Result<(), NoRecord> {
+        for (pos, record) in (self.first..).zip(&self.records) {
+            if !apply(pos, record) {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    fn discard_before(&mut self, pos: u64) -> Result<(), NoRecord> {
+        while self.first < pos && !self.records.is_empty() {
+            self.records.pop_front();
+            self.first += 1;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{options::Options, segment::BLOCK_SIZE, testing};
+
+    /// What a storage crate would do with whichever journal it is given.
+    fn exercise<J: Journal>(journal: &mut J) {
+        let records: Vec<Vec<u8>> = (0..200).map(|i| testing::payload(0, i, 3000)).collect();
+        let mut positions = Vec::new();
+        for chunk in records.chunks(10) {
+            positions.push(journal.append(&chunk[0]).unwrap());
+            let batch: Vec<&[u8]> = chunk[1..].iter().map(|r| &r[..]).collect();
+            positions.extend(journal.append_batch(&batch).unwrap());
+        }
+        journal.sync().unwrap();
+        assert!(positions.windows(2).all(|w| w[0] < w[1]));
+        for (pos, record) in positions.iter().zip(&records) {
+            assert_eq!(&journal.read(*pos).unwrap(), record);
+        }
+
+        let mut replayed = Vec::new();
+        journal
+            .replay(&mut |pos, data| {
+                replayed.push((pos, data.to_vec()));
+                true
+            })
+            .unwrap();
+        let expected: Vec<_> = positions.iter().copied().zip(records.clone()).collect();
+        assert_eq!(replayed, expected);
+        let mut seen = 0;
+        journal
+            .replay(&mut |_, _| {
+                seen += 1;
+                seen < 5
+            })
+            .unwrap();
+        assert_eq!(seen, 5);
+
+        journal.discard_before(positions[150]).unwrap();
+        assert!(journal.read(positions[0]).is_err());
+        for (pos, record) in positions.iter().zip(&records).skip(150) {
+            assert_eq!(&journal.read(*pos).unwrap(), record);
+        }
+    }
+
+    #[test]
+    fn journals_are_interchangeable() {
+        exercise(&mut MemoryJournal::new());
+
+        let dir = testing::temp_dir("journal_wal");
+        let mut wal = Wal::open(Options::new(&dir, 8 * BLOCK_SIZE as u64)).unwrap();
+        exercise(&mut wal);
+        drop(wal);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
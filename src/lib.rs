@@ -1,4 +1,33 @@
-mod error;
-mod options;
-mod segment;
+#[macro_use]
+mod failpoint;
+
+mod archive;
+mod cache;
+pub mod clock;
+pub mod compression;
+#[cfg(feature = "encryption")]
+mod encryption;
+mod entry_index;
+pub mod error;
+pub mod factory;
+pub mod format;
+pub mod journal;
+pub mod maintenance;
+mod manifest;
+#[cfg(all(feature = "mmap", not(unix)))]
+compile_error!("the mmap feature is only supported on unix");
+#[cfg(feature = "mmap")]
+mod mmap;
+pub mod options;
+mod platform;
+#[cfg(feature = "raft")]
+pub mod raft_storage;
+pub mod segment;
+pub mod stats;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+pub mod transform;
 pub mod wal;
+
+#[cfg(feature = "failpoints")]
+pub use failpoint::FAILPOINTS;
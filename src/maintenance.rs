@@ -0,0 +1,192 @@
+//! Background upkeep of a Wal shared between threads, see
+//! [`crate::wal::Wal::start_maintenance`].
+
+use std::{
+    path::Path,
+    sync::{Arc, Condvar, Mutex, PoisonError, RwLock, Weak},
+    thread::JoinHandle,
+    time::Duration,
+};
+
+use crate::{
+    error::{IoResultExt, WalError},
+    wal::Wal,
+};
+
+/// Handle to the maintenance thread of a Wal. Dropping it leaves the thread
+/// running until the Wal is closed or dropped.
+pub struct Maintenance {
+    pub(crate) shared: Arc<Shared>,
+}
+
+impl Maintenance {
+    /// Stop the thread and wait for it, failing with the error it stopped
+    /// on, if it did. Not to be called while holding the Wal's lock, which
+    /// the thread may be waiting for.
+    pub fn stop(self) -> Result<(), WalError> {
+        self.shared.stop()
+    }
+}
+
+/// What the thread shares with the Wal and its [`Maintenance`] handle,
+/// either of which stops it.
+#[derive(Default)]
+pub(crate) struct Shared {
+    stopped: Mutex<bool>,
+    wake: Condvar,
+    thread: Mutex<Option<JoinHandle<Result<(), WalError>>>>,
+}
+
+impl Shared {
+    /// Whether the thread was started and hasn't finished.
+    pub(crate) fn running(&self) -> bool {
+        self.thread
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .as_ref()
+            .is_some_and(|thread| !thread.is_finished())
+    }
+
+    /// Stop the thread and wait for it, unless called on the thread itself,
+    /// e.g. when it let go of the last reference to the Wal. A thread which
+    /// panicked is reported as `WalError::Poisoned`.
+    pub(crate) fn stop(&self) -> Result<(), WalError> {
+        *self.stopped.lock().unwrap_or_else(PoisonError::into_inner) = true;
+        self.wake.notify_all();
+        let thread = self
+            .thread
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .take();
+        match thread {
+            Some(thread) if thread.thread().id() != std::thread::current().id() => {
+                thread.join().unwrap_or(Err(WalError::Poisoned))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Wait for `every` to pass, and return whether the thread was stopped
+    /// meanwhile.
+    fn wait(&self, every: Duration) -> bool {
+        let stopped = self.stopped.lock().unwrap_or_else(PoisonError::into_inner);
+        let (stopped, _) = self
+            .wake
+            .wait_timeout_while(stopped, every, |stopped| !*stopped)
+            .unwrap_or_else(PoisonError::into_inner);
+        *stopped
+    }
+}
+
+/// Start the `wal-maintenance` thread, running a round of upkeep on `wal`
+/// every `every`. It only holds on to the Wal during a round, and stops at
+/// the first error, or once the Wal is gone.
+pub(crate) fn spawn(
+    wal: Weak<RwLock<Wal>>,
+    every: Duration,
+    dir_path: &Path,
+) -> Result<Maintenance, WalError> {
+    let shared = Arc::new(Shared::default());
+    let thread_shared = shared.clone();
+    let thread = std::thread::Builder::new()
+        .name("wal-maintenance".to_string())
+        .spawn(move || loop {
+            if thread_shared.wait(every) {
+                return Ok(());
+            }
+            let Some(wal) = wal.upgrade() else {
+                return Ok(());
+            };
+            let mut guard = wal.write().map_err(|_| WalError::Poisoned)?;
+            guard.maintain()?;
+        })
+        .context("start maintenance of", dir_path)?;
+    *shared.thread.lock().unwrap_or_else(PoisonError::into_inner) = Some(thread);
+    Ok(Maintenance { shared })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        options::{Options, RetentionPolicy, SyncPolicy},
+        segment::BLOCK_SIZE,
+        stats, testing,
+    };
+
+    #[test]
+    fn maintains_until_closed() {
+        let dir = testing::temp_dir("wal_maintenance");
+        let clock = testing::ManualClock::new();
+        let hour = std::time::Duration::from_secs(3600);
+        let every = std::time::Duration::from_millis(1);
+        let opts = Options::new(&dir, 4 * BLOCK_SIZE as u64)
+            .with_retention(RetentionPolicy::default().with_max_age(hour))
+            .with_sync_policy(SyncPolicy::EveryInterval(hour))
+            .with_read_stats_interval(hour)
+            .with_clock(clock.clone());
+        let mut wal = Wal::open(opts).unwrap();
+        let records = testing::write_records(&mut wal, 0, 100, 3000);
+        wal.read(records[0].pos).unwrap();
+        let wal = Arc::new(RwLock::new(wal));
+        let maintenance = Wal::start_maintenance(&wal, every).unwrap();
+        assert!(matches!(
+            Wal::start_maintenance(&wal, every),
+            Err(WalError::InvalidOptions(_))
+        ));
+        let name = maintenance
+            .shared
+            .thread
+            .lock()
+            .unwrap()
+            .as_ref()
+            .unwrap()
+            .thread()
+            .name()
+            .map(str::to_owned);
+        assert_eq!(name.as_deref(), Some("wal-maintenance"));
+        let stats_path = dir.join(stats::READ_STATS_FILE_NAME);
+
+        // Nothing is due yet.
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        {
+            let wal = wal.read().unwrap();
+            assert!(wal.segments().len() > 2);
+            assert!(!wal.unsynced_segments().is_empty());
+        }
+        assert!(!stats_path.exists());
+
+        // Once it is, the thread gets to it without anything else going on.
+        clock.advance(2 * hour);
+        let deadline = std::time::Instant::now() + std::time::Duration::from_secs(10);
+        loop {
+            {
+                let wal = wal.read().unwrap();
+                if wal.segments().len() == 1
+                    && wal.unsynced_segments().is_empty()
+                    && stats_path.exists()
+                {
+                    break;
+                }
+            }
+            assert!(std::time::Instant::now() < deadline, "not maintained");
+            std::thread::sleep(std::time::Duration::from_millis(1));
+        }
+        maintenance.stop().unwrap();
+
+        // Closing the Wal stops a thread started again and waits for it.
+        let maintenance = Wal::start_maintenance(&wal, every).unwrap();
+        let mut wal = wal;
+        let wal = loop {
+            match Arc::try_unwrap(wal) {
+                Ok(wal) => break wal.into_inner().unwrap(),
+                // In the middle of a round.
+                Err(shared) => wal = shared,
+            }
+        };
+        wal.close().unwrap();
+        assert!(maintenance.shared.thread.lock().unwrap().is_none());
+        maintenance.stop().unwrap();
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,482 @@
+use std::{io::Write, path::Path};
+
+use crate::{
+    error::{IoResultExt, WalError},
+    format::FormatInfo,
+    options::{Options, Preallocation},
+    platform::PositionalIo,
+    segment::{ChunkPosition, CHUNK_HEADER_SIZE, SEGMENT_FILE_SUFFIX},
+};
+
+pub(crate) const MANIFEST_FILE_NAME: &str = "MANIFEST";
+/// Where the log logically starts, see [`crate::wal::Wal::truncate_before`].
+pub(crate) const START_FILE_NAME: &str = "START";
+/// The id of the Wal and how often it was opened, see [`load_identity`].
+pub(crate) const IDENTITY_FILE_NAME: &str = "IDENTITY";
+/// Locked by the Wal writing to the directory, see [`lock_dir`].
+pub(crate) const LOCK_FILE_NAME: &str = "LOCK";
+/// Key of the lines indexing segment ids, see [`Manifest::load_segment_index`].
+const SEGMENT_KEY: &str = "segment";
+/// Version of the on-disk format.
+pub const FORMAT_VERSION: u32 = 1;
+
+/// The format a Wal directory was created with, stored as `key=value` lines.
+///
+/// Reopening a directory with options that would write an incompatible format
+/// fails, instead of producing segments the other side can't read.
+///
+/// The file also indexes the ids of the segments, one `segment=<id>` line
+/// appended before each segment file is created, so opening a directory with
+/// many segments doesn't have to list it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Manifest {
+    pub(crate) version: u32,
+    pub(crate) block_size: u32,
+    pub(crate) checksum: String,
+    /// Always `none`, see [`FormatInfo::compression`].
+    pub(crate) compression: String,
+    pub(crate) segment_suffix: String,
+    pub(crate) block_trailers: bool,
+    pub(crate) record_versions: bool,
+    pub(crate) chunk_alignment: u32,
+    pub(crate) preallocation: Preallocation,
+}
+
+impl Manifest {
+    /// The format the options would write.
+    pub(crate) fn for_options(options: &Options) -> Self {
+        Self {
+            version: FORMAT_VERSION,
+            block_size: options.block_len(),
+            checksum: "crc32".to_string(),
+            compression: "none".to_string(),
+            segment_suffix: SEGMENT_FILE_SUFFIX.to_string(),
+            block_trailers: options.block_trailers,
+            record_versions: options.record_versions,
+            chunk_alignment: options.chunk_alignment(),
+            preallocation: options.preallocation(),
+        }
+    }
+
+    pub(crate) fn format_info(&self) -> FormatInfo {
+        FormatInfo {
+            version: self.version,
+            block_size: self.block_size,
+            chunk_header_size: CHUNK_HEADER_SIZE,
+            checksum: self.checksum.clone(),
+            compression: self.compression.clone(),
+            block_trailers: self.block_trailers,
+            record_versions: self.record_versions,
+            chunk_alignment: self.chunk_alignment,
+            preallocation: self.preallocation,
+        }
+    }
+
+    pub(crate) fn load(dir_path: impl AsRef<Path>) -> Result<Option<Self>, WalError> {
+        let path = dir_path.as_ref().join(MANIFEST_FILE_NAME);
+        let content = match std::fs::read_to_string(&path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).context("read", &path),
+        };
+        let mut fields = std::collections::HashMap::new();
+        for line in complete_lines(&content).filter(|line| !line.is_empty()) {
+            let (key, value) = line
+                .split_once('=')
+                .ok_or_else(|| WalError::InvalidManifest(format!("malformed line {:?}", line)))?;
+            if key != SEGMENT_KEY {
+                fields.insert(key, value);
+            }
+        }
+        let field = |key: &str| {
+            fields
+                .get(key)
+                .map(|v| v.to_string())
+                .ok_or_else(|| WalError::InvalidManifest(format!("missing {}", key)))
+        };
+        // Fields added after the first format default to what came before them.
+        let field_or = |key: &str, default: &str| {
+            Ok::<_, WalError>(fields.get(key).copied().unwrap_or(default).to_string())
+        };
+        let number = |key: &str| {
+            field(key)?
+                .parse()
+                .map_err(|_| WalError::InvalidManifest(format!("invalid {}", key)))
+        };
+        Ok(Some(Self {
+            version: number("version")?,
+            block_size: number("block_size")?,
+            checksum: field("checksum")?,
+            compression: field("compression")?,
+            segment_suffix: field("segment_suffix")?,
+            block_trailers: field_or("block_trailers", "false")?
+                .parse()
+                .map_err(|_| WalError::InvalidManifest("invalid block_trailers".to_string()))?,
+            record_versions: field_or("record_versions", "false")?
+                .parse()
+                .map_err(|_| WalError::InvalidManifest("invalid record_versions".to_string()))?,
+            chunk_alignment: field_or("chunk_alignment", "1")?
+                .parse()
+                .map_err(|_| WalError::InvalidManifest("invalid chunk_alignment".to_string()))?,
+            preallocation: Preallocation::from_name(&field_or("preallocation", "none")?)
+                .ok_or_else(|| WalError::InvalidManifest("invalid preallocation".to_string()))?,
+        }))
+    }
+
+    /// Atomically replace the manifest in `dir_path`.
+    #[cfg(test)]
+    pub(crate) fn store(&self, dir_path: impl AsRef<Path>) -> Result<(), WalError> {
+        self.store_with_segments(dir_path, &[])
+    }
+
+    /// Atomically replace the manifest in `dir_path`, indexing `segment_ids`.
+    pub(crate) fn store_with_segments(
+        &self,
+        dir_path: impl AsRef<Path>,
+        segment_ids: &[u32],
+    ) -> Result<(), WalError> {
+        let path = dir_path.as_ref().join(MANIFEST_FILE_NAME);
+        let tmp = path.with_extension("tmp");
+        let mut file = std::fs::File::create(&tmp).context("create", &tmp)?;
+        file.write_all(self.contents(segment_ids).as_bytes())
+            .context("write", &tmp)?;
+        file.sync_all().context("fsync", &tmp)?;
+        std::fs::rename(&tmp, &path).context("rename", &tmp)?;
+        Ok(())
+    }
+
+    /// The manifest file indexing `segment_ids`.
+    pub(crate) fn contents(&self, segment_ids: &[u32]) -> String {
+        let mut contents = String::new();
+        for (key, value) in self.fields() {
+            contents.push_str(&format!("{}={}\n", key, value));
+        }
+        for id in segment_ids {
+            contents.push_str(&format!("{}={}\n", SEGMENT_KEY, id));
+        }
+        contents
+    }
+
+    /// The segment ids indexed in the manifest in `dir_path`, `None` if there
+    /// is no index to go by.
+    pub(crate) fn load_segment_index(
+        dir_path: impl AsRef<Path>,
+    ) -> Result<Option<std::collections::BTreeSet<u32>>, WalError> {
+        let path = dir_path.as_ref().join(MANIFEST_FILE_NAME);
+        let content = match std::fs::read_to_string(&path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).context("read", &path),
+        };
+        let mut ids = std::collections::BTreeSet::new();
+        for line in complete_lines(&content) {
+            if let Some(id) = line
+                .strip_prefix(SEGMENT_KEY)
+                .and_then(|rest| rest.strip_prefix('='))
+            {
+                match id.parse() {
+                    Ok(id) => ids.insert(id),
+                    Err(_) => return Ok(None),
+                };
+            }
+        }
+        Ok((!ids.is_empty()).then_some(ids))
+    }
+
+    /// Index a segment which is about to be created.
+    pub(crate) fn append_segment(dir_path: impl AsRef<Path>, id: u32) -> Result<(), WalError> {
+        let path = dir_path.as_ref().join(MANIFEST_FILE_NAME);
+        let mut file = std::fs::File::options()
+            .read(true)
+            .append(true)
+            .open(&path)
+            .context("open", &path)?;
+        // Cut off a line torn by an earlier crash, rather than extending it.
+        let len = file.metadata().context("stat", &path)?.len();
+        let mut last = [b'\n'];
+        if len > 0 {
+            file.read_exact_at(&mut last, len - 1)
+                .context("read", &path)?;
+        }
+        if last[0] != b'\n' {
+            let content = std::fs::read(&path).context("read", &path)?;
+            let complete = content
+                .iter()
+                .rposition(|b| *b == b'\n')
+                .map_or(0, |i| i + 1);
+            file.set_len(complete as u64).context("truncate", &path)?;
+        }
+        writeln!(file, "{}={}", SEGMENT_KEY, id).context("append", &path)?;
+        file.sync_data().context("fsync", &path)?;
+        Ok(())
+    }
+
+    /// Check that `requested` can be used with a directory described by `self`.
+    pub(crate) fn check(&self, requested: &Manifest) -> Result<(), WalError> {
+        for ((field, on_disk), (_, requested)) in self.fields().into_iter().zip(requested.fields())
+        {
+            if on_disk != requested {
+                return Err(WalError::OptionsMismatch {
+                    field,
+                    on_disk,
+                    requested,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    fn fields(&self) -> [(&'static str, String); 9] {
+        [
+            ("version", self.version.to_string()),
+            ("block_size", self.block_size.to_string()),
+            ("checksum", self.checksum.clone()),
+            ("compression", self.compression.clone()),
+            ("segment_suffix", self.segment_suffix.clone()),
+            ("block_trailers", self.block_trailers.to_string()),
+            ("record_versions", self.record_versions.to_string()),
+            ("chunk_alignment", self.chunk_alignment.to_string()),
+            ("preallocation", self.preallocation.name().to_string()),
+        ]
+    }
+}
+
+/// The logical start of the log in `dir_path`, `None` if none was recorded.
+pub(crate) fn load_start(dir_path: impl AsRef<Path>) -> Result<Option<ChunkPosition>, WalError> {
+    let path = dir_path.as_ref().join(START_FILE_NAME);
+    let content = match std::fs::read_to_string(&path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e).context("read", &path),
+    };
+    let parse = || {
+        let mut fields = content.split_whitespace();
+        let start = ChunkPosition {
+            segment_id: fields.next()?.parse().ok()?,
+            block_number: fields.next()?.parse().ok()?,
+            chunk_offset: fields.next()?.parse().ok()?,
+        };
+        fields.next().is_none().then_some(start)
+    };
+    match parse() {
+        Some(start) => Ok(Some(start)),
+        None => Err(WalError::InvalidManifest("invalid start".to_string())),
+    }
+}
+
+/// Atomically record `start` as the logical start of the log in `dir_path`.
+pub(crate) fn store_start(
+    dir_path: impl AsRef<Path>,
+    start: ChunkPosition,
+) -> Result<(), WalError> {
+    store_atomically(
+        &dir_path.as_ref().join(START_FILE_NAME),
+        &start_contents(start),
+    )
+}
+
+/// The contents of the file recording `start`.
+pub(crate) fn start_contents(start: ChunkPosition) -> String {
+    format!(
+        "{} {} {}\n",
+        start.segment_id, start.block_number, start.chunk_offset
+    )
+}
+
+/// The random id of the Wal in `dir_path` and the number of times it was
+/// opened for writing, which pick the blocks
+/// [`crate::options::OpenVerification::Sampled`] verifies. `None` if they
+/// were never recorded.
+pub(crate) fn load_identity(dir_path: impl AsRef<Path>) -> Result<Option<(u128, u64)>, WalError> {
+    let path = dir_path.as_ref().join(IDENTITY_FILE_NAME);
+    let content = match std::fs::read_to_string(&path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e).context("read", &path),
+    };
+    let parse = || {
+        let mut fields = content.split_whitespace();
+        let id = u128::from_str_radix(fields.next()?, 16).ok()?;
+        let opens = fields.next()?.parse().ok()?;
+        fields.next().is_none().then_some((id, opens))
+    };
+    match parse() {
+        Some(identity) => Ok(Some(identity)),
+        None => Err(WalError::InvalidManifest("invalid identity".to_string())),
+    }
+}
+
+/// Atomically record the id of the Wal in `dir_path` and its number of opens.
+pub(crate) fn store_identity(
+    dir_path: impl AsRef<Path>,
+    id: u128,
+    opens: u64,
+) -> Result<(), WalError> {
+    store_atomically(
+        &dir_path.as_ref().join(IDENTITY_FILE_NAME),
+        &format!("{id:032x} {opens}\n"),
+    )
+}
+
+/// Take the exclusive lock on the Wal in `dir_path`, held until the
+/// returned file is closed, so two writers never append to it at once.
+/// Fails with `WalError::DirectoryLocked` if another one holds it.
+pub(crate) fn lock_dir(dir_path: impl AsRef<Path>) -> Result<std::fs::File, WalError> {
+    let path = dir_path.as_ref().join(LOCK_FILE_NAME);
+    let file = std::fs::OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(&path)
+        .context("open", &path)?;
+    match file.try_lock() {
+        Ok(()) => Ok(file),
+        Err(std::fs::TryLockError::WouldBlock) => Err(WalError::DirectoryLocked {
+            path: dir_path.as_ref().to_path_buf(),
+        }),
+        Err(std::fs::TryLockError::Error(e)) => Err(e).context("lock", &path),
+    }
+}
+
+/// A random id for a new Wal.
+pub(crate) fn new_wal_id() -> u128 {
+    use std::hash::{BuildHasher, Hasher};
+    // Every `RandomState` is seeded differently.
+    let half = || {
+        let mut hasher = std::collections::hash_map::RandomState::new().build_hasher();
+        hasher.write_u128(
+            std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_nanos(),
+        );
+        hasher.write_u32(std::process::id());
+        hasher.finish() as u128
+    };
+    (half() << 64) | half()
+}
+
+/// Replace the file at `path` with `content`, so a crash leaves either.
+fn store_atomically(path: &Path, content: &str) -> Result<(), WalError> {
+    let tmp = path.with_extension("tmp");
+    let mut file = std::fs::File::create(&tmp).context("create", &tmp)?;
+    file.write_all(content.as_bytes()).context("write", &tmp)?;
+    file.sync_all().context("fsync", &tmp)?;
+    fail_point!("manifest::before_rename");
+    std::fs::rename(&tmp, path).context("rename", &tmp)?;
+    Ok(())
+}
+
+/// The lines of the manifest, leaving out a last line torn by a crash while
+/// it was being appended.
+fn complete_lines(content: &str) -> impl Iterator<Item = &str> {
+    content
+        .split_inclusive('\n')
+        .filter_map(|line| line.strip_suffix('\n'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{segment::BLOCK_SIZE, testing, wal::Wal};
+
+    #[test]
+    fn created_and_checked_on_open() {
+        let dir = testing::temp_dir("manifest");
+        let opts = || Options::new(&dir, 1024 * 1024);
+        drop(Wal::open(opts()).unwrap());
+        let manifest = Manifest::load(&dir).unwrap().unwrap();
+        assert_eq!(manifest, Manifest::for_options(&opts()));
+        // Compatible reopen.
+        let wal = Wal::open(opts()).unwrap();
+        assert_eq!(
+            *wal.format_info(),
+            FormatInfo {
+                version: FORMAT_VERSION,
+                block_size: BLOCK_SIZE,
+                chunk_header_size: CHUNK_HEADER_SIZE,
+                checksum: "crc32".to_string(),
+                compression: "none".to_string(),
+                block_trailers: false,
+                record_versions: false,
+                chunk_alignment: 1,
+                preallocation: Preallocation::None,
+            }
+        );
+        drop(wal);
+
+        let mut other = manifest.clone();
+        other.block_size = 4096;
+        other.store(&dir).unwrap();
+        // Options without a block size take the directory's.
+        let wal = Wal::open(opts()).unwrap();
+        assert_eq!(wal.format_info().block_size, 4096);
+        drop(wal);
+        match Wal::open(opts().with_block_size(BLOCK_SIZE)) {
+            Err(WalError::OptionsMismatch {
+                field,
+                on_disk,
+                requested,
+            }) => {
+                assert_eq!(field, "block_size");
+                assert_eq!(on_disk, "4096");
+                assert_eq!(requested, BLOCK_SIZE.to_string());
+            }
+            _ => panic!("expected an options mismatch"),
+        }
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn segment_index() {
+        let dir = testing::temp_dir("manifest_index");
+        let opts = || Options::new(&dir, 4 * BLOCK_SIZE as u64);
+        let mut wal = Wal::open(opts()).unwrap();
+        let records = testing::write_records(&mut wal, 0, 100, 3000);
+        drop(wal);
+        let last_id = records.last().unwrap().pos.segment_id;
+        assert!(last_id > 2);
+        let index = Manifest::load_segment_index(&dir).unwrap().unwrap();
+        assert_eq!(index, (1..=last_id).collect());
+
+        // A torn append is ignored, and the index is still used.
+        let mut file = std::fs::File::options()
+            .append(true)
+            .open(dir.join(MANIFEST_FILE_NAME))
+            .unwrap();
+        file.write_all(b"segm").unwrap();
+        let mut wal = Wal::open(opts()).unwrap();
+        testing::assert_read_back(&wal, &records);
+        let more = testing::write_records(&mut wal, 1, 50, 3000);
+        drop(wal);
+        let wal = Wal::open(opts()).unwrap();
+        testing::assert_read_back(&wal, &records);
+        testing::assert_read_back(&wal, &more);
+        let last_id = more.last().unwrap().pos.segment_id;
+        drop(wal);
+
+        // A missing segment makes the index stale, the directory is listed
+        // and the index rewritten.
+        std::fs::remove_file(crate::segment::segment_file_path(&dir, 1)).unwrap();
+        let wal = Wal::open(opts()).unwrap();
+        testing::assert_read_back(
+            &wal,
+            &records[records.iter().position(|r| r.pos.segment_id > 1).unwrap()..],
+        );
+        let index = Manifest::load_segment_index(&dir).unwrap().unwrap();
+        assert_eq!(index, (2..=last_id).collect());
+        drop(wal);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn invalid_manifest() {
+        let dir = testing::temp_dir("manifest_invalid");
+        std::fs::write(dir.join(MANIFEST_FILE_NAME), "version=1\n").unwrap();
+        assert!(matches!(
+            Wal::open(Options::new(&dir, 1024 * 1024)),
+            Err(WalError::InvalidManifest(_))
+        ));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
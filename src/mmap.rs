@@ -0,0 +1,134 @@
+//! Shared writable mappings of preallocated segment files, for the
+//! experimental `mmap` append path, and read-only mappings of sealed ones,
+//! see `Options::with_mmap_reads`.
+
+use std::{fs::File, io, os::unix::io::AsRawFd};
+
+pub(crate) struct MmapAppender {
+    ptr: *mut u8,
+    len: usize,
+}
+
+// The mapping is owned by the segment and only written through `&mut self`.
+unsafe impl Send for MmapAppender {}
+unsafe impl Sync for MmapAppender {}
+
+impl MmapAppender {
+    /// Map the first `len` bytes of `file`, which must be at least that long,
+    /// and fault the pages in up front.
+    pub(crate) fn map(file: &File, len: usize) -> io::Result<Self> {
+        #[cfg(target_os = "linux")]
+        let flags = libc::MAP_SHARED | libc::MAP_POPULATE;
+        #[cfg(not(target_os = "linux"))]
+        let flags = libc::MAP_SHARED;
+        // SAFETY: a fresh mapping of a file we hold open, checked for failure below.
+        let ptr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                flags,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        let appender = Self {
+            ptr: ptr as *mut u8,
+            len,
+        };
+        #[cfg(not(target_os = "linux"))]
+        for offset in (0..len).step_by(4096) {
+            // SAFETY: offset is within the mapping.
+            unsafe { std::ptr::read_volatile(appender.ptr.add(offset)) };
+        }
+        Ok(appender)
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.len
+    }
+
+    pub(crate) fn write_at(&mut self, offset: usize, buf: &[u8]) {
+        assert!(offset + buf.len() <= self.len, "write past the mapping");
+        // SAFETY: the range was checked to be within the mapping, and `buf`
+        // can't alias it as the mapping is never handed out.
+        unsafe { std::ptr::copy_nonoverlapping(buf.as_ptr(), self.ptr.add(offset), buf.len()) };
+    }
+
+    /// Write the dirty pages back to the file.
+    pub(crate) fn flush(&self) -> io::Result<()> {
+        // SAFETY: the whole range is our mapping.
+        if unsafe { libc::msync(self.ptr as *mut libc::c_void, self.len, libc::MS_SYNC) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+impl Drop for MmapAppender {
+    fn drop(&mut self) {
+        // SAFETY: unmapping our own mapping, nothing refers to it afterwards.
+        unsafe { libc::munmap(self.ptr as *mut libc::c_void, self.len) };
+    }
+}
+
+pub(crate) struct MmapReader {
+    ptr: *const u8,
+    len: usize,
+}
+
+// The mapping is only ever read.
+unsafe impl Send for MmapReader {}
+unsafe impl Sync for MmapReader {}
+
+impl MmapReader {
+    /// Map the first `len` bytes of `file` for reading, which must be
+    /// non-zero and at most its length. The file must not shrink while it is
+    /// mapped, touching pages past its end raises `SIGBUS`.
+    pub(crate) fn map(file: &File, len: usize) -> io::Result<Self> {
+        // SAFETY: a fresh mapping of a file we hold open, checked for failure below.
+        let ptr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                len,
+                libc::PROT_READ,
+                libc::MAP_SHARED,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self {
+            ptr: ptr as *const u8,
+            len,
+        })
+    }
+
+    /// Fill `buf` from `offset`, `false` if that is past the mapping.
+    pub(crate) fn read_at(&self, buf: &mut [u8], offset: u64) -> bool {
+        if offset + buf.len() as u64 > self.len as u64 {
+            return false;
+        }
+        // SAFETY: the range was checked to be within the mapping.
+        unsafe {
+            std::ptr::copy_nonoverlapping(
+                self.ptr.add(offset as usize),
+                buf.as_mut_ptr(),
+                buf.len(),
+            )
+        };
+        true
+    }
+}
+
+impl Drop for MmapReader {
+    fn drop(&mut self) {
+        // SAFETY: unmapping our own mapping, nothing refers to it afterwards.
+        unsafe { libc::munmap(self.ptr as *mut libc::c_void, self.len) };
+    }
+}
@@ -1,4 +1,770 @@
+use std::sync::Arc;
+
+use crate::{
+    clock::{Clock, SystemClock},
+    compression::Compression,
+    error::WalError,
+    factory::SegmentFactory,
+    segment::{BLOCK_SIZE, FILE_MODE_PERM, MAX_BLOCK_SIZE, MIN_BLOCK_SIZE},
+    transform::RecordTransform,
+    wal::INITIAL_SEGMENT_FILE_ID,
+};
+
+/// What happens after a thread panicked while holding the Wal's lock.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PoisonPolicy {
+    /// Continue at the end of the active segment file, as it is on disk.
+    #[default]
+    Recover,
+    /// Fail every later operation with `WalError::Poisoned`.
+    Fail,
+}
+
+/// What opening does with a file named like a segment whose id doesn't
+/// parse, see [`Options::with_invalid_segment_names`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum InvalidSegmentNames {
+    /// Fail with `WalError::InvalidSegmentFileName`.
+    #[default]
+    Fail,
+    /// Move the file into the [`QUARANTINE_DIR`] subdirectory of its
+    /// directory and carry on, recording the error in
+    /// [`crate::wal::Wal::open_report`]. Read-only Wals only record it.
+    Quarantine,
+}
+
+/// Which blocks opening verifies the checksums of, see
+/// [`Options::with_open_verification`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OpenVerification {
+    #[default]
+    None,
+    /// Every block, like [`crate::wal::Wal::verify`].
+    Full,
+    /// One in `one_in` blocks, picked from the Wal's id and the number of
+    /// times it has been opened, so every open samples other blocks, and
+    /// corruption anywhere is found over enough opens.
+    Sampled { one_in: u32 },
+}
+
+/// Where [`InvalidSegmentNames::Quarantine`] moves files to.
+pub const QUARANTINE_DIR: &str = "quarantine";
+
+/// How the active segment's file is preallocated, see
+/// [`Options::with_preallocation`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Preallocation {
+    /// Not at all, the file grows with every append.
+    #[default]
+    None,
+    /// Reserve the space with `fallocate(FALLOC_FL_KEEP_SIZE)`, leaving the
+    /// file as long as its data. Needs the `fallocate` feature, on Linux.
+    KeepSize,
+    /// Write zeros up to the segment size and sync them, so appends never
+    /// allocate or change the file's metadata.
+    ZeroFill,
+    /// Extend the file to the segment size without allocating, a hole the
+    /// appends fill in.
+    Sparse,
+}
+
+impl Preallocation {
+    /// Whether the file is longer than the data in it while the segment is
+    /// active, so its end has to be found by scanning it.
+    pub(crate) fn extends_file(self) -> bool {
+        matches!(self, Preallocation::ZeroFill | Preallocation::Sparse)
+    }
+
+    /// How the manifest calls it.
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            Preallocation::None => "none",
+            Preallocation::KeepSize => "keep-size",
+            Preallocation::ZeroFill => "zero-fill",
+            Preallocation::Sparse => "sparse",
+        }
+    }
+
+    pub(crate) fn from_name(name: &str) -> Option<Self> {
+        [
+            Preallocation::None,
+            Preallocation::KeepSize,
+            Preallocation::ZeroFill,
+            Preallocation::Sparse,
+        ]
+        .into_iter()
+        .find(|preallocation| preallocation.name() == name)
+    }
+}
+
+/// What [`crate::wal::Wal::write_batch`] does with a batch over the maximum
+/// size, see [`Options::with_max_batch_bytes`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OversizedBatch {
+    /// Fail with `WalError::BatchTooLarge`, writing none of it.
+    #[default]
+    Reject,
+    /// Write it as several batches, split between records, each all or
+    /// nothing on its own. A record over the maximum is a batch of its own.
+    Split,
+}
+
+/// When writes sync the segments to disk, see [`Options::with_sync_policy`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SyncPolicy {
+    /// Only on [`crate::wal::Wal::sync`] and friends.
+    #[default]
+    Never,
+    EveryWrite,
+    /// Once at least this many bytes were written since the last sync.
+    EveryNBytes(u64),
+    /// On the first write at least this long after the last sync.
+    EveryInterval(std::time::Duration),
+}
+
+/// Which sealed segments are removed automatically, see
+/// [`Options::with_retention`]. Keeps all of them by default.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct RetentionPolicy {
+    pub max_age: Option<std::time::Duration>,
+    pub max_segments: Option<usize>,
+}
+
+impl RetentionPolicy {
+    /// Remove sealed segments last written more than `max_age` ago.
+    pub fn with_max_age(mut self, max_age: std::time::Duration) -> Self {
+        self.max_age = Some(max_age);
+        self
+    }
+
+    /// Keep at most `max_segments` sealed segments, besides the active one.
+    pub fn with_max_segments(mut self, max_segments: usize) -> Self {
+        self.max_segments = Some(max_segments);
+        self
+    }
+}
+
+#[derive(Clone)]
 pub struct Options {
     pub(crate) dir_path: std::path::PathBuf,
     pub(crate) segment_size: u64,
+    /// The directory's own if `None`, [`BLOCK_SIZE`] for a new one.
+    pub(crate) block_size: Option<u32>,
+    /// The directory's own if `None`, 1 for a new one.
+    pub(crate) chunk_alignment: Option<u32>,
+    /// The directory's own if `None`, none for a new one.
+    pub(crate) preallocation: Option<Preallocation>,
+    /// Where segments are moved once they are sealed, if not `dir_path`.
+    pub(crate) sealed_dir: Option<std::path::PathBuf>,
+    /// Id of the first segment created in an empty directory.
+    pub(crate) first_segment_id: u32,
+    /// Size below which the active segment isn't sealed, see
+    /// `with_min_sealed_size`.
+    pub(crate) min_sealed_size: u64,
+    pub(crate) verify_after_write: bool,
+    pub(crate) poison_policy: PoisonPolicy,
+    /// See `with_max_batch_bytes`.
+    pub(crate) max_batch_bytes: Option<(u64, OversizedBatch)>,
+    pub(crate) sync_policy: SyncPolicy,
+    /// Permission bits set on segment files, left alone if `None`.
+    pub(crate) file_permissions: Option<u32>,
+    pub(crate) block_trailers: bool,
+    pub(crate) record_versions: bool,
+    #[cfg(feature = "mmap")]
+    pub(crate) mmap_appends: bool,
+    #[cfg(feature = "mmap")]
+    pub(crate) mmap_reads: bool,
+    pub(crate) page_cache_hints: bool,
+    pub(crate) direct_reads: bool,
+    pub(crate) transform: Option<Arc<dyn RecordTransform>>,
+    pub(crate) compression: Option<Compression>,
+    #[cfg(feature = "encryption")]
+    pub(crate) cipher: Option<Arc<crate::encryption::Cipher>>,
+    pub(crate) segment_factory: Option<Arc<dyn SegmentFactory>>,
+    /// Budget of the decoded record cache, see `with_decoded_cache_bytes`.
+    pub(crate) decoded_cache_bytes: usize,
+    /// Budget of the block cache, see `with_block_cache_bytes`.
+    pub(crate) block_cache_bytes: usize,
+    /// Bytes of the next segment read ahead of scans, see
+    /// `with_prefetch_bytes`.
+    pub(crate) prefetch_bytes: u64,
+    /// Bytes of appends held back before writing them out, see
+    /// `with_write_buffer_size`.
+    pub(crate) write_buffer_size: usize,
+    pub(crate) clock: Arc<dyn Clock>,
+    /// Bytes of sealed segments kept in `dir_path` before moving them to
+    /// `sealed_dir`, see `with_spill_threshold`.
+    pub(crate) spill_threshold: Option<u64>,
+    pub(crate) read_only: bool,
+    pub(crate) debug_assertions_as_errors: bool,
+    pub(crate) tail_check: bool,
+    pub(crate) open_verification: OpenVerification,
+    /// How often read statistics are persisted, never if `None`.
+    pub(crate) read_stats_interval: Option<std::time::Duration>,
+    pub(crate) retention: RetentionPolicy,
+    pub(crate) content_index: bool,
+    pub(crate) entry_index: bool,
+    pub(crate) invalid_segment_names: InvalidSegmentNames,
+}
+
+impl Options {
+    pub fn new(dir_path: impl Into<std::path::PathBuf>, segment_size: u64) -> Self {
+        Self {
+            dir_path: dir_path.into(),
+            segment_size,
+            block_size: None,
+            sealed_dir: None,
+            first_segment_id: INITIAL_SEGMENT_FILE_ID,
+            min_sealed_size: 0,
+            verify_after_write: false,
+            poison_policy: PoisonPolicy::Recover,
+            max_batch_bytes: None,
+            sync_policy: SyncPolicy::Never,
+            file_permissions: Some(FILE_MODE_PERM),
+            block_trailers: false,
+            record_versions: false,
+            #[cfg(feature = "mmap")]
+            mmap_appends: false,
+            #[cfg(feature = "mmap")]
+            mmap_reads: false,
+            page_cache_hints: false,
+            direct_reads: false,
+            transform: None,
+            compression: None,
+            #[cfg(feature = "encryption")]
+            cipher: None,
+            segment_factory: None,
+            decoded_cache_bytes: 0,
+            block_cache_bytes: 0,
+            prefetch_bytes: 0,
+            write_buffer_size: 0,
+            clock: Arc::new(SystemClock),
+            read_stats_interval: None,
+            spill_threshold: None,
+            read_only: false,
+            debug_assertions_as_errors: false,
+            tail_check: false,
+            open_verification: OpenVerification::None,
+            chunk_alignment: None,
+            preallocation: None,
+            retention: RetentionPolicy::default(),
+            content_index: false,
+            entry_index: false,
+            invalid_segment_names: InvalidSegmentNames::Fail,
+        }
+    }
+
+    /// Lay segments out in blocks of `block_size` bytes instead of
+    /// [`BLOCK_SIZE`], e.g. smaller ones for small records, or ones matching
+    /// the device. It has to be a power of two from [`MIN_BLOCK_SIZE`] to
+    /// [`MAX_BLOCK_SIZE`], no larger than the segment size, or opening fails
+    /// with `WalError::InvalidOptions`.
+    ///
+    /// This is part of the format: a directory can only be reopened with the
+    /// block size it was created with, which is also what it is reopened with
+    /// when this isn't set.
+    pub fn with_block_size(mut self, block_size: u32) -> Self {
+        self.block_size = Some(block_size);
+        self
+    }
+
+    /// The block size segments are laid out with.
+    pub(crate) fn block_len(&self) -> u32 {
+        self.block_size.unwrap_or(BLOCK_SIZE)
+    }
+
+    /// Start every record at a multiple of `alignment` bytes into its block,
+    /// e.g. 512 or 4096, padding the gap after the record before, so readers
+    /// using `O_DIRECT` or replicating extents of the device find records on
+    /// aligned offsets. Positions account for the padding. It has to be a
+    /// power of two no larger than the block size, or opening fails with
+    /// `WalError::InvalidOptions`.
+    ///
+    /// This is part of the format, like [`Options::with_block_size`].
+    pub fn with_chunk_alignment(mut self, alignment: u32) -> Self {
+        self.chunk_alignment = Some(alignment);
+        self
+    }
+
+    /// What chunks are aligned to.
+    pub(crate) fn chunk_alignment(&self) -> u32 {
+        self.chunk_alignment.unwrap_or(1)
+    }
+
+    /// Preallocate the active segment to the segment size with
+    /// `preallocation` whenever one becomes active. Sealed segments are cut
+    /// off at their data.
+    ///
+    /// With [`Preallocation::ZeroFill`] and [`Preallocation::Sparse`] the
+    /// active segment's file is longer than its data, so its end is found by
+    /// scanning it when opened, read-only included. This is part of the
+    /// format, like [`Options::with_block_size`], so every opener knows to.
+    pub fn with_preallocation(mut self, preallocation: Preallocation) -> Self {
+        self.preallocation = Some(preallocation);
+        self
+    }
+
+    pub(crate) fn preallocation(&self) -> Preallocation {
+        self.preallocation.unwrap_or_default()
+    }
+
+    /// Whether the active segment's file may be longer than its data.
+    pub(crate) fn extends_active_file(&self) -> bool {
+        #[cfg(feature = "mmap")]
+        if self.mmap_appends {
+            return true;
+        }
+        self.preallocation().extends_file()
+    }
+
+    pub(crate) fn check_layout(&self) -> Result<(), WalError> {
+        let block_size = self.block_len();
+        if !block_size.is_power_of_two() || !(MIN_BLOCK_SIZE..=MAX_BLOCK_SIZE).contains(&block_size)
+        {
+            return Err(WalError::InvalidOptions(format!(
+                "block size {} isn't a power of two from {} to {}",
+                block_size, MIN_BLOCK_SIZE, MAX_BLOCK_SIZE
+            )));
+        }
+        if block_size as u64 > self.segment_size {
+            return Err(WalError::InvalidOptions(format!(
+                "block size {} is larger than the segment size {}",
+                block_size, self.segment_size
+            )));
+        }
+        let alignment = self.chunk_alignment();
+        if !alignment.is_power_of_two() || alignment > block_size {
+            return Err(WalError::InvalidOptions(format!(
+                "chunk alignment {} isn't a power of two up to the block size {}",
+                alignment, block_size
+            )));
+        }
+        if cfg!(not(all(feature = "fallocate", target_os = "linux")))
+            && self.preallocation() == Preallocation::KeepSize
+            && !self.read_only
+        {
+            return Err(WalError::InvalidOptions(
+                "keep-size preallocation needs the fallocate feature on linux".to_string(),
+            ));
+        }
+        if cfg!(not(all(feature = "direct-io", target_os = "linux")))
+            && (self.page_cache_hints || self.direct_reads)
+        {
+            return Err(WalError::InvalidOptions(
+                "page cache hints and direct reads need the direct-io feature on linux".to_string(),
+            ));
+        }
+        #[cfg(feature = "mmap")]
+        if self.mmap_reads && self.direct_reads {
+            return Err(WalError::InvalidOptions(
+                "sealed segments are either mapped or read directly".to_string(),
+            ));
+        }
+        if self.min_sealed_size > self.segment_size {
+            return Err(WalError::InvalidOptions(format!(
+                "min sealed size {} is larger than the segment size {}",
+                self.min_sealed_size, self.segment_size
+            )));
+        }
+        if self.open_verification == (OpenVerification::Sampled { one_in: 0 }) {
+            return Err(WalError::InvalidOptions(
+                "can't sample one in 0 blocks".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Apply `transform` to every record written to and read from the Wal.
+    pub fn with_transform(mut self, transform: impl RecordTransform + 'static) -> Self {
+        self.transform = Some(Arc::new(transform));
+        self
+    }
+
+    /// Compress records with `compression` before chunking them, storing the
+    /// ones that don't get smaller as they are. Records are compressed as
+    /// stored, after the transform, so compress in the transform instead of
+    /// here if it encrypts. Records written without compression still read,
+    /// and so do compressed ones once it is turned off, as long as the codec
+    /// is compiled in.
+    pub fn with_compression(mut self, compression: Compression) -> Self {
+        self.compression = Some(compression);
+        self
+    }
+
+    /// Encrypt every chunk written with XChaCha20-Poly1305 under `key`, and
+    /// decrypt encrypted chunks read with it. Every chunk gets a random nonce
+    /// and is authenticated together with its position, growing it by 40
+    /// bytes. Chunks written in the clear, e.g. before encryption was
+    /// enabled, still read as they are.
+    #[cfg(feature = "encryption")]
+    pub fn with_encryption_key(mut self, key: [u8; 32]) -> Self {
+        self.cipher = Some(Arc::new(crate::encryption::Cipher::new(&key)));
+        self
+    }
+
+    /// Keep up to `decoded_cache_bytes` of recently read records which were
+    /// decoded by the transform or decompressed, see
+    /// [`Options::with_compression`], so repeated reads of the same
+    /// positions don't decode them again. Records stored as they were
+    /// written aren't cached. Off by default.
+    pub fn with_decoded_cache_bytes(mut self, decoded_cache_bytes: usize) -> Self {
+        self.decoded_cache_bytes = decoded_cache_bytes;
+        self
+    }
+
+    /// Keep up to `block_cache_bytes` of recently read blocks in memory,
+    /// shared by all segments, so repeated point reads of the same records
+    /// are served without reading the file. Only blocks the Wal has been
+    /// written past are cached, scans like [`crate::wal::Wal::replay`] don't
+    /// go through it. Off by default.
+    pub fn with_block_cache_bytes(mut self, block_cache_bytes: usize) -> Self {
+        self.block_cache_bytes = block_cache_bytes;
+        self
+    }
+
+    /// Once [`crate::wal::Wal::reader`] or [`crate::wal::Wal::replay`] get
+    /// within `prefetch_bytes` of the end of a segment, read the first
+    /// `prefetch_bytes` of the next sealed one on a background thread, into
+    /// the block cache if there is one and the page cache otherwise, so the
+    /// scan doesn't stall when it crosses over, e.g. on slow or remote
+    /// storage. The thread is named `wal-prefetch-<segment id>`, and closing
+    /// or dropping the Wal waits for it. Off by default.
+    pub fn with_prefetch_bytes(mut self, prefetch_bytes: u64) -> Self {
+        self.prefetch_bytes = prefetch_bytes;
+        self
+    }
+
+    /// Hold appends back in memory until `write_buffer_size` bytes add up,
+    /// then write them to the file with a single call, instead of a write
+    /// call per record, or several for a record spanning blocks. A record is
+    /// always written out whole. Whatever is held back goes to the file on
+    /// [`crate::wal::Wal::flush`], on every sync, so also as often as the
+    /// sync policy asks for one, before anything reads the segment, and when
+    /// the Wal is dropped. Records held back are lost if the process dies.
+    /// Has no effect on appends through a mapping. Off by default.
+    pub fn with_write_buffer_size(mut self, write_buffer_size: usize) -> Self {
+        self.write_buffer_size = write_buffer_size;
+        self
+    }
+
+    /// Create new segment files through `segment_factory`, e.g.
+    /// [`crate::factory::TempRename`], instead of a plain create.
+    pub fn with_segment_factory(mut self, segment_factory: impl SegmentFactory + 'static) -> Self {
+        self.segment_factory = Some(Arc::new(segment_factory));
+        self
+    }
+
+    /// Move segments to `sealed_dir` once they are sealed, e.g. from a small
+    /// fast disk to a large cheap one. Reads of sealed segments are served
+    /// from there transparently.
+    pub fn with_sealed_dir(mut self, sealed_dir: impl Into<std::path::PathBuf>) -> Self {
+        self.sealed_dir = Some(sealed_dir.into());
+        self
+    }
+
+    /// Keep sealed segments in `dir_path` until they add up to more than
+    /// `spill_threshold` bytes, then move the oldest to `sealed_dir`, instead
+    /// of moving every segment as it is sealed. E.g. with `dir_path` on tmpfs
+    /// and `sealed_dir` on disk, recent records are read back from memory and
+    /// memory use stays bounded; only records spilled to `sealed_dir` survive
+    /// a reboot. Without a `sealed_dir` this does nothing.
+    pub fn with_spill_threshold(mut self, spill_threshold: u64) -> Self {
+        self.spill_threshold = Some(spill_threshold);
+        self
+    }
+
+    /// Remove sealed segments, oldest first, once `retention` no longer keeps
+    /// them: on every rotation and when the Wal is opened. The age of a
+    /// segment is taken from its file's modification time, by the Wal's
+    /// [`Clock`]. See also [`crate::wal::Wal::purge_before`].
+    pub fn with_retention(mut self, retention: RetentionPolicy) -> Self {
+        self.retention = retention;
+        self
+    }
+
+    /// Start numbering segments at `first_segment_id` when the directory is
+    /// empty, e.g. so a restored node continues the sequence of its primary.
+    /// A directory which already has segments always continues after the
+    /// highest existing id.
+    pub fn with_first_segment_id(mut self, first_segment_id: u32) -> Self {
+        self.first_segment_id = first_segment_id;
+        self
+    }
+
+    /// Don't seal the active segment while it holds fewer than
+    /// `min_sealed_size` bytes: a record or batch which doesn't fit in the
+    /// rest of it is appended past the segment size instead, e.g. a large
+    /// batch after a few small records. Sealed segments are then never
+    /// tiny, so retention and shipping don't deal with many small files, at
+    /// the cost of some segments growing larger than the segment size.
+    pub fn with_min_sealed_size(mut self, min_sealed_size: u64) -> Self {
+        self.min_sealed_size = min_sealed_size;
+        self
+    }
+
+    /// Read every record back right after writing it, and fail the write if
+    /// its checksums or bytes don't match what was written.
+    pub fn with_verify_after_write(mut self, verify_after_write: bool) -> Self {
+        self.verify_after_write = verify_after_write;
+        self
+    }
+
+    /// Keep an in-memory index from the checksum of every record to its
+    /// positions, for [`crate::wal::Wal::find_by_content`]. It is built by
+    /// reading the whole Wal when opened, and takes a few bytes per record.
+    pub fn with_content_index(mut self, content_index: bool) -> Self {
+        self.content_index = content_index;
+        self
+    }
+
+    /// Number the records from 0 in write order, for
+    /// [`crate::wal::Wal::read_index`] and the other index lookups. The number
+    /// of every segment's first record is persisted, the positions are kept
+    /// in memory and rebuilt by reading the whole Wal when opened.
+    ///
+    /// Records written while it was disabled are numbered when it is enabled,
+    /// on from the first segment still in the Wal.
+    pub fn with_entry_index(mut self, entry_index: bool) -> Self {
+        self.entry_index = entry_index;
+        self
+    }
+
+    /// Take the time from `clock` instead of the system clocks.
+    pub fn with_clock(mut self, clock: impl Clock + 'static) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    /// Persist the read statistics of segments, see
+    /// [`crate::wal::Wal::read_stats`], on the first read at least `interval`
+    /// after the last time, so they survive reopening. Only persisted by
+    /// [`crate::wal::Wal::persist_read_stats`] by default.
+    pub fn with_read_stats_interval(mut self, interval: std::time::Duration) -> Self {
+        self.read_stats_interval = Some(interval);
+        self
+    }
+
+    /// Open an existing Wal for reading only, e.g. one archived to read-only
+    /// media or mounted read-only: nothing in the directory is created,
+    /// changed or removed, and writes fail with `WalError::ReadOnly`.
+    ///
+    /// Repairs of crashed rotations which open otherwise makes on disk, like
+    /// moving segments into `sealed_dir`, are left for a writable open; the
+    /// segments are read where they are.
+    pub fn with_read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+
+    /// Fail with `WalError::InvariantViolated` when an internal invariant is
+    /// found violated, in debug builds too, instead of panicking there, e.g.
+    /// where a panic would take down the whole process. Release builds never
+    /// panic on them.
+    pub fn with_debug_assertions_as_errors(mut self, debug_assertions_as_errors: bool) -> Self {
+        self.debug_assertions_as_errors = debug_assertions_as_errors;
+        self
+    }
+
+    /// Check on open that the active segment, once torn writes are cut off,
+    /// ends on a chunk boundary, e.g. after corruption at its end. If not,
+    /// writes continue at the last boundary, overwriting what follows it,
+    /// and the mismatch is recorded in [`crate::wal::Wal::open_report`].
+    /// Otherwise writes continue at the end of the file, after it.
+    pub fn with_tail_check(mut self, tail_check: bool) -> Self {
+        self.tail_check = tail_check;
+        self
+    }
+
+    /// Verify the checksums of the blocks on open, all of them or a sample,
+    /// e.g. to find corruption early without paying for a full scan on every
+    /// start. Corrupted blocks don't fail the open, they are recorded in
+    /// [`crate::wal::Wal::open_report`], with what was verified.
+    ///
+    /// Sampling keeps the Wal's id and its number of opens in the directory.
+    pub fn with_open_verification(mut self, open_verification: OpenVerification) -> Self {
+        self.open_verification = open_verification;
+        self
+    }
+
+    /// Sync after writes according to `sync_policy`, trading write latency for
+    /// durability. A write whose sync fails returns the error, the record
+    /// itself stays written. Batches are always synced.
+    pub fn with_sync_policy(mut self, sync_policy: SyncPolicy) -> Self {
+        self.sync_policy = sync_policy;
+        self
+    }
+
+    /// What to do with files named like segments whose ids don't parse,
+    /// e.g. left behind by a tool, when the segment index is rebuilt from a
+    /// listing of the directories. Fails opening by default.
+    pub fn with_invalid_segment_names(mut self, policy: InvalidSegmentNames) -> Self {
+        self.invalid_segment_names = policy;
+        self
+    }
+
+    /// Cap the bytes of the records of a batch given to
+    /// [`crate::wal::Wal::write_batch`], and so to
+    /// [`crate::wal::Wal::commit_staged`], at `max_batch_bytes`, rejecting
+    /// or splitting larger ones as `oversized` says. Unlimited by default.
+    pub fn with_max_batch_bytes(mut self, max_batch_bytes: u64, oversized: OversizedBatch) -> Self {
+        self.max_batch_bytes = Some((max_batch_bytes, oversized));
+        self
+    }
+
+    pub fn with_poison_policy(mut self, poison_policy: PoisonPolicy) -> Self {
+        self.poison_policy = poison_policy;
+        self
+    }
+
+    /// Set the permission bits of segment files to `file_permissions`
+    /// (`0o644` by default), or leave them as created if `None`, e.g. where
+    /// metadata is read-only.
+    ///
+    /// Failing to set them doesn't fail the Wal, it is recorded in
+    /// [`crate::wal::Wal::open_report`].
+    pub fn with_file_permissions(mut self, file_permissions: Option<u32>) -> Self {
+        self.file_permissions = file_permissions;
+        self
+    }
+
+    /// End every block with a small trailer holding its number of chunks and
+    /// the offset of the last one, see [`crate::wal::Wal::block_trailer`].
+    ///
+    /// This is part of the format: a directory can only be reopened with the
+    /// setting it was created with.
+    pub fn with_block_trailers(mut self, block_trailers: bool) -> Self {
+        self.block_trailers = block_trailers;
+        self
+    }
+
+    /// Store a version byte with every record, written with
+    /// [`WriteOptions::with_version`] and read with
+    /// [`crate::wal::Wal::read_with_version`], so applications can evolve
+    /// the format of their payloads without prefixing every one themselves.
+    ///
+    /// This is part of the format: a directory can only be reopened with the
+    /// setting it was created with.
+    pub fn with_record_versions(mut self, record_versions: bool) -> Self {
+        self.record_versions = record_versions;
+        self
+    }
+
+    /// Experimental: preallocate the active segment to `segment_size`, map it
+    /// into memory with its pages faulted in, and append by copying into the
+    /// mapping. Syncs `msync` the mapping.
+    ///
+    /// Sealed segments are truncated to their data; the active one stays
+    /// preallocated and its end is found by scanning it when reopened, so a
+    /// directory written this way has to be reopened with it enabled too.
+    #[cfg(feature = "mmap")]
+    pub fn with_mmap_appends(mut self, mmap_appends: bool) -> Self {
+        self.mmap_appends = mmap_appends;
+        self
+    }
+
+    /// Map sealed segments into memory read-only, and serve reads and
+    /// replays of them by copying out of the mapping rather than with a read
+    /// per chunk or window. The active segment is still read from its file.
+    ///
+    /// A sealed segment file must not be cut short by anything but the Wal
+    /// itself while it is mapped: reading pages past its end raises `SIGBUS`.
+    #[cfg(feature = "mmap")]
+    pub fn with_mmap_reads(mut self, mmap_reads: bool) -> Self {
+        self.mmap_reads = mmap_reads;
+        self
+    }
+
+    /// Advise the kernel with `posix_fadvise(2)` that segments are scanned
+    /// sequentially, and that the pages of sealed ones aren't needed once
+    /// they are synced and once a scan is past them, e.g. when the
+    /// application caches records itself. Needs the `direct-io` feature, on
+    /// Linux.
+    pub fn with_page_cache_hints(mut self, page_cache_hints: bool) -> Self {
+        self.page_cache_hints = page_cache_hints;
+        self
+    }
+
+    /// Read sealed segments with `O_DIRECT`, bypassing the page cache,
+    /// through buffers aligned to 4 KiB. Appends to the active segment, and
+    /// reads of it, still go through the page cache; combine with
+    /// [`Options::with_page_cache_hints`] to drop a segment's pages once it
+    /// is sealed and synced. Needs the `direct-io` feature, on Linux, and a
+    /// file system supporting `O_DIRECT`.
+    pub fn with_direct_reads(mut self, direct_reads: bool) -> Self {
+        self.direct_reads = direct_reads;
+        self
+    }
+}
+
+/// Every setting as the Wal runs with it, e.g. from
+/// [`crate::wal::Wal::options`] once the format was taken from the
+/// directory. Keys, transforms, factories and clocks only show whether they
+/// are set.
+impl std::fmt::Debug for Options {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut options = f.debug_struct("Options");
+        options
+            .field("dir_path", &self.dir_path)
+            .field("segment_size", &self.segment_size)
+            .field("block_size", &self.block_len())
+            .field("chunk_alignment", &self.chunk_alignment())
+            .field("preallocation", &self.preallocation())
+            .field("sealed_dir", &self.sealed_dir)
+            .field("spill_threshold", &self.spill_threshold)
+            .field("first_segment_id", &self.first_segment_id)
+            .field("min_sealed_size", &self.min_sealed_size)
+            .field("read_only", &self.read_only)
+            .field("sync_policy", &self.sync_policy)
+            .field("poison_policy", &self.poison_policy)
+            .field("max_batch_bytes", &self.max_batch_bytes)
+            .field("verify_after_write", &self.verify_after_write)
+            .field("file_permissions", &self.file_permissions)
+            .field("block_trailers", &self.block_trailers)
+            .field("record_versions", &self.record_versions);
+        #[cfg(feature = "mmap")]
+        options
+            .field("mmap_appends", &self.mmap_appends)
+            .field("mmap_reads", &self.mmap_reads);
+        options
+            .field("page_cache_hints", &self.page_cache_hints)
+            .field("direct_reads", &self.direct_reads);
+        options
+            .field("compression", &self.compression)
+            .field("transform", &self.transform.is_some());
+        #[cfg(feature = "encryption")]
+        options.field("encryption", &self.cipher.is_some());
+        options
+            .field("segment_factory", &self.segment_factory.is_some())
+            .field("decoded_cache_bytes", &self.decoded_cache_bytes)
+            .field("block_cache_bytes", &self.block_cache_bytes)
+            .field("prefetch_bytes", &self.prefetch_bytes)
+            .field("write_buffer_size", &self.write_buffer_size)
+            .field("read_stats_interval", &self.read_stats_interval)
+            .field(
+                "debug_assertions_as_errors",
+                &self.debug_assertions_as_errors,
+            )
+            .field("tail_check", &self.tail_check)
+            .field("open_verification", &self.open_verification)
+            .field("retention", &self.retention)
+            .field("content_index", &self.content_index)
+            .field("entry_index", &self.entry_index)
+            .field("invalid_segment_names", &self.invalid_segment_names)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Options of a single write, see [`crate::wal::Wal::write_with`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct WriteOptions {
+    pub(crate) version: u8,
+}
+
+impl WriteOptions {
+    /// Version of the record's payload format, needs
+    /// [`Options::with_record_versions`] unless 0.
+    pub fn with_version(mut self, version: u8) -> Self {
+        self.version = version;
+        self
+    }
 }
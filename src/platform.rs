@@ -0,0 +1,174 @@
+//! What the Wal needs from the OS beyond `std::fs`: positional I/O, file
+//! permissions and directory fsyncs, on unix and on Windows, and page cache
+//! hints and direct reads on Linux.
+
+use std::{fs::File, io, path::Path};
+
+/// Reads and writes at an offset, `pread`/`pwrite` on unix and
+/// `seek_read`/`seek_write` on Windows.
+///
+/// Windows moves the file cursor, which nothing in the Wal relies on, every
+/// access names its offset.
+pub(crate) trait PositionalIo {
+    fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()>;
+    fn write_all_at(&self, buf: &[u8], offset: u64) -> io::Result<()>;
+}
+
+#[cfg(unix)]
+impl PositionalIo for File {
+    fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        std::os::unix::fs::FileExt::read_exact_at(self, buf, offset)
+    }
+
+    fn write_all_at(&self, buf: &[u8], offset: u64) -> io::Result<()> {
+        std::os::unix::fs::FileExt::write_all_at(self, buf, offset)
+    }
+}
+
+#[cfg(windows)]
+impl PositionalIo for File {
+    fn read_exact_at(&self, mut buf: &mut [u8], mut offset: u64) -> io::Result<()> {
+        use std::os::windows::fs::FileExt;
+        while !buf.is_empty() {
+            match self.seek_read(buf, offset) {
+                Ok(0) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "failed to fill whole buffer",
+                    ))
+                }
+                Ok(n) => {
+                    buf = &mut buf[n..];
+                    offset += n as u64;
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    fn write_all_at(&self, mut buf: &[u8], mut offset: u64) -> io::Result<()> {
+        use std::os::windows::fs::FileExt;
+        while !buf.is_empty() {
+            match self.seek_write(buf, offset) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(n) => {
+                    buf = &buf[n..];
+                    offset += n as u64;
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Set the permission bits of the file at `path`. Windows has none, only a
+/// read-only attribute, so they are left as they are there.
+#[cfg_attr(not(unix), allow(unused_variables))]
+pub(crate) fn set_mode(path: &Path, mode: u32) -> io::Result<()> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+    }
+    Ok(())
+}
+
+/// Make the entries of the directory at `path` durable, e.g. after a rename
+/// into it. Windows can't open a directory as a `File`, and its file system
+/// makes renames durable with the file's metadata.
+#[cfg_attr(not(unix), allow(unused_variables))]
+pub(crate) fn sync_dir(path: &Path) -> io::Result<()> {
+    #[cfg(unix)]
+    File::open(path)?.sync_all()?;
+    Ok(())
+}
+
+/// How a range of a file is going to be read, for `posix_fadvise(2)`.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Advice {
+    Sequential,
+    DontNeed,
+}
+
+/// Tell the kernel how `len` bytes from `offset` of `file` are going to be
+/// read, all of it from `offset` on if `len` is 0. Only a hint, so errors are
+/// ignored, and nothing is done without the `direct-io` feature on Linux.
+#[cfg_attr(
+    not(all(feature = "direct-io", target_os = "linux")),
+    allow(unused_variables)
+)]
+pub(crate) fn advise(file: &File, offset: u64, len: u64, advice: Advice) {
+    #[cfg(all(feature = "direct-io", target_os = "linux"))]
+    {
+        use std::os::unix::io::AsRawFd;
+        let advice = match advice {
+            Advice::Sequential => libc::POSIX_FADV_SEQUENTIAL,
+            Advice::DontNeed => libc::POSIX_FADV_DONTNEED,
+        };
+        // SAFETY: a plain syscall on a file we hold open.
+        unsafe {
+            libc::posix_fadvise(
+                file.as_raw_fd(),
+                offset as libc::off_t,
+                len as libc::off_t,
+                advice,
+            )
+        };
+    }
+}
+
+/// What offsets, lengths and buffers of `O_DIRECT` reads are aligned to,
+/// the largest logical block size of common devices.
+#[cfg(all(feature = "direct-io", target_os = "linux"))]
+const DIRECT_ALIGNMENT: usize = 4096;
+
+/// Open the file at `path` for reads bypassing the page cache.
+#[cfg(all(feature = "direct-io", target_os = "linux"))]
+pub(crate) fn open_direct(path: &Path) -> io::Result<File> {
+    use std::os::unix::fs::OpenOptionsExt;
+    std::fs::OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_DIRECT)
+        .open(path)
+}
+
+/// Fill `buf` from `offset` of a file opened with [`open_direct`], through
+/// an aligned buffer covering the aligned blocks around the range.
+#[cfg(all(feature = "direct-io", target_os = "linux"))]
+pub(crate) fn read_exact_at_direct(file: &File, buf: &mut [u8], offset: u64) -> io::Result<()> {
+    use std::os::unix::fs::FileExt;
+    let skip = offset as usize % DIRECT_ALIGNMENT;
+    let needed = skip + buf.len();
+    let len = needed.next_multiple_of(DIRECT_ALIGNMENT);
+    let mut staging = vec![0; len + DIRECT_ALIGNMENT];
+    let shift = staging.as_ptr().align_offset(DIRECT_ALIGNMENT);
+    let aligned = &mut staging[shift..shift + len];
+    let start = offset - skip as u64;
+    let mut read = 0;
+    while read < needed {
+        match file.read_at(&mut aligned[read..], start + read as u64) {
+            Ok(0) => break,
+            Ok(n) => {
+                read += n;
+                // Short of a whole block, the file ends here.
+                if !n.is_multiple_of(DIRECT_ALIGNMENT) {
+                    break;
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    if read < needed {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "failed to fill whole buffer",
+        ));
+    }
+    buf.copy_from_slice(&aligned[skip..needed]);
+    Ok(())
+}
@@ -0,0 +1,456 @@
+//! A raft-rs log store on top of the Wal, see [`WalStorage`].
+
+use std::{
+    io::Write,
+    path::Path,
+    sync::{RwLock, RwLockReadGuard, RwLockWriteGuard},
+};
+
+use protobuf::{CodedInputStream, Message};
+use raft::{
+    eraftpb::{ConfState, Entry, HardState, Snapshot, SnapshotMetadata},
+    GetEntriesContext, RaftState, Storage, StorageError,
+};
+
+use crate::{
+    error::{IoResultExt, WalError},
+    options::Options,
+    segment::ChunkPosition,
+    wal::Wal,
+};
+
+pub(crate) const RAFT_STATE_FILE_NAME: &str = "RAFT_STATE";
+
+/// Builds the data of a snapshot at the index of its metadata, e.g. by
+/// serializing the state machine, see [`WalStorage::with_snapshot_builder`].
+pub type SnapshotBuilder = Box<dyn Fn(&SnapshotMetadata) -> raft::Result<Vec<u8>> + Send + Sync>;
+
+/// A raft-rs [`Storage`] keeping the entries in a [`Wal`], one record per
+/// entry numbered by its entry index, and the hard state, configuration and
+/// snapshot metadata in a file next to the segments.
+///
+/// Like raft-rs' `MemStorage`, the storage is written through `&self`, so it
+/// can be written while a `RawNode` holds it: [`WalStorage::append`] the
+/// entries and [`WalStorage::set_hard_state`] of every `Ready`,
+/// [`WalStorage::apply_snapshot`] the snapshots received, and
+/// [`WalStorage::compact`] the log once the state machine has a snapshot of
+/// its beginning.
+pub struct WalStorage {
+    core: RwLock<Core>,
+    snapshot_builder: Option<SnapshotBuilder>,
+}
+
+struct Core {
+    wal: Wal,
+    hard_state: HardState,
+    conf_state: ConfState,
+    /// The metadata of the last snapshot applied.
+    snapshot_metadata: SnapshotMetadata,
+    /// Raft indexes of the first and last entry in the Wal, `last` is
+    /// `first - 1` without any.
+    first: u64,
+    last: u64,
+    /// The raft index of an entry minus its entry index in the Wal, wrapping.
+    offset: u64,
+}
+
+impl WalStorage {
+    /// Open the Wal with `options`, with its entry index enabled, along with
+    /// the raft state stored with it.
+    pub fn open(options: Options) -> raft::Result<Self> {
+        let mut wal = Wal::open(options.with_entry_index(true)).map_err(store_error)?;
+        let (hard_state, conf_state, snapshot_metadata) = load_state(&wal.options().dir_path)?;
+        let mut first = snapshot_metadata.index + 1;
+        let mut last = snapshot_metadata.index;
+        let mut offset = 0;
+        if let (Some(first_index), Some(last_index)) = (
+            wal.first_index().map_err(store_error)?,
+            wal.last_index().map_err(store_error)?,
+        ) {
+            let entry =
+                Entry::parse_from_bytes(&wal.read_index(first_index).map_err(store_error)?)?;
+            if entry.index > snapshot_metadata.index {
+                offset = entry.index.wrapping_sub(first_index);
+                first = entry.index;
+                last = last_index.wrapping_add(offset);
+            } else {
+                // Stopped applying a snapshot before the entries were discarded.
+                let end = wal.visible_position().map_err(store_error)?;
+                wal.truncate_before(end).map_err(store_error)?;
+            }
+        }
+        Ok(Self {
+            core: RwLock::new(Core {
+                wal,
+                hard_state,
+                conf_state,
+                snapshot_metadata,
+                first,
+                last,
+                offset,
+            }),
+            snapshot_builder: None,
+        })
+    }
+
+    /// Fill the snapshots [`Storage::snapshot`] returns with the data
+    /// `builder` returns, they have no data otherwise.
+    pub fn with_snapshot_builder(mut self, builder: SnapshotBuilder) -> Self {
+        self.snapshot_builder = Some(builder);
+        self
+    }
+
+    /// Append `entries` and sync them. The entries from the index of the
+    /// first one on are discarded first, e.g. conflicting ones from an
+    /// earlier leader.
+    pub fn append(&self, entries: &[Entry]) -> raft::Result<()> {
+        let Some(head) = entries.first() else {
+            return Ok(());
+        };
+        let mut core = self.wl();
+        if head.index < core.first {
+            return Err(StorageError::Compacted.into());
+        }
+        if head.index > core.last + 1 {
+            return Err(StorageError::Unavailable.into());
+        }
+        if head.index <= core.last {
+            if head.index == core.first {
+                let end = core.wal.visible_position().map_err(store_error)?;
+                core.wal.truncate_before(end).map_err(store_error)?;
+            } else {
+                let pos = core.position(head.index - 1)?;
+                core.wal.truncate_after(pos).map_err(store_error)?;
+            }
+            core.last = head.index - 1;
+        }
+        for entry in entries {
+            let pos = core
+                .wal
+                .write(&entry.write_to_bytes()?)
+                .map_err(store_error)?;
+            if core.first > core.last {
+                let index = core.wal.index_of(pos).map_err(store_error)?;
+                core.offset = entry.index.wrapping_sub(index.unwrap_or_default());
+            }
+            core.last = entry.index;
+        }
+        core.wal.sync().map_err(store_error)
+    }
+
+    pub fn set_hard_state(&self, hard_state: HardState) -> raft::Result<()> {
+        let mut core = self.wl();
+        core.hard_state = hard_state;
+        core.store_state()
+    }
+
+    pub fn set_conf_state(&self, conf_state: ConfState) -> raft::Result<()> {
+        let mut core = self.wl();
+        core.conf_state = conf_state;
+        core.store_state()
+    }
+
+    /// Discard the entries before `compact_index`, e.g. once the state
+    /// machine has a snapshot including them. The entry at `compact_index`
+    /// is kept, so it has to be in the log.
+    pub fn compact(&self, compact_index: u64) -> raft::Result<()> {
+        let mut core = self.wl();
+        if compact_index <= core.first {
+            return Ok(());
+        }
+        if compact_index > core.last {
+            return Err(StorageError::Unavailable.into());
+        }
+        let pos = core.position(compact_index)?;
+        core.wal.truncate_before(pos).map_err(store_error)?;
+        core.first = compact_index;
+        Ok(())
+    }
+
+    /// Replace the log with `snapshot`, e.g. one received from the leader:
+    /// every entry is discarded, and the state is taken from the snapshot.
+    pub fn apply_snapshot(&self, snapshot: Snapshot) -> raft::Result<()> {
+        let mut core = self.wl();
+        let metadata = snapshot.get_metadata();
+        if core.first > metadata.index {
+            return Err(StorageError::SnapshotOutOfDate.into());
+        }
+        core.snapshot_metadata = metadata.clone();
+        core.hard_state.term = core.hard_state.term.max(metadata.term);
+        core.hard_state.commit = metadata.index;
+        core.conf_state = metadata.get_conf_state().clone();
+        core.store_state()?;
+        let end = core.wal.visible_position().map_err(store_error)?;
+        core.wal.truncate_before(end).map_err(store_error)?;
+        core.first = metadata.index + 1;
+        core.last = metadata.index;
+        Ok(())
+    }
+
+    fn rl(&self) -> RwLockReadGuard<'_, Core> {
+        self.core.read().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn wl(&self) -> RwLockWriteGuard<'_, Core> {
+        self.core.write().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Core {
+    fn position(&self, index: u64) -> raft::Result<ChunkPosition> {
+        self.wal
+            .position_of_index(index.wrapping_sub(self.offset))
+            .map_err(store_error)
+    }
+
+    /// The encoded entry at `index`, which has to be in the log.
+    fn read(&self, index: u64) -> raft::Result<Vec<u8>> {
+        self.wal.read(self.position(index)?).map_err(store_error)
+    }
+
+    fn term(&self, index: u64) -> raft::Result<u64> {
+        if index == self.snapshot_metadata.index {
+            return Ok(self.snapshot_metadata.term);
+        }
+        if index < self.first {
+            return Err(StorageError::Compacted.into());
+        }
+        if index > self.last {
+            return Err(StorageError::Unavailable.into());
+        }
+        Ok(Entry::parse_from_bytes(&self.read(index)?)?.term)
+    }
+
+    fn store_state(&self) -> raft::Result<()> {
+        store_state(
+            &self.wal.options().dir_path,
+            &self.hard_state,
+            &self.conf_state,
+            &self.snapshot_metadata,
+        )
+    }
+}
+
+impl Storage for WalStorage {
+    fn initial_state(&self) -> raft::Result<RaftState> {
+        let core = self.rl();
+        Ok(RaftState::new(
+            core.hard_state.clone(),
+            core.conf_state.clone(),
+        ))
+    }
+
+    fn entries(
+        &self,
+        low: u64,
+        high: u64,
+        max_size: impl Into<Option<u64>>,
+        _context: GetEntriesContext,
+    ) -> raft::Result<Vec<Entry>> {
+        let max_size = max_size.into();
+        let core = self.rl();
+        if low < core.first {
+            return Err(StorageError::Compacted.into());
+        }
+        if high > core.last + 1 {
+            return Err(StorageError::Unavailable.into());
+        }
+        // An entry's record is its encoding, so its length is the entry's
+        // size as raft-rs limits it.
+        let mut entries = Vec::new();
+        let mut size = 0;
+        for index in low..high {
+            let data = core.read(index)?;
+            size += data.len() as u64;
+            if !entries.is_empty() && max_size.is_some_and(|max| size > max) {
+                break;
+            }
+            entries.push(Entry::parse_from_bytes(&data)?);
+        }
+        Ok(entries)
+    }
+
+    fn term(&self, idx: u64) -> raft::Result<u64> {
+        self.rl().term(idx)
+    }
+
+    fn first_index(&self) -> raft::Result<u64> {
+        Ok(self.rl().first)
+    }
+
+    fn last_index(&self) -> raft::Result<u64> {
+        Ok(self.rl().last)
+    }
+
+    /// A snapshot at the commit index, with the data of the snapshot builder.
+    fn snapshot(&self, request_index: u64, _to: u64) -> raft::Result<Snapshot> {
+        let core = self.rl();
+        let mut snapshot = Snapshot::default();
+        let metadata = snapshot.mut_metadata();
+        metadata.index = core.hard_state.commit;
+        if metadata.index < request_index {
+            return Err(StorageError::SnapshotTemporarilyUnavailable.into());
+        }
+        metadata.term = core.term(metadata.index)?;
+        metadata.set_conf_state(core.conf_state.clone());
+        if let Some(builder) = &self.snapshot_builder {
+            let data = builder(snapshot.get_metadata())?;
+            snapshot.data = data.into();
+        }
+        Ok(snapshot)
+    }
+}
+
+fn store_error(e: WalError) -> raft::Error {
+    StorageError::Other(Box::new(e)).into()
+}
+
+/// The raft state stored in `dir_path`, the default one if none is.
+fn load_state(dir_path: &Path) -> raft::Result<(HardState, ConfState, SnapshotMetadata)> {
+    let path = dir_path.join(RAFT_STATE_FILE_NAME);
+    let content = match std::fs::read(&path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Default::default()),
+        Err(e) => return Err(e).context("read", &path).map_err(store_error),
+    };
+    let mut input = CodedInputStream::from_bytes(&content);
+    Ok((
+        input.read_message()?,
+        input.read_message()?,
+        input.read_message()?,
+    ))
+}
+
+/// Atomically replace the raft state stored in `dir_path`.
+fn store_state(
+    dir_path: &Path,
+    hard_state: &HardState,
+    conf_state: &ConfState,
+    snapshot_metadata: &SnapshotMetadata,
+) -> raft::Result<()> {
+    let mut content = Vec::new();
+    hard_state.write_length_delimited_to_vec(&mut content)?;
+    conf_state.write_length_delimited_to_vec(&mut content)?;
+    snapshot_metadata.write_length_delimited_to_vec(&mut content)?;
+    let path = dir_path.join(RAFT_STATE_FILE_NAME);
+    let tmp = path.with_extension("tmp");
+    (|| {
+        let mut file = std::fs::File::create(&tmp).context("create", &tmp)?;
+        file.write_all(&content).context("write", &tmp)?;
+        file.sync_all().context("fsync", &tmp)?;
+        std::fs::rename(&tmp, &path).context("rename", &tmp)
+    })()
+    .map_err(store_error)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{segment::BLOCK_SIZE, testing};
+
+    fn entry(index: u64, term: u64) -> Entry {
+        Entry {
+            index,
+            term,
+            data: testing::payload(term as usize, index as usize, 3000).into(),
+            ..Default::default()
+        }
+    }
+
+    fn entries(storage: &WalStorage) -> Vec<Entry> {
+        let (first, last) = (
+            storage.first_index().unwrap(),
+            storage.last_index().unwrap(),
+        );
+        storage
+            .entries(first, last + 1, None, GetEntriesContext::empty(false))
+            .unwrap()
+    }
+
+    #[test]
+    fn raft_log() {
+        let dir = testing::temp_dir("raft_storage");
+        let open = || WalStorage::open(Options::new(&dir, 4 * BLOCK_SIZE as u64)).unwrap();
+        let storage = open();
+        assert_eq!(storage.first_index().unwrap(), 1);
+        assert_eq!(storage.last_index().unwrap(), 0);
+        assert!(!storage.initial_state().unwrap().initialized());
+
+        let mut log: Vec<Entry> = (1..=100).map(|i| entry(i, 1)).collect();
+        storage.append(&log[..60]).unwrap();
+        storage.append(&log[60..]).unwrap();
+        assert_eq!(entries(&storage), log);
+        assert_eq!(storage.term(50).unwrap(), 1);
+        assert_eq!(
+            storage.term(101),
+            Err(raft::Error::Store(StorageError::Unavailable))
+        );
+        let limited = storage
+            .entries(10, 20, 7000, GetEntriesContext::empty(false))
+            .unwrap();
+        assert_eq!(limited, log[9..11]);
+        let first = storage
+            .entries(10, 20, 0, GetEntriesContext::empty(false))
+            .unwrap();
+        assert_eq!(first, log[9..10]);
+
+        // A new leader's entries replace the conflicting ones.
+        log.truncate(79);
+        log.extend((80..=90).map(|i| entry(i, 2)));
+        storage.append(&log[79..]).unwrap();
+        assert_eq!(entries(&storage), log);
+
+        let hard_state = HardState {
+            term: 2,
+            vote: 3,
+            commit: 85,
+            ..Default::default()
+        };
+        storage.set_hard_state(hard_state.clone()).unwrap();
+        let conf_state = ConfState::from((vec![1, 2, 3], vec![]));
+        storage.set_conf_state(conf_state.clone()).unwrap();
+        storage.compact(40).unwrap();
+        assert_eq!(storage.first_index().unwrap(), 40);
+        assert_eq!(
+            storage.term(39),
+            Err(raft::Error::Store(StorageError::Compacted))
+        );
+        drop(storage);
+
+        let storage = open().with_snapshot_builder(Box::new(|metadata| {
+            Ok(metadata.index.to_le_bytes().to_vec())
+        }));
+        assert_eq!(entries(&storage), log[39..]);
+        let state = storage.initial_state().unwrap();
+        assert_eq!(state.hard_state, hard_state);
+        assert_eq!(state.conf_state, conf_state);
+        let snapshot = storage.snapshot(80, 0).unwrap();
+        assert_eq!(snapshot.get_metadata().index, 85);
+        assert_eq!(snapshot.get_metadata().term, 2);
+        assert_eq!(snapshot.get_metadata().get_conf_state(), &conf_state);
+        assert_eq!(snapshot.get_data(), 85u64.to_le_bytes());
+        assert_eq!(
+            storage.snapshot(86, 0),
+            Err(raft::Error::Store(
+                StorageError::SnapshotTemporarilyUnavailable
+            ))
+        );
+
+        // A snapshot from the leader replaces the whole log.
+        let mut snapshot = Snapshot::default();
+        snapshot.mut_metadata().index = 200;
+        snapshot.mut_metadata().term = 3;
+        storage.apply_snapshot(snapshot).unwrap();
+        assert_eq!(storage.first_index().unwrap(), 201);
+        assert_eq!(storage.last_index().unwrap(), 200);
+        assert_eq!(storage.term(200).unwrap(), 3);
+        let log: Vec<Entry> = (201..=210).map(|i| entry(i, 3)).collect();
+        storage.append(&log).unwrap();
+        drop(storage);
+        let storage = open();
+        assert_eq!(entries(&storage), log);
+        assert_eq!(storage.initial_state().unwrap().hard_state.commit, 200);
+        drop(storage);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
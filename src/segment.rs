@@ -1,43 +1,134 @@
 use std::{
-    io::{Seek, Write},
-    os::unix::fs::{FileExt, PermissionsExt},
     path::Path,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
 };
 
-use crate::error::WalError;
+#[cfg(feature = "encryption")]
+use crate::encryption::{ChunkAt, Cipher};
+use crate::{
+    cache::BlockCache,
+    compression::{self, Compression, COMPRESSED},
+    error::{IoResultExt, WalError},
+    options::Preallocation,
+    platform::{self, PositionalIo},
+};
 
 /// 7 Bytes
 ///
 /// Checksum: 4
 ///
-/// Type: 2
+/// Length: 2
 ///
-/// Lenght: 1
-pub(crate) const CHUNK_HEADER_SIZE: u32 = 7;
+/// Type: 1
+pub const CHUNK_HEADER_SIZE: u32 = 7;
 
-/// 32 KB
-pub(crate) const BLOCK_SIZE: u32 = 32 * 1024;
+/// 32 KB, the default, see [`crate::options::Options::with_block_size`].
+pub const BLOCK_SIZE: u32 = 32 * 1024;
+/// The smallest block size, 512 B.
+pub const MIN_BLOCK_SIZE: u32 = 512;
+/// The largest block size, 64 KB: a chunk's length has to fit its 2 bytes.
+pub const MAX_BLOCK_SIZE: u32 = 64 * 1024;
 /// File mod
-const FILE_MODE_PERM: u32 = 0o644;
+pub(crate) const FILE_MODE_PERM: u32 = 0o644;
 /// File suffix
-pub(crate) const SEGMENT_FILE_SUFFIX: &'static str = ".seg";
+pub const SEGMENT_FILE_SUFFIX: &str = ".seg";
+
+pub(crate) fn segment_file_path(dir_path: impl AsRef<Path>, id: u32) -> std::path::PathBuf {
+    dir_path
+        .as_ref()
+        .join(format!("{:09}{}", id, SEGMENT_FILE_SUFFIX))
+}
+
+/// Replace the file of segment `id` in `dir_path` with a copy of its first
+/// `len` bytes, renamed over it rather than cutting it in place, so other
+/// links to the file, e.g. of a fork, and readers holding it keep all of it.
+pub(crate) fn replace_with_prefix(dir_path: &Path, id: u32, len: u64) -> Result<(), WalError> {
+    let path = segment_file_path(dir_path, id);
+    let tmp = path.with_extension("tmp");
+    let file = std::fs::File::open(&path).context("open", &path)?;
+    let mut copy = std::fs::File::create(&tmp).context("create", &tmp)?;
+    let copied =
+        std::io::copy(&mut std::io::Read::take(file, len), &mut copy).context("copy", &path)?;
+    if copied != len {
+        return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof)).context("copy", &path);
+    }
+    let permissions = std::fs::metadata(&path)
+        .context("stat", &path)?
+        .permissions();
+    std::fs::set_permissions(&tmp, permissions).context("chmod", &tmp)?;
+    copy.sync_all().context("fsync", &tmp)?;
+    std::fs::rename(&tmp, &path).context("rename", &tmp)?;
+    platform::sync_dir(dir_path).context("fsync", dir_path)
+}
+
+/// Every append to a segment file goes through here, written at `offset`
+/// rather than wherever the file ends, so a rewound write never leaves a gap.
+///
+/// Never inlined, so that stalls can be attributed to WAL I/O by attaching a
+/// uprobe (e.g. `bpftrace -e 'uprobe:<bin>:*wal_rs*segment*io_append* { ... }'`).
+#[inline(never)]
+fn io_append(file: &std::fs::File, buf: &[u8], offset: u64) -> std::io::Result<()> {
+    file.write_all_at(buf, offset)
+}
+
+/// Every fsync of a segment file goes through here, see [`io_append`].
+#[inline(never)]
+fn io_fsync(file: &std::fs::File) -> std::io::Result<()> {
+    file.sync_all()
+}
+
+/// Set in the type byte of the chunk ending a record when more records of
+/// the same batch follow it, see [`crate::wal::Wal::write_batch`].
+pub(crate) const BATCH_CONTINUES: u8 = 0x80;
+
+/// Set in the type byte of a chunk whose payload is encrypted, see
+/// [`crate::options::Options::with_encryption_key`].
+pub(crate) const ENCRYPTED: u8 = 0x20;
+
+/// Encrypts the payload of a chunk, given its block number, offset and type
+/// byte, see [`BlockWriter::write_sealed`].
+pub(crate) type Seal<'a> = &'a dyn Fn(u32, u64, u8, &[u8]) -> Vec<u8>;
+
+/// What ended a scan of the chunks of a block, see [`Segment::torn_tail`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BlockScanEnd {
+    /// Its padding or the end of the segment.
+    Clean,
+    Zeroed,
+    /// A chunk cut short by the end of the buffer.
+    Torn,
+    /// A chunk failing its checksum, which ends at `end`.
+    InvalidCrc {
+        end: usize,
+    },
+    InvalidType,
+}
 
-#[derive(Clone, Copy, PartialEq, Eq)]
-enum ChunkType {
+/// Which part of a record a chunk holds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChunkType {
+    /// The whole record.
     Full,
     First,
     Middle,
     Last,
 }
 
-impl From<u8> for ChunkType {
-    fn from(value: u8) -> Self {
+/// Fails with the byte if it isn't a chunk type, e.g. read from a corrupted
+/// header.
+impl TryFrom<u8> for ChunkType {
+    type Error = u8;
+
+    fn try_from(value: u8) -> Result<Self, u8> {
         match value {
-            0 => Self::Full,
-            1 => Self::First,
-            2 => Self::Middle,
-            3 => Self::Last,
-            _ => unreachable!(),
+            0 => Ok(Self::Full),
+            1 => Ok(Self::First),
+            2 => Ok(Self::Middle),
+            3 => Ok(Self::Last),
+            _ => Err(value),
         }
     }
 }
@@ -57,252 +148,2537 @@ impl From<ChunkType> for u8 {
 pub struct Segment {
     pub(crate) id: u32,
     file: std::sync::RwLock<std::fs::File>,
-    pub(crate) current_block_number: u32,
-    pub(crate) current_block_size: u32,
+    /// Where the next record goes.
+    writer: BlockWriter,
     file_path: std::path::PathBuf,
+    /// Whether data was appended since the last sync.
+    unsynced: AtomicBool,
+    /// Offset right after the last record readers may see, see
+    /// [`Segment::visible_size`].
+    visible: AtomicU64,
+    /// Offset up to which the segment is synced, see [`Segment::synced_size`].
+    synced: AtomicU64,
+    /// Appends go through this mapping instead of the file, see [`Segment::enable_mmap`].
+    #[cfg(feature = "mmap")]
+    mmap: Option<crate::mmap::MmapAppender>,
+    /// Reads are served from this mapping where it covers them, see
+    /// [`Segment::map_for_reads`].
+    #[cfg(feature = "mmap")]
+    read_map: std::sync::RwLock<Option<crate::mmap::MmapReader>>,
+    /// Opened with `O_DIRECT` to serve reads, see
+    /// [`Segment::enable_direct_reads`].
+    #[cfg(all(feature = "direct-io", target_os = "linux"))]
+    direct: std::sync::RwLock<Option<std::fs::File>>,
+    /// See [`crate::options::Options::with_page_cache_hints`].
+    page_cache_hints: bool,
+    /// The file may be longer than the data in it, see [`Segment::preallocate`].
+    preallocated: bool,
+    /// Reads of the segment, see [`crate::wal::Wal::read_stats`].
+    pub(crate) reads: crate::stats::ReadCounter,
+    /// Shared with the Wal's other segments, see
+    /// [`crate::options::Options::with_block_cache_bytes`].
+    block_cache: Option<Arc<Mutex<BlockCache>>>,
+    /// No longer the active segment, see [`Segment::mark_sealed`].
+    sealed: bool,
+    /// How records are compressed when written, see
+    /// [`crate::options::Options::with_compression`].
+    compression: Option<Compression>,
+    /// Encrypts chunks when written and decrypts them when read, see
+    /// [`crate::options::Options::with_encryption_key`].
+    #[cfg(feature = "encryption")]
+    cipher: Option<Arc<Cipher>>,
+    /// Appends not written to the file yet, see [`Segment::set_write_buffer`].
+    write_buffer: Mutex<WriteBuffer>,
+}
+
+/// Appends held back to be written to the file with a single call.
+#[derive(Debug, Default)]
+struct WriteBuffer {
+    /// Where `bytes` go in the file.
+    offset: u64,
+    bytes: Vec<u8>,
+    /// Write the buffer out once it holds this many bytes, 0 not to buffer.
+    capacity: usize,
 }
 
-#[derive(Debug)]
+/// The position of a record in the log, ordered the same way the records were written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct ChunkPosition {
     pub segment_id: u32,
     pub block_number: u32,
     pub chunk_offset: u64,
 }
 
+impl ChunkPosition {
+    /// Length of [`ChunkPosition::encode`]'s output.
+    pub const ENCODED_LEN: usize = 17;
+    /// Version of the encoding, its first byte.
+    const ENCODING_VERSION: u8 = 1;
+
+    /// A fixed-width encoding of the position, the same on every platform,
+    /// e.g. to store it in an external index: a version byte, then the
+    /// fields in little-endian.
+    pub fn encode(&self) -> [u8; Self::ENCODED_LEN] {
+        let mut buf = [0; Self::ENCODED_LEN];
+        buf[0] = Self::ENCODING_VERSION;
+        buf[1..5].copy_from_slice(&self.segment_id.to_le_bytes());
+        buf[5..9].copy_from_slice(&self.block_number.to_le_bytes());
+        buf[9..].copy_from_slice(&self.chunk_offset.to_le_bytes());
+        buf
+    }
+
+    /// Decode what [`ChunkPosition::encode`] returned, failing with
+    /// `WalError::InvalidEncodedPosition` if it is of another length or
+    /// version.
+    pub fn decode(buf: &[u8]) -> Result<Self, WalError> {
+        if buf.len() != Self::ENCODED_LEN {
+            return Err(WalError::InvalidEncodedPosition(format!(
+                "{} bytes instead of {}",
+                buf.len(),
+                Self::ENCODED_LEN
+            )));
+        }
+        if buf[0] != Self::ENCODING_VERSION {
+            return Err(WalError::InvalidEncodedPosition(format!(
+                "unknown version {}",
+                buf[0]
+            )));
+        }
+        Ok(Self {
+            segment_id: u32::from_le_bytes(buf[1..5].try_into().unwrap()),
+            block_number: u32::from_le_bytes(buf[5..9].try_into().unwrap()),
+            chunk_offset: u64::from_le_bytes(buf[9..].try_into().unwrap()),
+        })
+    }
+
+    /// The block the record starts in.
+    pub fn block_id(&self) -> BlockId {
+        BlockId {
+            segment_id: self.segment_id,
+            block_number: self.block_number,
+        }
+    }
+}
+
+/// Identifies a block of the log, ordered the same way the blocks were written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct BlockId {
+    pub segment_id: u32,
+    pub block_number: u32,
+}
+
+/// A segment of the Wal, see [`crate::wal::Wal::segments`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SegmentInfo {
+    pub segment_id: u32,
+    /// Bytes of the records visible in the segment, padding included.
+    pub size: u64,
+    /// Whether the Wal has rotated past the segment, which never changes again.
+    pub sealed: bool,
+}
+
+/// The chunks of a block, see [`crate::wal::Wal::read_block`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Block {
+    pub id: BlockId,
+    pub chunks: Vec<BlockChunk>,
+}
+
+/// A chunk of a [`Block`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlockChunk {
+    /// Offset of the chunk header in the block.
+    pub offset: u32,
+    pub chunk_type: ChunkType,
+    /// Whether the record is compressed, its chunks hold the compressed bytes.
+    pub compressed: bool,
+    /// The chunk's share of the record, decrypted but otherwise as stored: a
+    /// transform configured in the options applies to whole records, it is
+    /// not reversed here.
+    pub data: Vec<u8>,
+}
+
 impl Segment {
     pub fn open(dir_path: impl AsRef<Path>, id: u32) -> Result<Self, WalError> {
-        let file_name = format!("{:09}{}", id, SEGMENT_FILE_SUFFIX);
-        let file_name = dir_path.as_ref().join(file_name);
-        let file = std::fs::File::options()
-            .read(true)
-            .create(true)
-            .append(true)
-            .open(&file_name)?;
-        // Set file mod.
-        let mut perm = std::fs::metadata(&file_name)?.permissions();
-        perm.set_mode(FILE_MODE_PERM);
-        std::fs::set_permissions(&file_name, perm)?;
+        Self::open_with(
+            dir_path,
+            id,
+            std::fs::File::options().read(true).create(true).write(true),
+        )
+    }
+
+    /// Open an existing segment file for reading only, e.g. on read-only media.
+    pub fn open_read_only(dir_path: impl AsRef<Path>, id: u32) -> Result<Self, WalError> {
+        Self::open_with(dir_path, id, std::fs::File::options().read(true))
+    }
+
+    fn open_with(
+        dir_path: impl AsRef<Path>,
+        id: u32,
+        open_options: &std::fs::OpenOptions,
+    ) -> Result<Self, WalError> {
+        let file_name = segment_file_path(dir_path, id);
+        let file = open_options.open(&file_name).context("open", &file_name)?;
+        Self::from_file(file, id, file_name)
+    }
+
+    /// A segment in `file`, already open for reading and writing, which
+    /// `path` names in errors.
+    pub(crate) fn from_file(
+        file: std::fs::File,
+        id: u32,
+        path: std::path::PathBuf,
+    ) -> Result<Self, WalError> {
+        // Continue writing at the end of the file, the writer keeps track of
+        // its offset from here on.
+        let offset = file.metadata().context("stat", &path)?.len();
         Ok(Self {
             id,
             file: std::sync::RwLock::new(file),
-            current_block_number: 0,
-            current_block_size: 0,
-            file_path: file_name,
+            // Blocks have the default size until `set_block_len`.
+            writer: BlockWriter::new(
+                (offset / BLOCK_SIZE as u64) as u32,
+                (offset % BLOCK_SIZE as u64) as u32,
+            ),
+            file_path: path,
+            unsynced: AtomicBool::new(false),
+            visible: AtomicU64::new(offset),
+            synced: AtomicU64::new(offset),
+            #[cfg(feature = "mmap")]
+            mmap: None,
+            #[cfg(feature = "mmap")]
+            read_map: Default::default(),
+            #[cfg(all(feature = "direct-io", target_os = "linux"))]
+            direct: Default::default(),
+            page_cache_hints: false,
+            preallocated: false,
+            reads: Default::default(),
+            block_cache: None,
+            sealed: false,
+            compression: None,
+            #[cfg(feature = "encryption")]
+            cipher: None,
+            write_buffer: Default::default(),
         })
     }
 
-    pub fn sync(&self) -> Result<(), WalError> {
-        let file = self.file.write().unwrap();
-        file.sync_all()?;
+    /// Serve point reads of complete blocks from `block_cache`.
+    pub(crate) fn set_block_cache(&mut self, block_cache: Option<Arc<Mutex<BlockCache>>>) {
+        self.block_cache = block_cache;
+    }
+
+    pub(crate) fn block_cache(&self) -> Option<Arc<Mutex<BlockCache>>> {
+        self.block_cache.clone()
+    }
+
+    /// Pass hints on how the file is read to the kernel, see
+    /// [`crate::options::Options::with_page_cache_hints`].
+    pub(crate) fn set_page_cache_hints(&mut self, page_cache_hints: bool) {
+        self.page_cache_hints = page_cache_hints;
+    }
+
+    /// Hold appends back until `capacity` bytes add up, or until
+    /// [`Segment::flush`], and write them to the file at once, see
+    /// [`crate::options::Options::with_write_buffer_size`]. Not for appends
+    /// through a mapping, which make no write calls.
+    pub(crate) fn set_write_buffer(&mut self, capacity: usize) -> Result<(), WalError> {
+        self.flush()?;
+        self.write_buffer().capacity = capacity;
         Ok(())
     }
 
-    /// Remove log file from disk.
-    pub fn remove(&self) -> Result<(), WalError> {
-        std::fs::remove_file(&self.file_path)?;
+    fn write_buffer(&self) -> std::sync::MutexGuard<'_, WriteBuffer> {
+        self.write_buffer
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+    }
+
+    /// Write the appends held back in the write buffer to the file. Like
+    /// every other append this doesn't make them durable, see
+    /// [`Segment::sync`].
+    pub(crate) fn flush(&self) -> Result<(), WalError> {
+        let mut buffer = self.write_buffer();
+        if buffer.bytes.is_empty() {
+            return Ok(());
+        }
+        io_append(&self.file_write(), &buffer.bytes, buffer.offset)
+            .context("append", &self.file_path)?;
+        buffer.bytes.clear();
         Ok(())
     }
 
-    pub fn size(&self) -> u64 {
-        (self.current_block_number * BLOCK_SIZE + self.current_block_size) as u64
+    /// Add `bytes`, which go at `offset`, to the write buffer, and write the
+    /// buffer out once it is full. If that fails, `bytes` are dropped again
+    /// and what was held back before stays held back.
+    fn buffer_append(&self, offset: u64, bytes: &[u8]) -> Result<(), WalError> {
+        let mut buffer = self.write_buffer();
+        if buffer.bytes.is_empty() {
+            buffer.offset = offset;
+        }
+        debug_assert_eq!(buffer.offset + buffer.bytes.len() as u64, offset);
+        let len = buffer.bytes.len();
+        buffer.bytes.extend_from_slice(bytes);
+        self.unsynced.store(true, Ordering::Release);
+        if buffer.bytes.len() < buffer.capacity {
+            return Ok(());
+        }
+        if let Err(e) = io_append(&self.file_write(), &buffer.bytes, buffer.offset) {
+            buffer.bytes.truncate(len);
+            return Err(e).context("append", &self.file_path);
+        }
+        buffer.bytes.clear();
+        Ok(())
     }
 
-    pub fn write(&mut self, data: Vec<u8>) -> Result<ChunkPosition, WalError> {
-        // The left block space is not enough for a chunk header
-        if self.current_block_size + CHUNK_HEADER_SIZE >= BLOCK_SIZE {
-            // Zeror padding if necessary
-            if self.current_block_size < BLOCK_SIZE {
-                let padding = vec![0; (BLOCK_SIZE - self.current_block_size) as usize];
-                let mut file = self.file.write().unwrap();
-                file.write(&padding)?;
-            }
-            // Need a new block, clear the current block size.
-            self.current_block_number += 1;
-            self.current_block_size = 0;
-        }
-        // The start position(for read)
-        let position = ChunkPosition {
+    /// Drop what the write buffer holds from `offset` on, e.g. when the
+    /// writer is rewound.
+    fn discard_buffered_from(&self, offset: u64) {
+        let mut buffer = self.write_buffer();
+        let kept = offset.saturating_sub(buffer.offset) as usize;
+        buffer.bytes.truncate(kept);
+    }
+
+    /// The file to read from, once everything held back in the write buffer
+    /// is in it, so reads see every record appended.
+    fn file_for_reads(&self) -> Result<std::sync::RwLockReadGuard<'_, std::fs::File>, WalError> {
+        self.flush()?;
+        Ok(self.file_read())
+    }
+
+    /// Drop the cached pages of a sealed segment with nothing left to sync,
+    /// if asked to pass hints on.
+    fn release_pages(&self, file: &std::fs::File) {
+        if self.page_cache_hints && self.sealed && !self.has_unsynced_data() {
+            platform::advise(file, 0, 0, platform::Advice::DontNeed);
+        }
+    }
+
+    /// The codec the records written now are compressed with.
+    pub(crate) fn compression(&self) -> Option<Compression> {
+        self.compression
+    }
+
+    /// Compress the records written from now on with `compression`.
+    pub(crate) fn set_compression(&mut self, compression: Option<Compression>) {
+        self.compression = compression;
+    }
+
+    /// Encrypt the chunks written from now on with `cipher`, and decrypt
+    /// encrypted ones read with it.
+    #[cfg(feature = "encryption")]
+    pub(crate) fn set_cipher(&mut self, cipher: Option<Arc<Cipher>>) {
+        self.cipher = cipher;
+    }
+
+    /// Replace the payload of the chunk at the position, `data[start..]`, with
+    /// the plain one if the chunk is encrypted.
+    #[cfg_attr(not(feature = "encryption"), allow(unused_variables, clippy::ptr_arg))]
+    fn open_chunk(
+        &self,
+        block_number: u32,
+        chunk_offset: u64,
+        type_byte: u8,
+        data: &mut Vec<u8>,
+        start: usize,
+    ) -> Result<(), WalError> {
+        if type_byte & ENCRYPTED == 0 {
+            return Ok(());
+        }
+        #[cfg(feature = "encryption")]
+        if let Some(cipher) = &self.cipher {
+            let at = ChunkAt {
+                segment_id: self.id,
+                block_number,
+                chunk_offset,
+                type_byte,
+            };
+            let plain = cipher.open(at, &data[start..])?;
+            data.truncate(start);
+            data.extend_from_slice(&plain);
+            return Ok(());
+        }
+        // No key, or built without the encryption feature.
+        Err(WalError::DecryptionFailed {
             segment_id: self.id,
-            block_number: self.current_block_number,
-            chunk_offset: self.current_block_size as u64,
-        };
-        let data_size = data.len();
-        // The entire data and header can fit into the block
-        if self.current_block_size + data_size as u32 + CHUNK_HEADER_SIZE <= BLOCK_SIZE {
-            self.write_internal(data, ChunkType::Full)?;
-            return Ok(position);
-        }
-        // If the size of the data exceeds the size of the block,
-        // the data should be written to the block in batches.
-        let mut data_to_write_size = data_size;
-
-        while data_to_write_size > 0 {
-            // Calculate how much can fit in this block.(Each chunk has a header)
-            let mut chunk_size =
-                (BLOCK_SIZE - self.current_block_size - CHUNK_HEADER_SIZE) as usize;
-            // 确保不写入多余的数据
-            if chunk_size > data_to_write_size {
-                chunk_size = data_to_write_size;
-            }
-            let mut chunk = vec![0; chunk_size];
-
-            // data_size-data_to_write_size: 已经写入的数据量，即data当前的偏移
-            let cur_write_idx = data_size - data_to_write_size;
-            // chunk_size: 当前即将写入的数据量
-            let mut end = cur_write_idx + chunk_size as usize;
-            // In fact, this is not to be happend.
-            if end > data_size {
-                end = data_size
-            }
-            chunk.copy_from_slice(&data[cur_write_idx..end]);
-            // Write the chunks
-            if data_to_write_size == data_size {
-                // First chunk: when data_to_write_size == data_size
-                self.write_internal(chunk, ChunkType::First)?;
-            } else if data_to_write_size == chunk_size {
-                // Last chunk
-                self.write_internal(chunk, ChunkType::Last)?;
-            } else {
-                self.write_internal(chunk, ChunkType::Middle)?;
+            block_number,
+            offset: chunk_offset,
+        })
+    }
+
+    /// Set the permission bits of the file.
+    pub(crate) fn set_permissions(&self, mode: u32) -> Result<(), WalError> {
+        platform::set_mode(&self.file_path, mode).context("chmod", &self.file_path)
+    }
+
+    // Nothing a panicking thread could leave behind in a `File` needs repair,
+    // so the file locks simply recover from poisoning.
+    fn file_read(&self) -> std::sync::RwLockReadGuard<'_, std::fs::File> {
+        self.file
+            .read()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+    }
+
+    fn file_write(&self) -> std::sync::RwLockWriteGuard<'_, std::fs::File> {
+        self.file
+            .write()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+    }
+
+    /// Continue writing at the end of the file, discarding the bookkeeping.
+    pub(crate) fn resync_with_file(&mut self) -> Result<(), WalError> {
+        // Only whole records are ever held back.
+        self.flush()?;
+        if self.preallocated {
+            return self.recover_logical_tail();
+        }
+        let offset = self.metadata()?.len();
+        self.continue_at(offset)
+    }
+
+    /// Lay the segment out in blocks of `block_len` bytes, the block size it
+    /// was written with.
+    pub(crate) fn set_block_len(&mut self, block_len: u32) -> Result<(), WalError> {
+        let size = self.size();
+        self.writer.block_len = block_len;
+        self.continue_at(size)
+    }
+
+    /// Start every record at a multiple of `alignment` bytes into its block.
+    pub(crate) fn set_chunk_alignment(&mut self, alignment: u32) -> Result<(), WalError> {
+        let size = self.size();
+        self.writer.alignment = alignment;
+        self.continue_at(size)
+    }
+
+    /// The size of the segment's blocks.
+    pub fn block_len(&self) -> u32 {
+        self.writer.block_len
+    }
+
+    /// Offset in the file of `chunk_offset` bytes into block `block_number`.
+    pub(crate) fn offset_of(&self, block_number: u32, chunk_offset: u64) -> u64 {
+        block_number as u64 * self.block_len() as u64 + chunk_offset
+    }
+
+    /// End every block with a [`BlockTrailer`] from now on.
+    pub(crate) fn enable_block_trailers(&mut self) -> Result<(), WalError> {
+        self.writer.trailers = true;
+        self.continue_at(self.size())
+    }
+
+    /// Write the next record at `offset`.
+    fn continue_at(&mut self, offset: u64) -> Result<(), WalError> {
+        self.discard_buffered_from(offset);
+        self.visible.store(offset, Ordering::Release);
+        self.synced.fetch_min(offset, Ordering::AcqRel);
+        let block_len = self.block_len() as u64;
+        let block_number = (offset / block_len) as u32;
+        let block_size = (offset % block_len) as u32;
+        let trailers = self.writer.trailers;
+        let alignment = self.writer.alignment;
+        self.writer = BlockWriter::new(block_number, block_size)
+            .with_block_len(block_len as u32)
+            .with_chunk_alignment(alignment);
+        if trailers {
+            // Recount the chunks already in the current block.
+            let mut buf = vec![0; block_size as usize];
+            self.file_for_reads()?
+                .read_exact_at(&mut buf, block_number as u64 * block_len)
+                .context("read", &self.file_path)?;
+            let mut trailer = BlockTrailer::default();
+            let mut chunk_offset = 0;
+            while chunk_offset + CHUNK_HEADER_SIZE as usize <= buf.len() {
+                let header = &buf[chunk_offset..chunk_offset + CHUNK_HEADER_SIZE as usize];
+                if header.iter().all(|b| *b == 0) {
+                    break;
+                }
+                trailer.chunks += 1;
+                trailer.last_chunk_offset = chunk_offset as u16;
+                let length = u16::from_le_bytes(header[4..6].try_into().unwrap()) as usize;
+                chunk_offset = (chunk_offset + CHUNK_HEADER_SIZE as usize + length)
+                    .next_multiple_of(alignment as usize);
             }
-            // Update the left data size
-            data_to_write_size -= chunk_size;
+            self.writer = self.writer.with_trailers(trailer);
         }
-        Ok(position)
+        Ok(())
+    }
+
+    pub fn sync(&self) -> Result<(), WalError> {
+        self.flush()?;
+        let file = self.file_read();
+        let size = self.size();
+        let unsynced = self.unsynced.swap(false, Ordering::AcqRel);
+        #[cfg(feature = "mmap")]
+        if let Some(mmap) = &self.mmap {
+            if let Err(e) = mmap.flush() {
+                self.unsynced.fetch_or(unsynced, Ordering::AcqRel);
+                return Err(e).context("msync", &self.file_path);
+            }
+        }
+        if let Err(e) = io_fsync(&file) {
+            self.unsynced.fetch_or(unsynced, Ordering::AcqRel);
+            return Err(e).context("fsync", &self.file_path);
+        }
+        self.synced.store(size, Ordering::Release);
+        self.release_pages(&file);
+        Ok(())
     }
 
-    /// Write a chunk data to file
-    fn write_internal(
+    /// Preallocate the file to at least `len` bytes and append through a
+    /// mapping of it from now on, so an append is a memcpy and durability
+    /// comes from `msync` in [`Segment::sync`].
+    ///
+    /// The file is longer than the data in it until [`Segment::seal`], so a
+    /// segment reopened after a crash has to find its end with
+    /// [`Segment::recover_logical_tail`].
+    #[cfg(feature = "mmap")]
+    pub(crate) fn enable_mmap(&mut self, len: u64) -> Result<(), WalError> {
+        self.flush()?;
+        self.mmap = None;
+        let file = self.file_write();
+        let len = len
+            .max(self.size())
+            .max(file.metadata().context("stat", &self.file_path)?.len());
+        // Whole blocks, so the padding of the last one is mapped too.
+        let block_len = self.block_len() as u64;
+        let len = len.div_ceil(block_len) * block_len;
+        file.set_len(len).context("preallocate", &self.file_path)?;
+        let mmap =
+            crate::mmap::MmapAppender::map(&file, len as usize).context("mmap", &self.file_path)?;
+        drop(file);
+        self.mmap = Some(mmap);
+        self.preallocated = true;
+        Ok(())
+    }
+
+    /// Preallocate the file to at least `len` bytes with `preallocation`.
+    ///
+    /// Zero-fill and sparse preallocation make the file longer than the data
+    /// in it until [`Segment::seal`], like [`Segment::enable_mmap`]. Keep-size
+    /// preallocation only reserves the space past its end.
+    pub(crate) fn preallocate(
         &mut self,
-        chunk_data: Vec<u8>,
-        chunk_type: ChunkType,
+        len: u64,
+        preallocation: Preallocation,
     ) -> Result<(), WalError> {
-        let data_size = chunk_data.len();
-        let mut buf = vec![0; data_size + CHUNK_HEADER_SIZE as usize];
-        // Length: 2 Bytes, index:4-5
-        buf[4..6].copy_from_slice(&(data_size as u16).to_le_bytes());
-        // Type: 1 Byte, index:6
-        buf[6] = chunk_type.into();
-        // Data: N Bytes, index:7-end
-        buf[7..].copy_from_slice(&chunk_data);
-        // Checksum: 4 Bytes, index:0-3
-        let mut hasher = crc32fast::Hasher::new();
-        hasher.update(&buf[4..]);
-        let sum = hasher.finalize();
-        buf[0..4].copy_from_slice(&sum.to_le_bytes());
-        // Append to the file
-        // dbg!("begin write chunk to file");
-        let mut file = self.file.write().unwrap();
-        match file.write(&buf) {
-            Ok(_) => dbg!("write successful"),
-            Err(e) => dbg!(&format!("write failed: {:?}", e)),
-        };
-        // dbg!("end write chunk to file");
+        self.flush()?;
+        let file = self.file_write();
+        let file_len = file.metadata().context("stat", &self.file_path)?.len();
+        match preallocation {
+            Preallocation::None => return Ok(()),
+            Preallocation::KeepSize => {
+                #[cfg(all(feature = "fallocate", target_os = "linux"))]
+                if len > file_len {
+                    use std::os::unix::io::AsRawFd;
+                    // SAFETY: a plain syscall on a file we hold open.
+                    let ret = unsafe {
+                        libc::fallocate(
+                            file.as_raw_fd(),
+                            libc::FALLOC_FL_KEEP_SIZE,
+                            file_len as libc::off_t,
+                            (len - file_len) as libc::off_t,
+                        )
+                    };
+                    if ret != 0 {
+                        return Err(std::io::Error::last_os_error())
+                            .context("preallocate", &self.file_path);
+                    }
+                }
+                return Ok(());
+            }
+            Preallocation::ZeroFill => {
+                let zeros = vec![0; (len.saturating_sub(file_len) as usize).min(1 << 20)];
+                let mut offset = file_len;
+                while offset < len {
+                    let n = zeros.len().min((len - offset) as usize);
+                    file.write_all_at(&zeros[..n], offset)
+                        .context("preallocate", &self.file_path)?;
+                    offset += n as u64;
+                }
+                // Allocated for good, appends then only overwrite.
+                file.sync_all().context("fsync", &self.file_path)?;
+            }
+            Preallocation::Sparse => {
+                if len > file_len {
+                    file.set_len(len).context("preallocate", &self.file_path)?;
+                }
+            }
+        }
         drop(file);
-        if self.current_block_size > BLOCK_SIZE {
-            panic!("Wrong! Can not exceed the block size");
+        self.preallocated = true;
+        Ok(())
+    }
+
+    /// Called once the segment stops being the active one: stop appending
+    /// through the mapping, and cut any preallocated space off the end.
+    pub(crate) fn seal(&mut self) -> Result<(), WalError> {
+        self.flush()?;
+        self.mark_sealed();
+        #[cfg(feature = "mmap")]
+        {
+            self.mmap = None;
         }
-        // Update the corresponding fields
-        self.current_block_size += buf.len() as u32;
-        // A new block
-        if self.current_block_size == BLOCK_SIZE {
-            self.current_block_number += 1;
-            self.current_block_size = 0;
+        if self.preallocated {
+            let file = self.file_write();
+            if file.metadata().context("stat", &self.file_path)?.len() > self.size() {
+                file.set_len(self.size())
+                    .context("truncate", &self.file_path)?;
+            }
+            drop(file);
+            self.preallocated = false;
         }
+        self.release_pages(&self.file_read());
         Ok(())
     }
 
-    pub fn read(&self, mut block_number: u32, mut chunk_offset: u64) -> Result<Vec<u8>, WalError> {
-        let file = self.file.read().unwrap();
-        let stat = file.metadata()?;
-        let seg_size = stat.len();
-        let mut result = Vec::new();
-        loop {
-            // The size of current block.
-            let mut size = BLOCK_SIZE as u64;
-            // The start position of the block in the file.
-            let offset = (block_number * (BLOCK_SIZE as u32)) as u64;
-            // Deal with the last situation.
-            if offset + size > seg_size as u64 {
-                size = seg_size - offset;
-            }
-            let mut buf = vec![0; size as usize];
-            file.read_exact_at(&mut buf, offset)?;
-            // file.read_at(&mut buf, offset)?;
-            // dbg!(buf.len());
-            // dbg!(block_number, chunk_offset);
+    /// Serve reads of records and replays from a read-only mapping of the
+    /// segment's data instead of reading the file, see
+    /// [`crate::options::Options::with_mmap_reads`]. Only for sealed
+    /// segments, as the mapping doesn't grow with the file.
+    #[cfg(feature = "mmap")]
+    pub(crate) fn map_for_reads(&self) -> Result<(), WalError> {
+        let file = self.file_read();
+        let mut read_map = self.read_map_write();
+        *read_map = None;
+        if self.size() == 0 {
+            return Ok(());
+        }
+        let map = crate::mmap::MmapReader::map(&file, self.size() as usize)
+            .context("mmap", &self.file_path)?;
+        *read_map = Some(map);
+        Ok(())
+    }
 
-            // Header part
-            let mut header = vec![0; CHUNK_HEADER_SIZE as usize];
-            header.copy_from_slice(
-                &buf[chunk_offset as usize..(chunk_offset as usize + CHUNK_HEADER_SIZE as usize)],
-            );
-            // TODO: checksum
+    /// Serve reads of records and replays through a handle opened with
+    /// `O_DIRECT`, see [`crate::options::Options::with_direct_reads`].
+    #[cfg(all(feature = "direct-io", target_os = "linux"))]
+    pub(crate) fn enable_direct_reads(&self) -> Result<(), WalError> {
+        let file = platform::open_direct(&self.file_path).context("open", &self.file_path)?;
+        *self
+            .direct
+            .write()
+            .unwrap_or_else(std::sync::PoisonError::into_inner) = Some(file);
+        Ok(())
+    }
+
+    #[cfg(all(feature = "direct-io", target_os = "linux"))]
+    pub(crate) fn reads_directly(&self) -> bool {
+        self.direct
+            .read()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+            .is_some()
+    }
+
+    #[cfg(all(test, feature = "mmap"))]
+    pub(crate) fn is_mapped_for_reads(&self) -> bool {
+        self.read_map_write().is_some()
+    }
+
+    #[cfg(feature = "mmap")]
+    fn read_map_write(&self) -> std::sync::RwLockWriteGuard<'_, Option<crate::mmap::MmapReader>> {
+        self.read_map
+            .write()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+    }
+
+    /// Fill `buf` from `offset`, from the read mapping if it covers that, or
+    /// through the direct handle if there is one. Every read of the data
+    /// written goes through here.
+    fn read_exact_at(
+        &self,
+        file: &std::fs::File,
+        buf: &mut [u8],
+        offset: u64,
+    ) -> Result<(), WalError> {
+        #[cfg(feature = "mmap")]
+        if let Some(map) = &*self
+            .read_map
+            .read()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+        {
+            if map.read_at(buf, offset) {
+                return Ok(());
+            }
+        }
+        #[cfg(all(feature = "direct-io", target_os = "linux"))]
+        if let Some(direct) = &*self
+            .direct
+            .read()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+        {
+            return platform::read_exact_at_direct(direct, buf, offset)
+                .context("read", &self.file_path);
+        }
+        file.read_exact_at(buf, offset)
+            .context("read", &self.file_path)
+    }
+
+    /// Reject writes with `WalError::SegmentSealed` from now on, e.g. for a
+    /// segment older than the active one, so a stale handle to it can't
+    /// append.
+    pub(crate) fn mark_sealed(&mut self) {
+        self.sealed = true;
+    }
+
+    pub fn is_sealed(&self) -> bool {
+        self.sealed
+    }
+
+    pub fn info(&self) -> SegmentInfo {
+        SegmentInfo {
+            segment_id: self.id,
+            size: self.visible_size(),
+            sealed: self.sealed,
+        }
+    }
+
+    /// Where the batch the segment ends in starts, if its last record isn't the
+    /// last of its batch, i.e. a crash interrupted [`crate::wal::Wal::write_batch`].
+    ///
+    /// Blocks are read backwards from the end until a record which ends a
+    /// batch, usually just the last block.
+    pub(crate) fn unfinished_batch(&self) -> Result<Option<u64>, WalError> {
+        self.unfinished_batch_at(self.size())
+    }
+
+    /// [`Segment::unfinished_batch`] if the segment ended at `size`.
+    pub(crate) fn unfinished_batch_at(&self, size: u64) -> Result<Option<u64>, WalError> {
+        let block_len = self.block_len() as u64;
+        let capacity = self.writer.block_capacity() as usize;
+        let mut unfinished = false;
+        let mut block_number = size.div_ceil(block_len) as u32;
+        while block_number > 0 {
+            block_number -= 1;
+            let start = block_number as u64 * block_len;
+            // A torn or corrupted chunk ends what can be read, reads of it
+            // report the corruption.
+            let (chunks, _) = self.block_chunks(block_number, &self.block_prefix(block_number)?);
+            let ends = chunks.into_iter().filter(|(end, chunk_type, _)| {
+                matches!(chunk_type, ChunkType::Full | ChunkType::Last)
+                    && start + *end as u64 <= size
+            });
+            for (end, _, continues) in ends.rev() {
+                if !continues {
+                    // The batch starts with the next record, past any padding.
+                    let next = if end + CHUNK_HEADER_SIZE as usize >= capacity {
+                        start + block_len
+                    } else {
+                        start + end as u64
+                    };
+                    return Ok(unfinished.then_some(next));
+                }
+                unfinished = true;
+            }
+        }
+        Ok(unfinished.then_some(0))
+    }
+
+    /// Where the segment ends if a crash tore its last write: the end of its
+    /// last intact record, when a chunk cut short by the end of the segment,
+    /// a chunk failing its checksum or zeros the file was extended with before
+    /// the data reached it follow, or a record missing its last chunks.
+    ///
+    /// Anything but zeros after a chunk failing its checksum is corruption
+    /// rather than a torn write, and left for reads to report.
+    ///
+    /// Like [`Segment::unfinished_batch`], blocks are read backwards from the
+    /// end until one in which a record ends.
+    pub(crate) fn torn_tail(&self) -> Result<Option<u64>, WalError> {
+        let size = self.size();
+        let block_len = self.block_len() as u64;
+        let capacity = self.writer.block_capacity() as usize;
+        let mut block_number = size.div_ceil(block_len) as u32;
+        // Whether the blocks after the current one hold only zeros.
+        let mut zeroed_after = true;
+        while block_number > 0 {
+            block_number -= 1;
+            let start = block_number as u64 * block_len;
+            let buf = self.block_prefix(block_number)?;
+            let (chunks, scan_end) = self.block_chunks(block_number, &buf);
+            let scanned = chunks.last().map_or(0, |(end, ..)| *end);
+            let zeroed_from = |from: usize| zeroed_after && buf[from..].iter().all(|b| *b == 0);
+            match scan_end {
+                BlockScanEnd::Clean => {}
+                BlockScanEnd::Torn if start + buf.len() as u64 == size => {}
+                BlockScanEnd::Zeroed if zeroed_from(scanned) => {}
+                BlockScanEnd::InvalidCrc { end } if zeroed_from(end) => {}
+                _ => return Ok(None),
+            }
+            let Some(&(end, ..)) = chunks
+                .iter()
+                .rev()
+                .find(|(_, chunk_type, _)| matches!(chunk_type, ChunkType::Full | ChunkType::Last))
+            else {
+                // Only parts of a record which started in an earlier block.
+                zeroed_after = zeroed_from(0);
+                continue;
+            };
+            // Past the padding, if all of it was written.
+            let next = if end + CHUNK_HEADER_SIZE as usize >= capacity && start + block_len <= size
+            {
+                start + block_len
+            } else {
+                start + end as u64
+            };
+            return Ok((next < size).then_some(next));
+        }
+        Ok((size > 0).then_some(0))
+    }
+
+    /// Where the last chunk boundary is, if the segment doesn't end on one,
+    /// i.e. its last block can't be read to the end.
+    pub(crate) fn misaligned_tail(&self) -> Result<Option<u64>, WalError> {
+        let Some(last) = self.size().checked_sub(1) else {
+            return Ok(None);
+        };
+        let block_len = self.block_len() as u64;
+        // Every block starts with a chunk, so the last one is enough.
+        let block_number = (last / block_len) as u32;
+        let (chunks, scan_end) = self.block_chunks(block_number, &self.block_prefix(block_number)?);
+        let scanned = chunks.last().map_or(0, |(end, ..)| *end);
+        Ok((scan_end != BlockScanEnd::Clean)
+            .then_some(block_number as u64 * block_len + scanned as u64))
+    }
+
+    /// The part of block `block_number` the segment holds.
+    fn block_prefix(&self, block_number: u32) -> Result<Vec<u8>, WalError> {
+        let block_len = self.block_len() as u64;
+        let start = block_number as u64 * block_len;
+        let mut buf = vec![0; block_len.min(self.size() - start) as usize];
+        self.file_for_reads()?
+            .read_exact_at(&mut buf, start)
+            .context("read", &self.file_path)?;
+        Ok(buf)
+    }
+
+    /// The intact chunks at the start of `buf`, block `block_number`, as their
+    /// ends, types and whether their batch continues, and what ended the scan.
+    fn block_chunks(
+        &self,
+        block_number: u32,
+        buf: &[u8],
+    ) -> (Vec<(usize, ChunkType, bool)>, BlockScanEnd) {
+        let capacity = self.writer.block_capacity() as usize;
+        let alignment = self.writer.alignment as usize;
+        let mut chunks = Vec::new();
+        let mut offset = 0;
+        // The rest of the block is padding past there.
+        while offset + (CHUNK_HEADER_SIZE as usize) < capacity {
+            if offset >= buf.len() {
+                break;
+            }
+            if offset + CHUNK_HEADER_SIZE as usize > buf.len() {
+                return (chunks, BlockScanEnd::Torn);
+            }
+            let header = &buf[offset..offset + CHUNK_HEADER_SIZE as usize];
+            if header.iter().all(|b| *b == 0) {
+                return (chunks, BlockScanEnd::Zeroed);
+            }
+            let length = u16::from_le_bytes(header[4..6].try_into().unwrap()) as usize;
+            let end = offset + CHUNK_HEADER_SIZE as usize + length;
+            if end > buf.len() {
+                return (chunks, BlockScanEnd::Torn);
+            }
+            if check_crc(self.id, block_number, offset as u64, &buf[offset..end]).is_err() {
+                return (chunks, BlockScanEnd::InvalidCrc { end });
+            }
+            let Ok(chunk_type) = chunk_type(self.id, block_number, offset as u64, header[6]) else {
+                return (chunks, BlockScanEnd::InvalidType);
+            };
+            chunks.push((end, chunk_type, header[6] & BATCH_CONTINUES != 0));
+            offset = end.next_multiple_of(alignment);
+        }
+        (chunks, BlockScanEnd::Clean)
+    }
+
+    /// Cut the segment off at `len`, e.g. to discard an unfinished batch.
+    pub(crate) fn truncate(&mut self, len: u64) -> Result<(), WalError> {
+        self.discard_buffered_from(len);
+        // The blocks from `len` on are written anew.
+        if let Some(cache) = &self.block_cache {
+            cache
+                .lock()
+                .unwrap_or_else(std::sync::PoisonError::into_inner)
+                .forget_segment_from(BlockId {
+                    segment_id: self.id,
+                    block_number: (len / self.block_len() as u64) as u32,
+                });
+        }
+        #[cfg(feature = "mmap")]
+        if self.mmap.is_some() {
+            // The file stays preallocated, zero the cut off part instead.
+            let zeros = vec![0; (self.size() - len) as usize];
+            if let Some(mmap) = &mut self.mmap {
+                mmap.write_at(len as usize, &zeros);
+            }
+            self.unsynced.store(true, Ordering::Release);
+            return self.continue_at(len);
+        }
+        if self.preallocated {
+            let zeros = vec![0; (self.size() - len) as usize];
+            self.file_write()
+                .write_all_at(&zeros, len)
+                .context("truncate", &self.file_path)?;
+            self.unsynced.store(true, Ordering::Release);
+            return self.continue_at(len);
+        }
+        self.file_write()
+            .set_len(len)
+            .context("truncate", &self.file_path)?;
+        self.unsynced.store(true, Ordering::Release);
+        self.continue_at(len)
+    }
+
+    /// Stop reading at `len`, leaving the file as it is, e.g. to hide an
+    /// unfinished batch when the segment can't be truncated.
+    pub(crate) fn end_at(&mut self, len: u64) -> Result<(), WalError> {
+        self.continue_at(len)
+    }
+
+    /// Continue writing after the last intact record, rather than at the end
+    /// of the file, which may have been preallocated past it.
+    pub(crate) fn recover_logical_tail(&mut self) -> Result<(), WalError> {
+        let mut reader = SegmentReader::new(self);
+        reader.size = self.metadata()?.len();
+        let mut end = 0;
+        while let Ok(Some(_)) = reader.next_record() {
+            end = reader.offset;
+        }
+        self.preallocated = true;
+        self.continue_at(end)
+    }
+
+    /// Append `buf` at the end of the segment, which is at `offset`.
+    fn append(&mut self, offset: u64, buf: &[u8]) -> Result<(), WalError> {
+        #[cfg(feature = "mmap")]
+        if let Some(mmap) = &self.mmap {
+            // Records may overflow the segment size, grow the mapping for them.
+            if offset + buf.len() as u64 > mmap.len() as u64 {
+                self.enable_mmap(2 * (offset + buf.len() as u64))?;
+            }
+            if let Some(mmap) = &mut self.mmap {
+                mmap.write_at(offset as usize, buf);
+            }
+            self.unsynced.store(true, Ordering::Release);
+            return Ok(());
+        }
+        let file = self.file_write();
+        io_append(&file, buf, offset).context("append", &self.file_path)?;
+        self.unsynced.store(true, Ordering::Release);
+        Ok(())
+    }
+
+    /// Whether appends go through a mapping instead of write calls.
+    fn appends_mapped(&self) -> bool {
+        #[cfg(feature = "mmap")]
+        if self.mmap.is_some() {
+            return true;
+        }
+        false
+    }
+
+    /// Whether data was appended since the last successful sync.
+    pub fn has_unsynced_data(&self) -> bool {
+        self.unsynced.load(Ordering::Acquire)
+    }
+
+    /// Remove log file from disk.
+    pub fn remove(&self) -> Result<(), WalError> {
+        std::fs::remove_file(&self.file_path).context("remove", &self.file_path)?;
+        Ok(())
+    }
+
+    /// Move the log file into `dir_path`, falling back to a copy when the
+    /// directories are on different file systems.
+    pub fn relocate(&mut self, dir_path: impl AsRef<Path>) -> Result<(), WalError> {
+        self.flush()?;
+        let target = segment_file_path(&dir_path, self.id);
+        if std::fs::rename(&self.file_path, &target).is_err() {
+            // Copy under a temporary name first, so a crash never leaves a
+            // partial copy behind that looks like a complete segment.
+            let tmp = target.with_extension("tmp");
+            std::fs::copy(&self.file_path, &tmp).context("copy", &self.file_path)?;
+            std::fs::File::open(&tmp)
+                .and_then(|tmp| tmp.sync_all())
+                .context("fsync", &tmp)?;
+            std::fs::rename(&tmp, &target).context("rename", &tmp)?;
+            std::fs::remove_file(&self.file_path).context("remove", &self.file_path)?;
+        }
+        let file = std::fs::File::options()
+            .read(true)
+            .write(true)
+            .open(&target)
+            .context("open", &target)?;
+        self.file = std::sync::RwLock::new(file);
+        self.file_path = target;
+        #[cfg(feature = "mmap")]
+        if self.read_map_write().is_some() {
+            self.map_for_reads()?;
+        }
+        #[cfg(all(feature = "direct-io", target_os = "linux"))]
+        if self.reads_directly() {
+            self.enable_direct_reads()?;
+        }
+        Ok(())
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.file_path
+    }
+
+    pub fn size(&self) -> u64 {
+        self.writer.offset()
+    }
+
+    /// Offset right after the last record fully appended, the end of what
+    /// reads see. Records of a batch become visible together with its last
+    /// one, chunks of a record still being appended never do.
+    pub fn visible_size(&self) -> u64 {
+        self.visible.load(Ordering::Acquire)
+    }
+
+    /// Offset up to which the segment has been synced.
+    pub fn synced_size(&self) -> u64 {
+        self.synced.load(Ordering::Acquire)
+    }
+
+    /// Where the next record will be laid out.
+    pub fn writer(&self) -> BlockWriter {
+        self.writer
+    }
+
+    pub fn write(&mut self, data: Vec<u8>) -> Result<ChunkPosition, WalError> {
+        self.write_slice(&data)
+    }
+
+    /// Like [`Segment::write`], for a record borrowed from the caller.
+    pub fn write_slice(&mut self, data: &[u8]) -> Result<ChunkPosition, WalError> {
+        self.write_in_batch(data, false)
+    }
+
+    /// Write a record of a batch, flagged if more records of the batch follow.
+    pub(crate) fn write_in_batch(
+        &mut self,
+        data: &[u8],
+        continues: bool,
+    ) -> Result<ChunkPosition, WalError> {
+        if self.sealed {
+            return Err(WalError::SegmentSealed {
+                segment_id: self.id,
+            });
+        }
+        let compressed = match self.compression {
+            Some(compression) => compression.compress(data)?,
+            None => None,
+        };
+        let (data, flags) = match &compressed {
+            Some(compressed) => (&compressed[..], COMPRESSED),
+            None => (data, 0),
+        };
+        #[cfg(feature = "encryption")]
+        let (cipher, segment_id) = (self.cipher.clone(), self.id);
+        #[cfg(feature = "encryption")]
+        let seal_chunk = cipher.as_deref().map(|cipher| {
+            move |block_number, chunk_offset, type_byte, payload: &[u8]| {
+                let at = ChunkAt {
+                    segment_id,
+                    block_number,
+                    chunk_offset,
+                    type_byte,
+                };
+                cipher.seal(at, payload)
+            }
+        });
+        #[cfg(feature = "encryption")]
+        let seal = seal_chunk
+            .as_ref()
+            .map(|seal| (crate::encryption::OVERHEAD, seal as Seal<'_>));
+        #[cfg(not(feature = "encryption"))]
+        let seal = None;
+        let mut writer = self.writer;
+        // Padding and trailers ending a block are held back and appended with
+        // the next chunk, saving a write per block boundary. Mapped appends
+        // don't make a syscall per piece. With a write buffer the whole record
+        // is put together first and goes into the buffer as one.
+        let coalesce = !self.appends_mapped();
+        let buffered = coalesce && self.write_buffer().capacity > 0;
+        let mut record: Option<(u64, Vec<u8>)> = None;
+        let mut held: Option<(u64, Vec<u8>)> = None;
+        let mut written =
+            writer.write_sealed(data, continues, flags, seal, |offset, piece| match piece {
+                Piece::Padding(bytes) | Piece::Trailer(bytes) | Piece::Chunk(bytes) if buffered => {
+                    record
+                        .get_or_insert_with(|| (offset, Vec::new()))
+                        .1
+                        .extend_from_slice(bytes);
+                    Ok(())
+                }
+                Piece::Padding(bytes) | Piece::Trailer(bytes) if coalesce => {
+                    if matches!(piece, Piece::Padding(_)) {
+                        fail_point!("segment::before_padding");
+                    }
+                    held.get_or_insert_with(|| (offset, Vec::new()))
+                        .1
+                        .extend_from_slice(bytes);
+                    Ok(())
+                }
+                Piece::Padding(padding) => {
+                    fail_point!("segment::before_padding");
+                    self.append(offset, padding)
+                }
+                Piece::Trailer(trailer) => self.append(offset, trailer),
+                Piece::Chunk(chunk) => {
+                    fail_point!("segment::before_chunk");
+                    let Some((start, buf)) = &mut held else {
+                        fail_point!("segment::torn_chunk", {
+                            let _ = self.append(offset, &chunk[..chunk.len() / 2]);
+                        });
+                        return self.append(offset, chunk);
+                    };
+                    let len = buf.len();
+                    buf.extend_from_slice(chunk);
+                    fail_point!("segment::torn_chunk", {
+                        let _ = self.append(*start, &buf[..len + chunk.len() / 2]);
+                    });
+                    let appended = self.append(*start, buf);
+                    if appended.is_ok() {
+                        held = None;
+                    } else {
+                        // Still held back, rewound to below.
+                        buf.truncate(len);
+                    }
+                    appended
+                }
+            });
+        if let (Ok(_), Some((start, bytes))) = (&written, record.take()) {
+            if let Err(e) = self.buffer_append(start, &bytes) {
+                written = Err(e);
+                // Rewound to below.
+                held = Some((start, Vec::new()));
+            }
+        }
+        // The record ended a block, append what is still held back.
+        if written.is_ok() {
+            if let Some((start, buf)) = held.take() {
+                if let Err(e) = self.append(start, &buf) {
+                    written = Err(e);
+                    held = Some((start, buf));
+                }
+            }
+        }
+        // Whatever was appended before a failure stays accounted for, bytes
+        // held back never made it to the file.
+        self.writer = writer;
+        if let (Err(_), Some((start, _))) = (&written, &held) {
+            self.continue_at(*start)?;
+        }
+        let (block_number, chunk_offset) = written?;
+        if !continues {
+            self.visible.store(self.size(), Ordering::Release);
+        }
+        Ok(ChunkPosition {
+            segment_id: self.id,
+            block_number,
+            chunk_offset,
+        })
+    }
+
+    pub fn read(&self, block_number: u32, chunk_offset: u64) -> Result<Vec<u8>, WalError> {
+        Ok(self.read_with_next(block_number, chunk_offset)?.0)
+    }
+
+    /// Read the record at the position, and return the position right after it
+    /// as `(block_number, chunk_offset)`. The returned position equals `size()`
+    /// when the record is the last one in the segment.
+    pub fn read_with_next(
+        &self,
+        block_number: u32,
+        chunk_offset: u64,
+    ) -> Result<(Vec<u8>, u32, u64), WalError> {
+        let mut data = Vec::new();
+        let (block_number, chunk_offset, _) =
+            self.read_chunks(block_number, chunk_offset, &mut data, self.visible_size())?;
+        Ok((data, block_number, chunk_offset))
+    }
+
+    /// Like [`Segment::read`], but also see the records of a batch that isn't
+    /// visible yet, e.g. to verify them right after appending them.
+    pub(crate) fn read_appended(
+        &self,
+        block_number: u32,
+        chunk_offset: u64,
+    ) -> Result<Vec<u8>, WalError> {
+        let mut data = Vec::new();
+        self.read_chunks(block_number, chunk_offset, &mut data, self.size())?;
+        Ok(data)
+    }
+
+    /// Like [`Segment::read_with_next`], but replace the contents of `buf` with
+    /// the record instead of allocating a new one.
+    pub fn read_with_next_into(
+        &self,
+        block_number: u32,
+        chunk_offset: u64,
+        buf: &mut Vec<u8>,
+    ) -> Result<(u32, u64), WalError> {
+        let (block_number, chunk_offset, _) =
+            self.read_stored_into(block_number, chunk_offset, buf)?;
+        Ok((block_number, chunk_offset))
+    }
+
+    /// Like [`Segment::read_with_next_into`], also returning whether the
+    /// record was stored compressed.
+    pub(crate) fn read_stored_into(
+        &self,
+        block_number: u32,
+        chunk_offset: u64,
+        buf: &mut Vec<u8>,
+    ) -> Result<(u32, u64, bool), WalError> {
+        buf.clear();
+        self.read_chunks(block_number, chunk_offset, buf, self.visible_size())
+    }
 
+    /// Append the record at the position to `result`, verifying the checksum
+    /// of every chunk, and return the position right after it and whether it
+    /// was stored compressed. Only the first `seg_size` bytes are read.
+    fn read_chunks(
+        &self,
+        mut block_number: u32,
+        mut chunk_offset: u64,
+        result: &mut Vec<u8>,
+        seg_size: u64,
+    ) -> Result<(u32, u64, bool), WalError> {
+        let file = self.file_for_reads()?;
+        let block_len = self.block_len() as u64;
+        // A record running past the end is still being written, or was torn.
+        let (start_block, start_offset) = (block_number, chunk_offset);
+        let incomplete = || WalError::IncompleteRecord {
+            segment_id: self.id,
+            block_number: start_block,
+            offset: start_offset,
+        };
+        let invalid_position = || WalError::InvalidPosition {
+            segment_id: self.id,
+            block_number: start_block,
+            offset: start_offset,
+        };
+        let alignment = self.writer.alignment as u64;
+        if chunk_offset + CHUNK_HEADER_SIZE as u64 >= self.writer.block_capacity() as u64
+            || !chunk_offset.is_multiple_of(alignment)
+        {
+            return Err(invalid_position());
+        }
+        let record_start = result.len();
+        let mut compressed = false;
+        // The block whose cached copy was dropped for a failed checksum.
+        let mut repaired = None;
+        loop {
+            // The start position of the chunk in the file.
+            let offset = block_number as u64 * block_len + chunk_offset;
+            if offset + CHUNK_HEADER_SIZE as u64 > seg_size {
+                return Err(incomplete());
+            }
+            // Header part
+            let block = self.cached_block(&file, block_number, seg_size)?;
+            let mut header = [0; CHUNK_HEADER_SIZE as usize];
+            match &block {
+                Some(block) => header
+                    .copy_from_slice(&block[chunk_offset as usize..][..CHUNK_HEADER_SIZE as usize]),
+                None => self.read_exact_at(&file, &mut header, offset)?,
+            }
+            // A zeroed header is padding, or a region zero-filled or
+            // hole-punched, never a chunk.
+            let is_start = (block_number, chunk_offset) == (start_block, start_offset);
+            if header.iter().all(|b| *b == 0) {
+                return Err(if is_start {
+                    invalid_position()
+                } else {
+                    incomplete()
+                });
+            }
             // Length
             let length = u16::from_le_bytes(header[4..6].try_into().unwrap()) as usize;
-            dbg!(length);
+            let end = chunk_offset as usize + CHUNK_HEADER_SIZE as usize + length;
+            if end > self.writer.block_capacity() as usize {
+                if block.is_some() && repaired != Some(block_number) {
+                    repaired = Some(block_number);
+                    self.forget_corrupt(block_number);
+                    continue;
+                }
+                return Err(invalid_length(self.id, block_number, chunk_offset, length));
+            }
+            if offset + (CHUNK_HEADER_SIZE as usize + length) as u64 > seg_size {
+                return Err(incomplete());
+            }
+
+            // Read the data straight into the record.
+            let start = result.len();
+            result.resize(start + length, 0);
+            match &block {
+                Some(block) => result[start..].copy_from_slice(
+                    &block[chunk_offset as usize + CHUNK_HEADER_SIZE as usize..end],
+                ),
+                None => self.read_exact_at(
+                    &file,
+                    &mut result[start..],
+                    offset + CHUNK_HEADER_SIZE as u64,
+                )?,
+            }
 
-            // Copy data
-            let start = chunk_offset as usize + CHUNK_HEADER_SIZE as usize;
-            result.extend_from_slice(&buf[start..start + length]);
+            let mut hasher = crc32fast::Hasher::new();
+            hasher.update(&header[4..]);
+            hasher.update(&result[start..]);
+            if u32::from_le_bytes(header[0..4].try_into().unwrap()) != hasher.finalize() {
+                // The cached copy went bad, read the block from the file again.
+                if block.is_some() && repaired != Some(block_number) {
+                    repaired = Some(block_number);
+                    self.forget_corrupt(block_number);
+                    result.truncate(start);
+                    continue;
+                }
+                return Err(WalError::InvalidCrc {
+                    segment_id: self.id,
+                    block_number,
+                    offset: chunk_offset,
+                });
+            }
 
             // Type
-            let chunk_type: ChunkType = header[6].into();
+            let chunk_type = chunk_type(self.id, block_number, chunk_offset, header[6])?;
+            if is_start && (chunk_type == ChunkType::Middle || chunk_type == ChunkType::Last) {
+                return Err(invalid_position());
+            }
+            self.open_chunk(block_number, chunk_offset, header[6], result, start)?;
+            compressed |= header[6] & COMPRESSED != 0;
             if chunk_type == ChunkType::Full || chunk_type == ChunkType::Last {
+                chunk_offset = (end as u64).next_multiple_of(alignment);
                 break;
             }
             block_number += 1;
             chunk_offset = 0;
         }
-        Ok(result)
+        if compressed {
+            let record = compression::decompress(&result[record_start..])?;
+            result.truncate(record_start);
+            result.extend_from_slice(&record);
+        }
+        // The rest of the block is padding if it can't hold another chunk header.
+        if chunk_offset + CHUNK_HEADER_SIZE as u64 >= self.writer.block_capacity() as u64 {
+            block_number += 1;
+            chunk_offset = 0;
+        }
+        Ok((block_number, chunk_offset, compressed))
+    }
+
+    /// Read the first `len` bytes of the segment ahead of a scan getting
+    /// there, into the block cache if there is one, so a point read of them
+    /// is served from memory, and else only the page cache.
+    pub(crate) fn prefetch(&self, len: u64) -> Result<(), WalError> {
+        let file = self.file_for_reads()?;
+        let seg_size = self.visible_size();
+        let len = len.min(seg_size);
+        let block_len = self.block_len() as u64;
+        if self.block_cache.is_some() {
+            for block_number in 0..len.div_ceil(block_len) {
+                self.cached_block(&file, block_number as u32, seg_size)?;
+            }
+            return Ok(());
+        }
+        let mut window = vec![0; (READAHEAD_BLOCKS * block_len).min(len) as usize];
+        let mut offset = 0;
+        while offset < len {
+            let n = window.len().min((len - offset) as usize);
+            self.read_exact_at(&file, &mut window[..n], offset)?;
+            offset += n as u64;
+        }
+        Ok(())
+    }
+
+    /// Block `block_number` from the block cache, read into it if missing, if
+    /// there is one and the segment has been written past the block.
+    ///
+    /// Blocks are verified once, when they are put into the cache. A block
+    /// which fails as read from the file isn't cached, `None` then has the
+    /// caller read the file and report what is corrupt. A cached block
+    /// whose chunk fails its checksum later is dropped by
+    /// [`Segment::read_chunks`] and read from the file again, counted in
+    /// [`crate::wal::Wal::block_cache_repairs`].
+    fn cached_block(
+        &self,
+        file: &std::fs::File,
+        block_number: u32,
+        seg_size: u64,
+    ) -> Result<Option<Arc<[u8]>>, WalError> {
+        let Some(cache) = &self.block_cache else {
+            return Ok(None);
+        };
+        let block_len = self.block_len() as u64;
+        let start = block_number as u64 * block_len;
+        // Blocks of a batch still being appended may be cut off again if it
+        // fails, they are only cached once it is visible.
+        if start + block_len > seg_size.min(self.visible_size()) {
+            return Ok(None);
+        }
+        let lock = || {
+            cache
+                .lock()
+                .unwrap_or_else(std::sync::PoisonError::into_inner)
+        };
+        let id = BlockId {
+            segment_id: self.id,
+            block_number,
+        };
+        let cached = lock().get(id);
+        if cached.is_some() {
+            return Ok(cached);
+        }
+        let mut buf = vec![0; block_len as usize];
+        self.read_exact_at(file, &mut buf, start)?;
+        if verify_block(self.id, block_number, &buf, self.writer).is_err() {
+            return Ok(None);
+        }
+        let block: Arc<[u8]> = buf.into();
+        lock().insert(id, block.clone());
+        Ok(Some(block))
+    }
+
+    /// Drop block `block_number` from the block cache, if it is cached, once
+    /// it turned out corrupt, see [`Segment::cached_block`].
+    fn forget_corrupt(&self, block_number: u32) {
+        #[cfg(feature = "tracing")]
+        tracing::warn!(
+            segment_id = self.id,
+            block_number,
+            "block failed verification"
+        );
+        if let Some(cache) = &self.block_cache {
+            cache
+                .lock()
+                .unwrap_or_else(std::sync::PoisonError::into_inner)
+                .forget_corrupt(BlockId {
+                    segment_id: self.id,
+                    block_number,
+                });
+        }
+    }
+
+    /// Read a block with a single I/O and parse its chunks, verifying their
+    /// checksums. Only what reads see is read, see [`Segment::visible_size`],
+    /// so a block past it has no chunks.
+    pub fn read_block(&self, block_number: u32) -> Result<Vec<BlockChunk>, WalError> {
+        let file = self.file_for_reads()?;
+        let block_len = self.block_len() as u64;
+        let start = block_number as u64 * block_len;
+        let visible = self.visible_size().saturating_sub(start);
+        let mut buf = vec![0; block_len.min(visible) as usize];
+        self.read_exact_at(&file, &mut buf, start)?;
+        drop(file);
+
+        let mut chunks = Vec::new();
+        let mut offset = 0;
+        // Stop at the padding, or at the end of what has been written.
+        while offset + CHUNK_HEADER_SIZE as usize <= buf.len()
+            && offset + (CHUNK_HEADER_SIZE as usize) < self.writer.block_capacity() as usize
+        {
+            let header = &buf[offset..offset + CHUNK_HEADER_SIZE as usize];
+            if header.iter().all(|b| *b == 0) {
+                break;
+            }
+            let length = u16::from_le_bytes(header[4..6].try_into().unwrap()) as usize;
+            let end = offset + CHUNK_HEADER_SIZE as usize + length;
+            if end > self.writer.block_capacity() as usize {
+                return Err(invalid_length(self.id, block_number, offset as u64, length));
+            }
+            if end > buf.len() {
+                break;
+            }
+            check_crc(self.id, block_number, offset as u64, &buf[offset..end])?;
+            let type_byte = buf[offset + 6];
+            let mut data = buf[offset + CHUNK_HEADER_SIZE as usize..end].to_vec();
+            self.open_chunk(block_number, offset as u64, type_byte, &mut data, 0)?;
+            chunks.push(BlockChunk {
+                offset: offset as u32,
+                chunk_type: chunk_type(self.id, block_number, offset as u64, type_byte)?,
+                compressed: type_byte & COMPRESSED != 0,
+                data,
+            });
+            offset = end.next_multiple_of(self.writer.alignment as usize);
+        }
+        Ok(chunks)
+    }
+
+    /// Verify the checksum of every chunk and block trailer up to
+    /// [`Segment::visible_size`], reading about `VERIFY_READ_BYTES` of whole
+    /// blocks per I/O. With the `rayon` feature the blocks of a read are
+    /// verified in parallel.
+    pub fn verify(&self) -> Result<VerifyReport, WalError> {
+        let size = self.visible_size();
+        let block_len = self.block_len() as u64;
+        let mut report = VerifyReport {
+            segments: 1,
+            bytes: size,
+            ..Default::default()
+        };
+        let read_len = (VERIFY_READ_BYTES / block_len).max(1) * block_len;
+        let mut buf = Vec::new();
+        let mut start = 0;
+        while start < size {
+            let len = read_len.min(size - start);
+            buf.resize(len as usize, 0);
+            self.read_exact_at(&*self.file_for_reads()?, &mut buf, start)?;
+            let first_block = (start / block_len) as u32;
+            let blocks: Vec<(u32, &[u8])> = buf
+                .chunks(block_len as usize)
+                .enumerate()
+                .map(|(i, block)| (first_block + i as u32, block))
+                .collect();
+            let verify = |&(block_number, block): &(u32, &[u8])| {
+                verify_block(self.id, block_number, block, self.writer)
+            };
+            #[cfg(feature = "rayon")]
+            let verified: Vec<_> = {
+                use rayon::prelude::*;
+                blocks.par_iter().map(verify).collect()
+            };
+            #[cfg(not(feature = "rayon"))]
+            let verified: Vec<_> = blocks.iter().map(verify).collect();
+            // Report the first corruption, wherever it was found first. The
+            // file is the source of truth, a cached copy of the block goes.
+            for (&(block_number, _), chunks) in blocks.iter().zip(verified) {
+                match chunks {
+                    Ok(chunks) => report.chunks += chunks,
+                    Err(e) => {
+                        self.forget_corrupt(block_number);
+                        return Err(e);
+                    }
+                }
+                report.blocks += 1;
+            }
+            start += len;
+        }
+        Ok(report)
+    }
+
+    /// Verify the block `block_number` alone, like [`Segment::verify`], e.g.
+    /// one of a sample. The report counts no segment.
+    pub(crate) fn verify_block(&self, block_number: u32) -> Result<VerifyReport, WalError> {
+        let block_len = self.block_len() as u64;
+        let start = block_number as u64 * block_len;
+        let mut buf = vec![0; block_len.min(self.visible_size().saturating_sub(start)) as usize];
+        self.read_exact_at(&*self.file_for_reads()?, &mut buf, start)?;
+        let chunks = verify_block(self.id, block_number, &buf, self.writer)
+            .inspect_err(|_| self.forget_corrupt(block_number))?;
+        Ok(VerifyReport {
+            blocks: 1,
+            chunks,
+            bytes: buf.len() as u64,
+            ..Default::default()
+        })
+    }
+
+    /// Number of blocks holding the segment's visible data, the last one
+    /// possibly unfinished.
+    pub(crate) fn block_count(&self) -> u32 {
+        self.visible_size().div_ceil(self.block_len() as u64) as u32
+    }
+
+    /// The trailer of a finished block, `None` if block trailers are disabled
+    /// or the block is the one still being written.
+    pub fn block_trailer(&self, block_number: u32) -> Result<Option<BlockTrailer>, WalError> {
+        if !self.writer.trailers || block_number >= self.writer.block_number() {
+            return Ok(None);
+        }
+        let mut buf = [0; BLOCK_TRAILER_SIZE as usize];
+        let capacity = self.writer.block_capacity();
+        let offset = block_number as u64 * self.block_len() as u64 + capacity as u64;
+        self.read_exact_at(&*self.file_for_reads()?, &mut buf, offset)?;
+        BlockTrailer::decode(self.id, block_number, capacity, &buf)
     }
 
     pub fn metadata(&self) -> Result<std::fs::Metadata, WalError> {
-        Ok(self.file.read().unwrap().metadata()?)
+        self.file_read().metadata().context("stat", &self.file_path)
+    }
+}
+
+impl Drop for Segment {
+    /// Write out what is still held back in the write buffer. Errors are
+    /// ignored, [`Segment::sync`] reports them.
+    fn drop(&mut self) {
+        let _ = self.flush();
+    }
+}
+
+/// Bytes read at once by [`Segment::verify`], rounded down to whole blocks
+/// but at least one.
+const VERIFY_READ_BYTES: u64 = 4 * 1024 * 1024;
+
+/// What [`Segment::verify`] checked.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct VerifyReport {
+    pub segments: u64,
+    pub blocks: u64,
+    pub chunks: u64,
+    pub bytes: u64,
+}
+
+impl std::ops::AddAssign for VerifyReport {
+    fn add_assign(&mut self, other: Self) {
+        self.segments += other.segments;
+        self.blocks += other.blocks;
+        self.chunks += other.chunks;
+        self.bytes += other.bytes;
+    }
+}
+
+/// Verify the chunks of a block laid out by `layout`, which may be cut short
+/// by the end of the segment, and its trailer if it is complete. Returns the
+/// number of chunks.
+fn verify_block(
+    segment_id: u32,
+    block_number: u32,
+    block: &[u8],
+    layout: BlockWriter,
+) -> Result<u64, WalError> {
+    let capacity = layout.block_capacity() as usize;
+    let mut chunks = 0;
+    let mut offset = 0;
+    while offset + CHUNK_HEADER_SIZE as usize <= block.len()
+        && offset + (CHUNK_HEADER_SIZE as usize) < capacity
+    {
+        let header = &block[offset..offset + CHUNK_HEADER_SIZE as usize];
+        // The rest of the block is padding, or a zeroed region.
+        if header.iter().all(|b| *b == 0) {
+            break;
+        }
+        let length = u16::from_le_bytes(header[4..6].try_into().unwrap()) as usize;
+        let end = offset + CHUNK_HEADER_SIZE as usize + length;
+        if end > capacity {
+            return Err(invalid_length(
+                segment_id,
+                block_number,
+                offset as u64,
+                length,
+            ));
+        }
+        if end > block.len() {
+            return Err(WalError::IncompleteRecord {
+                segment_id,
+                block_number,
+                offset: offset as u64,
+            });
+        }
+        check_crc(segment_id, block_number, offset as u64, &block[offset..end])?;
+        chunk_type(segment_id, block_number, offset as u64, header[6])?;
+        chunks += 1;
+        offset = end.next_multiple_of(layout.alignment as usize);
+    }
+    if layout.trailers && block.len() == layout.block_len as usize {
+        BlockTrailer::decode(
+            segment_id,
+            block_number,
+            capacity as u32,
+            &block[capacity..],
+        )?;
+    }
+    Ok(chunks)
+}
+
+/// Bytes laid out by a [`BlockWriter`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Piece<'a> {
+    /// Zeros filling the rest of a block that can't hold another chunk header.
+    Padding(&'a [u8]),
+    /// A chunk, header included.
+    Chunk(&'a [u8]),
+    /// The trailer ending a block, see [`BlockTrailer`].
+    Trailer(&'a [u8]),
+}
+
+/// Lays records out as typed chunks and block padding.
+///
+/// It only tracks the position in the segment, the caller appends the pieces
+/// it is handed, in order. Every write path shares it, so the on-disk layout
+/// has a single definition.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlockWriter {
+    block_number: u32,
+    block_size: u32,
+    /// The size of the blocks, not to be confused with `block_size`.
+    block_len: u32,
+    /// Whether blocks end with a [`BlockTrailer`].
+    trailers: bool,
+    /// The trailer of the current block so far.
+    trailer: BlockTrailer,
+    /// Chunks start at multiples of this many bytes into their block.
+    alignment: u32,
+}
+
+impl BlockWriter {
+    /// Start laying out at `block_size` bytes into block `block_number`.
+    pub fn new(block_number: u32, block_size: u32) -> Self {
+        Self {
+            block_number,
+            block_size,
+            block_len: BLOCK_SIZE,
+            trailers: false,
+            trailer: BlockTrailer::default(),
+            alignment: 1,
+        }
+    }
+
+    /// Start every record at a multiple of `alignment` bytes into its block,
+    /// padding the gap after the record before it. A power of two no larger
+    /// than the block.
+    pub fn with_chunk_alignment(mut self, alignment: u32) -> Self {
+        self.alignment = alignment;
+        self
+    }
+
+    /// What chunks are aligned to, 1 unless set with
+    /// [`BlockWriter::with_chunk_alignment`].
+    pub fn chunk_alignment(&self) -> u32 {
+        self.alignment
+    }
+
+    /// Lay out blocks of `block_len` bytes instead of [`BLOCK_SIZE`].
+    pub fn with_block_len(mut self, block_len: u32) -> Self {
+        self.block_len = block_len;
+        self
+    }
+
+    /// End every block with a trailer. `trailer` describes the chunks already
+    /// in the current block.
+    pub fn with_trailers(mut self, trailer: BlockTrailer) -> Self {
+        self.trailers = true;
+        self.trailer = trailer;
+        self
+    }
+
+    pub fn block_number(&self) -> u32 {
+        self.block_number
+    }
+
+    /// The number of bytes used in the current block.
+    pub fn block_size(&self) -> u32 {
+        self.block_size
+    }
+
+    /// Bytes of a block available to chunks and padding.
+    pub fn block_capacity(&self) -> u32 {
+        block_capacity(self.block_len, self.trailers)
+    }
+
+    /// The size of the blocks, [`BLOCK_SIZE`] unless set with
+    /// [`BlockWriter::with_block_len`].
+    pub fn block_len(&self) -> u32 {
+        self.block_len
+    }
+
+    /// Offset of the next piece in the segment.
+    pub fn offset(&self) -> u64 {
+        self.block_number as u64 * self.block_len as u64 + self.block_size as u64
+    }
+
+    /// The most data one record can hold if its chunks have to end by offset
+    /// `end` in the segment.
+    pub fn payload_capacity(&self, end: u64) -> u64 {
+        let capacity = self.block_capacity();
+        let (mut block_number, mut block_size) = (self.block_number, self.block_size);
+        let mut payload = 0;
+        loop {
+            if block_size + CHUNK_HEADER_SIZE >= capacity {
+                block_number += 1;
+                block_size = 0;
+            }
+            let start = block_number as u64 * self.block_len as u64 + block_size as u64;
+            if start + CHUNK_HEADER_SIZE as u64 > end {
+                return payload;
+            }
+            let room = (capacity - block_size - CHUNK_HEADER_SIZE) as u64;
+            let fits = room.min(end - start - CHUNK_HEADER_SIZE as u64);
+            payload += fits;
+            if fits < room {
+                return payload;
+            }
+            block_number += 1;
+            block_size = 0;
+        }
+    }
+
+    /// Lay `data` out as one record, handing every piece to `emit` together
+    /// with its offset in the segment, and return the position of the record
+    /// as `(block_number, chunk_offset)`.
+    ///
+    /// The writer only moves past a piece once `emit` accepted it, so if
+    /// `emit` fails it still points right after the last accepted piece.
+    pub fn write<E>(
+        &mut self,
+        data: &[u8],
+        emit: impl FnMut(u64, Piece<'_>) -> Result<(), E>,
+    ) -> Result<(u32, u64), E> {
+        self.write_in_batch(data, false, emit)
+    }
+
+    /// Like [`BlockWriter::write`], setting [`BATCH_CONTINUES`] in the chunk
+    /// ending the record if `continues`.
+    pub(crate) fn write_in_batch<E>(
+        &mut self,
+        data: &[u8],
+        continues: bool,
+        emit: impl FnMut(u64, Piece<'_>) -> Result<(), E>,
+    ) -> Result<(u32, u64), E> {
+        self.write_sealed(data, continues, 0, None, emit)
+    }
+
+    /// Like [`BlockWriter::write_in_batch`], also setting `flags` in the type
+    /// byte of every chunk, e.g. [`COMPRESSED`], and with `seal` encrypting
+    /// the payload of every chunk with room for the payload to grow by its
+    /// overhead. Chunks which can't hold more than the overhead are written
+    /// empty and in the clear instead, until the block is full.
+    pub(crate) fn write_sealed<E>(
+        &mut self,
+        data: &[u8],
+        continues: bool,
+        flags: u8,
+        seal: Option<(u32, Seal<'_>)>,
+        mut emit: impl FnMut(u64, Piece<'_>) -> Result<(), E>,
+    ) -> Result<(u32, u64), E> {
+        let capacity = self.block_capacity();
+        let mut chunk = Vec::with_capacity(
+            CHUNK_HEADER_SIZE as usize + data.len().min(self.block_len as usize),
+        );
+
+        let mut position = None;
+        let mut written = 0;
+        loop {
+            // Chunks start at a multiple of the alignment, pad up to it. The
+            // rest of the block can't hold a chunk header, pad it.
+            let aligned = self.block_size.next_multiple_of(self.alignment);
+            if aligned + CHUNK_HEADER_SIZE >= capacity {
+                self.finish_block(&mut emit)?;
+            } else if aligned > self.block_size {
+                let padding = vec![0; (aligned - self.block_size) as usize];
+                emit(self.offset(), Piece::Padding(&padding))?;
+                self.block_size = aligned;
+            }
+            let position = *position.get_or_insert((self.block_number, self.block_size as u64));
+
+            let mut room = (capacity - self.block_size - CHUNK_HEADER_SIZE) as usize;
+            let seal = match seal {
+                Some((overhead, seal)) => {
+                    room = room.saturating_sub(overhead as usize);
+                    (room > 0).then_some(seal)
+                }
+                None => None,
+            };
+            let len = room.min(data.len() - written);
+            let last = written + len == data.len();
+            let chunk_type = match (written == 0, last) {
+                (true, true) => ChunkType::Full,
+                (true, false) => ChunkType::First,
+                (false, false) => ChunkType::Middle,
+                (false, true) => ChunkType::Last,
+            };
+            let mut type_byte = u8::from(chunk_type) | flags;
+            if last && continues {
+                type_byte |= BATCH_CONTINUES;
+            }
+            let payload = &data[written..written + len];
+            match seal {
+                Some(seal) => {
+                    type_byte |= ENCRYPTED;
+                    let sealed = seal(
+                        self.block_number,
+                        self.block_size as u64,
+                        type_byte,
+                        payload,
+                    );
+                    encode_chunk(&sealed, type_byte, &mut chunk);
+                }
+                None => encode_chunk(payload, type_byte, &mut chunk),
+            }
+            emit(self.offset(), Piece::Chunk(&chunk))?;
+            written += len;
+            self.trailer.chunks += 1;
+            self.trailer.last_chunk_offset = self.block_size as u16;
+            self.block_size += chunk.len() as u32;
+            // With trailers, a block is finished as soon as it is full, so
+            // only the last block of a segment can lack its trailer.
+            if self.block_size == capacity
+                || self.trailers && self.block_size + CHUNK_HEADER_SIZE >= capacity
+            {
+                self.finish_block(&mut emit)?;
+            }
+            if last {
+                return Ok(position);
+            }
+        }
+    }
+
+    /// Pad the current block and append its trailer, then move to the next one.
+    fn finish_block<E>(
+        &mut self,
+        emit: &mut impl FnMut(u64, Piece<'_>) -> Result<(), E>,
+    ) -> Result<(), E> {
+        let capacity = self.block_capacity();
+        if self.block_size < capacity {
+            let padding = vec![0; (capacity - self.block_size) as usize];
+            emit(self.offset(), Piece::Padding(&padding))?;
+            self.block_size = capacity;
+        }
+        if self.trailers {
+            emit(self.offset(), Piece::Trailer(&self.trailer.encode()))?;
+        }
+        self.block_number += 1;
+        self.block_size = 0;
+        self.trailer = BlockTrailer::default();
+        Ok(())
+    }
+}
+
+/// Size of a [`BlockTrailer`] on disk.
+pub const BLOCK_TRAILER_SIZE: u32 = 8;
+
+/// Bytes of a block of `block_len` available to chunks and padding.
+pub(crate) fn block_capacity(block_len: u32, trailers: bool) -> u32 {
+    if trailers {
+        block_len - BLOCK_TRAILER_SIZE
+    } else {
+        block_len
+    }
+}
+
+/// What ends every finished block when block trailers are enabled, so a
+/// block's last chunk can be found without parsing it from the start.
+///
+/// Chunks: 2, Offset of the last chunk: 2, Checksum of both: 4
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct BlockTrailer {
+    /// Chunks in the block, continuations of a record from the previous
+    /// block included.
+    pub chunks: u16,
+    pub last_chunk_offset: u16,
+}
+
+impl BlockTrailer {
+    fn encode(&self) -> [u8; BLOCK_TRAILER_SIZE as usize] {
+        let mut buf = [0; BLOCK_TRAILER_SIZE as usize];
+        buf[0..2].copy_from_slice(&self.chunks.to_le_bytes());
+        buf[2..4].copy_from_slice(&self.last_chunk_offset.to_le_bytes());
+        let sum = crc32fast::hash(&buf[0..4]);
+        buf[4..8].copy_from_slice(&sum.to_le_bytes());
+        buf
+    }
+
+    /// `None` if the trailer hasn't been written. `offset` is where it is in
+    /// the block.
+    fn decode(
+        segment_id: u32,
+        block_number: u32,
+        offset: u32,
+        buf: &[u8],
+    ) -> Result<Option<Self>, WalError> {
+        if buf.iter().all(|b| *b == 0) {
+            return Ok(None);
+        }
+        if u32::from_le_bytes(buf[4..8].try_into().unwrap()) != crc32fast::hash(&buf[0..4]) {
+            return Err(WalError::InvalidCrc {
+                segment_id,
+                block_number,
+                offset: offset as u64,
+            });
+        }
+        Ok(Some(Self {
+            chunks: u16::from_le_bytes(buf[0..2].try_into().unwrap()),
+            last_chunk_offset: u16::from_le_bytes(buf[2..4].try_into().unwrap()),
+        }))
+    }
+}
+
+/// Encode a chunk into `buf`, replacing its contents.
+fn encode_chunk(data: &[u8], type_byte: u8, buf: &mut Vec<u8>) {
+    buf.clear();
+    // Checksum: 4 Bytes, index:0-3, filled in below.
+    buf.extend_from_slice(&[0; 4]);
+    // Length: 2 Bytes, index:4-5
+    buf.extend_from_slice(&(data.len() as u16).to_le_bytes());
+    // Type: 1 Byte, index:6
+    buf.push(type_byte);
+    // Data: N Bytes, index:7-end
+    buf.extend_from_slice(data);
+    let sum = crc32fast::hash(&buf[4..]);
+    buf[0..4].copy_from_slice(&sum.to_le_bytes());
+}
+
+/// The type of a chunk from its type byte, ignoring [`BATCH_CONTINUES`],
+/// [`COMPRESSED`] and [`ENCRYPTED`].
+fn chunk_type(
+    segment_id: u32,
+    block_number: u32,
+    offset: u64,
+    chunk_type: u8,
+) -> Result<ChunkType, WalError> {
+    ChunkType::try_from(chunk_type & !(BATCH_CONTINUES | COMPRESSED | ENCRYPTED)).map_err(
+        |chunk_type| WalError::InvalidChunkType {
+            segment_id,
+            block_number,
+            offset,
+            chunk_type,
+        },
+    )
+}
+
+/// The error for a chunk whose header claims `length` bytes, more than the
+/// rest of its block holds.
+fn invalid_length(segment_id: u32, block_number: u32, offset: u64, length: usize) -> WalError {
+    WalError::InvalidChunkLength {
+        segment_id,
+        block_number,
+        offset,
+        length: length as u16,
+    }
+}
+
+/// Check the checksum of a whole chunk, header included.
+fn check_crc(
+    segment_id: u32,
+    block_number: u32,
+    offset: u64,
+    chunk: &[u8],
+) -> Result<(), WalError> {
+    let sum = u32::from_le_bytes(chunk[0..4].try_into().unwrap());
+    if sum != crc32fast::hash(&chunk[4..]) {
+        return Err(WalError::InvalidCrc {
+            segment_id,
+            block_number,
+            offset,
+        });
+    }
+    Ok(())
+}
+
+/// Blocks read from disk at once by a [`SegmentReader`].
+const READAHEAD_BLOCKS: u64 = 32;
+
+/// Sequential scan over the records of a segment.
+///
+/// Reads `READAHEAD_BLOCKS` blocks per I/O and verifies the checksum of every
+/// chunk, so memory is bounded by the window plus the record being assembled.
+/// A chunk torn by a crash at the end of the segment ends the scan.
+pub(crate) struct SegmentReader<'a> {
+    segment: &'a Segment,
+    size: u64,
+    window: Vec<u8>,
+    /// Offset of `window[0]` in the file.
+    window_start: u64,
+    /// Offset of the next chunk in the file.
+    offset: u64,
+}
+
+impl<'a> SegmentReader<'a> {
+    pub(crate) fn new(segment: &'a Segment) -> Self {
+        Self {
+            segment,
+            size: segment.visible_size(),
+            window: Vec::new(),
+            window_start: 0,
+            offset: 0,
+        }
+    }
+
+    /// Offset in the segment right after the last record returned.
+    pub(crate) fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// Continue the scan with the record starting at `offset`.
+    pub(crate) fn seek(&mut self, offset: u64) {
+        self.offset = offset;
+    }
+
+    pub(crate) fn next_record(&mut self) -> Result<Option<(Vec<u8>, ChunkPosition)>, WalError> {
+        let block_len = self.segment.block_len() as u64;
+        let mut record = Vec::new();
+        let mut position = None;
+        let mut after_hole = false;
+        let mut compressed = false;
+        loop {
+            // Chunks start at multiples of the alignment, and the rest of the
+            // block is padding if it can't hold another chunk header.
+            self.offset = self
+                .offset
+                .next_multiple_of(self.segment.writer.alignment as u64);
+            let in_block = self.offset % block_len;
+            if in_block + CHUNK_HEADER_SIZE as u64 >= self.segment.writer.block_capacity() as u64 {
+                self.offset += block_len - in_block;
+            }
+            if self.offset + CHUNK_HEADER_SIZE as u64 > self.size {
+                return Ok(None);
+            }
+            let chunk_start = self.offset;
+            self.fill(chunk_start)?;
+            let header_start = (chunk_start - self.window_start) as usize;
+            let header = &self.window[header_start..header_start + CHUNK_HEADER_SIZE as usize];
+            let block_number = (chunk_start / block_len) as u32;
+            let chunk_offset = chunk_start % block_len;
+            // A zeroed header is never a valid chunk, not even an empty record,
+            // whose checksum is that of a zero length and type: the region was
+            // zero-filled or hole-punched, so the rest of the block is treated
+            // as padding.
+            if header.iter().all(|b| *b == 0) {
+                self.offset = (block_number as u64 + 1) * block_len;
+                after_hole = true;
+                record.clear();
+                position = None;
+                continue;
+            }
+            let length = u16::from_le_bytes(header[4..6].try_into().unwrap()) as u64;
+            if chunk_offset + CHUNK_HEADER_SIZE as u64 + length
+                > self.segment.writer.block_capacity() as u64
+            {
+                return Err(invalid_length(
+                    self.segment.id,
+                    block_number,
+                    chunk_offset,
+                    length as usize,
+                ));
+            }
+            if chunk_start + CHUNK_HEADER_SIZE as u64 + length > self.size {
+                return Ok(None);
+            }
+            let chunk_end = header_start + CHUNK_HEADER_SIZE as usize + length as usize;
+            let chunk = &self.window[header_start..chunk_end];
+
+            check_crc(self.segment.id, block_number, chunk_offset, chunk)?;
+            let chunk_type = chunk_type(self.segment.id, block_number, chunk_offset, chunk[6])?;
+            self.offset = chunk_start + CHUNK_HEADER_SIZE as u64 + length;
+            if after_hole
+                && position.is_none()
+                && (chunk_type == ChunkType::Middle || chunk_type == ChunkType::Last)
+            {
+                // The rest of a record whose start was zeroed.
+                continue;
+            }
+            after_hole = false;
+            position.get_or_insert(ChunkPosition {
+                segment_id: self.segment.id,
+                block_number,
+                chunk_offset,
+            });
+            let start = record.len();
+            record.extend_from_slice(&chunk[CHUNK_HEADER_SIZE as usize..]);
+            let type_byte = chunk[6];
+            self.segment
+                .open_chunk(block_number, chunk_offset, type_byte, &mut record, start)?;
+            compressed |= type_byte & COMPRESSED != 0;
+
+            if chunk_type == ChunkType::Full || chunk_type == ChunkType::Last {
+                if compressed {
+                    record = compression::decompress(&record)?;
+                }
+                return Ok(Some((record, position.unwrap())));
+            }
+        }
+    }
+
+    /// Make sure the block holding `offset` is in the window. Chunks never
+    /// cross blocks, so the whole chunk at `offset` is then available.
+    fn fill(&mut self, offset: u64) -> Result<(), WalError> {
+        let block_len = self.segment.block_len() as u64;
+        let window_end = self.window_start + self.window.len() as u64;
+        if offset >= self.window_start && offset < window_end {
+            let block_end = (offset / block_len + 1) * block_len;
+            if block_end.min(self.size) <= window_end {
+                return Ok(());
+            }
+        }
+        let file = self.segment.file_for_reads()?;
+        if self.segment.page_cache_hints {
+            if self.window.is_empty() {
+                platform::advise(&file, 0, 0, platform::Advice::Sequential);
+            } else if self.segment.sealed {
+                // The scan is past the window.
+                let len = self.window.len() as u64;
+                platform::advise(&file, self.window_start, len, platform::Advice::DontNeed);
+            }
+        }
+        self.window_start = offset - offset % block_len;
+        let len = (READAHEAD_BLOCKS * block_len).min(self.size - self.window_start);
+        self.window.resize(len as usize, 0);
+        self.segment
+            .read_exact_at(&file, &mut self.window, self.window_start)
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::testing;
+
+    #[test]
+    fn segment_write() {
+        let dir = testing::temp_dir("segment_write");
+        let mut seg = Segment::open(&dir, 1).unwrap();
+
+        let s = "A".repeat(2028);
+        seg.write(s.into_bytes()).unwrap();
+
+        let s = "A".repeat(30 * 1024);
+        seg.write(s.into_bytes()).unwrap();
+
+        let s = "A".to_string().into_bytes();
+        seg.write(s).unwrap();
+
+        let s = "A".repeat(33 * 1024);
+        seg.write(s.into_bytes()).unwrap();
+
+        let s = "A".repeat(66 * 1024);
+        seg.write(s.into_bytes()).unwrap();
+
+        assert_eq!(seg.size(), seg.metadata().unwrap().len());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn scan_skips_zeroed_regions() {
+        let dir = testing::temp_dir("segment_holes");
+        let mut seg = Segment::open(&dir, 1).unwrap();
+        let mut positions = Vec::new();
+        for i in 0..6 {
+            // Records of 20KB, so most of them span two blocks.
+            positions.push(seg.write(vec![i; 20 * 1024]).unwrap());
+        }
+        // Zero the first block, as a hole punch would.
+        let file = std::fs::File::options()
+            .write(true)
+            .open(seg.path())
+            .unwrap();
+        file.write_all_at(&vec![0; BLOCK_SIZE as usize], 0).unwrap();
+
+        let mut reader = SegmentReader::new(&seg);
+        let mut scanned = Vec::new();
+        while let Some((data, pos)) = reader.next_record().unwrap() {
+            scanned.push((data[0], pos));
+        }
+        // Record 1 started in the zeroed block, its continuation is skipped.
+        let expected: Vec<_> = (2..6).map(|i| (i as u8, positions[i])).collect();
+        assert_eq!(scanned, expected);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn block_writer_layout() {
+        let mut writer = BlockWriter::new(0, BLOCK_SIZE - 5);
+        let mut pieces = Vec::new();
+        let position = writer
+            .write(&[1; 40_000], |offset, piece| {
+                pieces.push(match piece {
+                    Piece::Padding(padding) => (offset, None, padding.len()),
+                    Piece::Chunk(chunk) => (
+                        offset,
+                        Some(ChunkType::try_from(chunk[6]).unwrap()),
+                        chunk.len(),
+                    ),
+                    Piece::Trailer(_) => panic!("trailers are disabled"),
+                });
+                Ok::<_, ()>(())
+            })
+            .unwrap();
+        let block = BLOCK_SIZE as u64;
+        let first_len = (BLOCK_SIZE - CHUNK_HEADER_SIZE) as usize;
+        assert_eq!(position, (1, 0));
+        assert_eq!(
+            pieces,
+            [
+                (block - 5, None, 5),
+                (block, Some(ChunkType::First), BLOCK_SIZE as usize),
+                (2 * block, Some(ChunkType::Last), 40_000 - first_len + 7),
+            ]
+        );
+        assert_eq!(writer.offset(), 2 * block + (40_000 - first_len + 7) as u64);
 
-    // #[test]
-    // fn wal_write() {
-    //     let path: std::path::PathBuf = std::path::PathBuf::from("/tmp/000001.log");
-    //     let mut wal = Segment::open(&path).unwrap();
+        // A failed piece isn't accounted for.
+        let mut writer = BlockWriter::new(0, 0);
+        assert!(writer.write(&[1; 10], |_, _| Err(())).is_err());
+        assert_eq!(writer.offset(), 0);
+    }
+
+    #[test]
+    fn empty_chunk_is_not_zeroed() {
+        let mut chunk = Vec::new();
+        encode_chunk(&[], ChunkType::Full.into(), &mut chunk);
+        assert_eq!(chunk.len(), CHUNK_HEADER_SIZE as usize);
+        assert!(chunk.iter().any(|b| *b != 0));
+    }
 
-    //     let s = "A".repeat(2028);
-    //     wal.write(s.into_bytes()).unwrap();
+    #[test]
+    fn block_trailers() {
+        let dir = testing::temp_dir("segment_trailers");
+        let mut seg = Segment::open(&dir, 1).unwrap();
+        seg.enable_block_trailers().unwrap();
+        let check = |seg: &Segment| {
+            for block_number in 0..seg.writer.block_number() {
+                let chunks = seg.read_block(block_number).unwrap();
+                let trailer = seg.block_trailer(block_number).unwrap().unwrap();
+                assert_eq!(trailer.chunks as usize, chunks.len());
+                assert_eq!(
+                    trailer.last_chunk_offset as u32,
+                    chunks.last().unwrap().offset
+                );
+            }
+            assert_eq!(seg.block_trailer(seg.writer.block_number()).unwrap(), None);
+        };
+        let mut positions = Vec::new();
+        for i in 0..20 {
+            let len = [100, 40_000, 10, 3000, BLOCK_SIZE as usize][i % 5];
+            positions.push((seg.write(vec![i as u8; len]).unwrap(), len));
+        }
+        check(&seg);
+        assert_eq!(seg.size(), seg.metadata().unwrap().len());
 
-    //     let s = "A".repeat(30 * 1024);
-    //     wal.write(s.into_bytes()).unwrap();
+        // Reopened in the middle of a block, the count continues.
+        drop(seg);
+        let mut seg = Segment::open(&dir, 1).unwrap();
+        seg.enable_block_trailers().unwrap();
+        for i in 20..30 {
+            positions.push((seg.write(vec![i as u8; 3000]).unwrap(), 3000));
+        }
+        check(&seg);
+        for (i, (pos, len)) in positions.iter().enumerate() {
+            assert_eq!(
+                seg.read(pos.block_number, pos.chunk_offset).unwrap(),
+                vec![i as u8; *len]
+            );
+        }
+        let mut reader = SegmentReader::new(&seg);
+        let mut scanned = Vec::new();
+        while let Some((_, pos)) = reader.next_record().unwrap() {
+            scanned.push(pos);
+        }
+        assert_eq!(
+            scanned,
+            positions.iter().map(|(pos, _)| *pos).collect::<Vec<_>>()
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
 
-    //     let s = "A".to_string().into_bytes();
-    //     wal.write(s).unwrap();
+    #[test]
+    fn segment_read() {
+        let dir = testing::temp_dir("segment_read");
+        let mut seg = Segment::open(&dir, 1).unwrap();
+        // One block
+        let s = "A".repeat(2028);
+        let pos = seg.write(s.clone().into_bytes()).unwrap();
+        assert_eq!(
+            seg.read(pos.block_number, pos.chunk_offset).unwrap(),
+            s.into_bytes()
+        );
 
-    //     let s = "A".repeat(33 * 1024);
-    //     wal.write(s.into_bytes()).unwrap();
+        // Multiple blocks
+        let s = "B".repeat(45 * 1024);
+        let pos = seg.write(s.clone().into_bytes()).unwrap();
+        assert_eq!(
+            seg.read(pos.block_number, pos.chunk_offset).unwrap(),
+            s.into_bytes()
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
 
-    //     let s = "A".repeat(66 * 1024);
-    //     wal.write(s.into_bytes()).unwrap();
+    #[test]
+    fn rewound_writes_overwrite_in_place() {
+        let dir = testing::temp_dir("segment_rewound_writes");
+        let mut seg = Segment::open(&dir, 1).unwrap();
+        seg.write_slice(b"first").unwrap();
+        let end = seg.size();
+        seg.write_slice(b"discarded").unwrap();
+        // The file stays as long as it is, the next write goes at `end`.
+        seg.continue_at(end).unwrap();
+        let pos = seg.write_slice(b"second").unwrap();
+        assert_eq!(pos.chunk_offset, end);
+        assert_eq!(
+            seg.read(pos.block_number, pos.chunk_offset).unwrap(),
+            b"second"
+        );
+        assert_eq!(seg.size(), end + CHUNK_HEADER_SIZE as u64 + 6);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
 
-    //     dbg!(wal.current_block_size);
-    //     // std::fs::remove_file(path).unwrap();
-    // }
+    #[test]
+    fn batches_become_visible_with_their_last_record() {
+        let dir = testing::temp_dir("segment_visible_batches");
+        let mut seg = Segment::open(&dir, 1).unwrap();
+        let first = seg.write_in_batch(b"first", true).unwrap();
+        assert_eq!(seg.visible_size(), 0);
+        assert!(matches!(
+            seg.read(first.block_number, first.chunk_offset),
+            Err(WalError::IncompleteRecord { .. })
+        ));
+        assert_eq!(
+            seg.read_appended(first.block_number, first.chunk_offset)
+                .unwrap(),
+            b"first"
+        );
+        seg.write_in_batch(b"last", false).unwrap();
+        assert_eq!(seg.visible_size(), seg.size());
+        assert_eq!(
+            seg.read(first.block_number, first.chunk_offset).unwrap(),
+            b"first"
+        );
+        let mut reader = SegmentReader::new(&seg);
+        assert!(reader.next_record().unwrap().is_some());
+        assert!(reader.next_record().unwrap().is_some());
+        assert!(reader.next_record().unwrap().is_none());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
 
-    // #[test]
-    // fn wal_read() {
-    //     let path: std::path::PathBuf = std::path::PathBuf::from("/tmp/000001.log");
-    //     let mut wal = Segment::open(&path).unwrap();
-    //     // One block
-    //     let s = "A".repeat(2028);
-    //     let pos = wal.write(s.into_bytes()).unwrap();
-    //     wal.read(pos.block_number, pos.chunk_offset).unwrap();
+    #[test]
+    fn sealed_segments_reject_writes() {
+        let dir = testing::temp_dir("segment_sealed");
+        let mut seg = Segment::open(&dir, 1).unwrap();
+        let pos = seg.write_slice(b"record").unwrap();
+        seg.seal().unwrap();
+        assert!(seg.is_sealed());
+        let size = seg.size();
+        assert!(matches!(
+            seg.write_slice(b"stale"),
+            Err(WalError::SegmentSealed { segment_id: 1 })
+        ));
+        assert_eq!(seg.size(), size);
+        assert_eq!(
+            seg.read(pos.block_number, pos.chunk_offset).unwrap(),
+            b"record"
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
 
-    //     // Multiple blocks
-    //     let s = "A".repeat(45 * 1024);
-    //     let pos = wal.write(s.into_bytes()).unwrap();
-    //     wal.read(pos.block_number, pos.chunk_offset).unwrap();
-    // }
+    #[test]
+    fn coalesced_padding_matches_layout() {
+        for trailers in [false, true] {
+            let dir = testing::temp_dir("segment_coalesced_padding");
+            let mut seg = Segment::open(&dir, 1).unwrap();
+            let mut layout = BlockWriter::new(0, 0);
+            if trailers {
+                seg.enable_block_trailers().unwrap();
+                layout = layout.with_trailers(BlockTrailer::default());
+            }
+            let mut expected = Vec::new();
+            // Leave less than a chunk header at the end of every block, and
+            // end one record exactly at a block boundary.
+            let capacity = seg.writer().block_capacity() as usize;
+            for len in [
+                capacity - 10,
+                100,
+                capacity - 100 - 2 * 7 - 3,
+                0,
+                capacity - 7,
+            ] {
+                let data = vec![len as u8; len];
+                seg.write_slice(&data).unwrap();
+                layout
+                    .write(&data, |_, piece| {
+                        let (Piece::Padding(bytes) | Piece::Chunk(bytes) | Piece::Trailer(bytes)) =
+                            piece;
+                        expected.extend_from_slice(bytes);
+                        Ok::<_, ()>(())
+                    })
+                    .unwrap();
+            }
+            assert_eq!(std::fs::read(seg.path()).unwrap(), expected);
+            assert_eq!(seg.size(), expected.len() as u64);
+            std::fs::remove_dir_all(dir).unwrap();
+        }
+    }
+
+    #[test]
+    fn read_verifies_checksums() {
+        let dir = testing::temp_dir("segment_read_crc");
+        let mut seg = Segment::open(&dir, 1).unwrap();
+        seg.write(vec![1; 100]).unwrap();
+        // Corrupt the chunk in the second block.
+        let pos = seg.write(vec![2; BLOCK_SIZE as usize]).unwrap();
+        let mut bytes = std::fs::read(seg.path()).unwrap();
+        bytes[BLOCK_SIZE as usize + 100] ^= 0xff;
+        std::fs::write(seg.path(), bytes).unwrap();
+
+        assert!(seg.read(0, 0).is_ok());
+        assert!(matches!(
+            seg.read(pos.block_number, pos.chunk_offset),
+            Err(WalError::InvalidCrc {
+                segment_id: 1,
+                block_number: 1,
+                offset: 0
+            })
+        ));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+    #[test]
+    fn encoded_positions() {
+        let pos = ChunkPosition {
+            segment_id: 0x0102_0304,
+            block_number: 7,
+            chunk_offset: 1 << 40,
+        };
+        let encoded = pos.encode();
+        assert_eq!(encoded, [1, 4, 3, 2, 1, 7, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0]);
+        assert_eq!(ChunkPosition::decode(&encoded).unwrap(), pos);
+        for invalid in [&encoded[..16], &[&encoded[..], &[0]].concat(), &[2; 17]] {
+            assert!(matches!(
+                ChunkPosition::decode(invalid),
+                Err(WalError::InvalidEncodedPosition(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn lengths_past_the_block_are_corruption() {
+        let dir = testing::temp_dir("segment_invalid_length");
+        let mut seg = Segment::open(&dir, 1).unwrap();
+        let first = seg.write(vec![1; 100]).unwrap();
+        let second = seg.write(vec![2; 100]).unwrap();
+        seg.write(vec![3; 3 * BLOCK_SIZE as usize]).unwrap();
+        let last = seg.write(vec![4; 100]).unwrap();
+        let file = std::fs::File::options()
+            .write(true)
+            .open(seg.path())
+            .unwrap();
+        let claim = |pos: ChunkPosition, length: u16| {
+            let offset = seg.offset_of(pos.block_number, pos.chunk_offset) + 4;
+            file.write_all_at(&length.to_le_bytes(), offset).unwrap();
+        };
+        // Past its own block, the rest of it and the file are long enough.
+        claim(second, u16::MAX);
+        let invalid = |result: Result<_, WalError>| {
+            matches!(
+                result,
+                Err(WalError::InvalidChunkLength {
+                    segment_id: 1,
+                    block_number: 0,
+                    offset: 107,
+                    length: u16::MAX,
+                })
+            )
+        };
+        assert!(seg.read(first.block_number, first.chunk_offset).is_ok());
+        assert!(invalid(
+            seg.read(second.block_number, second.chunk_offset)
+                .map(|_| ())
+        ));
+        assert!(invalid(seg.read_block(0).map(|_| ())));
+        let mut reader = SegmentReader::new(&seg);
+        assert_eq!(reader.next_record().unwrap().unwrap().1, first);
+        assert!(invalid(reader.next_record().map(|_| ())));
+        assert!(seg.verify().is_err());
+
+        // Past the end of the file but within the block, as a torn write.
+        claim(second, 100);
+        claim(last, (BLOCK_SIZE - last.chunk_offset as u32 - 8) as u16);
+        assert!(seg.read(second.block_number, second.chunk_offset).is_ok());
+        assert!(matches!(
+            seg.read(last.block_number, last.chunk_offset),
+            Err(WalError::IncompleteRecord { .. })
+        ));
+        // Only the end of the record before it is read.
+        assert_eq!(seg.read_block(last.block_number).unwrap().len(), 1);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
 }
@@ -0,0 +1,122 @@
+//! Read statistics of segments, e.g. for a tiering policy to keep frequently
+//! read segments local and archive cold ones.
+
+use std::{
+    collections::HashMap,
+    io::Write,
+    path::Path,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, SystemTime},
+};
+
+use crate::error::{IoResultExt, WalError};
+
+pub(crate) const READ_STATS_FILE_NAME: &str = "READ_STATS";
+
+/// How often a segment has been read, see [`crate::wal::Wal::read_stats`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SegmentReadStats {
+    pub segment_id: u32,
+    /// Reads since the segment was created, as of the last time the stats were
+    /// persisted before this open, plus the reads since.
+    pub reads: u64,
+    pub last_read: Option<SystemTime>,
+}
+
+/// How far a consumer is behind the end of the Wal, see
+/// [`crate::wal::Wal::lag`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Lag {
+    /// Bytes of the segments after the last record applied, padding and chunk
+    /// headers included.
+    pub bytes: u64,
+    pub records: u64,
+    /// Segments holding records not applied yet.
+    pub segments: u32,
+}
+
+/// Read counters of one segment, updated without locking.
+#[derive(Debug, Default)]
+pub(crate) struct ReadCounter {
+    reads: AtomicU64,
+    /// Milliseconds since the epoch, 0 if never read.
+    last_read_ms: AtomicU64,
+}
+
+impl ReadCounter {
+    pub(crate) fn record(&self, now: SystemTime) {
+        self.reads.fetch_add(1, Ordering::Relaxed);
+        self.last_read_ms
+            .fetch_max(epoch_ms(now), Ordering::Relaxed);
+    }
+
+    pub(crate) fn stats(&self, segment_id: u32) -> SegmentReadStats {
+        let last_read_ms = self.last_read_ms.load(Ordering::Relaxed);
+        SegmentReadStats {
+            segment_id,
+            reads: self.reads.load(Ordering::Relaxed),
+            last_read: (last_read_ms > 0)
+                .then(|| SystemTime::UNIX_EPOCH + Duration::from_millis(last_read_ms)),
+        }
+    }
+
+    /// Continue from stats persisted earlier.
+    pub(crate) fn restore(&self, stats: &SegmentReadStats) {
+        self.reads.fetch_add(stats.reads, Ordering::Relaxed);
+        if let Some(last_read) = stats.last_read {
+            self.last_read_ms
+                .fetch_max(epoch_ms(last_read), Ordering::Relaxed);
+        }
+    }
+}
+
+fn epoch_ms(time: SystemTime) -> u64 {
+    // Never 0 for a read, which means "never read".
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(1, |d| (d.as_millis() as u64).max(1))
+}
+
+/// Load the stats persisted in `dir_path`, by segment id. Missing or
+/// unreadable stats start over, they are only advisory.
+pub(crate) fn load(dir_path: impl AsRef<Path>) -> HashMap<u32, SegmentReadStats> {
+    let path = dir_path.as_ref().join(READ_STATS_FILE_NAME);
+    let Ok(content) = std::fs::read_to_string(path) else {
+        return HashMap::new();
+    };
+    content
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split(' ').map(|field| field.parse::<u64>().ok());
+            let (id, reads, last_read_ms) = (fields.next()??, fields.next()??, fields.next()??);
+            let stats = SegmentReadStats {
+                segment_id: u32::try_from(id).ok()?,
+                reads,
+                last_read: (last_read_ms > 0)
+                    .then(|| SystemTime::UNIX_EPOCH + Duration::from_millis(last_read_ms)),
+            };
+            Some((stats.segment_id, stats))
+        })
+        .collect()
+}
+
+/// Atomically replace the stats persisted in `dir_path`.
+pub(crate) fn store(
+    dir_path: impl AsRef<Path>,
+    stats: impl IntoIterator<Item = SegmentReadStats>,
+) -> Result<(), WalError> {
+    let path = dir_path.as_ref().join(READ_STATS_FILE_NAME);
+    let tmp = path.with_extension("tmp");
+    let mut file = std::fs::File::create(&tmp).context("create", &tmp)?;
+    for stats in stats {
+        let last_read_ms = stats.last_read.map_or(0, epoch_ms);
+        writeln!(
+            file,
+            "{} {} {}",
+            stats.segment_id, stats.reads, last_read_ms
+        )
+        .context("write", &tmp)?;
+    }
+    file.sync_all().context("fsync", &tmp)?;
+    std::fs::rename(&tmp, &path).context("rename", &tmp)?;
+    Ok(())
+}
@@ -0,0 +1,362 @@
+//! Helpers for exercising a [`Wal`] from tests.
+//!
+//! Every payload is tagged with the writer and sequence number that produced it,
+//! so a record read back at a position can be traced to exactly one write.
+//! Enable the `testing` feature to use these from another crate.
+
+#[cfg(feature = "failpoints")]
+pub mod crash;
+
+use std::{
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant, SystemTime},
+};
+
+use crate::{
+    clock::Clock,
+    error::WalError,
+    segment::{CHUNK_HEADER_SIZE, MAX_BLOCK_SIZE},
+    wal::{ChunkPosition, Wal},
+};
+
+/// Size of the writer/sequence tag at the start of every generated payload.
+pub const PAYLOAD_TAG_SIZE: usize = 16;
+
+/// A Wal that can be written and read through a shared reference.
+pub trait SharedWal {
+    fn write(&self, data: &[u8]) -> Result<ChunkPosition, WalError>;
+
+    fn read(&self, pos: ChunkPosition) -> Result<Vec<u8>, WalError>;
+}
+
+impl SharedWal for Mutex<Wal> {
+    fn write(&self, data: &[u8]) -> Result<ChunkPosition, WalError> {
+        self.lock().unwrap().write(data)
+    }
+
+    fn read(&self, pos: ChunkPosition) -> Result<Vec<u8>, WalError> {
+        self.lock().unwrap().read(pos)
+    }
+}
+
+/// A [`Clock`] which only moves when advanced, so time-based behavior can be
+/// tested without sleeping. Clones share the same time.
+#[derive(Debug, Clone)]
+pub struct ManualClock {
+    now: Arc<Mutex<(Instant, SystemTime)>>,
+}
+
+impl ManualClock {
+    /// Start at the current time.
+    pub fn new() -> Self {
+        Self {
+            now: Arc::new(Mutex::new((Instant::now(), SystemTime::now()))),
+        }
+    }
+
+    pub fn advance(&self, by: Duration) {
+        let mut now = self.now.lock().unwrap();
+        now.0 += by;
+        now.1 += by;
+    }
+}
+
+impl Default for ManualClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        self.now.lock().unwrap().0
+    }
+
+    fn system_time(&self) -> SystemTime {
+        self.now.lock().unwrap().1
+    }
+}
+
+/// A record acknowledged by the Wal.
+#[derive(Debug, Clone)]
+pub struct WrittenRecord {
+    pub writer: usize,
+    pub seq: usize,
+    pub pos: ChunkPosition,
+    pub data: Vec<u8>,
+}
+
+/// Create a fresh, empty directory under the system temp dir.
+pub fn temp_dir(name: &str) -> PathBuf {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let nanos = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_nanos();
+    let dir = std::env::temp_dir().join(format!(
+        "wal-rs-{}-{}-{}-{}",
+        name,
+        std::process::id(),
+        COUNTER.fetch_add(1, Ordering::Relaxed),
+        nanos
+    ));
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Build a payload of `len` bytes (at least [`PAYLOAD_TAG_SIZE`]) tagged with `writer` and `seq`.
+pub fn payload(writer: usize, seq: usize, len: usize) -> Vec<u8> {
+    let len = len.max(PAYLOAD_TAG_SIZE);
+    let mut data = Vec::with_capacity(len);
+    data.extend_from_slice(&(writer as u64).to_le_bytes());
+    data.extend_from_slice(&(seq as u64).to_le_bytes());
+    let fill = (writer * 31 + seq) as u8;
+    data.extend((PAYLOAD_TAG_SIZE..len).map(|i| fill.wrapping_add(i as u8)));
+    data
+}
+
+/// Recover the `(writer, seq)` tag of a payload built by [`payload`].
+pub fn parse_payload(data: &[u8]) -> Option<(usize, usize)> {
+    if data.len() < PAYLOAD_TAG_SIZE {
+        return None;
+    }
+    let writer = u64::from_le_bytes(data[0..8].try_into().unwrap()) as usize;
+    let seq = u64::from_le_bytes(data[8..16].try_into().unwrap()) as usize;
+    Some((writer, seq))
+}
+
+/// Write `count` tagged records of `len` bytes from a single writer.
+pub fn write_records(wal: &mut Wal, writer: usize, count: usize, len: usize) -> Vec<WrittenRecord> {
+    (0..count)
+        .map(|seq| {
+            let data = payload(writer, seq, len);
+            let pos = wal.write(&data).unwrap();
+            WrittenRecord {
+                writer,
+                seq,
+                pos,
+                data,
+            }
+        })
+        .collect()
+}
+
+/// Spawn `writers` threads which each write `per_writer` tagged records of `len` bytes.
+pub fn run_concurrent_writers<W: SharedWal + Sync>(
+    wal: &W,
+    writers: usize,
+    per_writer: usize,
+    len: usize,
+) -> Vec<WrittenRecord> {
+    std::thread::scope(|s| {
+        let handles: Vec<_> = (0..writers)
+            .map(|writer| {
+                s.spawn(move || {
+                    (0..per_writer)
+                        .map(|seq| {
+                            let data = payload(writer, seq, len);
+                            let pos = wal.write(&data).unwrap();
+                            WrittenRecord {
+                                writer,
+                                seq,
+                                pos,
+                                data,
+                            }
+                        })
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect();
+        handles
+            .into_iter()
+            .flat_map(|h| h.join().unwrap())
+            .collect()
+    })
+}
+
+/// Spawn `readers` threads which each read back every record, starting at a different offset.
+pub fn run_concurrent_readers<W: SharedWal + Sync>(
+    wal: &W,
+    readers: usize,
+    records: &[WrittenRecord],
+) {
+    std::thread::scope(|s| {
+        for reader in 0..readers {
+            s.spawn(move || {
+                let n = records.len();
+                for i in 0..n {
+                    let record = &records[(i + reader * n / readers.max(1)) % n];
+                    assert_record(record, wal.read(record.pos));
+                }
+            });
+        }
+    });
+}
+
+/// Run writers and readers at the same time, readers only reading acknowledged positions.
+pub fn run_concurrent_mixed<W: SharedWal + Sync>(
+    wal: &W,
+    writers: usize,
+    readers: usize,
+    per_writer: usize,
+    len: usize,
+) -> Vec<WrittenRecord> {
+    let acked = Mutex::new(Vec::new());
+    let remaining = AtomicU64::new(writers as u64);
+    std::thread::scope(|s| {
+        for writer in 0..writers {
+            let (acked, remaining) = (&acked, &remaining);
+            s.spawn(move || {
+                for seq in 0..per_writer {
+                    let data = payload(writer, seq, len);
+                    let pos = wal.write(&data).unwrap();
+                    acked.lock().unwrap().push(WrittenRecord {
+                        writer,
+                        seq,
+                        pos,
+                        data,
+                    });
+                }
+                remaining.fetch_sub(1, Ordering::AcqRel);
+            });
+        }
+        for reader in 0..readers {
+            let (acked, remaining) = (&acked, &remaining);
+            s.spawn(move || {
+                let mut i = reader;
+                loop {
+                    let done = remaining.load(Ordering::Acquire) == 0;
+                    let record = {
+                        let acked = acked.lock().unwrap();
+                        if acked.is_empty() {
+                            None
+                        } else {
+                            Some(acked[i % acked.len()].clone())
+                        }
+                    };
+                    if let Some(record) = record {
+                        assert_record(&record, wal.read(record.pos));
+                    }
+                    if done {
+                        break;
+                    }
+                    i += 1;
+                }
+            });
+        }
+    });
+    acked.into_inner().unwrap()
+}
+
+/// Assert that every record reads back to exactly the bytes that were written.
+pub fn assert_read_back(wal: &Wal, records: &[WrittenRecord]) {
+    for record in records {
+        assert_record(record, wal.read(record.pos));
+    }
+}
+
+/// Assert that no two records share or overlap positions, and that each
+/// writer's records landed in the log in the order they were written.
+pub fn assert_no_interleaving(records: &[WrittenRecord]) {
+    let mut sorted: Vec<&WrittenRecord> = records.iter().collect();
+    sorted.sort_by_key(|r| r.pos);
+    for pair in sorted.windows(2) {
+        let (prev, next) = (pair[0], pair[1]);
+        assert_ne!(
+            prev.pos, next.pos,
+            "two records share position {:?}",
+            prev.pos
+        );
+        if prev.pos.segment_id == next.pos.segment_id {
+            // A record occupies at least its payload plus one chunk header.
+            let prev_end =
+                linear_offset(&prev.pos) + (CHUNK_HEADER_SIZE as usize + prev.data.len()) as u64;
+            assert!(
+                linear_offset(&next.pos) >= prev_end,
+                "record at {:?} overlaps record at {:?}",
+                next.pos,
+                prev.pos
+            );
+        }
+    }
+
+    let mut last_seen: std::collections::HashMap<usize, &WrittenRecord> = Default::default();
+    for record in sorted {
+        if let Some(prev) = last_seen.insert(record.writer, record) {
+            assert!(
+                prev.seq < record.seq,
+                "writer {} record {} was logged after record {}",
+                record.writer,
+                prev.seq,
+                record.seq
+            );
+        }
+    }
+}
+
+fn assert_record(record: &WrittenRecord, read: Result<Vec<u8>, WalError>) {
+    let data = read.unwrap_or_else(|e| panic!("read at {:?} failed: {}", record.pos, e));
+    assert_eq!(
+        parse_payload(&data),
+        Some((record.writer, record.seq)),
+        "record at {:?} belongs to another write",
+        record.pos
+    );
+    assert!(
+        data == record.data,
+        "record at {:?} is corrupted",
+        record.pos
+    );
+}
+
+/// Blocks are taken to be as large as possible, so records never seem closer
+/// than they are, whatever the block size.
+fn linear_offset(pos: &ChunkPosition) -> u64 {
+    pos.block_number as u64 * MAX_BLOCK_SIZE as u64 + pos.chunk_offset
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn payload_round_trip() {
+        let data = payload(3, 42, 100);
+        assert_eq!(data.len(), 100);
+        assert_eq!(parse_payload(&data), Some((3, 42)));
+        assert_eq!(payload(1, 1, 0).len(), PAYLOAD_TAG_SIZE);
+    }
+
+    #[test]
+    fn manual_clock() {
+        let clock = ManualClock::new();
+        let (start, wall) = (clock.now(), clock.system_time());
+        assert_eq!(clock.now(), start);
+        clock.clone().advance(Duration::from_secs(90));
+        assert_eq!(clock.now() - start, Duration::from_secs(90));
+        assert_eq!(
+            clock.system_time().duration_since(wall).unwrap(),
+            Duration::from_secs(90)
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "overlaps")]
+    fn detect_overlap() {
+        let record = |seq, chunk_offset| WrittenRecord {
+            writer: 0,
+            seq,
+            pos: ChunkPosition {
+                segment_id: 1,
+                block_number: 0,
+                chunk_offset,
+            },
+            data: payload(0, seq, 64),
+        };
+        assert_no_interleaving(&[record(0, 0), record(1, 10)]);
+    }
+}
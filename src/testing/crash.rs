@@ -0,0 +1,242 @@
+//! Crash-matrix runner: kill a child process at randomized failpoints, then
+//! reopen the Wal and check that nothing acknowledged was lost.
+//!
+//! The child is the calling test binary itself, re-run with `--exact <test_name>`.
+//! [`run`] therefore has to be the first thing the named test does.
+
+use std::{
+    io::Write,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+};
+
+use crate::{failpoint::FAILPOINTS, options::Options, wal::Wal};
+
+use super::{payload, WrittenRecord};
+
+const CHILD_DIR_ENV: &str = "WAL_RS_CRASH_DIR";
+const ACK_FILE_NAME: &str = "acks";
+/// Records in each batch the child writes.
+const BATCH_LEN: usize = 2;
+/// Writer of the records the child discards again with `truncate_after`.
+const SCRATCH_WRITER: usize = 2;
+
+/// A single run of the matrix: which failpoint fired, and on which hit.
+#[derive(Debug, Clone)]
+pub struct CrashCase {
+    pub failpoint: &'static str,
+    pub hit: u64,
+}
+
+/// Configuration of the matrix.
+pub struct CrashMatrix {
+    /// Full path of the calling test, as passed to the test harness with `--exact`.
+    pub test_name: &'static str,
+    /// Seed for picking failpoints and hit counts, so a failing case can be replayed.
+    pub seed: u64,
+    pub cases: usize,
+    /// The most writes a child performs; it exits cleanly if no failpoint fired by then.
+    pub records: usize,
+    pub segment_size: u64,
+}
+
+/// Run the matrix, or act as the child if this process was spawned by it.
+pub fn run(matrix: &CrashMatrix) {
+    if let Ok(dir) = std::env::var(CHILD_DIR_ENV) {
+        child(matrix, Path::new(&dir));
+        std::process::exit(0);
+    }
+
+    let mut rng = matrix.seed.max(1);
+    for case in 0..matrix.cases {
+        let failpoint = FAILPOINTS[(next(&mut rng) % FAILPOINTS.len() as u64) as usize];
+        // Favour early hits, so failpoints reached only a few times fire too.
+        let most = next(&mut rng) % matrix.records as u64 + 1;
+        let crash_case = CrashCase {
+            failpoint,
+            hit: next(&mut rng) % most + 1,
+        };
+        let dir = super::temp_dir(&format!("crash-{}", case));
+        let status = Command::new(std::env::current_exe().unwrap())
+            .args([matrix.test_name, "--exact", "--test-threads=1"])
+            .env(CHILD_DIR_ENV, &dir)
+            .env(
+                crate::failpoint::FAILPOINT_ENV,
+                format!("{}={}", crash_case.failpoint, crash_case.hit),
+            )
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()
+            .unwrap();
+        let acked = read_acks(&dir);
+        check_recovery(matrix, &dir, &acked).unwrap_or_else(|e| {
+            panic!(
+                "{:?} (seed {}, status {}): {}",
+                crash_case, matrix.seed, status, e
+            )
+        });
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
+
+fn child(matrix: &CrashMatrix, dir: &Path) {
+    let mut wal = Wal::open(Options::new(wal_dir(dir), matrix.segment_size)).unwrap();
+    let mut acks = std::fs::File::create(dir.join(ACK_FILE_NAME)).unwrap();
+    let mut first = None;
+    let mut seq = 0;
+    while seq < matrix.records {
+        // Every third write is a batch.
+        let len = match seq % 3 {
+            2 => BATCH_LEN.min(matrix.records - seq),
+            _ => 1,
+        };
+        let data: Vec<Vec<u8>> = (seq..seq + len)
+            .map(|seq| payload(0, seq, record_len(seq)))
+            .collect();
+        let records: Vec<&[u8]> = data.iter().map(|data| &data[..]).collect();
+        let positions = wal.write_batch(&records).unwrap();
+        let first = *first.get_or_insert(positions[0]);
+        // Only a write that returned counts as acknowledged.
+        for pos in &positions {
+            writeln!(
+                acks,
+                "{} {} {} {}",
+                seq, pos.segment_id, pos.block_number, pos.chunk_offset
+            )
+            .unwrap();
+            seq += 1;
+        }
+        // Neither discards an acknowledged record, both pass their failpoints.
+        // The scratch record, often in a segment of its own, is never
+        // acknowledged.
+        if seq % 10 < len {
+            let scratch = payload(SCRATCH_WRITER, seq, scratch_len(matrix));
+            wal.write(&scratch).unwrap();
+            wal.truncate_after(*positions.last().unwrap()).unwrap();
+            wal.truncate_before(first).unwrap();
+        }
+    }
+}
+
+/// Reopen the crashed Wal: the recovered log must be exactly the acknowledged
+/// records, in order, and the log must still accept and serve new writes.
+///
+/// The records of the write in flight at the crash, or of the one whose
+/// acknowledgement was torn, may have made it to disk after them as well,
+/// and so may a scratch record if the crash cut its `truncate_after` short.
+fn check_recovery(matrix: &CrashMatrix, dir: &Path, acked: &[WrittenRecord]) -> Result<(), String> {
+    let mut wal = Wal::open(Options::new(wal_dir(dir), matrix.segment_size))
+        .map_err(|e| format!("reopen failed: {}", e))?;
+    let mut recovered = 0;
+    for item in wal.reader() {
+        let (data, pos) = item.map_err(|e| {
+            format!(
+                "recovered log unreadable after {} records: {}",
+                recovered, e
+            )
+        })?;
+        match acked.get(recovered) {
+            Some(record) if record.pos == pos && record.data == data => {}
+            Some(record) => {
+                return Err(format!(
+                    "recovered record {} at {:?} is not acknowledged record {} at {:?}",
+                    recovered, pos, record.seq, record.pos
+                ))
+            }
+            None if recovered < acked.len() + BATCH_LEN
+                && data == payload(0, recovered, record_len(recovered)) => {}
+            None if (acked.len()..=acked.len() + BATCH_LEN)
+                .any(|seq| data == payload(SCRATCH_WRITER, seq, scratch_len(matrix))) => {}
+            None => {
+                return Err(format!(
+                    "unexpected record {} at {:?} after the {} acknowledged",
+                    recovered,
+                    pos,
+                    acked.len()
+                ))
+            }
+        }
+        recovered += 1;
+    }
+    if recovered < acked.len() {
+        return Err(format!(
+            "acknowledged record {} at {:?} lost",
+            acked[recovered].seq, acked[recovered].pos
+        ));
+    }
+    let data = payload(1, 0, 100);
+    let pos = wal
+        .write(&data)
+        .map_err(|e| format!("write after reopen failed: {}", e))?;
+    match wal.read(pos) {
+        Ok(read) if read == data => Ok(()),
+        _ => Err(format!("write after reopen at {:?} unreadable", pos)),
+    }
+}
+
+fn read_acks(dir: &Path) -> Vec<WrittenRecord> {
+    let Ok(acks) = std::fs::read_to_string(dir.join(ACK_FILE_NAME)) else {
+        return Vec::new();
+    };
+    acks.lines()
+        .filter_map(|line| {
+            // The last line may be torn by the crash.
+            let fields: Vec<u64> = line
+                .split(' ')
+                .map(|f| f.parse().ok())
+                .collect::<Option<_>>()?;
+            let [seq, segment_id, block_number, chunk_offset] = fields[..] else {
+                return None;
+            };
+            Some(WrittenRecord {
+                writer: 0,
+                seq: seq as usize,
+                pos: crate::wal::ChunkPosition {
+                    segment_id: segment_id as u32,
+                    block_number: block_number as u32,
+                    chunk_offset,
+                },
+                data: payload(0, seq as usize, record_len(seq as usize)),
+            })
+        })
+        .collect()
+}
+
+fn wal_dir(dir: &Path) -> PathBuf {
+    dir.join("wal")
+}
+
+/// Mix small, block-sized and multi-block records.
+fn record_len(seq: usize) -> usize {
+    [64, 3000, 40 * 1024, 700][seq % 4]
+}
+
+/// Half a segment, so the scratch record rotates to a new one about every
+/// other time.
+fn scratch_len(matrix: &CrashMatrix) -> usize {
+    matrix.segment_size as usize / 2
+}
+
+/// xorshift64
+fn next(state: &mut u64) -> u64 {
+    *state ^= *state << 13;
+    *state ^= *state >> 7;
+    *state ^= *state << 17;
+    *state
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn crash_matrix() {
+        run(&CrashMatrix {
+            test_name: "testing::crash::tests::crash_matrix",
+            seed: 0x5eed,
+            cases: 48,
+            records: 40,
+            segment_size: 256 * 1024,
+        });
+    }
+}
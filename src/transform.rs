@@ -0,0 +1,125 @@
+use crate::error::WalError;
+
+/// A payload transform applied to every record of a Wal, e.g. encryption,
+/// compression or a schema envelope.
+///
+/// `encode` runs before the record is chunked and written, `decode` runs on
+/// the bytes read back, so `decode(encode(data))` must return `data`.
+pub trait RecordTransform: Send + Sync {
+    fn encode(&self, data: &[u8]) -> Result<Vec<u8>, WalError>;
+
+    fn decode(&self, data: Vec<u8>) -> Result<Vec<u8>, WalError>;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{options::Options, segment::BLOCK_SIZE, testing, wal::Wal};
+
+    struct Xor(u8);
+
+    impl RecordTransform for Xor {
+        fn encode(&self, data: &[u8]) -> Result<Vec<u8>, WalError> {
+            Ok(data.iter().map(|b| b ^ self.0).collect())
+        }
+
+        fn decode(&self, data: Vec<u8>) -> Result<Vec<u8>, WalError> {
+            Ok(data.into_iter().map(|b| b ^ self.0).collect())
+        }
+    }
+
+    struct Reject;
+
+    impl RecordTransform for Reject {
+        fn encode(&self, _: &[u8]) -> Result<Vec<u8>, WalError> {
+            Err(WalError::TransformFailed("rejected".into()))
+        }
+
+        fn decode(&self, data: Vec<u8>) -> Result<Vec<u8>, WalError> {
+            Ok(data)
+        }
+    }
+
+    #[test]
+    fn applied_symmetrically() {
+        let dir = testing::temp_dir("transform");
+        let opts = Options::new(&dir, 1024 * 1024).with_transform(Xor(0x5a));
+        let mut wal = Wal::open(opts).unwrap();
+        let pos = wal.write(b"plain text").unwrap();
+        assert_eq!(wal.read(pos).unwrap(), b"plain text");
+        drop(wal);
+
+        let mut on_disk = Vec::new();
+        for entry in std::fs::read_dir(&dir).unwrap() {
+            on_disk.extend(std::fs::read(entry.unwrap().path()).unwrap());
+        }
+        assert!(!on_disk.windows(10).any(|w| w == b"plain text"));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    /// Counts the records it decodes.
+    #[derive(Clone, Default)]
+    struct Counting(std::sync::Arc<std::sync::atomic::AtomicUsize>);
+
+    impl RecordTransform for Counting {
+        fn encode(&self, data: &[u8]) -> Result<Vec<u8>, WalError> {
+            Ok(data.to_vec())
+        }
+
+        fn decode(&self, data: Vec<u8>) -> Result<Vec<u8>, WalError> {
+            self.0.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            Ok(data)
+        }
+    }
+
+    #[test]
+    fn decoded_cache() {
+        let dir = testing::temp_dir("transform_cache");
+        let counting = Counting::default();
+        let decodes = || counting.0.load(std::sync::atomic::Ordering::Relaxed);
+        let opts = Options::new(&dir, 1024 * 1024)
+            .with_transform(counting.clone())
+            .with_decoded_cache_bytes(100);
+        let mut wal = Wal::open(opts).unwrap();
+        let a = wal.write(&[1; 60]).unwrap();
+        let b = wal.write(&[2; 60]).unwrap();
+        for _ in 0..3 {
+            assert_eq!(wal.read(a).unwrap(), [1; 60]);
+        }
+        assert_eq!(decodes(), 1);
+        // Reading `b` evicts `a`, as both don't fit the budget.
+        assert_eq!(wal.read(b).unwrap(), [2; 60]);
+        assert_eq!(wal.read(a).unwrap(), [1; 60]);
+        assert_eq!(decodes(), 3);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn decoded_cache_after_purge() {
+        let dir = testing::temp_dir("transform_cache_purge");
+        let opts = Options::new(&dir, 4 * BLOCK_SIZE as u64)
+            .with_transform(Counting::default())
+            .with_decoded_cache_bytes(1 << 20);
+        let mut wal = Wal::open(opts).unwrap();
+        let records = testing::write_records(&mut wal, 0, 100, 3000);
+        let first = records[0].pos;
+        assert_eq!(wal.read(first).unwrap(), records[0].data);
+        let last = records.last().unwrap().pos;
+        assert!(!wal.purge_before(last).unwrap().is_empty());
+        // Not served from the cache once its segment is gone.
+        assert!(wal.read(first).is_err());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn encode_error() {
+        let dir = testing::temp_dir("transform_error");
+        let opts = Options::new(&dir, 1024 * 1024).with_transform(Reject);
+        let mut wal = Wal::open(opts).unwrap();
+        assert!(matches!(
+            wal.write(b"data"),
+            Err(WalError::TransformFailed(_))
+        ));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
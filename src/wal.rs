@@ -1,132 +1,5480 @@
 use std::{
+    borrow::Cow,
     collections::HashMap,
-    rc::Rc,
-    sync::{Mutex, RwLock},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard,
+    },
 };
 
+pub use crate::segment::{
+    Block, BlockChunk, BlockId, BlockTrailer, ChunkPosition, ChunkType, SegmentInfo, VerifyReport,
+};
 use crate::{
-    error::WalError,
-    options::Options,
-    segment::{self, ChunkPosition, Segment, BLOCK_SIZE, CHUNK_HEADER_SIZE, SEGMENT_FILE_SUFFIX},
+    archive::{self, ArchiveWriter},
+    cache::{BlockCache, DecodedCache},
+    compression::Compression,
+    entry_index::{self, EntryIndex},
+    error::{IoResultExt, WalError},
+    format::FormatInfo,
+    maintenance::{self, Maintenance},
+    manifest::{self, Manifest},
+    options::{
+        InvalidSegmentNames, OpenVerification, Options, OversizedBatch, PoisonPolicy,
+        RetentionPolicy, SyncPolicy, WriteOptions, QUARANTINE_DIR,
+    },
+    segment::{self, Segment, SegmentReader, CHUNK_HEADER_SIZE, SEGMENT_FILE_SUFFIX},
+    stats::{self, Lag, SegmentReadStats},
 };
 
-const INITIAL_SEGMENT_FILE_ID: u32 = 1;
+#[cfg(any(feature = "tokio", feature = "async-std", feature = "smol"))]
+pub mod r#async;
+
+pub(crate) const INITIAL_SEGMENT_FILE_ID: u32 = 1;
 
+/// A write-ahead log in a directory of segment files.
+///
+/// `Wal` is `Send + Sync`: reads take `&self` and can run from many threads
+/// at once, writes take `&mut self`, e.g. behind a lock held only to write.
 pub struct Wal {
-    active_segment: Rc<RwLock<Option<Segment>>>,
-    older_segments: HashMap<u32, Rc<Segment>>,
+    active_segment: Arc<RwLock<Segment>>,
+    older_segments: HashMap<u32, Arc<Segment>>,
     options: Options,
+    /// Number of live `FreezeGuard`s, writes fail while it is non-zero.
+    frozen: Arc<AtomicUsize>,
+    report: OpenReport,
+    format: FormatInfo,
+    /// Records decoded by the transform or decompressed, if they are cached.
+    decoded: Option<Mutex<DecodedCache>>,
+    /// When the read statistics were last persisted.
+    read_stats_persisted: Mutex<std::time::Instant>,
+    /// Bytes written since the last sync by the sync policy, and when that was.
+    unsynced_bytes: u64,
+    last_sync: std::time::Instant,
+    /// Checksums of the records, see [`Options::with_content_index`].
+    content_index: Option<ContentIndex>,
+    /// Positions of the records by index, see [`Options::with_entry_index`].
+    entry_index: Option<EntryIndex>,
+    /// Where the log logically starts, see [`Wal::truncate_before`].
+    start: Option<ChunkPosition>,
+    /// Opened with [`Wal::open_file`], without a directory to rotate in.
+    single_file: bool,
+    /// Records waiting for [`Wal::commit_staged`].
+    staged: Vec<Vec<u8>>,
+    /// Held while the Wal is open for writing, see [`manifest::lock_dir`].
+    _dir_lock: Option<std::fs::File>,
+    /// Threads started by [`Options::with_prefetch_bytes`] which may still be
+    /// running, joined when the Wal is closed or dropped.
+    prefetches: Mutex<Vec<std::thread::JoinHandle<()>>>,
+    /// The thread started by [`Wal::start_maintenance`], stopped when the
+    /// Wal is closed or dropped.
+    maintenance: Option<Arc<maintenance::Shared>>,
+}
+
+/// Positions of the records by [`Wal::checksum_of`], in write order.
+#[derive(Default)]
+struct ContentIndex {
+    positions: HashMap<u32, Vec<ChunkPosition>>,
+    /// No position is in a segment before this one.
+    first_segment_id: u32,
+}
+
+/// Keeps the Wal read-only until dropped, see [`Wal::freeze`].
+pub struct FreezeGuard {
+    frozen: Arc<AtomicUsize>,
+}
+
+impl Drop for FreezeGuard {
+    fn drop(&mut self) {
+        self.frozen.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// Problems which didn't stop [`Wal::open`], see [`Wal::open_report`].
+#[derive(Debug, Default)]
+pub struct OpenReport {
+    /// Non-fatal errors, e.g. segment file permissions that couldn't be set.
+    pub warnings: Vec<WalError>,
+    /// What was verified on open, see [`Options::with_open_verification`];
+    /// the corrupted blocks found are among the warnings.
+    pub verified: Option<VerifyReport>,
+}
+
+/// Bounds on a single page of [`Wal::read_range`], unbounded by default.
+///
+/// A page always holds at least one record, so paging makes progress even
+/// when a record is larger than `max_bytes`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ReadLimits {
+    max_bytes: Option<usize>,
+    max_records: Option<usize>,
+    max_segments: Option<usize>,
+}
+
+impl ReadLimits {
+    pub fn with_max_bytes(mut self, max_bytes: usize) -> Self {
+        self.max_bytes = Some(max_bytes);
+        self
+    }
+
+    pub fn with_max_records(mut self, max_records: usize) -> Self {
+        self.max_records = Some(max_records);
+        self
+    }
+
+    /// The most segments a page's records may come from.
+    pub fn with_max_segments(mut self, max_segments: usize) -> Self {
+        self.max_segments = Some(max_segments);
+        self
+    }
+}
+
+/// Records read by [`Wal::read_range`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RangePage {
+    pub records: Vec<(ChunkPosition, Vec<u8>)>,
+    /// Where the rest of the range starts, `None` once it has been read.
+    pub next: Option<ChunkPosition>,
+}
+
+/// Iterates over every record of a Wal in log order, see [`Wal::reader`].
+pub struct WalReader<'a> {
+    wal: &'a Wal,
+    /// The next record to read, `None` once done, or until `started`.
+    next: Option<ChunkPosition>,
+    started: bool,
+    /// The segment whose successor was prefetched, see
+    /// [`Options::with_prefetch_bytes`].
+    prefetched: Option<u32>,
+}
+
+impl Iterator for WalReader<'_> {
+    type Item = Result<(Vec<u8>, ChunkPosition), WalError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if !self.started {
+            self.started = true;
+            match self.wal.first_position() {
+                Ok(first) => self.next = first,
+                Err(e) => return Some(Err(e)),
+            }
+        }
+        let pos = self.next.take()?;
+        // Ends after an error, `next` stays `None`.
+        let read = self.wal.read_next(pos).map(|(data, next)| {
+            self.next = next;
+            (data, pos)
+        });
+        if let Some(next) = self
+            .next
+            .filter(|next| self.prefetched != Some(next.segment_id))
+        {
+            if let Some(seg) = self.wal.older_segments.get(&next.segment_id) {
+                let offset = seg.offset_of(next.block_number, next.chunk_offset);
+                if self.wal.prefetch_if_near_end(seg, offset) {
+                    self.prefetched = Some(next.segment_id);
+                }
+            }
+        }
+        Some(read)
+    }
+}
+
+/// How far a [`Wal::replay_with_progress`] got.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReplayProgress {
+    /// Bytes of the segments scanned so far, skipped ones included.
+    pub bytes_processed: u64,
+    /// Bytes of all segments when the replay started.
+    pub total_bytes: u64,
+    /// Records handed to the callback so far.
+    pub records: u64,
+    pub elapsed: std::time::Duration,
+}
+
+impl ReplayProgress {
+    /// Estimate the time left from the rate so far, `None` until there is one.
+    pub fn estimate_remaining(&self) -> Option<std::time::Duration> {
+        if self.bytes_processed == 0 {
+            return None;
+        }
+        let remaining = self.total_bytes.saturating_sub(self.bytes_processed);
+        Some(
+            self.elapsed
+                .mul_f64(remaining as f64 / self.bytes_processed as f64),
+        )
+    }
+}
+
+/// What [`Wal::replay`] does after the callback has seen a record.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReplayControl {
+    Continue,
+    /// Skip the rest of the current segment and continue with the next one.
+    SkipSegment,
+    Stop,
 }
 
 impl Wal {
-    pub fn open(options: Options) -> Result<Self, WalError> {
+    pub fn open(mut options: Options) -> Result<Self, WalError> {
         // Create the directory if not exists.
-        std::fs::create_dir(&options.dir_path)?;
-        // Get all segment file id.
-        let mut segment_ids = Vec::new();
-        for entry in std::fs::read_dir(&options.dir_path)? {
-            let entry = entry?;
-            let path = entry.path();
-            if path.is_dir() {
+        if !options.read_only {
+            std::fs::create_dir_all(&options.dir_path).context("create", &options.dir_path)?;
+        }
+        // Before anything in the directory is read, so a writer opening at
+        // the same time can't get in between.
+        let dir_lock = match options.read_only {
+            true => None,
+            false => Some(manifest::lock_dir(&options.dir_path)?),
+        };
+        // Refuse options which don't match the format on disk.
+        let mut requested = Manifest::for_options(&options);
+        let manifest = match Manifest::load(&options.dir_path)? {
+            Some(manifest) => {
+                // Without a block size of their own, options take the one the
+                // directory was created with.
+                if options.block_size.is_none() {
+                    requested.block_size = manifest.block_size;
+                }
+                if options.chunk_alignment.is_none() {
+                    requested.chunk_alignment = manifest.chunk_alignment;
+                }
+                if options.preallocation.is_none() {
+                    requested.preallocation = manifest.preallocation;
+                }
+                manifest.check(&requested)?;
+                manifest
+            }
+            None => requested,
+        };
+        options.block_size = Some(manifest.block_size);
+        options.chunk_alignment = Some(manifest.chunk_alignment);
+        options.preallocation = Some(manifest.preallocation);
+        options.check_layout()?;
+        if let Some(sealed_dir) = options.sealed_dir.as_ref().filter(|_| !options.read_only) {
+            std::fs::create_dir_all(sealed_dir).context("create", sealed_dir)?;
+        }
+        let mut report = OpenReport::default();
+        // Get all segment file id, from the index if it is intact.
+        let index = Manifest::load_segment_index(&options.dir_path)?;
+        let located = match &index {
+            Some(index) => locate_segments(&options, index)?,
+            None => None,
+        };
+        let indexed = located.is_some();
+        let (mut segment_ids, mut sealed_ids) = match located {
+            Some(located) => located,
+            None => {
+                let sealed_ids = match &options.sealed_dir {
+                    Some(sealed_dir) => scan_segment_ids(&options, sealed_dir, &mut report)?,
+                    None => Vec::new(),
+                };
+                let segment_ids = scan_segment_ids(&options, &options.dir_path, &mut report)?;
+                (segment_ids, sealed_ids)
+            }
+        };
+        segment_ids.sort();
+        let max_sealed_id = sealed_ids.iter().max().copied();
+
+        // The max one in the data directory is the active segment file, unless
+        // it has already been sealed.
+        let mut active_id = match segment_ids.last() {
+            Some(&id) if max_sealed_id.is_none_or(|sealed| id > sealed) => {
+                segment_ids.pop().unwrap()
+            }
+            _ => max_sealed_id.map_or(options.first_segment_id, |sealed| sealed + 1),
+        };
+        let mut active_dir = options.dir_path.as_path();
+        if let Some(newest) = max_sealed_id.filter(|_| {
+            options.read_only && !segment::segment_file_path(active_dir, active_id).is_file()
+        }) {
+            // Only sealed segments are left, e.g. of an archived Wal; the newest
+            // has to be the active one, as there is nowhere to create another.
+            sealed_ids.retain(|&id| id != newest);
+            active_dir = options.sealed_dir.as_ref().unwrap();
+            active_id = newest;
+        }
+        if options.read_only {
+            // Leave the index as it is.
+        } else if !indexed {
+            let mut all_ids: Vec<u32> = segment_ids.iter().chain(&sealed_ids).copied().collect();
+            all_ids.push(active_id);
+            all_ids.sort();
+            all_ids.dedup();
+            manifest.store_with_segments(&options.dir_path, &all_ids)?;
+        } else if !index.as_ref().unwrap().contains(&active_id) {
+            Manifest::append_segment(&options.dir_path, active_id)?;
+        }
+        let block_cache = (options.block_cache_bytes > 0)
+            .then(|| Arc::new(Mutex::new(BlockCache::new(options.block_cache_bytes))));
+        let mut active_segment =
+            open_segment(&options, active_dir, active_id, &mut report.warnings)?;
+        active_segment.set_block_cache(block_cache.clone());
+        recover_active(&options, &mut active_segment, &mut report)?;
+
+        let mut older_segments = HashMap::new();
+        for seg_id in sealed_ids {
+            let sealed_dir = options.sealed_dir.as_ref().unwrap();
+            let mut seg = open_segment(&options, sealed_dir, seg_id, &mut report.warnings)?;
+            seg.set_block_cache(block_cache.clone());
+            seg.mark_sealed();
+            older_segments.insert(seg_id, Arc::new(seg));
+        }
+        let newest_id = segment_ids.last().copied();
+        for seg_id in segment_ids {
+            let mut seg = open_segment(&options, &options.dir_path, seg_id, &mut report.warnings)?;
+            seg.set_block_cache(block_cache.clone());
+            seg.mark_sealed();
+            if options.extends_active_file() && Some(seg_id) == newest_id {
+                // Crashed before the preallocated space was cut off on rotation.
+                seg.recover_logical_tail()?;
+                if !options.read_only {
+                    seg.seal()?;
+                }
+            }
+            if options.read_only {
+                // Where a completed copy exists, it is read instead.
+                older_segments.entry(seg_id).or_insert(Arc::new(seg));
                 continue;
             }
-            let file_name = match entry.file_name().into_string() {
-                Ok(s) => s,
-                Err(_) => continue,
-            };
-            let id: u32 = file_name[0..file_name.find(SEGMENT_FILE_SUFFIX).unwrap()].parse()?;
-            segment_ids.push(id);
+            if options.sealed_dir.is_some() && older_segments.contains_key(&seg_id) {
+                // Crashed after the copy had been completed.
+                seg.remove()?;
+                continue;
+            }
+            older_segments.insert(seg_id, Arc::new(seg));
+        }
+        if !options.read_only {
+            apply_retention(&options, &mut older_segments, active_id)?;
+            // Crashed before sealed segments were moved, or they are kept in
+            // the data directory up to the spill threshold.
+            relocate_sealed(&options, &mut older_segments)?;
+        }
+        // Segments created by rotations which crashed before anything was
+        // written to them: the newest stays the active segment, the empty ones
+        // before it are removed, so they never end up between records.
+        if active_segment.size() == 0 && !options.read_only {
+            let last_written = older_segments
+                .values()
+                .filter(|seg| seg.size() > 0)
+                .map(|seg| seg.id)
+                .max();
+            let mut empty: Vec<u32> = older_segments
+                .keys()
+                .copied()
+                .filter(|&id| last_written.is_none_or(|last| id > last))
+                .collect();
+            empty.sort();
+            for id in &empty {
+                older_segments.remove(id).unwrap().remove()?;
+            }
+            if !empty.is_empty() {
+                let mut ids: Vec<u32> = older_segments.keys().copied().collect();
+                ids.push(active_id);
+                ids.sort();
+                manifest.store_with_segments(&options.dir_path, &ids)?;
+            }
+        }
+
+        for seg in older_segments.values() {
+            prepare_sealed_reads(&options, seg)?;
+        }
+
+        report.verified = verify_on_open(
+            &options,
+            older_segments.values().map(|seg| seg.as_ref()),
+            &active_segment,
+            &mut report.warnings,
+        )?;
+
+        let persisted = stats::load(&options.dir_path);
+        for seg in older_segments
+            .values()
+            .map(|seg| seg.as_ref())
+            .chain([&active_segment])
+        {
+            if let Some(stats) = persisted.get(&seg.id) {
+                seg.reads.restore(stats);
+            }
+        }
+
+        let start = manifest::load_start(&options.dir_path)?;
+        let format = manifest.format_info();
+        let mut wal = Self::assemble(options, active_segment, older_segments, report, format);
+        wal._dir_lock = dir_lock;
+        wal.start = start;
+        wal.build_content_index()?;
+        if wal.options.entry_index {
+            let firsts = entry_index::load(&wal.options.dir_path)?;
+            let mut index = EntryIndex::default();
+            let active_seg = wal.active()?;
+            let mut segments: Vec<&Segment> = wal
+                .older_segments
+                .values()
+                .map(|seg| seg.as_ref())
+                .chain([&*active_seg])
+                .collect();
+            segments.sort_by_key(|seg| seg.id);
+            for seg in segments {
+                index.add_segment(seg.id, firsts.get(&seg.id).copied());
+                let mut reader = SegmentReader::new(seg);
+                while let Some((_, pos)) = reader.next_record()? {
+                    index.push(pos);
+                }
+            }
+            drop(active_seg);
+            if !wal.options.read_only {
+                entry_index::store(&wal.options.dir_path, &index)?;
+            }
+            wal.entry_index = Some(index);
+        }
+        #[cfg(feature = "tracing")]
+        tracing::info!(options = ?wal.options, "opened wal");
+        Ok(wal)
+    }
+
+    /// Open a Wal of a single segment in `file`, for environments where it
+    /// can't manage a directory, e.g. with a file handle passed into a
+    /// sandbox: nothing else is created, listed or written. `file` has to be
+    /// open for reading, and for writing unless read-only.
+    ///
+    /// Without a manifest, `options` has to lay records out the way the file
+    /// was written, and `options.dir_path` only names the file in errors.
+    /// The Wal never rotates: a write which doesn't fit in the segment size
+    /// fails with `WalError::LogFull`. Options which need a directory, a
+    /// sealed dir, retention, the entry index, persisted read statistics or
+    /// sampled open verification, fail the open with
+    /// `WalError::InvalidOptions`, and so do methods which need one with
+    /// `WalError::NoDirectory`.
+    ///
+    /// `file` has to be a regular file: the log continues at its length, and
+    /// recovery cuts it, neither of which works on a block device or other
+    /// special file. Those fail with `WalError::NotARegularFile`.
+    pub fn open_file(file: std::fs::File, mut options: Options) -> Result<Self, WalError> {
+        if options.sealed_dir.is_some()
+            || options.retention != RetentionPolicy::default()
+            || options.entry_index
+            || options.read_stats_interval.is_some()
+            || matches!(options.open_verification, OpenVerification::Sampled { .. })
+        {
+            return Err(WalError::InvalidOptions(
+                "the options need a Wal directory".to_string(),
+            ));
         }
-        // Empty directory, just initialize a new segment file and return.
-        if segment_ids.is_empty() {
-            let mut seg = segment::Segment::open(&options.dir_path, INITIAL_SEGMENT_FILE_ID)?;
-            let offset = seg.metadata()?.len();
-            seg.current_block_number = (offset / BLOCK_SIZE as u64) as u32;
-            seg.current_block_size = (offset % BLOCK_SIZE as u64) as u32;
-            return Ok(Self {
-                active_segment: Rc::new(RwLock::new(Some(seg))),
-                older_segments: HashMap::new(),
-                options,
+        if !file
+            .metadata()
+            .context("stat", &options.dir_path)?
+            .file_type()
+            .is_file()
+        {
+            return Err(WalError::NotARegularFile {
+                path: options.dir_path,
             });
-        } else {
-            // Open the segment file in order, get the max one as the active segment file.
-            let len = segment_ids.len();
-            let mut active_segment = None;
-            let mut older_segments = HashMap::new();
-            segment_ids.sort();
-
-            for (i, seg_id) in segment_ids.into_iter().enumerate() {
-                let mut seg = segment::Segment::open(&options.dir_path, seg_id)?;
-                if i == len - 1 {
-                    let offset = seg.metadata()?.len();
-                    seg.current_block_number = (offset / BLOCK_SIZE as u64) as u32;
-                    seg.current_block_size = (offset % BLOCK_SIZE as u64) as u32;
-                    active_segment = Some(seg);
-                } else {
-                    older_segments.insert(seg_id, Rc::new(seg));
-                }
-            }
-
-            Ok(Self {
-                active_segment: Rc::new(RwLock::new(active_segment)),
-                older_segments,
-                options: options,
-            })
+        }
+        let requested = Manifest::for_options(&options);
+        options.block_size = Some(requested.block_size);
+        options.chunk_alignment = Some(requested.chunk_alignment);
+        options.preallocation = Some(requested.preallocation);
+        options.check_layout()?;
+        let mut report = OpenReport::default();
+        let seg = Segment::from_file(file, options.first_segment_id, options.dir_path.clone())?;
+        let mut active_segment = configure_segment(&options, seg)?;
+        active_segment.set_block_cache(
+            (options.block_cache_bytes > 0)
+                .then(|| Arc::new(Mutex::new(BlockCache::new(options.block_cache_bytes)))),
+        );
+        recover_active(&options, &mut active_segment, &mut report)?;
+        report.verified = verify_on_open(&options, [], &active_segment, &mut report.warnings)?;
+        let format = requested.format_info();
+        let mut wal = Self::assemble(options, active_segment, HashMap::new(), report, format);
+        wal.single_file = true;
+        wal.build_content_index()?;
+        #[cfg(feature = "tracing")]
+        tracing::info!(options = ?wal.options, "opened wal");
+        Ok(wal)
+    }
+
+    fn assemble(
+        options: Options,
+        active_segment: Segment,
+        older_segments: HashMap<u32, Arc<Segment>>,
+        report: OpenReport,
+        format: FormatInfo,
+    ) -> Self {
+        Self {
+            content_index: None,
+            entry_index: None,
+            start: None,
+            single_file: false,
+            staged: Vec::new(),
+            _dir_lock: None,
+            prefetches: Mutex::default(),
+            maintenance: None,
+            read_stats_persisted: Mutex::new(options.clock.now()),
+            unsynced_bytes: 0,
+            last_sync: options.clock.now(),
+            active_segment: Arc::new(RwLock::new(active_segment)),
+            older_segments,
+            frozen: Arc::new(AtomicUsize::new(0)),
+            report,
+            format,
+            decoded: (options.decoded_cache_bytes > 0)
+                .then(|| Mutex::new(DecodedCache::new(options.decoded_cache_bytes))),
+            options,
+        }
+    }
+
+    /// Index the records written before the Wal was opened, if asked to
+    /// keep a content index.
+    fn build_content_index(&mut self) -> Result<(), WalError> {
+        if !self.options.content_index {
+            return Ok(());
+        }
+        let mut index = ContentIndex::default();
+        for record in self.reader() {
+            let (data, pos) = record?;
+            index
+                .positions
+                .entry(crc32fast::hash(&data))
+                .or_default()
+                .push(pos);
+        }
+        self.content_index = Some(index);
+        Ok(())
+    }
+
+    /// Whether the record at `pos` was discarded by [`Wal::truncate_before`].
+    fn before_start(&self, pos: ChunkPosition) -> bool {
+        self.start.is_some_and(|start| pos < start)
+    }
+
+    /// Fail with `WalError::BeforeStart` if the record at `pos` was discarded
+    /// by [`Wal::truncate_before`].
+    fn check_start(&self, pos: ChunkPosition) -> Result<(), WalError> {
+        match self.before_start(pos) {
+            true => Err(WalError::BeforeStart {
+                segment_id: pos.segment_id,
+                block_number: pos.block_number,
+                offset: pos.chunk_offset,
+            }),
+            false => Ok(()),
+        }
+    }
+
+    /// Fail with `WalError::NoDirectory` if the Wal was opened with
+    /// [`Wal::open_file`].
+    fn check_directory(&self, op: &'static str) -> Result<(), WalError> {
+        match self.single_file {
+            true => Err(WalError::NoDirectory { op }),
+            false => Ok(()),
         }
     }
 
+    /// Append a record and return its position.
+    ///
+    /// An empty record is valid, e.g. as a marker: it takes a chunk header
+    /// and reads back as empty, never as padding.
     pub fn write(&mut self, data: &[u8]) -> Result<ChunkPosition, WalError> {
-        let mut active_seg = self.active_segment.write().unwrap();
-        let mut active_seg = active_seg.as_mut().unwrap();
-        let id = active_seg.id;
+        self.write_with(data, &WriteOptions::default())
+    }
+
+    /// [`Wal::write`] with per-record options, e.g. the version of the
+    /// record's payload format.
+    pub fn write_with(
+        &mut self,
+        data: &[u8],
+        write_options: &WriteOptions,
+    ) -> Result<ChunkPosition, WalError> {
+        if self.options.read_only {
+            return Err(WalError::ReadOnly);
+        }
+        if self.frozen.load(Ordering::Acquire) > 0 {
+            return Err(WalError::Frozen);
+        }
+        if write_options.version != 0 && !self.options.record_versions {
+            return Err(WalError::RecordVersionsDisabled);
+        }
+        let checksum = self.content_index.is_some().then(|| crc32fast::hash(data));
+        let data = self.envelope(data, write_options.version)?;
+        // The fullness check and the rotation happen under the same lock, so a
+        // reader never sees the old active segment missing from both places.
+        let mut guard = lock_active_mut(&self.active_segment, self.options.poison_policy)?;
+        let active_seg = &mut *guard;
         // If the active segment file is full, close it and create a new one.
-        if self.is_full(data.len() as u64) {
-            let mut seg = Segment::open(&self.options.dir_path, id + 1)?;
-            self.older_segments
-                .insert(id, std::rc::Rc::new(std::mem::replace(active_seg, seg)));
+        if self.exceeds_segment_size(active_seg, data.len() as u64) {
+            if self.single_file {
+                return Err(WalError::LogFull);
+            }
+            if !self.too_small_to_seal(active_seg) {
+                rotate(
+                    &self.options,
+                    active_seg,
+                    &mut self.older_segments,
+                    &mut self.report.warnings,
+                )?;
+            }
+        }
+        let pos = self.append(active_seg, &data, false)?;
+        drop(guard);
+        self.forget_removed_segments();
+        self.index_contents(checksum.into_iter().zip([pos]));
+        self.index_entries([pos])?;
+        self.sync_if_due(data.len() as u64)?;
+        Ok(pos)
+    }
+
+    /// Append the records as one batch, under a single lock acquisition and
+    /// with a single fsync, and return their positions.
+    ///
+    /// The batch is all or nothing: its records are flagged as such on disk,
+    /// and a batch cut short by a crash is discarded when the Wal is reopened,
+    /// as is one whose write failed. All records of a batch go into the same
+    /// segment, which is rotated first if the batch doesn't fit.
+    ///
+    /// A batch over [`Options::with_max_batch_bytes`] is rejected, or split
+    /// into batches which are written in order. If one of those fails after
+    /// others were written, they stay written, and the error is
+    /// `WalError::BatchPartlyWritten` with their positions.
+    pub fn write_batch(&mut self, records: &[&[u8]]) -> Result<Vec<ChunkPosition>, WalError> {
+        let Some((max, oversized)) = self.options.max_batch_bytes else {
+            return self.write_unsplit_batch(records);
+        };
+        let bytes: u64 = records.iter().map(|data| data.len() as u64).sum();
+        if bytes <= max {
+            return self.write_unsplit_batch(records);
+        }
+        if oversized == OversizedBatch::Reject {
+            return Err(WalError::BatchTooLarge { bytes, max });
+        }
+        let mut positions = Vec::with_capacity(records.len());
+        let mut rest = records;
+        while !rest.is_empty() {
+            // As many records as fit, at least one.
+            let mut len = 1;
+            let mut bytes = rest[0].len() as u64;
+            while len < rest.len() && bytes + rest[len].len() as u64 <= max {
+                bytes += rest[len].len() as u64;
+                len += 1;
+            }
+            let (batch, next) = rest.split_at(len);
+            match self.write_unsplit_batch(batch) {
+                Ok(written) => positions.extend(written),
+                Err(e) if positions.is_empty() => return Err(e),
+                Err(e) => {
+                    return Err(WalError::BatchPartlyWritten {
+                        written: positions,
+                        source: Box::new(e),
+                    })
+                }
+            }
+            rest = next;
+        }
+        Ok(positions)
+    }
+
+    fn write_unsplit_batch(&mut self, records: &[&[u8]]) -> Result<Vec<ChunkPosition>, WalError> {
+        if self.options.read_only {
+            return Err(WalError::ReadOnly);
+        }
+        if self.frozen.load(Ordering::Acquire) > 0 {
+            return Err(WalError::Frozen);
+        }
+        let checksums: Vec<u32> = match self.content_index {
+            Some(_) => records.iter().map(|data| crc32fast::hash(data)).collect(),
+            None => Vec::new(),
+        };
+        let records = records
+            .iter()
+            .map(|data| self.envelope(data, 0))
+            .collect::<Result<Vec<_>, _>>()?;
+        if records.is_empty() {
+            return Ok(Vec::new());
+        }
+        let mut guard = lock_active_mut(&self.active_segment, self.options.poison_policy)?;
+        let active_seg = &mut *guard;
+        // Every record but the last brings another chunk header.
+        let len = records.iter().map(|data| data.len() as u64).sum::<u64>()
+            + (records.len() as u64 - 1) * CHUNK_HEADER_SIZE as u64;
+        if active_seg.size() > 0 && self.exceeds_segment_size(active_seg, len) {
+            if self.single_file {
+                return Err(WalError::LogFull);
+            }
+            if !self.too_small_to_seal(active_seg) {
+                rotate(
+                    &self.options,
+                    active_seg,
+                    &mut self.older_segments,
+                    &mut self.report.warnings,
+                )?;
+            }
+        }
+        let start = active_seg.size();
+        let written = records
+            .iter()
+            .enumerate()
+            .map(|(i, data)| self.append(active_seg, data, i + 1 < records.len()))
+            .collect::<Result<Vec<_>, _>>()
+            .and_then(|positions| {
+                fail_point!("wal::before_batch_sync");
+                active_seg.sync().map(|_| positions)
+            });
+        if written.is_err() {
+            // Best effort, reopening discards the unfinished batch anyway.
+            let _ = active_seg.truncate(start);
+        }
+        drop(guard);
+        // The batch is synced; a segment sealed before it may still be due.
+        if let Ok(positions) = &written {
+            self.forget_removed_segments();
+            self.index_contents(checksums.into_iter().zip(positions.iter().copied()));
+            self.index_entries(positions.iter().copied())?;
+            self.sync_if_due(0)?;
+        }
+        written
+    }
+
+    /// Keep a record in memory until [`Wal::commit_staged`] writes it, or
+    /// [`Wal::discard_staged`] drops it.
+    pub fn stage(&mut self, data: &[u8]) {
+        self.staged.push(data.to_vec());
+    }
+
+    /// Number of records staged and not committed or discarded yet.
+    pub fn staged(&self) -> usize {
+        self.staged.len()
+    }
+
+    /// Write the staged records as one batch, see [`Wal::write_batch`], and
+    /// return their positions. The records which weren't written if that
+    /// fails stay staged: all of them, unless a batch split by
+    /// [`Options::with_max_batch_bytes`] failed with
+    /// `WalError::BatchPartlyWritten` after some of its parts were written.
+    pub fn commit_staged(&mut self) -> Result<Vec<ChunkPosition>, WalError> {
+        let mut staged = std::mem::take(&mut self.staged);
+        let records: Vec<&[u8]> = staged.iter().map(|data| &data[..]).collect();
+        let written = self.write_batch(&records);
+        drop(records);
+        match &written {
+            Ok(_) => {}
+            Err(WalError::BatchPartlyWritten { written, .. }) => {
+                staged.drain(..written.len());
+                self.staged = staged;
+            }
+            Err(_) => self.staged = staged,
+        }
+        written
+    }
+
+    /// Drop the staged records without writing them.
+    pub fn discard_staged(&mut self) {
+        self.staged.clear();
+    }
+
+    /// Start prefetching the sealed segment after `seg` if a scan at `offset`
+    /// in it is near its end, see [`Options::with_prefetch_bytes`], and
+    /// return whether it was.
+    fn prefetch_if_near_end(&self, seg: &Segment, offset: u64) -> bool {
+        let bytes = self.options.prefetch_bytes;
+        if bytes == 0 || seg.visible_size().saturating_sub(offset) > bytes {
+            return false;
+        }
+        let next = self
+            .older_segments
+            .iter()
+            .filter(|(&id, _)| id > seg.id)
+            .min_by_key(|(&id, _)| id);
+        if let Some((_, next)) = next {
+            let next = next.clone();
+            // Only a hint: a read which fails here fails again once the scan
+            // gets there, and is reported then. So is a thread which can't be
+            // started.
+            let spawned = std::thread::Builder::new()
+                .name(format!("wal-prefetch-{}", next.id))
+                .spawn(move || {
+                    let _ = next.prefetch(bytes);
+                });
+            if let Ok(handle) = spawned {
+                let mut prefetches = self
+                    .prefetches
+                    .lock()
+                    .unwrap_or_else(std::sync::PoisonError::into_inner);
+                prefetches.retain(|handle| !handle.is_finished());
+                prefetches.push(handle);
+            }
+        }
+        true
+    }
+
+    /// Wait for the prefetch threads still running, each of which reads at
+    /// most [`Options::with_prefetch_bytes`].
+    fn join_prefetches(&self) {
+        let prefetches = std::mem::take(
+            &mut *self
+                .prefetches
+                .lock()
+                .unwrap_or_else(std::sync::PoisonError::into_inner),
+        );
+        for handle in prefetches {
+            let _ = handle.join();
+        }
+    }
+
+    /// Add written records to the content index, if there is one, and drop
+    /// the ones of segments which are gone.
+    fn index_contents(&mut self, records: impl IntoIterator<Item = (u32, ChunkPosition)>) {
+        if self.content_index.is_none() {
+            return;
+        }
+        let first_id = match self.older_segments.keys().min() {
+            Some(&id) => id,
+            None => self.active_unchecked().id,
+        };
+        let Some(index) = &mut self.content_index else {
+            return;
+        };
+        for (checksum, pos) in records {
+            index.positions.entry(checksum).or_default().push(pos);
+        }
+        if index.first_segment_id < first_id {
+            index.positions.retain(|_, positions| {
+                positions.retain(|pos| pos.segment_id >= first_id);
+                !positions.is_empty()
+            });
+            index.first_segment_id = first_id;
+        }
+    }
+
+    /// Drop the cached decoded records of segments which are gone, removed
+    /// by the retention policy or [`Wal::purge_before`], so reads of them
+    /// fail like reads of their files.
+    fn forget_removed_segments(&self) {
+        let Some(decoded) = &self.decoded else {
+            return;
+        };
+        let first_id = match self.older_segments.keys().min() {
+            Some(&id) => id,
+            None => self.active_unchecked().id,
+        };
+        decoded
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .forget_before(first_id);
+    }
+
+    /// Number written records in the entry index, if there is one, and drop
+    /// the ones of segments which are gone. The first indexes are persisted
+    /// whenever segments start or go.
+    fn index_entries(
+        &mut self,
+        positions: impl IntoIterator<Item = ChunkPosition>,
+    ) -> Result<(), WalError> {
+        if self.entry_index.is_none() {
+            return Ok(());
+        }
+        let first_id = match self.older_segments.keys().min() {
+            Some(&id) => id,
+            None => self.active_unchecked().id,
+        };
+        let Some(index) = &mut self.entry_index else {
+            return Ok(());
+        };
+        let mut changed = index.forget_before(first_id);
+        for pos in positions {
+            changed |= index.push(pos);
+        }
+        if changed {
+            entry_index::store(&self.options.dir_path, index)?;
+        }
+        Ok(())
+    }
+
+    /// Sync the segments if the sync policy asks for it after `written` more
+    /// bytes.
+    fn sync_if_due(&mut self, written: u64) -> Result<(), WalError> {
+        self.unsynced_bytes += written;
+        let due = match self.options.sync_policy {
+            SyncPolicy::Never => false,
+            SyncPolicy::EveryWrite => true,
+            SyncPolicy::EveryNBytes(bytes) => self.unsynced_bytes >= bytes,
+            SyncPolicy::EveryInterval(interval) => {
+                self.options.clock.now().duration_since(self.last_sync) >= interval
+            }
+        };
+        if !due {
+            return Ok(());
+        }
+        self.sync()?;
+        self.unsynced_bytes = 0;
+        self.last_sync = self.options.clock.now();
+        Ok(())
+    }
+
+    /// Turn a record into the record as stored: encode it, and put its
+    /// version byte in front if records have one.
+    fn envelope<'a>(&self, data: &'a [u8], version: u8) -> Result<Cow<'a, [u8]>, WalError> {
+        let data = match &self.options.transform {
+            Some(transform) => Cow::Owned(transform.encode(data)?),
+            None => Cow::Borrowed(data),
+        };
+        if self.options.record_versions {
+            return Ok(Cow::Owned([&[version][..], &data].concat()));
         }
-        active_seg.write(data.to_vec())
+        Ok(data)
     }
 
+    /// Append a record as stored to the active segment, checking it was laid
+    /// out after the ones before it, and reading it back if asked to.
+    fn append(
+        &self,
+        active_seg: &mut Segment,
+        data: &[u8],
+        batch_continues: bool,
+    ) -> Result<ChunkPosition, WalError> {
+        let size = active_seg.size();
+        let pos = active_seg.write_in_batch(data, batch_continues)?;
+        self.check_invariant(
+            pos.segment_id == active_seg.id
+                && active_seg.offset_of(pos.block_number, pos.chunk_offset) >= size
+                && active_seg.size() > size,
+            "records are appended at the end of the active segment",
+        )?;
+        if self.options.verify_after_write
+            && active_seg.read_appended(pos.block_number, pos.chunk_offset)? != data
+        {
+            return Err(WalError::WriteVerificationFailed {
+                segment_id: pos.segment_id,
+                block_number: pos.block_number,
+                offset: pos.chunk_offset,
+            });
+        }
+        Ok(pos)
+    }
+
+    /// Append every item as a record, in order, and return their positions.
+    ///
+    /// Items are written as the iterator yields them, so buffers such as
+    /// `bytes::Bytes` from the network are chunked straight from where they
+    /// are, without collecting them first. Each item is written as by
+    /// [`Wal::write`], not as a batch: records before a failing one stay
+    /// written, and they may span segments.
+    pub fn write_iter<I>(&mut self, items: I) -> Result<Vec<ChunkPosition>, WalError>
+    where
+        I: IntoIterator,
+        I::Item: AsRef<[u8]>,
+    {
+        items
+            .into_iter()
+            .map(|item| self.write(item.as_ref()))
+            .collect()
+    }
+
+    /// Read the record at `pos`, failing with `WalError::InvalidCrc` if any
+    /// of its chunks is corrupted.
     pub fn read(&self, pos: ChunkPosition) -> Result<Vec<u8>, WalError> {
-        let active_seg = self.active_segment.read().unwrap();
-        let active_seg = active_seg.as_ref();
+        let mut data = Vec::new();
+        self.read_into_buf(pos, &mut data)?;
+        Ok(data)
+    }
+
+    /// Read the record at `pos` together with the version it was written
+    /// with, 0 unless written with [`WriteOptions::with_version`].
+    pub fn read_with_version(&self, pos: ChunkPosition) -> Result<(u8, Vec<u8>), WalError> {
+        let mut data = Vec::new();
+        let version = self.read_versioned_into_buf(pos, &mut data)?;
+        Ok((version, data))
+    }
+
+    /// Like [`Wal::read`], but replace the contents of `buf` with the record,
+    /// reusing its allocation, e.g. to read many records in a loop. Decoding
+    /// by a transform may still allocate.
+    pub fn read_into_buf(&self, pos: ChunkPosition, buf: &mut Vec<u8>) -> Result<(), WalError> {
+        self.read_versioned_into_buf(pos, buf)?;
+        Ok(())
+    }
+
+    fn read_versioned_into_buf(
+        &self,
+        pos: ChunkPosition,
+        buf: &mut Vec<u8>,
+    ) -> Result<u8, WalError> {
+        self.check_start(pos)?;
+        let Some(decoded) = &self.decoded else {
+            let (_, version, _) = self.read_next_into(pos, buf)?;
+            self.persist_read_stats_if_due();
+            return Ok(version);
+        };
+        // A poisoned cache only holds complete records, keep using it.
+        if let Some((version, data)) = decoded.lock().unwrap_or_else(|e| e.into_inner()).get(pos) {
+            buf.clear();
+            buf.extend_from_slice(data);
+            return Ok(version);
+        }
+        let (_, version, compressed) = self.read_next_into(pos, buf)?;
+        // Records stored as they were written cost nothing to decode.
+        if compressed || self.options.transform.is_some() {
+            decoded
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .insert(pos, version, buf.clone());
+        }
+        self.persist_read_stats_if_due();
+        Ok(version)
+    }
+
+    /// Read the record at `pos`, and return the position of the record after it,
+    /// if one has been written.
+    pub(crate) fn read_next(
+        &self,
+        pos: ChunkPosition,
+    ) -> Result<(Vec<u8>, Option<ChunkPosition>), WalError> {
+        let mut data = Vec::new();
+        let (next, _, _) = self.read_next_into(pos, &mut data)?;
+        self.persist_read_stats_if_due();
+        Ok((data, next))
+    }
+
+    /// Like [`Wal::read_next`], reading the record into `buf`, and also
+    /// returning its version and whether it was stored compressed.
+    fn read_next_into(
+        &self,
+        pos: ChunkPosition,
+        buf: &mut Vec<u8>,
+    ) -> Result<(Option<ChunkPosition>, u8, bool), WalError> {
+        self.check_start(pos)?;
+        let active_seg = self.active()?;
+        let active_seg = &*active_seg;
         // Find the segment file according to the position
-        let seg;
-        if pos.segment_id == active_seg.unwrap().id {
-            seg = active_seg;
+        let seg = if pos.segment_id == active_seg.id {
+            Some(active_seg)
         } else {
-            seg = self
-                .older_segments
+            self.older_segments
                 .get(&pos.segment_id)
-                .map(|seg| seg.as_ref());
+                .map(|seg| seg.as_ref())
+        };
+
+        let Some(seg) = seg else {
+            return Err(WalError::SegmentFileNotFound);
+        };
+        let (block_number, chunk_offset, compressed) =
+            seg.read_stored_into(pos.block_number, pos.chunk_offset, buf)?;
+        self.record_read(seg);
+        let next_offset = seg.offset_of(block_number, chunk_offset);
+        self.check_invariant(
+            next_offset > seg.offset_of(pos.block_number, pos.chunk_offset),
+            "the next record starts after the one read",
+        )?;
+        let next = if next_offset < seg.visible_size() {
+            Some(ChunkPosition {
+                segment_id: seg.id,
+                block_number,
+                chunk_offset,
+            })
+        } else {
+            // Continue with the first record of the next non-empty segment.
+            self.older_segments
+                .values()
+                .map(|seg| seg.as_ref())
+                .chain(std::iter::once(active_seg))
+                .filter(|next| next.id > seg.id && next.visible_size() > 0)
+                .map(|next| next.id)
+                .min()
+                .map(|segment_id| ChunkPosition {
+                    segment_id,
+                    block_number: 0,
+                    chunk_offset: 0,
+                })
+        };
+        let version = self.open_envelope(pos, buf)?;
+        Ok((next, version, compressed))
+    }
+
+    /// Turn a record as stored into the record written: take off its version
+    /// byte, if records have one, and decode it. Returns the version.
+    fn open_envelope(&self, pos: ChunkPosition, data: &mut Vec<u8>) -> Result<u8, WalError> {
+        let mut version = 0;
+        if self.options.record_versions {
+            if data.is_empty() {
+                return Err(WalError::IncompleteRecord {
+                    segment_id: pos.segment_id,
+                    block_number: pos.block_number,
+                    offset: pos.chunk_offset,
+                });
+            }
+            version = data.remove(0);
+        }
+        if let Some(transform) = &self.options.transform {
+            *data = transform.decode(std::mem::take(data))?;
         }
+        Ok(version)
+    }
 
-        if seg.is_none() {
-            Err(WalError::SegmentFileNotFound)
+    /// Read a whole block with one I/O, e.g. to process the log a block at a
+    /// time. Records spanning blocks show up as their `First`, `Middle` and
+    /// `Last` chunks in consecutive blocks.
+    pub fn read_block(&self, id: BlockId) -> Result<Block, WalError> {
+        let active_seg = self.active()?;
+        let seg = if id.segment_id == active_seg.id {
+            &*active_seg
         } else {
-            seg.unwrap().read(pos.block_number, pos.chunk_offset)
+            match self.older_segments.get(&id.segment_id) {
+                Some(seg) => seg.as_ref(),
+                None => return Err(WalError::SegmentFileNotFound),
+            }
+        };
+        let chunks = seg.read_block(id.block_number)?;
+        self.record_read(seg);
+        drop(active_seg);
+        self.persist_read_stats_if_due();
+        Ok(Block { id, chunks })
+    }
+
+    /// Read all complete records starting in a block, with one I/O for the
+    /// block, e.g. for a scanner which processes the log a block at a time.
+    /// Only a record continuing into later blocks needs more reads; one still
+    /// being written is left out, as are the ends of records starting in
+    /// earlier blocks and the records before the start of the log.
+    pub fn read_block_records(
+        &self,
+        segment_id: u32,
+        block_number: u32,
+    ) -> Result<Vec<(ChunkPosition, Vec<u8>)>, WalError> {
+        let block = self.read_block(BlockId {
+            segment_id,
+            block_number,
+        })?;
+        let mut records = Vec::new();
+        for chunk in block.chunks {
+            let pos = ChunkPosition {
+                segment_id,
+                block_number,
+                chunk_offset: chunk.offset as u64,
+            };
+            if self.before_start(pos) {
+                continue;
+            }
+            match chunk.chunk_type {
+                ChunkType::Full => {
+                    let mut data = if chunk.compressed {
+                        crate::compression::decompress(&chunk.data)?
+                    } else {
+                        chunk.data
+                    };
+                    self.open_envelope(pos, &mut data)?;
+                    records.push((pos, data));
+                }
+                ChunkType::First => match self.read(pos) {
+                    Ok(data) => records.push((pos, data)),
+                    Err(WalError::IncompleteRecord { .. }) => {}
+                    Err(e) => return Err(e),
+                },
+                ChunkType::Middle | ChunkType::Last => {}
+            }
         }
+        Ok(records)
     }
 
-    pub fn is_full(&self, delta: u64) -> bool {
-        let seg = self.active_segment.read().unwrap();
-        seg.as_ref().unwrap().size() + delta + CHUNK_HEADER_SIZE as u64 > self.options.segment_size
+    /// Read the records from `range.start()` through `range.end()`, up to
+    /// `limits`. Continue with `page.next..=range.end()` until `next` is `None`.
+    pub fn read_range(
+        &self,
+        range: std::ops::RangeInclusive<ChunkPosition>,
+        limits: ReadLimits,
+    ) -> Result<RangePage, WalError> {
+        let mut page = RangePage {
+            records: Vec::new(),
+            next: None,
+        };
+        let (mut bytes, mut segments) = (0, 0);
+        let mut pos = Some(*range.start());
+        while let Some(current) = pos.filter(|pos| pos <= range.end()) {
+            let new_segment = page
+                .records
+                .last()
+                .is_none_or(|(last, _)| last.segment_id != current.segment_id);
+            let full = !page.records.is_empty()
+                && (limits
+                    .max_records
+                    .is_some_and(|max| page.records.len() >= max)
+                    || new_segment && limits.max_segments.is_some_and(|max| segments >= max));
+            if full {
+                page.next = Some(current);
+                break;
+            }
+            let (data, next) = self.read_next(current)?;
+            if !page.records.is_empty()
+                && limits.max_bytes.is_some_and(|max| bytes + data.len() > max)
+            {
+                page.next = Some(current);
+                break;
+            }
+            bytes += data.len();
+            segments += new_segment as usize;
+            page.records.push((current, data));
+            pos = next;
+        }
+        Ok(page)
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    /// The trailer of a finished block, `None` unless the Wal was created
+    /// with block trailers, or for the block still being written.
+    pub fn block_trailer(&self, id: BlockId) -> Result<Option<BlockTrailer>, WalError> {
+        let active_seg = self.active()?;
+        let active_seg = &*active_seg;
+        if id.segment_id == active_seg.id {
+            return active_seg.block_trailer(id.block_number);
+        }
+        match self.older_segments.get(&id.segment_id) {
+            Some(seg) => seg.block_trailer(id.block_number),
+            None => Err(WalError::SegmentFileNotFound),
+        }
+    }
 
-    #[test]
-    fn work() {
-        let name = "000900101.wal".to_string();
-        let opts = Options {
-            dir_path: "/tmp/wal".into(),
-            segment_size: 1024 * 1024 * 1024,
+    /// Verify the checksums of every chunk and block trailer in all segments,
+    /// in order, and fail with the first corruption found. Reads several
+    /// megabytes per I/O; with the `rayon` feature, blocks are verified on all
+    /// cores, so this is cheap enough to run routinely.
+    pub fn verify(&self) -> Result<VerifyReport, WalError> {
+        let active_seg = self.active()?;
+        let mut segments: Vec<&Segment> = self
+            .older_segments
+            .values()
+            .map(|seg| seg.as_ref())
+            .chain([&*active_seg])
+            .collect();
+        segments.sort_by_key(|seg| seg.id);
+        let mut report = VerifyReport::default();
+        for seg in segments {
+            report += seg.verify()?;
+        }
+        Ok(report)
+    }
+
+    /// Blocks dropped from the block cache since the Wal was opened because
+    /// the cached copy failed its checksums, or the file's did, see
+    /// [`Options::with_block_cache_bytes`].
+    pub fn block_cache_repairs(&self) -> u64 {
+        self.active_unchecked().block_cache().map_or(0, |cache| {
+            cache
+                .lock()
+                .unwrap_or_else(std::sync::PoisonError::into_inner)
+                .repairs()
+        })
+    }
+
+    /// Append every record from `range.start()` through `range.end()` to `dest`,
+    /// in order, and return the positions they were written at in `dest`.
+    ///
+    /// Records are decoded with this Wal's options and re-chunked with `dest`'s,
+    /// so the two may use different segment sizes or transforms.
+    pub fn copy_range(
+        &self,
+        range: std::ops::RangeInclusive<ChunkPosition>,
+        dest: &mut Wal,
+    ) -> Result<Vec<ChunkPosition>, WalError> {
+        let mut positions = Vec::new();
+        let mut data = Vec::new();
+        let mut pos = Some(*range.start());
+        while let Some(current) = pos.filter(|pos| pos <= range.end()) {
+            let (next, version, _) = self.read_next_into(current, &mut data)?;
+            self.persist_read_stats_if_due();
+            positions.push(dest.write_with(&data, &WriteOptions::default().with_version(version))?);
+            pos = next;
+        }
+        Ok(positions)
+    }
+
+    /// Feed every record to `apply` in log order until it asks to stop.
+    ///
+    /// Segments are scanned sequentially with readahead, and every chunk's
+    /// checksum is verified on the way, `WalError::InvalidCrc` is returned for
+    /// the first corrupted one.
+    pub fn replay<F>(&self, apply: F) -> Result<(), WalError>
+    where
+        F: FnMut(ChunkPosition, &[u8]) -> Result<ReplayControl, WalError>,
+    {
+        self.replay_with_progress(0, |_| {}, apply)
+    }
+
+    /// [`Wal::replay`], calling `progress` after every `every` records (never
+    /// if 0) and once more when the replay ends, e.g. to show the progress of
+    /// a long recovery.
+    pub fn replay_with_progress<F, P>(
+        &self,
+        every: u64,
+        mut progress: P,
+        mut apply: F,
+    ) -> Result<(), WalError>
+    where
+        F: FnMut(ChunkPosition, &[u8]) -> Result<ReplayControl, WalError>,
+        P: FnMut(&ReplayProgress),
+    {
+        let active_seg = self.active()?;
+        let mut segments: Vec<&Segment> = self
+            .older_segments
+            .values()
+            .map(|seg| seg.as_ref())
+            .chain([&*active_seg])
+            .collect();
+        segments.sort_by_key(|seg| seg.id);
+
+        let clock = &self.options.clock;
+        let start = clock.now();
+        let mut state = ReplayProgress {
+            bytes_processed: 0,
+            total_bytes: segments.iter().map(|seg| seg.visible_size()).sum(),
+            records: 0,
+            elapsed: std::time::Duration::ZERO,
         };
-        let mut wal = Wal::open(opts).unwrap();
-        let pos = wal.write("amazing lyf is better".as_bytes());
+        'segments: for seg in segments {
+            let segment_start = state.bytes_processed;
+            let mut reader = SegmentReader::new(seg);
+            let mut prefetched = false;
+            while let Some((mut data, pos)) = reader.next_record()? {
+                if !prefetched {
+                    prefetched = self.prefetch_if_near_end(seg, reader.offset());
+                }
+                if self.before_start(pos) {
+                    state.bytes_processed = segment_start + reader.offset();
+                    continue;
+                }
+                self.open_envelope(pos, &mut data)?;
+                let control = apply(pos, &data)?;
+                state.records += 1;
+                state.bytes_processed = segment_start + reader.offset();
+                if every > 0 && state.records.is_multiple_of(every) {
+                    state.elapsed = clock.now() - start;
+                    progress(&state);
+                }
+                match control {
+                    ReplayControl::Continue => {}
+                    ReplayControl::SkipSegment => break,
+                    ReplayControl::Stop => break 'segments,
+                }
+            }
+            state.bytes_processed = segment_start + seg.visible_size();
+        }
+        state.elapsed = clock.now() - start;
+        progress(&state);
+        Ok(())
+    }
+
+    /// Sync a single segment, active or sealed, e.g. after a replication
+    /// stream has been applied into it.
+    pub fn sync_segment(&self, segment_id: u32) -> Result<(), WalError> {
+        let active_seg = self.active()?;
+        let active_seg = &*active_seg;
+        if active_seg.id == segment_id {
+            return active_seg.sync();
+        }
+        match self.older_segments.get(&segment_id) {
+            Some(seg) => seg.sync(),
+            None => Err(WalError::SegmentFileNotFound),
+        }
+    }
+
+    /// Write the records held back in the write buffer to the file, without
+    /// syncing them, see [`Options::with_write_buffer_size`].
+    pub fn flush(&self) -> Result<(), WalError> {
+        self.active()?.flush()
+    }
+
+    /// Sync every segment with data that hasn't been synced yet.
+    pub fn sync(&self) -> Result<(), WalError> {
+        let active_seg = self.active()?;
+        for seg in self.older_segments.values() {
+            if seg.has_unsynced_data() {
+                seg.sync()?;
+            }
+        }
+        active_seg.sync()
+    }
+
+    /// Ids of the segments with data that hasn't been synced yet, in order.
+    pub fn unsynced_segments(&self) -> Vec<u32> {
+        let active_seg = self.active_unchecked();
+        let mut ids: Vec<u32> = self
+            .older_segments
+            .values()
+            .map(|seg| seg.as_ref())
+            .chain([&*active_seg])
+            .filter(|seg| seg.has_unsynced_data())
+            .map(|seg| seg.id)
+            .collect();
+        ids.sort();
+        ids
+    }
+
+    /// Stop the maintenance thread, if there is one, wait for prefetches
+    /// still running, sync every segment and persist the read statistics, if
+    /// they are kept, then close the segment files. Unlike dropping the Wal,
+    /// this fails if any of it does, or if the maintenance thread stopped on
+    /// an error. Nothing is written if the Wal is read-only.
+    pub fn close(self) -> Result<(), WalError> {
+        let maintained = self.stop_maintenance();
+        self.join_prefetches();
+        if self.options.read_only {
+            return maintained;
+        }
+        self.sync()?;
+        if self.options.read_stats_interval.is_some() {
+            self.persist_read_stats()?;
+        }
+        maintained
+    }
+
+    /// Run the Wal's upkeep on a background thread, `wal-maintenance`, every
+    /// `every` until the Wal is closed or dropped, or [`Maintenance::stop`]
+    /// is called: remove the sealed segments older than the retention
+    /// policy's age, sync once the interval of `SyncPolicy::EveryInterval`
+    /// has passed without a write syncing, and persist the read statistics
+    /// when their interval is due. Without it, all of these only happen as a
+    /// side effect of writes, reads or reopening.
+    ///
+    /// A round takes the write lock and stops the thread if it fails; the
+    /// error is returned by [`Maintenance::stop`] or [`Wal::close`]. Between
+    /// rounds the thread doesn't hold on to the Wal, but during one it does,
+    /// so to take the Wal out of its `Arc`, e.g. to close it, stop the thread
+    /// first. Only one thread runs at a time, starting another fails with
+    /// `WalError::InvalidOptions`.
+    pub fn start_maintenance(
+        wal: &Arc<RwLock<Wal>>,
+        every: std::time::Duration,
+    ) -> Result<Maintenance, WalError> {
+        let mut guard = wal.write().map_err(|_| WalError::Poisoned)?;
+        if guard.options.read_only {
+            return Err(WalError::ReadOnly);
+        }
+        if guard.maintenance.as_ref().is_some_and(|m| m.running()) {
+            return Err(WalError::InvalidOptions(
+                "maintenance is running already".to_string(),
+            ));
+        }
+        let started = maintenance::spawn(Arc::downgrade(wal), every, &guard.options.dir_path)?;
+        guard.maintenance = Some(started.shared.clone());
+        Ok(started)
+    }
+
+    /// One round of [`Wal::start_maintenance`].
+    pub(crate) fn maintain(&mut self) -> Result<(), WalError> {
+        // Segments aren't removed from under a freeze, like by purge_before.
+        if self.options.retention.max_age.is_some() && self.frozen.load(Ordering::Acquire) == 0 {
+            let active_id = self.active_unchecked().id;
+            apply_retention(&self.options, &mut self.older_segments, active_id)?;
+            self.forget_removed_segments();
+            self.index_contents([]);
+            self.index_entries([])?;
+        }
+        if matches!(self.options.sync_policy, SyncPolicy::EveryInterval(_)) {
+            self.sync_if_due(0)?;
+        }
+        self.persist_read_stats_if_due();
+        Ok(())
+    }
+
+    /// Stop the maintenance thread, if there is one, and wait for it.
+    fn stop_maintenance(&self) -> Result<(), WalError> {
+        match &self.maintenance {
+            Some(maintenance) => maintenance.stop(),
+            None => Ok(()),
+        }
+    }
+
+    /// Remove every segment and every other file the Wal keeps, then its
+    /// directory and the sealed dir, if there is one. Removing a directory
+    /// fails if files the Wal doesn't know about are left in it.
+    pub fn delete(self) -> Result<(), WalError> {
+        if self.options.read_only {
+            return Err(WalError::ReadOnly);
+        }
+        if self.frozen.load(Ordering::Acquire) > 0 {
+            return Err(WalError::Frozen);
+        }
+        self.check_directory("delete")?;
+        self.join_prefetches();
+        let mut paths = vec![self.active_unchecked().path().to_path_buf()];
+        paths.extend(
+            self.older_segments
+                .values()
+                .map(|seg| seg.path().to_path_buf()),
+        );
+        let options = self.options.clone();
+        // Close the segment files before removing them.
+        drop(self);
+        for path in &paths {
+            std::fs::remove_file(path).context("remove", path)?;
+        }
+        let dirs = std::iter::once(&options.dir_path).chain(&options.sealed_dir);
+        for dir_path in dirs {
+            for name in [
+                manifest::MANIFEST_FILE_NAME,
+                manifest::START_FILE_NAME,
+                manifest::IDENTITY_FILE_NAME,
+                manifest::LOCK_FILE_NAME,
+                entry_index::ENTRY_INDEX_FILE_NAME,
+                stats::READ_STATS_FILE_NAME,
+                #[cfg(feature = "raft")]
+                crate::raft_storage::RAFT_STATE_FILE_NAME,
+            ] {
+                let path = dir_path.join(name);
+                for path in [path.with_extension("tmp"), path] {
+                    match std::fs::remove_file(&path) {
+                        Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                            return Err(e).context("remove", &path);
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            let quarantine = dir_path.join(QUARANTINE_DIR);
+            if quarantine.exists() {
+                std::fs::remove_dir_all(&quarantine).context("remove", &quarantine)?;
+            }
+            std::fs::remove_dir(dir_path).context("remove", dir_path)?;
+        }
+        Ok(())
+    }
+
+    /// Remove the sealed segments all of whose records come before `pos`,
+    /// e.g. once they are applied and checkpointed, and return their ids.
+    /// The active segment is never removed.
+    ///
+    /// Readers which already hold a removed segment, e.g. an iterator over
+    /// it, keep reading it until they are done.
+    pub fn purge_before(&mut self, pos: ChunkPosition) -> Result<Vec<u32>, WalError> {
+        if self.options.read_only {
+            return Err(WalError::ReadOnly);
+        }
+        if self.frozen.load(Ordering::Acquire) > 0 {
+            return Err(WalError::Frozen);
+        }
+        let mut ids: Vec<u32> = self
+            .older_segments
+            .keys()
+            .copied()
+            .filter(|&id| id < pos.segment_id)
+            .collect();
+        ids.sort();
+        let active_id = self.active_unchecked().id;
+        purge_segments(&self.options, &mut self.older_segments, &ids, active_id)?;
+        self.forget_removed_segments();
+        self.index_contents([]);
+        self.index_entries([])?;
+        Ok(ids)
+    }
+
+    /// Make `pos` the start of the log, e.g. once the records before it are
+    /// checkpointed: [`Wal::first_position`], readers and replays start at
+    /// it, and the segments before `pos`'s are removed like by
+    /// [`Wal::purge_before`]. `pos` is recorded in the directory first, so it
+    /// holds across reopens.
+    ///
+    /// `pos` has to be where a record starts, or [`Wal::visible_position`] to
+    /// discard every record. Reads of the records before it fail with
+    /// `WalError::BeforeStart`, those left in `pos`'s segment too, and
+    /// [`Wal::read_block_records`] and [`Wal::lag`] leave them out.
+    pub fn truncate_before(&mut self, pos: ChunkPosition) -> Result<(), WalError> {
+        if self.options.read_only {
+            return Err(WalError::ReadOnly);
+        }
+        if self.frozen.load(Ordering::Acquire) > 0 {
+            return Err(WalError::Frozen);
+        }
+        self.check_directory("truncate_before")?;
+        if self.start.is_some_and(|start| start >= pos) {
+            return Ok(());
+        }
+        if pos != self.visible_position()? {
+            self.read_next(pos)?;
+        }
+        manifest::store_start(&self.options.dir_path, pos)?;
+        self.start = Some(pos);
+        fail_point!("wal::before_truncate_purge");
+        self.purge_before(pos)?;
+        Ok(())
+    }
+
+    /// Discard every record after `pos`, e.g. conflicting entries of a
+    /// consensus log, and continue writing right after it. The segments
+    /// after `pos`'s are removed, newest first, and `pos`'s is cut off after
+    /// its record, so a crash part way leaves some of them, never a gap.
+    ///
+    /// If `pos` is in a sealed segment, writing continues in a new segment
+    /// after it. Fails with `WalError::TruncateInBatch` if the record at
+    /// `pos` isn't the last of its batch.
+    pub fn truncate_after(&mut self, pos: ChunkPosition) -> Result<(), WalError> {
+        if self.options.read_only {
+            return Err(WalError::ReadOnly);
+        }
+        if self.frozen.load(Ordering::Acquire) > 0 {
+            return Err(WalError::Frozen);
+        }
+        let mut guard = lock_active_mut(&self.active_segment, self.options.poison_policy)?;
+        let active_seg = &mut *guard;
+        let in_active = pos.segment_id == active_seg.id;
+        let seg = match in_active {
+            true => &*active_seg,
+            false => self
+                .older_segments
+                .get(&pos.segment_id)
+                .ok_or(WalError::SegmentFileNotFound)?,
+        };
+        let (block_number, chunk_offset) =
+            seg.read_with_next_into(pos.block_number, pos.chunk_offset, &mut Vec::new())?;
+        let end = seg.offset_of(block_number, chunk_offset).min(seg.size());
+        if seg.unfinished_batch_at(end)?.is_some() {
+            return Err(WalError::TruncateInBatch {
+                segment_id: pos.segment_id,
+                block_number: pos.block_number,
+                offset: pos.chunk_offset,
+            });
+        }
+        let dir_path = seg.path().parent().map(|dir| dir.to_path_buf());
+
+        if in_active {
+            fail_point!("wal::before_truncate_cut");
+            active_seg.truncate(end)?;
+            active_seg.sync()?;
+        } else {
+            fail_point!("wal::before_truncate_remove");
+            active_seg.remove()?;
+            let mut newer: Vec<u32> = self
+                .older_segments
+                .keys()
+                .copied()
+                .filter(|&id| id > pos.segment_id)
+                .collect();
+            newer.sort();
+            for id in newer.iter().rev() {
+                fail_point!("wal::before_truncate_remove");
+                self.older_segments.remove(id).unwrap().remove()?;
+            }
+            // Cut off in a copy, a fork's hard link and readers holding the
+            // segment keep the whole file.
+            drop(self.older_segments.remove(&pos.segment_id));
+            let dir_path = dir_path.unwrap_or_default();
+            fail_point!("wal::before_truncate_cut");
+            segment::replace_with_prefix(&dir_path, pos.segment_id, end)?;
+            let warnings = &mut self.report.warnings;
+            let mut seg = open_segment(&self.options, &dir_path, pos.segment_id, warnings)?;
+            seg.set_block_cache(active_seg.block_cache());
+            seg.truncate(end)?;
+            seg.sync()?;
+            seg.mark_sealed();
+            prepare_sealed_reads(&self.options, &seg)?;
+            self.older_segments.insert(pos.segment_id, Arc::new(seg));
+
+            let mut ids: Vec<u32> = self.older_segments.keys().copied().collect();
+            ids.sort();
+            Manifest::for_options(&self.options)
+                .store_with_segments(&self.options.dir_path, &ids)?;
+            let mut seg = open_next_segment(&self.options, pos.segment_id + 1, warnings)?;
+            seg.set_block_cache(active_seg.block_cache());
+            *active_seg = seg;
+        }
+        if let Some(cache) = active_seg.block_cache() {
+            cache
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .forget_from(BlockId {
+                    segment_id: pos.segment_id,
+                    block_number: pos.block_number,
+                });
+        }
+        drop(guard);
+        if let Some(decoded) = &self.decoded {
+            decoded
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .forget_after(pos);
+        }
+        if let Some(index) = &mut self.content_index {
+            index.positions.retain(|_, positions| {
+                positions.retain(|p| *p <= pos);
+                !positions.is_empty()
+            });
+        }
+        if let Some(index) = &mut self.entry_index {
+            index.forget_after(pos);
+            entry_index::store(&self.options.dir_path, index)?;
+        }
+        if self.start.is_some_and(|start| start > pos) {
+            // Nothing is left after the start, the next write starts the log.
+            let start = self.visible_position()?;
+            manifest::store_start(&self.options.dir_path, start)?;
+            self.start = Some(start);
+        }
+        Ok(())
+    }
+
+    /// Sync every segment and reject writes with `WalError::Frozen` until the
+    /// returned guard drops, so the files on disk can be snapshotted consistently.
+    pub fn freeze(&self) -> Result<FreezeGuard, WalError> {
+        self.frozen.fetch_add(1, Ordering::AcqRel);
+        let guard = FreezeGuard {
+            frozen: self.frozen.clone(),
+        };
+        self.active()?.sync()?;
+        for seg in self.older_segments.values() {
+            seg.sync()?;
+        }
+        Ok(guard)
+    }
+
+    /// Clone the Wal into `dir_path` and open the clone.
+    ///
+    /// Sealed segments never change, so they are hard linked (copied when the
+    /// link fails, e.g. across file systems); only the active segment is copied.
+    pub fn fork_to(&self, dir_path: impl Into<std::path::PathBuf>) -> Result<Wal, WalError> {
+        self.check_directory("fork_to")?;
+        let mut options = self.options.clone();
+        options.dir_path = dir_path.into();
+        options.sealed_dir = None;
+        std::fs::create_dir_all(&options.dir_path).context("create", &options.dir_path)?;
+        if !list_segment_ids(&options.dir_path)?.is_empty() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::AlreadyExists,
+                "already contains segment files",
+            ))
+            .context("fork", &options.dir_path);
+        }
+        for seg in self.older_segments.values() {
+            let target = segment::segment_file_path(&options.dir_path, seg.id);
+            if std::fs::hard_link(seg.path(), &target).is_err() {
+                std::fs::copy(seg.path(), &target).context("copy", seg.path())?;
+            }
+        }
+        let active_seg = self.active()?;
+        let active_seg = &*active_seg;
+        // Appends still held back in the write buffer aren't in the file yet.
+        active_seg.flush()?;
+        std::fs::copy(
+            active_seg.path(),
+            segment::segment_file_path(&options.dir_path, active_seg.id),
+        )
+        .context("copy", active_seg.path())?;
+        if let Some(index) = &self.entry_index {
+            entry_index::store(&options.dir_path, index)?;
+        }
+        if let Some(start) = self.start {
+            manifest::store_start(&options.dir_path, start)?;
+        }
+        Wal::open(options)
+    }
+
+    /// Iterate over every record across all segments, in log order, e.g. to
+    /// replay the log on startup without holding a position yet. Block
+    /// padding and segment boundaries are stepped over.
+    pub fn reader(&self) -> WalReader<'_> {
+        WalReader {
+            wal: self,
+            next: None,
+            started: false,
+            prefetched: None,
+        }
+    }
+
+    /// The CRC32 of the record at `pos`, over its data as read back. It is
+    /// the same however the record was chunked, compressed or encrypted, so
+    /// replicas can compare records by it.
+    pub fn checksum_of(&self, pos: ChunkPosition) -> Result<u32, WalError> {
+        Ok(crc32fast::hash(&self.read(pos)?))
+    }
+
+    /// The position of the earliest record whose data is `data`, e.g. to
+    /// skip records a primary ships again during catch-up. Candidates from
+    /// the content index are read back and compared, so checksums which
+    /// collide never match.
+    ///
+    /// Fails with `WalError::ContentIndexDisabled` unless opened with
+    /// [`Options::with_content_index`].
+    pub fn find_by_content(&self, data: &[u8]) -> Result<Option<ChunkPosition>, WalError> {
+        let Some(index) = &self.content_index else {
+            return Err(WalError::ContentIndexDisabled);
+        };
+        for &pos in index
+            .positions
+            .get(&crc32fast::hash(data))
+            .into_iter()
+            .flatten()
+        {
+            if self.read(pos)? == data {
+                return Ok(Some(pos));
+            }
+        }
+        Ok(None)
+    }
+
+    /// The data of the record with `index`, see [`Options::with_entry_index`].
+    /// Fails with `WalError::NoSuchEntry` if no record has it.
+    pub fn read_index(&self, index: u64) -> Result<Vec<u8>, WalError> {
+        self.read(self.position_of_index(index)?)
+    }
+
+    /// The position of the record with `index`, e.g. to truncate the log by
+    /// index. Fails with `WalError::NoSuchEntry` if no record has it, or it
+    /// comes before the start of the log.
+    pub fn position_of_index(&self, index: u64) -> Result<ChunkPosition, WalError> {
+        self.entry_index()?
+            .position(index)
+            .filter(|&pos| !self.before_start(pos))
+            .ok_or(WalError::NoSuchEntry { index })
+    }
+
+    /// The index of the record at `pos`, e.g. one just written, `None` if no
+    /// record starts there.
+    pub fn index_of(&self, pos: ChunkPosition) -> Result<Option<u64>, WalError> {
+        Ok(self.entry_index()?.index_of(pos))
+    }
+
+    /// The index of the record at [`Wal::first_position`], `None` if the Wal
+    /// has no record.
+    pub fn first_index(&self) -> Result<Option<u64>, WalError> {
+        let index = self.entry_index()?;
+        Ok(self.first_position()?.and_then(|pos| index.index_of(pos)))
+    }
+
+    /// The index of the last record written, `None` if the Wal has no record.
+    pub fn last_index(&self) -> Result<Option<u64>, WalError> {
+        let index = self.entry_index()?;
+        match self.first_position()? {
+            Some(_) => Ok(index.last()),
+            None => Ok(None),
+        }
+    }
+
+    /// The entry index, failing with `WalError::EntryIndexDisabled` unless
+    /// opened with [`Options::with_entry_index`].
+    fn entry_index(&self) -> Result<&EntryIndex, WalError> {
+        self.entry_index
+            .as_ref()
+            .ok_or(WalError::EntryIndexDisabled)
+    }
+
+    /// The position of the earliest record still in the Wal, `None` if it has
+    /// none, e.g. to tell clients how far back history is available.
+    pub fn first_position(&self) -> Result<Option<ChunkPosition>, WalError> {
+        let first = self.first_position_from(None)?;
+        match self.start {
+            Some(start) if first.is_some_and(|first| first < start) => {
+                self.first_position_from(Some(start))
+            }
+            _ => Ok(first),
+        }
+    }
+
+    /// The position of the earliest record in the segments at or after
+    /// `from`, before the logical start too if `None`. `from` needn't be
+    /// where a record starts, e.g. a start set at the end of the records
+    /// which the next one was padded or rotated away from.
+    fn first_position_from(
+        &self,
+        from: Option<ChunkPosition>,
+    ) -> Result<Option<ChunkPosition>, WalError> {
+        let active_seg = self.active()?;
+        let mut segments: Vec<&Segment> = self
+            .older_segments
+            .values()
+            .map(|seg| seg.as_ref())
+            .chain([&*active_seg])
+            .filter(|seg| from.is_none_or(|from| seg.id >= from.segment_id))
+            .collect();
+        segments.sort_by_key(|seg| seg.id);
+        for seg in segments {
+            let mut reader = SegmentReader::new(seg);
+            if let Some(from) = from.filter(|from| from.segment_id == seg.id) {
+                reader.seek(seg.offset_of(from.block_number, from.chunk_offset));
+            }
+            if let Some((_, pos)) = reader.next_record()? {
+                return Ok(Some(pos));
+            }
+        }
+        Ok(None)
+    }
+
+    /// The end of the records reads see: every record written before it can
+    /// be read, none after it. A record becomes visible once fully appended,
+    /// the records of a batch together with its last one, so what a write
+    /// returned is readable right after it returns and a reader never sees
+    /// part of a record or batch.
+    ///
+    /// Positions compare in log order, so `pos < wal.visible_position()?`
+    /// tells whether the record at `pos` is visible.
+    pub fn visible_position(&self) -> Result<ChunkPosition, WalError> {
+        let active_seg = self.active()?;
+        Ok(end_position(&active_seg, active_seg.visible_size()))
+    }
+
+    /// The end of the records that survive a crash, at most
+    /// [`Wal::visible_position`]: the records before it are synced, the ones
+    /// after it may be lost.
+    pub fn durable_position(&self) -> Result<ChunkPosition, WalError> {
+        let active_seg = self.active()?;
+        let mut segments: Vec<&Segment> = self
+            .older_segments
+            .values()
+            .map(|seg| seg.as_ref())
+            .collect();
+        segments.sort_by_key(|seg| seg.id);
+        let seg = segments
+            .into_iter()
+            .find(|seg| seg.synced_size() < seg.visible_size())
+            .unwrap_or(&active_seg);
+        Ok(end_position(seg, seg.synced_size().min(seg.visible_size())))
+    }
+
+    /// How far a consumer which has applied the records through `applied`,
+    /// none if `None`, is behind the end of the Wal, e.g. to alert when a
+    /// downstream applier falls behind. Consumers track their own positions.
+    /// Records before the start of the log don't count.
+    ///
+    /// The records behind are scanned to count them, so this costs as much
+    /// I/O as the lag, and is meant to be polled rather than called per
+    /// record.
+    pub fn lag(&self, applied: Option<ChunkPosition>) -> Result<Lag, WalError> {
+        let active_seg = self.active()?;
+        let mut segments: Vec<&Segment> = self
+            .older_segments
+            .values()
+            .map(|seg| seg.as_ref())
+            .chain([&*active_seg])
+            .filter(|seg| applied.is_none_or(|pos| seg.id >= pos.segment_id))
+            .collect();
+        segments.sort_by_key(|seg| seg.id);
+        if let Some(pos) = applied {
+            if segments.first().is_none_or(|seg| seg.id != pos.segment_id) {
+                return Err(WalError::SegmentFileNotFound);
+            }
+        }
+
+        let mut lag = Lag::default();
+        for seg in segments {
+            let mut reader = SegmentReader::new(seg);
+            if let Some(pos) = applied.filter(|pos| pos.segment_id == seg.id) {
+                reader.seek(seg.offset_of(pos.block_number, pos.chunk_offset));
+                // Skip the record applied last.
+                if reader.next_record()?.is_none() {
+                    return Err(WalError::IncompleteRecord {
+                        segment_id: pos.segment_id,
+                        block_number: pos.block_number,
+                        offset: pos.chunk_offset,
+                    });
+                }
+            }
+            let mut start = reader.offset();
+            let mut records = 0;
+            while let Some((_, pos)) = reader.next_record()? {
+                match self.before_start(pos) {
+                    true => start = reader.offset(),
+                    false => records += 1,
+                }
+            }
+            lag.bytes += seg.visible_size() - start;
+            lag.records += records;
+            lag.segments += (records > 0) as u32;
+        }
+        Ok(lag)
+    }
+
+    /// Stream the segments with ids in `segments`, and a manifest indexing
+    /// them, into `writer` as a single archive, e.g. to attach a slice of the
+    /// Wal to a bug report or move it to another machine. See
+    /// [`Wal::import_archive`].
+    pub fn export_archive(
+        &self,
+        segments: std::ops::RangeInclusive<u32>,
+        writer: impl std::io::Write,
+    ) -> Result<(), WalError> {
+        self.check_directory("export_archive")?;
+        let active_seg = self.active()?;
+        let mut exported: Vec<&Segment> = self
+            .older_segments
+            .values()
+            .map(|seg| seg.as_ref())
+            .chain([&*active_seg])
+            .filter(|seg| segments.contains(&seg.id))
+            .collect();
+        exported.sort_by_key(|seg| seg.id);
+        let ids: Vec<u32> = exported.iter().map(|seg| seg.id).collect();
+
+        let mut archive = ArchiveWriter::new(writer)?;
+        archive.manifest(&Manifest::for_options(&self.options).contents(&ids))?;
+        if let Some(start) = self.start {
+            archive.start(&manifest::start_contents(start))?;
+        }
+        if let Some(index) = &self.entry_index {
+            archive.entry_index(&entry_index::contents(index))?;
+        }
+        for seg in exported {
+            // Only what has been written, not space preallocated past it,
+            // including what the write buffer still holds back.
+            seg.flush()?;
+            archive.segment(seg.id, seg.path(), seg.size())?;
+        }
+        archive.finish()
+    }
+
+    /// Unpack an archive written by [`Wal::export_archive`] into `dir_path`,
+    /// to be opened with [`Wal::open`] from there. The directory must not
+    /// hold a Wal already.
+    pub fn import_archive(
+        reader: impl std::io::Read,
+        dir_path: impl AsRef<std::path::Path>,
+    ) -> Result<(), WalError> {
+        archive::unpack(reader, dir_path.as_ref())
+    }
+
+    /// How often each segment has been read from disk, by point reads, ranges,
+    /// copies and blocks, ordered by segment id. Replays aren't counted, and
+    /// neither are reads served by the decoded record cache.
+    pub fn read_stats(&self) -> Vec<SegmentReadStats> {
+        let active_seg = self.active_unchecked();
+        let mut stats: Vec<SegmentReadStats> = self
+            .older_segments
+            .values()
+            .map(|seg| seg.as_ref())
+            .chain([&*active_seg])
+            .map(|seg| seg.reads.stats(seg.id))
+            .collect();
+        stats.sort_by_key(|stats| stats.segment_id);
+        stats
+    }
+
+    /// Every segment, ordered by id. All but the active one, the last, are
+    /// sealed.
+    pub fn segments(&self) -> Vec<SegmentInfo> {
+        let active_seg = self.active_unchecked();
+        let mut segments: Vec<SegmentInfo> = self
+            .older_segments
+            .values()
+            .map(|seg| seg.as_ref())
+            .chain([&*active_seg])
+            .map(Segment::info)
+            .collect();
+        segments.sort_by_key(|info| info.segment_id);
+        segments
+    }
+
+    /// Persist [`Wal::read_stats`], so they are continued when reopened.
+    pub fn persist_read_stats(&self) -> Result<(), WalError> {
+        if self.options.read_only {
+            return Err(WalError::ReadOnly);
+        }
+        self.check_directory("persist_read_stats")?;
+        stats::store(&self.options.dir_path, self.read_stats())
+    }
+
+    fn record_read(&self, seg: &Segment) {
+        seg.reads.record(self.options.clock.system_time());
+    }
+
+    /// Persist the read statistics if the interval for it has passed. Takes
+    /// the active segment's lock, so not to be called while holding it.
+    fn persist_read_stats_if_due(&self) {
+        let Some(interval) = self
+            .options
+            .read_stats_interval
+            .filter(|_| !self.options.read_only)
+        else {
+            return;
+        };
+        let now = self.options.clock.now();
+        // Another reader is persisting them already.
+        let Ok(mut persisted) = self.read_stats_persisted.try_lock() else {
+            return;
+        };
+        if now.duration_since(*persisted) >= interval {
+            *persisted = now;
+            // The stats are advisory, a read doesn't fail because they couldn't
+            // be persisted; the next interval tries again.
+            let _ = stats::store(&self.options.dir_path, self.read_stats());
+        }
+    }
+
+    /// The options the Wal runs with, including what they were defaulted to
+    /// and took from the directory, e.g. to log them with `{:?}`.
+    pub fn options(&self) -> &Options {
+        &self.options
+    }
+
+    /// Compress the records of the segments created from now on with
+    /// `compression`, e.g. to roll a codec out gradually; the active segment
+    /// keeps the one it has. Every record names its codec, so segments
+    /// written with any of them read back alike, including after reopening
+    /// with other options, and nothing already written is rewritten.
+    pub fn set_compression(&mut self, compression: Option<Compression>) {
+        self.options.compression = compression;
+    }
+
+    /// The codec the records written now are compressed with, the active
+    /// segment's; one set by [`Wal::set_compression`] since applies from the
+    /// next segment on.
+    pub fn compression(&self) -> Option<Compression> {
+        self.active_unchecked().compression()
+    }
+
+    /// The on-disk format of the Wal.
+    pub fn format_info(&self) -> &FormatInfo {
+        &self.format
+    }
+
+    /// What went wrong without failing the open, and since then; segments
+    /// created by rotation add their warnings here too.
+    pub fn open_report(&self) -> &OpenReport {
+        &self.report
+    }
+
+    pub fn is_full(&self, delta: u64) -> bool {
+        let seg = self.active_unchecked();
+        self.exceeds_segment_size(&seg, delta)
+    }
+
+    /// The largest record which still goes into the active segment, with its
+    /// chunk headers and the padding before them counted against the segment
+    /// size, e.g. to size batches so they fill segments exactly. With a
+    /// transform, this bounds the encoded record.
+    pub fn active_segment_remaining_capacity(&self) -> u64 {
+        let seg = self.active_unchecked();
+        let capacity = seg.writer().payload_capacity(self.options.segment_size);
+        capacity.saturating_sub(self.options.record_versions as u64)
+    }
+
+    /// Lock the active segment for reading, applying the poison policy.
+    fn active(&self) -> Result<RwLockReadGuard<'_, Segment>, WalError> {
+        if self.active_segment.is_poisoned() {
+            // Recovering needs the write lock.
+            drop(lock_active_mut(
+                &self.active_segment,
+                self.options.poison_policy,
+            )?);
+        }
+        self.active_segment.read().map_err(|_| WalError::Poisoned)
+    }
+
+    /// Lock the active segment for reading sizes and flags, which stay
+    /// meaningful even if a writer panicked.
+    fn active_unchecked(&self) -> RwLockReadGuard<'_, Segment> {
+        self.active_segment
+            .read()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+    }
+
+    /// Check an internal invariant. A violation is a bug in the Wal: debug
+    /// builds panic on it, unless `Options::with_debug_assertions_as_errors`,
+    /// release builds always fail with `WalError::InvariantViolated`.
+    fn check_invariant(&self, holds: bool, invariant: &'static str) -> Result<(), WalError> {
+        if holds {
+            return Ok(());
+        }
+        if cfg!(debug_assertions) && !self.options.debug_assertions_as_errors {
+            panic!("invariant violated: {}", invariant);
+        }
+        Err(WalError::InvariantViolated(invariant))
+    }
+
+    fn exceeds_segment_size(&self, seg: &Segment, delta: u64) -> bool {
+        seg.size() + delta + CHUNK_HEADER_SIZE as u64 > self.options.segment_size
+    }
+
+    /// Whether the active segment is kept past the segment size rather than
+    /// sealed, see [`Options::with_min_sealed_size`].
+    fn too_small_to_seal(&self, seg: &Segment) -> bool {
+        seg.size() < self.options.min_sealed_size
+    }
+}
+
+impl Drop for Wal {
+    /// Stop the maintenance thread and wait for the prefetch threads still
+    /// running, so none outlives the Wal.
+    fn drop(&mut self) {
+        let _ = self.stop_maintenance();
+        self.join_prefetches();
+    }
+}
+
+/// Seal the active segment and continue in a new one.
+fn rotate(
+    options: &Options,
+    active_seg: &mut Segment,
+    older_segments: &mut HashMap<u32, Arc<Segment>>,
+    warnings: &mut Vec<WalError>,
+) -> Result<(), WalError> {
+    let id = active_seg.id;
+    fail_point!("wal::before_rotate");
+    // Nothing has changed yet if this fails: records which still fit are
+    // appended to the current segment, and the next write retries.
+    let mut seg =
+        open_next_segment(options, id + 1, warnings).map_err(|e| WalError::RotationFailed {
+            segment_id: id + 1,
+            source: Box::new(e),
+        })?;
+    seg.set_block_cache(active_seg.block_cache());
+    fail_point!("wal::after_rotate");
+    let mut sealed = std::mem::replace(active_seg, seg);
+    let sealed_ok = sealed
+        .seal()
+        .and_then(|_| prepare_sealed_reads(options, &sealed));
+    older_segments.insert(id, Arc::new(sealed));
+    // A segment which failed to move is still readable where it is, and is
+    // moved on the next rotation or open.
+    sealed_ok?;
+    apply_retention(options, older_segments, id + 1)?;
+    relocate_sealed(options, older_segments)
+}
+
+/// Map a sealed segment for reads, or open it for direct reads, if the
+/// options ask to.
+#[cfg_attr(
+    not(any(feature = "mmap", all(feature = "direct-io", target_os = "linux"))),
+    allow(unused_variables)
+)]
+fn prepare_sealed_reads(options: &Options, seg: &Segment) -> Result<(), WalError> {
+    #[cfg(feature = "mmap")]
+    if options.mmap_reads {
+        seg.map_for_reads()?;
+    }
+    #[cfg(all(feature = "direct-io", target_os = "linux"))]
+    if options.direct_reads {
+        seg.enable_direct_reads()?;
+    }
+    Ok(())
+}
+
+/// Make the active segment ready for appending after it was opened: find
+/// its end, discard what a crash left of a torn write or an unfinished
+/// batch, and preallocate and map it as the options ask.
+fn recover_active(
+    options: &Options,
+    seg: &mut Segment,
+    report: &mut OpenReport,
+) -> Result<(), WalError> {
+    if options.extends_active_file() {
+        seg.recover_logical_tail()?;
+    }
+    // A crash tore the last write, or interrupted a batch: discard what
+    // was written of it, so the next write doesn't land after garbage.
+    for discarded in [Segment::torn_tail, Segment::unfinished_batch] {
+        let Some(end) = discarded(seg)? else {
+            continue;
+        };
+        if options.read_only {
+            seg.end_at(end)?;
+        } else {
+            seg.truncate(end)?;
+            seg.sync()?;
+        }
+    }
+    if options.tail_check {
+        if let Some(offset) = seg.misaligned_tail()? {
+            report.warnings.push(WalError::MisalignedTail {
+                segment_id: seg.id,
+                offset,
+                len: seg.size(),
+            });
+            seg.end_at(offset)?;
+        }
+    }
+    if !options.read_only {
+        seg.preallocate(options.segment_size, options.preallocation())?;
+    }
+    #[cfg(feature = "mmap")]
+    if options.mmap_appends && !options.read_only {
+        seg.enable_mmap(options.segment_size)?;
+    }
+    Ok(())
+}
+
+/// Verify the blocks of the segments the options ask to on open, recording
+/// the corrupted ones in `warnings`. `None` if nothing is verified.
+fn verify_on_open<'a>(
+    options: &Options,
+    older_segments: impl IntoIterator<Item = &'a Segment>,
+    active_seg: &'a Segment,
+    warnings: &mut Vec<WalError>,
+) -> Result<Option<VerifyReport>, WalError> {
+    let (one_in, seed) = match options.open_verification {
+        OpenVerification::None => return Ok(None),
+        OpenVerification::Full => (1, 0),
+        OpenVerification::Sampled { one_in } => (one_in as u64, sample_seed(options)?),
+    };
+    let mut segments: Vec<&Segment> = older_segments.into_iter().chain([active_seg]).collect();
+    segments.sort_by_key(|seg| seg.id);
+    let mut report = VerifyReport::default();
+    let mut record = |verified: Result<VerifyReport, WalError>| match verified {
+        Ok(verified) => {
+            report += verified;
+            Ok(())
+        }
+        Err(e @ WalError::Io { .. }) => Err(e),
+        Err(e) => {
+            warnings.push(e);
+            Ok(())
+        }
+    };
+    for seg in segments {
+        if one_in == 1 {
+            record(seg.verify())?;
+            continue;
+        }
+        record(Ok(VerifyReport {
+            segments: 1,
+            ..Default::default()
+        }))?;
+        for block_number in 0..seg.block_count() {
+            let block = ((seg.id as u64) << 32) | block_number as u64;
+            if mix(seed ^ block).is_multiple_of(one_in) {
+                record(seg.verify_block(block_number))?;
+            }
+        }
+    }
+    Ok(Some(report))
+}
+
+/// The seed picking the blocks sampled on this open, from the Wal's id and
+/// its number of opens, counting this one unless read-only.
+fn sample_seed(options: &Options) -> Result<u64, WalError> {
+    let (id, mut opens) =
+        manifest::load_identity(&options.dir_path)?.unwrap_or_else(|| (manifest::new_wal_id(), 0));
+    if !options.read_only {
+        opens += 1;
+        manifest::store_identity(&options.dir_path, id, opens)?;
+    }
+    Ok(mix(id as u64 ^ mix((id >> 64) as u64 ^ mix(opens))))
+}
+
+/// The splitmix64 finalizer, spreading every bit of `x` over the result.
+fn mix(mut x: u64) -> u64 {
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    x ^ (x >> 31)
+}
+
+/// Remove the oldest sealed segments which the retention policy no longer
+/// keeps. Segments are removed in order, so the ones left are contiguous.
+fn apply_retention(
+    options: &Options,
+    older_segments: &mut HashMap<u32, Arc<Segment>>,
+    active_id: u32,
+) -> Result<(), WalError> {
+    let retention = options.retention;
+    let mut ids: Vec<u32> = older_segments.keys().copied().collect();
+    ids.sort();
+    let mut expired = ids
+        .len()
+        .saturating_sub(retention.max_segments.unwrap_or(usize::MAX));
+    if let Some(max_age) = retention.max_age {
+        let now = options.clock.system_time();
+        for &id in &ids[expired..] {
+            let modified = older_segments[&id]
+                .metadata()?
+                .modified()
+                .context("stat", older_segments[&id].path())?;
+            if now.duration_since(modified).unwrap_or_default() <= max_age {
+                break;
+            }
+            expired += 1;
+        }
+    }
+    purge_segments(options, older_segments, &ids[..expired], active_id)
+}
+
+/// Remove the sealed segments `ids`, in ascending order, and then drop them
+/// from the index. A crash in between leaves an index listing removed
+/// segments, which the next open notices and rebuilds.
+fn purge_segments(
+    options: &Options,
+    older_segments: &mut HashMap<u32, Arc<Segment>>,
+    ids: &[u32],
+    active_id: u32,
+) -> Result<(), WalError> {
+    if ids.is_empty() {
+        return Ok(());
+    }
+    for id in ids {
+        // Readers still holding the segment keep reading it, its file stays
+        // open until they let go.
+        older_segments[id].remove()?;
+        older_segments.remove(id);
+    }
+    let mut remaining: Vec<u32> = older_segments.keys().copied().collect();
+    remaining.push(active_id);
+    remaining.sort();
+    Manifest::for_options(options).store_with_segments(&options.dir_path, &remaining)
+}
+
+/// Open a segment file, creating it through the segment factory if there is
+/// one, and set its permissions. Failing to set them is recorded in
+/// `warnings` rather than failing the open.
+fn open_segment(
+    options: &Options,
+    dir_path: &std::path::Path,
+    id: u32,
+    warnings: &mut Vec<WalError>,
+) -> Result<Segment, WalError> {
+    if options.read_only {
+        return configure_segment(options, Segment::open_read_only(dir_path, id)?);
+    }
+    if let Some(factory) = &options.segment_factory {
+        let path = segment::segment_file_path(dir_path, id);
+        if !path.exists() {
+            factory.create(&path)?;
+        }
+    }
+    let seg = configure_segment(options, Segment::open(dir_path, id)?)?;
+    if let Some(mode) = options.file_permissions {
+        if let Err(e) = seg.set_permissions(mode) {
+            warnings.push(e);
+        }
+    }
+    Ok(seg)
+}
+
+/// Lay a segment out, and write to it, the way the options ask.
+fn configure_segment(options: &Options, mut seg: Segment) -> Result<Segment, WalError> {
+    seg.set_block_len(options.block_len())?;
+    seg.set_chunk_alignment(options.chunk_alignment())?;
+    if !options.read_only {
+        seg.set_compression(options.compression);
+    }
+    #[cfg(feature = "encryption")]
+    seg.set_cipher(options.cipher.clone());
+    seg.set_page_cache_hints(options.page_cache_hints);
+    seg.set_write_buffer(options.write_buffer_size)?;
+    if options.block_trailers {
+        seg.enable_block_trailers()?;
+    }
+    Ok(seg)
+}
+
+/// Move sealed segments still in `dir_path` to `sealed_dir`, if there is
+/// one: all of them, or with a spill threshold the oldest ones, until the
+/// rest add up to at most the threshold.
+fn relocate_sealed(
+    options: &Options,
+    older_segments: &mut HashMap<u32, Arc<Segment>>,
+) -> Result<(), WalError> {
+    let Some(sealed_dir) = &options.sealed_dir else {
+        return Ok(());
+    };
+    let mut local: Vec<(u32, u64)> = older_segments
+        .values()
+        .filter(|seg| seg.path().parent() == Some(options.dir_path.as_path()))
+        .map(|seg| (seg.id, seg.size()))
+        .collect();
+    local.sort();
+    let mut bytes: u64 = local.iter().map(|(_, size)| size).sum();
+    for (id, size) in local {
+        if options
+            .spill_threshold
+            .is_some_and(|threshold| bytes <= threshold)
+        {
+            break;
+        }
+        // A segment which is shared can't be moved now, a later rotation or
+        // open moves it.
+        if let Some(seg) = older_segments.get_mut(&id).and_then(Arc::get_mut) {
+            seg.relocate(sealed_dir)?;
+        }
+        bytes -= size;
+    }
+    Ok(())
+}
+
+/// Open the segment to rotate to, indexing it first.
+fn open_next_segment(
+    options: &Options,
+    id: u32,
+    warnings: &mut Vec<WalError>,
+) -> Result<Segment, WalError> {
+    Manifest::append_segment(&options.dir_path, id)?;
+    let mut seg = open_segment(options, &options.dir_path, id, warnings)?;
+    seg.preallocate(options.segment_size, options.preallocation())?;
+    #[cfg(feature = "mmap")]
+    if options.mmap_appends {
+        seg.enable_mmap(options.segment_size)?;
+    }
+    Ok(seg)
+}
+
+/// Find the indexed segments, as `(in dir_path, in sealed_dir)` ids, without
+/// listing the directories. `None` if the index is stale: a segment it lists
+/// is gone. Only the newest one may be missing, if a crash came between
+/// indexing it and creating it.
+#[allow(clippy::type_complexity)]
+fn locate_segments(
+    options: &Options,
+    index: &std::collections::BTreeSet<u32>,
+) -> Result<Option<(Vec<u32>, Vec<u32>)>, WalError> {
+    let exists = |dir: &std::path::Path, id| {
+        let path = segment::segment_file_path(dir, id);
+        match std::fs::metadata(&path) {
+            Ok(metadata) => Ok(metadata.is_file()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(e).context("stat", &path),
+        }
+    };
+    let newest = index.last().copied();
+    let (mut segment_ids, mut sealed_ids) = (Vec::new(), Vec::new());
+    for &id in index {
+        let in_dir = exists(&options.dir_path, id)?;
+        let in_sealed = match &options.sealed_dir {
+            Some(sealed_dir) => exists(sealed_dir, id)?,
+            None => false,
+        };
+        if in_dir {
+            segment_ids.push(id);
+        }
+        if in_sealed {
+            sealed_ids.push(id);
+        }
+        if !in_dir && !in_sealed && Some(id) != newest {
+            return Ok(None);
+        }
+    }
+    Ok(Some((segment_ids, sealed_ids)))
+}
+
+/// The position `offset` bytes into `seg`, e.g. where the records end.
+fn end_position(seg: &Segment, offset: u64) -> ChunkPosition {
+    let block_len = seg.block_len() as u64;
+    ChunkPosition {
+        segment_id: seg.id,
+        block_number: (offset / block_len) as u32,
+        chunk_offset: offset % block_len,
+    }
+}
+
+/// Lock the active segment for writing, applying the poison policy.
+fn lock_active_mut(
+    active_segment: &RwLock<Segment>,
+    poison_policy: PoisonPolicy,
+) -> Result<RwLockWriteGuard<'_, Segment>, WalError> {
+    match active_segment.write() {
+        Ok(active_seg) => Ok(active_seg),
+        Err(poisoned) => match poison_policy {
+            PoisonPolicy::Fail => Err(WalError::Poisoned),
+            PoisonPolicy::Recover => {
+                // The panicking writer may have appended only part of its
+                // record, the file is the source of truth.
+                let mut active_seg = poisoned.into_inner();
+                active_seg.resync_with_file()?;
+                active_segment.clear_poison();
+                Ok(active_seg)
+            }
+        },
+    }
+}
+
+/// Ids of all segment files in `dir_path`.
+fn list_segment_ids(dir_path: &std::path::Path) -> Result<Vec<u32>, WalError> {
+    let (segment_ids, invalid) = list_segment_files(dir_path)?;
+    match invalid.into_iter().next() {
+        Some(name) => Err(WalError::InvalidSegmentFileName { name }),
+        None => Ok(segment_ids),
+    }
+}
+
+/// [`list_segment_ids`], applying the policy for invalid names.
+fn scan_segment_ids(
+    options: &Options,
+    dir_path: &std::path::Path,
+    report: &mut OpenReport,
+) -> Result<Vec<u32>, WalError> {
+    if options.invalid_segment_names == InvalidSegmentNames::Fail {
+        return list_segment_ids(dir_path);
+    }
+    let (segment_ids, invalid) = list_segment_files(dir_path)?;
+    for name in invalid {
+        if !options.read_only {
+            let quarantine = dir_path.join(QUARANTINE_DIR);
+            std::fs::create_dir_all(&quarantine).context("create", &quarantine)?;
+            let target = quarantine.join(name.file_name().unwrap_or_default());
+            std::fs::rename(&name, &target).context("rename", &name)?;
+        }
+        report
+            .warnings
+            .push(WalError::InvalidSegmentFileName { name });
+    }
+    Ok(segment_ids)
+}
+
+/// Ids of all segment files in `dir_path`, and paths of the files named
+/// like segments whose ids don't parse.
+fn list_segment_files(
+    dir_path: &std::path::Path,
+) -> Result<(Vec<u32>, Vec<std::path::PathBuf>), WalError> {
+    let mut segment_ids = Vec::new();
+    let mut invalid = Vec::new();
+    for entry in std::fs::read_dir(dir_path).context("list", dir_path)? {
+        let entry = entry.context("list", dir_path)?;
+        let path = entry.path();
+        if path.is_dir() {
+            continue;
+        }
+        let file_name = match entry.file_name().into_string() {
+            Ok(s) => s,
+            Err(_) => continue,
+        };
+        let Some(id) = file_name.strip_suffix(SEGMENT_FILE_SUFFIX) else {
+            continue;
+        };
+        match id.parse() {
+            Ok(id) => segment_ids.push(id),
+            Err(_) => invalid.push(path),
+        }
+    }
+    Ok((segment_ids, invalid))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{options::Preallocation, segment::BLOCK_SIZE, testing};
+
+    #[test]
+    fn work() {
+        let dir = testing::temp_dir("wal_work");
+        let opts = Options::new(&dir, 1024 * 1024 * 1024);
+        let mut wal = Wal::open(opts).unwrap();
+        let pos = wal.write("amazing lyf is better".as_bytes()).unwrap();
+        assert_eq!(wal.read(pos).unwrap(), "amazing lyf is better".as_bytes());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn freeze() {
+        let dir = testing::temp_dir("wal_freeze");
+        let mut wal = Wal::open(Options::new(&dir, 1024 * 1024)).unwrap();
+        let pos = wal.write(b"before").unwrap();
+
+        let guard = wal.freeze().unwrap();
+        assert!(matches!(wal.write(b"during"), Err(WalError::Frozen)));
+        assert_eq!(wal.read(pos).unwrap(), b"before");
+        drop(guard);
+
+        let pos = wal.write(b"after").unwrap();
+        assert_eq!(wal.read(pos).unwrap(), b"after");
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn sealed_dir() {
+        let dir = testing::temp_dir("wal_hot");
+        let sealed_dir = testing::temp_dir("wal_cold");
+        let opts = || Options::new(&dir, 4 * BLOCK_SIZE as u64).with_sealed_dir(&sealed_dir);
+        let mut wal = Wal::open(opts()).unwrap();
+        let records = testing::write_records(&mut wal, 0, 64, 4096);
+        testing::assert_read_back(&wal, &records);
+        let active_id = records.last().unwrap().pos.segment_id;
+        assert_eq!(list_segment_ids(&dir).unwrap(), vec![active_id]);
+        assert_eq!(
+            list_segment_ids(&sealed_dir).unwrap().len() as u32,
+            active_id - INITIAL_SEGMENT_FILE_ID
+        );
+        drop(wal);
+
+        let wal = Wal::open(opts()).unwrap();
+        testing::assert_read_back(&wal, &records);
+        std::fs::remove_dir_all(dir).unwrap();
+        std::fs::remove_dir_all(sealed_dir).unwrap();
+    }
+
+    #[test]
+    fn fork_to() {
+        let dir = testing::temp_dir("wal_origin");
+        let fork_dir = testing::temp_dir("wal_fork");
+        let mut wal = Wal::open(Options::new(&dir, 4 * BLOCK_SIZE as u64)).unwrap();
+        let records = testing::write_records(&mut wal, 0, 40, 4096);
+
+        let mut fork = wal.fork_to(&fork_dir).unwrap();
+        testing::assert_read_back(&fork, &records);
+        // Both sides keep evolving independently.
+        let origin_records = testing::write_records(&mut wal, 1, 10, 4096);
+        let fork_records = testing::write_records(&mut fork, 2, 10, 4096);
+        testing::assert_read_back(&wal, &origin_records);
+        testing::assert_read_back(&fork, &fork_records);
+        testing::assert_read_back(&fork, &records);
+        assert!(wal.fork_to(&fork_dir).is_err());
+        std::fs::remove_dir_all(dir).unwrap();
+        std::fs::remove_dir_all(fork_dir).unwrap();
+    }
+
+    #[test]
+    fn first_segment_id() {
+        let dir = testing::temp_dir("wal_first_id");
+        let opts = || Options::new(&dir, 4 * BLOCK_SIZE as u64).with_first_segment_id(1000);
+        let mut wal = Wal::open(opts()).unwrap();
+        assert_eq!(wal.write(b"first").unwrap().segment_id, 1000);
+        let records = testing::write_records(&mut wal, 0, 40, 4096);
+        drop(wal);
+
+        let mut wal = Wal::open(opts()).unwrap();
+        let last_id = records.last().unwrap().pos.segment_id;
+        assert!(last_id > 1000);
+        assert_eq!(wal.write(b"next").unwrap().segment_id, last_id);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn copy_range() {
+        let dir = testing::temp_dir("wal_copy_src");
+        let dest_dir = testing::temp_dir("wal_copy_dest");
+        let mut wal = Wal::open(Options::new(&dir, 4 * BLOCK_SIZE as u64)).unwrap();
+        let records = testing::write_records(&mut wal, 0, 60, 3000);
+        let mut dest = Wal::open(Options::new(&dest_dir, 16 * BLOCK_SIZE as u64)).unwrap();
+
+        let copied = wal
+            .copy_range(records[5].pos..=records[50].pos, &mut dest)
+            .unwrap();
+        assert_eq!(copied.len(), 46);
+        for (record, pos) in records[5..=50].iter().zip(copied) {
+            assert_eq!(dest.read(pos).unwrap(), record.data);
+        }
+        std::fs::remove_dir_all(dir).unwrap();
+        std::fs::remove_dir_all(dest_dir).unwrap();
+    }
+
+    #[test]
+    fn replay() {
+        let dir = testing::temp_dir("wal_replay");
+        let mut wal = Wal::open(Options::new(&dir, 4 * BLOCK_SIZE as u64)).unwrap();
+        let mut records = testing::write_records(&mut wal, 0, 30, 3000);
+        records.extend(testing::write_records(&mut wal, 1, 5, 70 * 1024));
+
+        let mut replayed = Vec::new();
+        wal.replay(|pos, data| {
+            replayed.push((pos, data.to_vec()));
+            Ok(ReplayControl::Continue)
+        })
+        .unwrap();
+        assert_eq!(replayed.len(), records.len());
+        for (record, (pos, data)) in records.iter().zip(replayed) {
+            assert_eq!(record.pos, pos);
+            assert_eq!(record.data, data);
+        }
+
+        let mut count = 0;
+        wal.replay(|_, _| {
+            count += 1;
+            Ok(if count == 3 {
+                ReplayControl::Stop
+            } else {
+                ReplayControl::Continue
+            })
+        })
+        .unwrap();
+        assert_eq!(count, 3);
+
+        let mut segments = Vec::new();
+        wal.replay(|pos, _| {
+            segments.push(pos.segment_id);
+            Ok(ReplayControl::SkipSegment)
+        })
+        .unwrap();
+        segments.dedup();
+        assert_eq!(
+            segments.len(),
+            segments
+                .iter()
+                .collect::<std::collections::HashSet<_>>()
+                .len()
+        );
+        assert_eq!(
+            *segments.last().unwrap(),
+            records.last().unwrap().pos.segment_id
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn replay_detects_corruption() {
+        let dir = testing::temp_dir("wal_replay_crc");
+        let mut wal = Wal::open(Options::new(&dir, 1024 * 1024)).unwrap();
+        testing::write_records(&mut wal, 0, 10, 100);
+        drop(wal);
+
+        let path = segment::segment_file_path(&dir, INITIAL_SEGMENT_FILE_ID);
+        let mut bytes = std::fs::read(&path).unwrap();
+        let corrupted = CHUNK_HEADER_SIZE as usize + 100 + CHUNK_HEADER_SIZE as usize + 20;
+        bytes[corrupted] ^= 0xff;
+        std::fs::write(&path, bytes).unwrap();
+
+        let wal = Wal::open(Options::new(&dir, 1024 * 1024)).unwrap();
+        let mut count = 0;
+        let result = wal.replay(|_, _| {
+            count += 1;
+            Ok(ReplayControl::Continue)
+        });
+        assert_eq!(count, 1);
+        assert!(matches!(
+            result,
+            Err(WalError::InvalidCrc {
+                segment_id: INITIAL_SEGMENT_FILE_ID,
+                block_number: 0,
+                offset: 107,
+            })
+        ));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn sync_segment() {
+        let dir = testing::temp_dir("wal_sync_segment");
+        let mut wal = Wal::open(Options::new(&dir, 4 * BLOCK_SIZE as u64)).unwrap();
+        assert!(wal.unsynced_segments().is_empty());
+        let records = testing::write_records(&mut wal, 0, 40, 4096);
+        let last_id = records.last().unwrap().pos.segment_id;
+        let all: Vec<u32> = (INITIAL_SEGMENT_FILE_ID..=last_id).collect();
+        assert_eq!(wal.unsynced_segments(), all);
+
+        wal.sync_segment(INITIAL_SEGMENT_FILE_ID).unwrap();
+        wal.sync_segment(last_id).unwrap();
+        assert_eq!(wal.unsynced_segments(), all[1..all.len() - 1]);
+        assert!(matches!(
+            wal.sync_segment(last_id + 1),
+            Err(WalError::SegmentFileNotFound)
+        ));
+        drop(wal.freeze().unwrap());
+        assert!(wal.unsynced_segments().is_empty());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn concurrent_writers_and_readers() {
+        let dir = testing::temp_dir("wal_concurrent");
+        let wal =
+            std::sync::Mutex::new(Wal::open(Options::new(&dir, 8 * BLOCK_SIZE as u64)).unwrap());
+        let records = testing::run_concurrent_writers(&wal, 4, 50, 2000);
+        testing::run_concurrent_readers(&wal, 4, &records);
+        testing::assert_no_interleaving(&records);
+        let mixed = testing::run_concurrent_mixed(&wal, 3, 3, 30, 9000);
+        testing::assert_no_interleaving(&mixed);
+        testing::assert_read_back(&wal.lock().unwrap(), &mixed);
+        testing::assert_read_back(&wal.lock().unwrap(), &records);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn retry_failed_rotation() {
+        let dir = testing::temp_dir("wal_rotation_failure");
+        let mut wal = Wal::open(Options::new(&dir, 2 * BLOCK_SIZE as u64)).unwrap();
+        // Nothing can be created where the next segment file belongs.
+        let blocker = segment::segment_file_path(&dir, INITIAL_SEGMENT_FILE_ID + 1);
+        std::fs::create_dir(&blocker).unwrap();
+
+        let first = wal.write(&[1; 60 * 1024]).unwrap();
+        assert!(matches!(
+            wal.write(&[2; 10 * 1024]),
+            Err(WalError::RotationFailed { segment_id: 2, .. })
+        ));
+        // The current segment keeps taking what still fits.
+        let small = wal.write(&[3; 1024]).unwrap();
+        assert_eq!(small.segment_id, INITIAL_SEGMENT_FILE_ID);
+
+        std::fs::remove_dir(&blocker).unwrap();
+        let rotated = wal.write(&[2; 10 * 1024]).unwrap();
+        assert_eq!(rotated.segment_id, INITIAL_SEGMENT_FILE_ID + 1);
+        assert_eq!(wal.read(first).unwrap(), vec![1; 60 * 1024]);
+        assert_eq!(wal.read(small).unwrap(), vec![3; 1024]);
+        assert_eq!(wal.read(rotated).unwrap(), vec![2; 10 * 1024]);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn verify_after_write() {
+        let dir = testing::temp_dir("wal_verify_after_write");
+        let opts = Options::new(&dir, 4 * BLOCK_SIZE as u64).with_verify_after_write(true);
+        let mut wal = Wal::open(opts).unwrap();
+        let mut records = testing::write_records(&mut wal, 0, 20, 3000);
+        records.extend(testing::write_records(&mut wal, 1, 3, 50 * 1024));
+        testing::assert_read_back(&wal, &records);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn failed_batch_is_not_cached() {
+        let dir = testing::temp_dir("wal_failed_batch_cache");
+        let opts = Options::new(&dir, 1024 * 1024)
+            .with_verify_after_write(true)
+            .with_block_cache_bytes(1024 * 1024);
+        let wal = Wal::open(opts).unwrap();
+        let mut guard = wal.active_segment.write().unwrap();
+        let seg = &mut *guard;
+        let (cache, segment_id) = (seg.block_cache().unwrap(), seg.id);
+        let block = |block_number| {
+            cache.lock().unwrap().get(BlockId {
+                segment_id,
+                block_number,
+            })
+        };
+
+        // Verifying a record of a batch still being appended leaves its
+        // blocks uncached.
+        let first = vec![1; 2 * BLOCK_SIZE as usize];
+        let pos = seg.write_in_batch(&first, true).unwrap();
+        assert_eq!(seg.read_appended(0, 0).unwrap(), first);
+        assert!(block(0).is_none());
+        // Once visible they are cached, until the segment is cut off.
+        seg.write_in_batch(b"last", false).unwrap();
+        assert_eq!(seg.read(0, 0).unwrap(), first);
+        assert!(block(0).is_some());
+        seg.truncate(0).unwrap();
+        assert!(block(0).is_none() && block(1).is_none());
+        let second = vec![2; 2 * BLOCK_SIZE as usize];
+        assert_eq!(seg.write_slice(&second).unwrap(), pos);
+        drop(guard);
+        assert_eq!(wal.read(pos).unwrap(), second);
+        drop(wal);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn unfinished_batch_is_not_read() {
+        let dir = testing::temp_dir("wal_unfinished_batch_read");
+        let wal = Wal::open(Options::new(&dir, 1024 * 1024)).unwrap();
+        let mut guard = wal.active_segment.write().unwrap();
+        let seg = &mut *guard;
+        seg.write_slice(b"first").unwrap();
+        let visible = seg.visible_size();
+
+        // Block and verification reads stop where reads do.
+        seg.write_in_batch(&[1; 3000], true).unwrap();
+        assert!(seg.size() > visible);
+        assert_eq!(seg.read_block(0).unwrap().len(), 1);
+        assert_eq!(seg.verify().unwrap().bytes, visible);
+        seg.write_in_batch(b"last", false).unwrap();
+        assert_eq!(seg.read_block(0).unwrap().len(), 3);
+        assert_eq!(seg.verify().unwrap().bytes, seg.size());
+        drop(guard);
+        drop(wal);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn io_error_context() {
+        let dir = testing::temp_dir("wal_io_error");
+        let not_a_dir = dir.join("file");
+        std::fs::write(&not_a_dir, b"").unwrap();
+        match Wal::open(Options::new(&not_a_dir, 1024 * 1024)) {
+            Err(WalError::Io { op, path, .. }) => {
+                assert_eq!(op, "create");
+                assert_eq!(path, not_a_dir);
+            }
+            _ => panic!("expected an io error"),
+        }
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    fn poison(wal: &Wal) {
+        let _ = std::thread::scope(|s| {
+            s.spawn(|| {
+                let _active_seg = wal.active_segment.write().unwrap();
+                panic!("writer panicked");
+            })
+            .join()
+        });
+        assert!(wal.active_segment.is_poisoned());
+    }
+
+    #[test]
+    fn recover_poisoned_lock() {
+        let dir = testing::temp_dir("wal_poison_recover");
+        let mut wal = Wal::open(Options::new(&dir, 1024 * 1024)).unwrap();
+        let before = wal.write(b"before").unwrap();
+        poison(&wal);
+        assert_eq!(wal.read(before).unwrap(), b"before");
+        let after = wal.write(b"after").unwrap();
+        assert_eq!(wal.read(after).unwrap(), b"after");
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn fail_on_poisoned_lock() {
+        let dir = testing::temp_dir("wal_poison_fail");
+        let opts = Options::new(&dir, 1024 * 1024).with_poison_policy(PoisonPolicy::Fail);
+        let mut wal = Wal::open(opts).unwrap();
+        let before = wal.write(b"before").unwrap();
+        poison(&wal);
+        assert!(matches!(wal.read(before), Err(WalError::Poisoned)));
+        assert!(matches!(wal.write(b"after"), Err(WalError::Poisoned)));
+        assert!(!wal.is_full(0));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn file_permissions() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = testing::temp_dir("wal_permissions");
+        let mode = |wal: &Wal| {
+            let active = wal.active_unchecked();
+            active.metadata().unwrap().permissions().mode() & 0o777
+        };
+        let wal =
+            Wal::open(Options::new(&dir, 1024 * 1024).with_file_permissions(Some(0o600))).unwrap();
+        assert_eq!(mode(&wal), 0o600);
+        assert!(wal.open_report().warnings.is_empty());
+        drop(wal);
+
+        // Left alone when not configured.
+        let wal = Wal::open(Options::new(&dir, 1024 * 1024).with_file_permissions(None)).unwrap();
+        assert_eq!(mode(&wal), 0o600);
+        drop(wal);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn read_block() {
+        let dir = testing::temp_dir("wal_read_block");
+        let mut wal = Wal::open(Options::new(&dir, 1024 * 1024)).unwrap();
+        let small = wal.write(&[1; 100]).unwrap();
+        let large = wal.write(&[2; 40 * 1024]).unwrap();
+        wal.write(&[3; 10]).unwrap();
+
+        let block = wal.read_block(small.block_id()).unwrap();
+        assert_eq!(block.id, small.block_id());
+        let chunks: Vec<_> = block
+            .chunks
+            .iter()
+            .map(|c| (c.offset, c.chunk_type))
+            .collect();
+        assert_eq!(
+            chunks,
+            [
+                (0, ChunkType::Full),
+                (large.chunk_offset as u32, ChunkType::First)
+            ]
+        );
+        assert_eq!(block.chunks[0].data, [1; 100]);
+
+        let next = wal
+            .read_block(BlockId {
+                segment_id: large.segment_id,
+                block_number: 1,
+            })
+            .unwrap();
+        let data: Vec<u8> = block.chunks[1]
+            .data
+            .iter()
+            .chain(&next.chunks[0].data)
+            .copied()
+            .collect();
+        assert_eq!(data, [2; 40 * 1024]);
+        assert_eq!(next.chunks[0].chunk_type, ChunkType::Last);
+        assert_eq!(next.chunks[1].data, [3; 10]);
+        // Past the end.
+        let empty = wal
+            .read_block(BlockId {
+                segment_id: large.segment_id,
+                block_number: 5,
+            })
+            .unwrap();
+        assert!(empty.chunks.is_empty());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn read_range_pages() {
+        let dir = testing::temp_dir("wal_read_range");
+        let mut wal = Wal::open(Options::new(&dir, 4 * BLOCK_SIZE as u64)).unwrap();
+        let records = testing::write_records(&mut wal, 0, 100, 3000);
+        let range = records[3].pos..=records[90].pos;
+        let expected: Vec<_> = records[3..=90]
+            .iter()
+            .map(|r| (r.pos, r.data.clone()))
+            .collect();
+
+        let all = wal
+            .read_range(range.clone(), ReadLimits::default())
+            .unwrap();
+        assert_eq!(all.records, expected);
+        assert_eq!(all.next, None);
+
+        for limits in [
+            ReadLimits::default().with_max_records(7),
+            ReadLimits::default().with_max_bytes(10_000),
+            ReadLimits::default().with_max_segments(1),
+            // Smaller than a record, still one per page.
+            ReadLimits::default().with_max_bytes(1),
+        ] {
+            let mut read = Vec::new();
+            let mut start = Some(*range.start());
+            while let Some(pos) = start {
+                let page = wal.read_range(pos..=*range.end(), limits).unwrap();
+                assert!(!page.records.is_empty());
+                if let Some(max) = limits.max_records {
+                    assert!(page.records.len() <= max);
+                }
+                if limits.max_bytes == Some(10_000) {
+                    assert!(page.records.iter().map(|(_, d)| d.len()).sum::<usize>() <= 10_000);
+                }
+                if limits.max_segments.is_some() {
+                    let first = page.records[0].0.segment_id;
+                    assert!(page.records.iter().all(|(pos, _)| pos.segment_id == first));
+                }
+                read.extend(page.records);
+                start = page.next;
+            }
+            assert_eq!(read, expected);
+        }
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn replay_progress() {
+        let dir = testing::temp_dir("wal_replay_progress");
+        let mut wal = Wal::open(Options::new(&dir, 4 * BLOCK_SIZE as u64)).unwrap();
+        testing::write_records(&mut wal, 0, 95, 3000);
+        let mut reports = Vec::new();
+        wal.replay_with_progress(10, |p| reports.push(*p), |_, _| Ok(ReplayControl::Continue))
+            .unwrap();
+        let counts: Vec<u64> = reports.iter().map(|p| p.records).collect();
+        assert_eq!(counts, [10, 20, 30, 40, 50, 60, 70, 80, 90, 95]);
+        assert!(reports
+            .windows(2)
+            .all(|w| w[0].bytes_processed < w[1].bytes_processed));
+        let last = reports.last().unwrap();
+        assert_eq!(last.bytes_processed, last.total_bytes);
+        assert_eq!(last.estimate_remaining(), Some(std::time::Duration::ZERO));
+
+        // Stopping early still reports once.
+        let mut reports = Vec::new();
+        wal.replay_with_progress(0, |p| reports.push(*p), |_, _| Ok(ReplayControl::Stop))
+            .unwrap();
+        assert_eq!(reports.len(), 1);
+        assert_eq!(reports[0].records, 1);
+        assert!(reports[0].bytes_processed < reports[0].total_bytes);
+        drop(wal);
+
+        // Elapsed time comes from the configured clock.
+        let clock = testing::ManualClock::new();
+        let wal =
+            Wal::open(Options::new(&dir, 4 * BLOCK_SIZE as u64).with_clock(clock.clone())).unwrap();
+        let mut elapsed = Vec::new();
+        wal.replay_with_progress(
+            30,
+            |p| elapsed.push(p.elapsed),
+            |_, _| {
+                clock.advance(std::time::Duration::from_secs(1));
+                Ok(ReplayControl::Continue)
+            },
+        )
+        .unwrap();
+        let secs: Vec<u64> = elapsed.iter().map(|d| d.as_secs()).collect();
+        assert_eq!(secs, [30, 60, 90, 95]);
+        drop(wal);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn empty_records() {
+        let dir = testing::temp_dir("wal_empty_records");
+        let mut wal = Wal::open(Options::new(&dir, 1024 * 1024)).unwrap();
+        // Leave room for exactly one more chunk header in the first block.
+        let filler = wal.write(&vec![1; (BLOCK_SIZE - 15) as usize]).unwrap();
+        let empty = wal.write(&[]).unwrap();
+        assert_eq!(empty.chunk_offset, (BLOCK_SIZE - 8) as u64);
+        // The rest of the block is padding now.
+        let next_block = wal.write(&[]).unwrap();
+        assert_eq!((next_block.block_number, next_block.chunk_offset), (1, 0));
+        let last = wal.write(&[2]).unwrap();
+
+        assert!(wal.read(empty).unwrap().is_empty());
+        assert!(wal.read(next_block).unwrap().is_empty());
+        assert_eq!(wal.read_next(empty).unwrap().1, Some(next_block));
+        let mut replayed = Vec::new();
+        wal.replay(|pos, data| {
+            replayed.push((pos, data.len()));
+            Ok(ReplayControl::Continue)
+        })
+        .unwrap();
+        assert_eq!(
+            replayed,
+            [
+                (filler, (BLOCK_SIZE - 15) as usize),
+                (empty, 0),
+                (next_block, 0),
+                (last, 1)
+            ]
+        );
+        let block = wal.read_block(empty.block_id()).unwrap();
+        assert_eq!(block.chunks[1].chunk_type, ChunkType::Full);
+        assert!(block.chunks[1].data.is_empty());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn empty_trailing_segments() {
+        let dir = testing::temp_dir("wal_empty_trailing");
+        let opts = || Options::new(&dir, 4 * BLOCK_SIZE as u64);
+        let mut wal = Wal::open(opts()).unwrap();
+        let records = testing::write_records(&mut wal, 0, 10, 3000);
+        drop(wal);
+        // Rotations which crashed right after creating their segment.
+        for id in 2..=3 {
+            Manifest::append_segment(&dir, id).unwrap();
+            std::fs::File::create(segment::segment_file_path(&dir, id)).unwrap();
+        }
+
+        let mut wal = Wal::open(opts()).unwrap();
+        assert!(!segment::segment_file_path(&dir, 2).exists());
+        let pos = wal.write(b"after").unwrap();
+        assert_eq!(pos.segment_id, 3);
+        testing::assert_read_back(&wal, &records);
+        assert_eq!(wal.read_next(records[9].pos).unwrap().1, Some(pos));
+        drop(wal);
+        let index = Manifest::load_segment_index(&dir).unwrap().unwrap();
+        assert_eq!(index, [1, 3].into());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn visible_and_durable_positions() {
+        let dir = testing::temp_dir("wal_visible_position");
+        let mut wal = Wal::open(Options::new(&dir, 4 * BLOCK_SIZE as u64)).unwrap();
+        let start = wal.visible_position().unwrap();
+        assert_eq!(wal.durable_position().unwrap(), start);
+        let pos = wal.write(b"record").unwrap();
+        assert_eq!(pos, start);
+        let visible = wal.visible_position().unwrap();
+        assert!(pos < visible);
+        assert_eq!(wal.durable_position().unwrap(), start);
+        wal.sync().unwrap();
+        assert_eq!(wal.durable_position().unwrap(), visible);
+
+        // Across a rotation the unsynced sealed segment holds durability back.
+        let records = testing::write_records(&mut wal, 0, 20, 9000);
+        let last = records.last().unwrap().pos;
+        assert!(last.segment_id > pos.segment_id);
+        assert!(last < wal.visible_position().unwrap());
+        let durable = wal.durable_position().unwrap();
+        assert!(durable < last);
+        wal.sync().unwrap();
+        assert_eq!(
+            wal.durable_position().unwrap(),
+            wal.visible_position().unwrap()
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn sealed_segments() {
+        let dir = testing::temp_dir("wal_sealed_segments");
+        let opts = || Options::new(&dir, 4 * BLOCK_SIZE as u64);
+        let mut wal = Wal::open(opts()).unwrap();
+        testing::write_records(&mut wal, 0, 20, 9000);
+        let check = |wal: &Wal| {
+            let segments = wal.segments();
+            assert!(segments.len() > 1);
+            let (active, sealed) = segments.split_last().unwrap();
+            assert!(!active.sealed);
+            assert!(sealed.iter().all(|info| info.sealed && info.size > 0));
+            assert!(segments
+                .windows(2)
+                .all(|w| w[0].segment_id < w[1].segment_id));
+        };
+        check(&wal);
+        drop(wal);
+        check(&Wal::open(opts()).unwrap());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn block_size() {
+        for trailers in [false, true] {
+            let dir = testing::temp_dir("wal_block_size");
+            let opts = || {
+                Options::new(&dir, 16 * 4096)
+                    .with_block_size(4096)
+                    .with_block_trailers(trailers)
+            };
+            let mut wal = Wal::open(opts()).unwrap();
+            let records = testing::write_records(&mut wal, 0, 100, 1500);
+            let large = wal.write(&[7; 10_000]).unwrap();
+            assert!(large.chunk_offset < 4096);
+            drop(wal);
+
+            let wal = Wal::open(opts()).unwrap();
+            assert_eq!(wal.format_info().block_size, 4096);
+            testing::assert_read_back(&wal, &records);
+            assert_eq!(wal.read(large).unwrap(), [7; 10_000]);
+            let mut replayed = 0;
+            wal.replay(|_, _| {
+                replayed += 1;
+                Ok(ReplayControl::Continue)
+            })
+            .unwrap();
+            assert_eq!(replayed, records.len() + 1);
+            wal.verify().unwrap();
+            drop(wal);
+            std::fs::remove_dir_all(dir).unwrap();
+        }
+
+        let dir = testing::temp_dir("wal_block_size_invalid");
+        for (segment_size, block_size) in [(1024 * 1024, 1000), (1024 * 1024, 256), (2048, 4096)] {
+            let opts = Options::new(&dir, segment_size).with_block_size(block_size);
+            assert!(matches!(Wal::open(opts), Err(WalError::InvalidOptions(_))));
+        }
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn chunk_alignment() {
+        for (alignment, trailers) in [(512, false), (4096, true)] {
+            let dir = testing::temp_dir("wal_chunk_alignment");
+            let opts = || {
+                Options::new(&dir, 16 * BLOCK_SIZE as u64)
+                    .with_chunk_alignment(alignment)
+                    .with_block_trailers(trailers)
+            };
+            let mut wal = Wal::open(opts()).unwrap();
+            let mut records = testing::write_records(&mut wal, 0, 50, 700);
+            records.extend(testing::write_records(&mut wal, 1, 10, 40_000));
+            assert!(records
+                .iter()
+                .all(|record| record.pos.chunk_offset.is_multiple_of(alignment as u64)));
+            drop(wal);
+
+            // Reopened with the alignment it was created with.
+            let wal =
+                Wal::open(Options::new(&dir, 16 * BLOCK_SIZE as u64).with_block_trailers(trailers))
+                    .unwrap();
+            assert_eq!(wal.format_info().chunk_alignment, alignment);
+            testing::assert_read_back(&wal, &records);
+            assert_eq!(wal.reader().count(), records.len());
+            wal.verify().unwrap();
+            drop(wal);
+            let mut wal = Wal::open(opts()).unwrap();
+            let pos = wal.write(b"after").unwrap();
+            assert!(pos.chunk_offset.is_multiple_of(alignment as u64));
+            assert_eq!(
+                wal.read_next(records.last().unwrap().pos).unwrap().1,
+                Some(pos)
+            );
+            drop(wal);
+            assert!(matches!(
+                Wal::open(opts().with_chunk_alignment(alignment / 2)),
+                Err(WalError::OptionsMismatch { .. })
+            ));
+            std::fs::remove_dir_all(dir).unwrap();
+        }
+
+        let dir = testing::temp_dir("wal_chunk_alignment_invalid");
+        for alignment in [0, 1000, 2 * BLOCK_SIZE] {
+            let opts = Options::new(&dir, 1024 * 1024).with_chunk_alignment(alignment);
+            assert!(matches!(Wal::open(opts), Err(WalError::InvalidOptions(_))));
+        }
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn preallocation() {
+        let mut modes = vec![Preallocation::Sparse, Preallocation::ZeroFill];
+        if cfg!(all(feature = "fallocate", target_os = "linux")) {
+            modes.push(Preallocation::KeepSize);
+        }
+        for preallocation in modes {
+            let dir = testing::temp_dir("wal_preallocation");
+            let segment_size = 8 * BLOCK_SIZE as u64;
+            let mut wal =
+                Wal::open(Options::new(&dir, segment_size).with_preallocation(preallocation))
+                    .unwrap();
+            let mut records = testing::write_records(&mut wal, 0, 100, 3000);
+            assert!(records.last().unwrap().pos.segment_id > INITIAL_SEGMENT_FILE_ID);
+            for seg in wal.older_segments.values() {
+                assert_eq!(seg.metadata().unwrap().len(), seg.size());
+            }
+            let active = wal.active_unchecked();
+            let active_len = active.metadata().unwrap().len();
+            if preallocation.extends_file() {
+                assert_eq!(active_len, segment_size);
+            } else {
+                assert_eq!(active_len, active.size());
+            }
+            drop(active);
+            drop(wal);
+
+            // Reopened with the preallocation it was created with, the data
+            // ends where it was written up to rather than at the file's end.
+            let wal = Wal::open(Options::new(&dir, segment_size).with_read_only(true)).unwrap();
+            assert_eq!(wal.format_info().preallocation, preallocation);
+            testing::assert_read_back(&wal, &records);
+            assert_eq!(wal.reader().count(), records.len());
+            drop(wal);
+            let mut wal = Wal::open(Options::new(&dir, segment_size)).unwrap();
+            let last = records.last().unwrap().pos;
+            records.extend(testing::write_records(&mut wal, 1, 40, 3000));
+            assert_eq!(wal.read_next(last).unwrap().1, Some(records[100].pos));
+            testing::assert_read_back(&wal, &records);
+            wal.verify().unwrap();
+            drop(wal);
+            assert!(matches!(
+                Wal::open(Options::new(&dir, segment_size).with_preallocation(Preallocation::None)),
+                Err(WalError::OptionsMismatch { .. })
+            ));
+            std::fs::remove_dir_all(dir).unwrap();
+        }
+    }
+
+    #[test]
+    fn purge_before() {
+        let dir = testing::temp_dir("wal_purge_before");
+        let opts = || Options::new(&dir, 4 * BLOCK_SIZE as u64);
+        let mut wal = Wal::open(opts()).unwrap();
+        let records = testing::write_records(&mut wal, 0, 100, 3000);
+        let keep = records
+            .iter()
+            .position(|record| record.pos.segment_id == INITIAL_SEGMENT_FILE_ID + 2)
+            .unwrap();
+
+        let purged = wal.purge_before(records[keep + 1].pos).unwrap();
+        assert_eq!(
+            purged,
+            [INITIAL_SEGMENT_FILE_ID, INITIAL_SEGMENT_FILE_ID + 1]
+        );
+        for id in &purged {
+            assert!(!segment::segment_file_path(&dir, *id).exists());
+        }
+        assert!(wal.read(records[0].pos).is_err());
+        testing::assert_read_back(&wal, &records[keep..]);
+        assert_eq!(wal.first_position().unwrap(), Some(records[keep].pos));
+        // Nothing left before the position's own segment.
+        assert!(wal.purge_before(records[keep].pos).unwrap().is_empty());
+        drop(wal);
+
+        let wal = Wal::open(opts()).unwrap();
+        testing::assert_read_back(&wal, &records[keep..]);
+        assert_eq!(wal.reader().count(), records.len() - keep);
+        drop(wal);
+        let mut wal = Wal::open(opts().with_read_only(true)).unwrap();
+        assert!(matches!(
+            wal.purge_before(records.last().unwrap().pos),
+            Err(WalError::ReadOnly)
+        ));
+        drop(wal);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn retention_policy() {
+        let dir = testing::temp_dir("wal_retention_policy");
+        let clock = testing::ManualClock::new();
+        let opts = |retention| {
+            Options::new(&dir, 4 * BLOCK_SIZE as u64)
+                .with_retention(retention)
+                .with_clock(clock.clone())
+        };
+        let mut wal = Wal::open(opts(RetentionPolicy::default().with_max_segments(2))).unwrap();
+        let mut records = Vec::new();
+        for writer in 0..10 {
+            records.extend(testing::write_records(&mut wal, writer, 30, 3000));
+            assert!(wal.segments().len() <= 3);
+        }
+        let oldest = wal.segments()[0].segment_id;
+        assert!(oldest > INITIAL_SEGMENT_FILE_ID);
+        let kept: Vec<_> = records
+            .into_iter()
+            .filter(|record| record.pos.segment_id >= oldest)
+            .collect();
+        testing::assert_read_back(&wal, &kept);
+        drop(wal);
+
+        // Applied when opened, too.
+        let wal = Wal::open(opts(RetentionPolicy::default().with_max_segments(1))).unwrap();
+        assert_eq!(wal.segments().len(), 2);
+        assert_eq!(wal.segments()[0].segment_id, oldest + 1);
+        drop(wal);
+
+        let hour = std::time::Duration::from_secs(3600);
+        let wal = Wal::open(opts(RetentionPolicy::default().with_max_age(hour))).unwrap();
+        assert_eq!(wal.segments().len(), 2);
+        drop(wal);
+        clock.advance(2 * hour);
+        let wal = Wal::open(opts(RetentionPolicy::default().with_max_age(hour))).unwrap();
+        let segments = wal.segments();
+        assert_eq!(segments.len(), 1);
+        assert!(!segments[0].sealed);
+        let active: Vec<_> = kept
+            .into_iter()
+            .filter(|record| record.pos.segment_id == segments[0].segment_id)
+            .collect();
+        testing::assert_read_back(&wal, &active);
+        drop(wal);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn content_index() {
+        let dir = testing::temp_dir("wal_content_index");
+        let opts = || Options::new(&dir, 4 * BLOCK_SIZE as u64).with_content_index(true);
+        let mut wal = Wal::open(opts()).unwrap();
+        let records = testing::write_records(&mut wal, 0, 100, 3000);
+        let dup = wal.write(b"shipped twice").unwrap();
+        let batch = wal.write_batch(&[b"in a batch", b"shipped twice"]).unwrap();
+        for record in &records {
+            assert_eq!(
+                wal.checksum_of(record.pos).unwrap(),
+                crc32fast::hash(&record.data)
+            );
+            assert_eq!(wal.find_by_content(&record.data).unwrap(), Some(record.pos));
+        }
+        assert_eq!(wal.find_by_content(b"shipped twice").unwrap(), Some(dup));
+        assert_eq!(wal.find_by_content(b"in a batch").unwrap(), Some(batch[0]));
+        assert_eq!(wal.find_by_content(b"never written").unwrap(), None);
+        drop(wal);
+
+        // Rebuilt when opened, and pruned with the segments.
+        let mut wal = Wal::open(opts()).unwrap();
+        assert_eq!(wal.find_by_content(b"shipped twice").unwrap(), Some(dup));
+        wal.purge_before(dup).unwrap();
+        assert_eq!(wal.find_by_content(&records[0].data).unwrap(), None);
+        assert_eq!(wal.find_by_content(b"shipped twice").unwrap(), Some(dup));
+        drop(wal);
+
+        let wal = Wal::open(Options::new(&dir, 4 * BLOCK_SIZE as u64)).unwrap();
+        assert_eq!(
+            wal.checksum_of(dup).unwrap(),
+            crc32fast::hash(b"shipped twice")
+        );
+        assert!(matches!(
+            wal.find_by_content(b"shipped twice"),
+            Err(WalError::ContentIndexDisabled)
+        ));
+        drop(wal);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn truncate_after_fork() {
+        let dir = testing::temp_dir("wal_truncate_after_fork");
+        let fork_dir = testing::temp_dir("wal_truncate_after_forked");
+        let mut wal = Wal::open(Options::new(&dir, 4 * BLOCK_SIZE as u64)).unwrap();
+        let records = testing::write_records(&mut wal, 0, 100, 3000);
+        let fork = wal.fork_to(&fork_dir).unwrap();
+        let kept = records
+            .iter()
+            .position(|record| record.pos.segment_id == INITIAL_SEGMENT_FILE_ID + 1)
+            .unwrap()
+            - 10;
+
+        // Cutting off the source's first segment leaves the fork's alone.
+        wal.truncate_after(records[kept].pos).unwrap();
+        testing::assert_read_back(&wal, &records[..=kept]);
+        assert_eq!(wal.reader().count(), kept + 1);
+        testing::assert_read_back(&fork, &records);
+        drop(fork);
+        let fork = Wal::open(Options::new(&fork_dir, 4 * BLOCK_SIZE as u64)).unwrap();
+        assert_eq!(fork.reader().count(), records.len());
+        drop(fork);
+        drop(wal);
+        std::fs::remove_dir_all(dir).unwrap();
+        std::fs::remove_dir_all(fork_dir).unwrap();
+    }
+
+    #[test]
+    fn truncate_after() {
+        let dir = testing::temp_dir("wal_truncate_after");
+        let opts = || Options::new(&dir, 4 * BLOCK_SIZE as u64).with_block_cache_bytes(1024 * 1024);
+        let mut wal = Wal::open(opts()).unwrap();
+        let mut records = testing::write_records(&mut wal, 0, 100, 3000);
+        testing::assert_read_back(&wal, &records);
+        let active_id = records.last().unwrap().pos.segment_id;
+        let keep = records
+            .iter()
+            .rposition(|record| record.pos.segment_id == active_id)
+            .unwrap()
+            - 3;
+
+        // Within the active segment.
+        wal.truncate_after(records[keep].pos).unwrap();
+        records.truncate(keep + 1);
+        records.extend(testing::write_records(&mut wal, 1, 5, 3000));
+        assert_eq!(
+            wal.read_next(records[keep].pos).unwrap().1,
+            Some(records[keep + 1].pos)
+        );
+        testing::assert_read_back(&wal, &records);
+        assert_eq!(wal.reader().count(), records.len());
+
+        // Within a sealed segment, the segments after it are removed.
+        let keep = 5;
+        assert_eq!(records[keep].pos.segment_id, INITIAL_SEGMENT_FILE_ID);
+        wal.truncate_after(records[keep].pos).unwrap();
+        records.truncate(keep + 1);
+        assert_eq!(wal.segments().len(), 2);
+        assert!(!segment::segment_file_path(&dir, INITIAL_SEGMENT_FILE_ID + 2).exists());
+        records.extend(testing::write_records(&mut wal, 2, 60, 3000));
+        assert_eq!(
+            wal.read_next(records[keep].pos).unwrap().1,
+            Some(records[keep + 1].pos)
+        );
+        testing::assert_read_back(&wal, &records);
+        assert_eq!(wal.reader().count(), records.len());
+
+        // Never into a batch.
+        let batch = wal.write_batch(&[b"one", b"two", b"three"]).unwrap();
+        assert!(matches!(
+            wal.truncate_after(batch[0]),
+            Err(WalError::TruncateInBatch { .. })
+        ));
+        wal.truncate_after(batch[2]).unwrap();
+        assert_eq!(wal.read(batch[1]).unwrap(), b"two");
+        wal.truncate_after(records.last().unwrap().pos).unwrap();
+        drop(wal);
+
+        let wal = Wal::open(opts()).unwrap();
+        testing::assert_read_back(&wal, &records);
+        assert_eq!(wal.reader().count(), records.len());
+        wal.verify().unwrap();
+        drop(wal);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn invalid_segment_file_names() {
+        let dir = testing::temp_dir("wal_invalid_segment_file_names");
+        let opts = || Options::new(&dir, 1024 * 1024);
+        let mut wal = Wal::open(opts()).unwrap();
+        let first = wal.write(b"first").unwrap();
+        drop(wal);
+        // Without an index the directory is listed.
+        std::fs::remove_file(dir.join(crate::manifest::MANIFEST_FILE_NAME)).unwrap();
+        let junk = dir.join(format!("junk{}", SEGMENT_FILE_SUFFIX));
+        std::fs::write(&junk, b"not a segment").unwrap();
+        match Wal::open(opts()) {
+            Err(WalError::InvalidSegmentFileName { name }) => assert_eq!(name, junk),
+            other => panic!("unexpected {:?}", other.map(|_| ())),
+        }
+
+        // Read-only only reports it, writable moves it out of the way.
+        let quarantine = || opts().with_invalid_segment_names(InvalidSegmentNames::Quarantine);
+        let wal = Wal::open(quarantine().with_read_only(true)).unwrap();
+        assert!(matches!(
+            &wal.open_report().warnings[..],
+            [WalError::InvalidSegmentFileName { name }] if *name == junk
+        ));
+        assert!(junk.exists());
+        drop(wal);
+        let mut wal = Wal::open(quarantine()).unwrap();
+        assert_eq!(wal.open_report().warnings.len(), 1);
+        assert!(!junk.exists());
+        assert!(dir
+            .join(QUARANTINE_DIR)
+            .join(junk.file_name().unwrap())
+            .is_file());
+        let pos = wal.write(b"data").unwrap();
+        drop(wal);
+        let wal = Wal::open(opts()).unwrap();
+        assert_eq!(wal.read(first).unwrap(), b"first");
+        assert_eq!(wal.read(pos).unwrap(), b"data");
+        drop(wal);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn truncate_before() {
+        let dir = testing::temp_dir("wal_truncate_before");
+        let opts = || Options::new(&dir, 4 * BLOCK_SIZE as u64);
+        let mut wal = Wal::open(opts()).unwrap();
+        let mut records = testing::write_records(&mut wal, 0, 100, 3000);
+        let start = records
+            .iter()
+            .position(|record| record.pos.segment_id == INITIAL_SEGMENT_FILE_ID + 1)
+            .unwrap()
+            + 2;
+        let mut invalid = records[start].pos;
+        invalid.chunk_offset += 1;
+        assert!(wal.truncate_before(invalid).is_err());
+
+        wal.truncate_before(records[start].pos).unwrap();
+        records.drain(..start);
+        assert!(!segment::segment_file_path(&dir, INITIAL_SEGMENT_FILE_ID).exists());
+        let check = |wal: &Wal, records: &[testing::WrittenRecord]| {
+            assert_eq!(wal.first_position().unwrap(), Some(records[0].pos));
+            let read: Vec<_> = wal.reader().map(|record| record.unwrap().1).collect();
+            assert_eq!(read, records.iter().map(|r| r.pos).collect::<Vec<_>>());
+            let mut replayed = Vec::new();
+            wal.replay(|pos, _| {
+                replayed.push(pos);
+                Ok(ReplayControl::Continue)
+            })
+            .unwrap();
+            assert_eq!(replayed, read);
+        };
+        check(&wal, &records);
+        // An earlier start changes nothing.
+        wal.truncate_before(records[0].pos).unwrap();
+        drop(wal);
+
+        let mut wal = Wal::open(opts()).unwrap();
+        check(&wal, &records);
+        wal.truncate_before(wal.visible_position().unwrap())
+            .unwrap();
+        assert_eq!(wal.first_position().unwrap(), None);
+        assert_eq!(wal.reader().count(), 0);
+        let records = testing::write_records(&mut wal, 1, 10, 3000);
+        check(&wal, &records);
+        drop(wal);
+        let mut wal = Wal::open(opts()).unwrap();
+        check(&wal, &records);
+
+        // The next record can start after the end, in the next block or
+        // segment.
+        let first_id = wal.active_unchecked().id;
+        for i in 0..100 {
+            wal.truncate_before(wal.visible_position().unwrap())
+                .unwrap();
+            let pos = wal.write(&testing::payload(2, i, 5000)).unwrap();
+            assert_eq!(wal.first_position().unwrap(), Some(pos));
+        }
+        assert!(wal.active_unchecked().id > first_id + 1);
+        drop(wal);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn before_start() {
+        let dir = testing::temp_dir("wal_before_start");
+        let opts = |dir| Options::new(dir, 4 * BLOCK_SIZE as u64).with_entry_index(true);
+        let mut wal = Wal::open(opts(&dir)).unwrap();
+        let mut records = testing::write_records(&mut wal, 0, 100, 3000);
+        let start = records
+            .iter()
+            .position(|record| record.pos.segment_id == INITIAL_SEGMENT_FILE_ID + 1)
+            .unwrap()
+            + 5;
+        wal.truncate_before(records[start].pos).unwrap();
+        let discarded = records.drain(..start).next_back().unwrap();
+        assert_eq!(discarded.pos.segment_id, records[0].pos.segment_id);
+
+        // Left in the start's segment, the records before it are gone all the
+        // same.
+        assert!(matches!(
+            wal.read(discarded.pos),
+            Err(WalError::BeforeStart { .. })
+        ));
+        let block = wal
+            .read_block_records(discarded.pos.segment_id, discarded.pos.block_number)
+            .unwrap();
+        assert!(block.iter().all(|(pos, _)| *pos >= records[0].pos));
+        let first = wal.first_index().unwrap().unwrap();
+        assert_eq!(first, start as u64);
+        assert!(matches!(
+            wal.read_index(first - 1),
+            Err(WalError::NoSuchEntry { .. })
+        ));
+        assert_eq!(wal.read_index(first).unwrap(), records[0].data);
+        assert_eq!(wal.lag(None).unwrap().records, records.len() as u64);
+
+        // Forks and archives start at the same record.
+        let check = |copy: &Wal| {
+            assert_eq!(copy.first_position().unwrap(), Some(records[0].pos));
+            assert_eq!(copy.reader().count(), records.len());
+            assert_eq!(copy.first_index().unwrap(), Some(first));
+            assert!(copy.read(discarded.pos).is_err());
+        };
+        let fork_dir = testing::temp_dir("wal_before_start_fork");
+        check(&wal.fork_to(&fork_dir).unwrap());
+        let copy_dir = testing::temp_dir("wal_before_start_archive");
+        let mut archive = Vec::new();
+        wal.export_archive(0..=u32::MAX, &mut archive).unwrap();
+        Wal::import_archive(&archive[..], &copy_dir).unwrap();
+        check(&Wal::open(opts(&copy_dir)).unwrap());
+        drop(wal);
+        std::fs::remove_dir_all(dir).unwrap();
+        std::fs::remove_dir_all(fork_dir).unwrap();
+        std::fs::remove_dir_all(copy_dir).unwrap();
+    }
+
+    #[test]
+    fn entry_index() {
+        let dir = testing::temp_dir("wal_entry_index");
+        let opts = || Options::new(&dir, 4 * BLOCK_SIZE as u64).with_entry_index(true);
+        let mut wal = Wal::open(opts()).unwrap();
+        assert_eq!(wal.first_index().unwrap(), None);
+        assert_eq!(wal.last_index().unwrap(), None);
+        let written = |wal: &mut Wal, writer, count| {
+            testing::write_records(wal, writer, count, 3000)
+                .into_iter()
+                .map(|record| (record.pos, record.data))
+        };
+        let mut records: Vec<_> = written(&mut wal, 0, 100).collect();
+        let batch: Vec<Vec<u8>> = (100..110).map(|i| testing::payload(0, i, 3000)).collect();
+        let refs: Vec<&[u8]> = batch.iter().map(|r| &r[..]).collect();
+        let positions = wal.write_batch(&refs).unwrap();
+        records.extend(positions.into_iter().zip(batch));
+        assert!(wal.segments().len() > 2);
+        let check = |wal: &Wal, records: &[(ChunkPosition, Vec<u8>)], first: u64| {
+            for (i, (pos, data)) in (first..).zip(records) {
+                assert_eq!(wal.index_of(*pos).unwrap(), Some(i));
+                assert_eq!(wal.position_of_index(i).unwrap(), *pos);
+                assert_eq!(&wal.read_index(i).unwrap(), data);
+            }
+            assert_eq!(wal.first_index().unwrap(), Some(first));
+            assert_eq!(
+                wal.last_index().unwrap(),
+                Some(first + records.len() as u64 - 1)
+            );
+        };
+        check(&wal, &records, 0);
+        assert!(matches!(
+            wal.read_index(110),
+            Err(WalError::NoSuchEntry { index: 110 })
+        ));
+
+        // Numbers stay the same once the segments before are gone, and
+        // across reopens.
+        let second = wal.segments()[1].segment_id;
+        let kept = records.iter().position(|(pos, _)| pos.segment_id == second);
+        let kept = kept.unwrap();
+        wal.purge_before(records[kept].0).unwrap();
+        check(&wal, &records[kept..], kept as u64);
+        assert!(matches!(
+            wal.read_index(0),
+            Err(WalError::NoSuchEntry { index: 0 })
+        ));
+        drop(wal);
+        let mut wal = Wal::open(opts()).unwrap();
+        check(&wal, &records[kept..], kept as u64);
+
+        // Truncating renumbers what is written after.
+        wal.truncate_after(records[kept + 5].0).unwrap();
+        records.truncate(kept + 6);
+        records.extend(written(&mut wal, 1, 30));
+        check(&wal, &records[kept..], kept as u64);
+        drop(wal);
+        let wal = Wal::open(opts()).unwrap();
+        check(&wal, &records[kept..], kept as u64);
+        drop(wal);
+
+        let wal = Wal::open(Options::new(&dir, 4 * BLOCK_SIZE as u64)).unwrap();
+        assert!(matches!(
+            wal.read_index(kept as u64),
+            Err(WalError::EntryIndexDisabled)
+        ));
+        drop(wal);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn effective_options() {
+        let dir = testing::temp_dir("wal_effective_options");
+        let wal = Wal::open(Options::new(&dir, 8 * 4096).with_block_size(4096)).unwrap();
+        drop(wal);
+        let wal = Wal::open(Options::new(&dir, 8 * 4096).with_sync_policy(SyncPolicy::EveryWrite))
+            .unwrap();
+        assert_eq!(wal.options().block_len(), 4096);
+        let dump = format!("{:?}", wal.options());
+        assert!(dump.contains("block_size: 4096"), "{dump}");
+        assert!(dump.contains("preallocation: None"), "{dump}");
+        assert!(dump.contains("sync_policy: EveryWrite"), "{dump}");
+        drop(wal);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn open_file() {
+        let dir = testing::temp_dir("wal_open_file");
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("log");
+        let open = || {
+            let file = std::fs::File::options()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(&path)
+                .unwrap();
+            Wal::open_file(file, Options::new(&path, 4 * BLOCK_SIZE as u64))
+        };
+        let mut wal = open().unwrap();
+        let mut records = Vec::new();
+        loop {
+            let data = testing::payload(0, records.len(), 3000);
+            match wal.write(&data) {
+                Ok(pos) => records.push(testing::WrittenRecord {
+                    writer: 0,
+                    seq: records.len(),
+                    pos,
+                    data,
+                }),
+                Err(WalError::LogFull) => break,
+                Err(e) => panic!("{e}"),
+            }
+        }
+        assert!(records.len() > 30);
+        assert!(records
+            .iter()
+            .all(|r| r.pos.segment_id == INITIAL_SEGMENT_FILE_ID));
+        assert!(matches!(
+            wal.truncate_before(records[1].pos),
+            Err(WalError::NoDirectory { .. })
+        ));
+        wal.truncate_after(records[9].pos).unwrap();
+        records.truncate(10);
+        records.extend(testing::write_records(&mut wal, 1, 5, 3000));
+        drop(wal);
+
+        let wal = open().unwrap();
+        testing::assert_read_back(&wal, &records);
+        assert_eq!(wal.reader().count(), records.len());
+        drop(wal);
+        let entries: Vec<_> = std::fs::read_dir(&dir).unwrap().collect();
+        assert_eq!(entries.len(), 1);
+
+        let file = std::fs::File::open(&path).unwrap();
+        let opts = Options::new(&path, 4 * BLOCK_SIZE as u64)
+            .with_retention(RetentionPolicy::default().with_max_segments(1));
+        assert!(matches!(
+            Wal::open_file(file, opts),
+            Err(WalError::InvalidOptions(_))
+        ));
+
+        // A device is refused, its length is no end of the log.
+        let device = std::fs::File::options()
+            .read(true)
+            .write(true)
+            .open("/dev/null")
+            .unwrap();
+        assert!(matches!(
+            Wal::open_file(device, Options::new("/dev/null", 4 * BLOCK_SIZE as u64)),
+            Err(WalError::NotARegularFile { .. })
+        ));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn remaining_capacity() {
+        for trailers in [false, true] {
+            let dir = testing::temp_dir("wal_remaining_capacity");
+            let segment_size = 3 * BLOCK_SIZE as u64 + 100;
+            let opts = Options::new(&dir, segment_size).with_block_trailers(trailers);
+            let mut wal = Wal::open(opts).unwrap();
+            for len in [0, 10, BLOCK_SIZE as usize - 30, 5000] {
+                wal.write(&vec![1; len]).unwrap();
+                let remaining = wal.active_segment_remaining_capacity();
+                // Exactly that much fills the segment, one more byte doesn't fit.
+                for (len, fits) in [(remaining, true), (remaining + 1, false)] {
+                    let mut probe = wal.fork_to(dir.join("probe")).unwrap();
+                    let pos = probe.write(&vec![2; len as usize]).unwrap();
+                    let size = probe.active_unchecked().size();
+                    assert_eq!(pos.segment_id == 1 && size <= segment_size, fits);
+                    if fits {
+                        assert_eq!(probe.active_segment_remaining_capacity(), 0);
+                    }
+                    drop(probe);
+                    std::fs::remove_dir_all(dir.join("probe")).unwrap();
+                }
+            }
+            drop(wal);
+            std::fs::remove_dir_all(dir).unwrap();
+        }
+    }
+
+    #[test]
+    fn write_iter() {
+        let dir = testing::temp_dir("wal_write_iter");
+        let mut wal = Wal::open(Options::new(&dir, 4 * BLOCK_SIZE as u64)).unwrap();
+        let payloads: Vec<Vec<u8>> = (0..100).map(|i| testing::payload(0, i, 3000)).collect();
+        // Borrowed items, yielded lazily.
+        let positions = wal.write_iter(payloads.iter().map(|p| &p[..])).unwrap();
+        assert!(positions.last().unwrap().segment_id > 1);
+        for (pos, payload) in positions.iter().zip(&payloads) {
+            assert_eq!(wal.read(*pos).unwrap(), *payload);
+        }
+        assert_eq!(wal.write_iter(Vec::<Vec<u8>>::new()).unwrap(), []);
+
+        let guard = wal.freeze().unwrap();
+        assert!(matches!(wal.write_iter([b"frozen"]), Err(WalError::Frozen)));
+        drop(guard);
+        drop(wal);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn read_stats() {
+        let dir = testing::temp_dir("wal_read_stats");
+        let clock = testing::ManualClock::new();
+        let opts = || {
+            Options::new(&dir, 4 * BLOCK_SIZE as u64)
+                .with_clock(clock.clone())
+                .with_read_stats_interval(std::time::Duration::from_secs(60))
+        };
+        let mut wal = Wal::open(opts()).unwrap();
+        let records = testing::write_records(&mut wal, 0, 100, 3000);
+        let (cold, hot) = (records[0].pos, records[99].pos);
+        assert!(hot.segment_id > cold.segment_id);
+        assert!(wal
+            .read_stats()
+            .iter()
+            .all(|s| s.reads == 0 && s.last_read.is_none()));
+
+        wal.read(cold).unwrap();
+        clock.advance(std::time::Duration::from_secs(30));
+        for _ in 0..3 {
+            wal.read(hot).unwrap();
+        }
+        wal.read_block(hot.block_id()).unwrap();
+        let stats = |wal: &Wal, id| {
+            *wal.read_stats()
+                .iter()
+                .find(|s| s.segment_id == id)
+                .unwrap()
+        };
+        assert_eq!(stats(&wal, hot.segment_id).reads, 4);
+        assert_eq!(stats(&wal, cold.segment_id).reads, 1);
+        assert_eq!(
+            stats(&wal, hot.segment_id)
+                .last_read
+                .unwrap()
+                .duration_since(stats(&wal, cold.segment_id).last_read.unwrap())
+                .unwrap(),
+            std::time::Duration::from_secs(30)
+        );
+        // Not persisted before the interval has passed.
+        assert!(!dir.join(stats::READ_STATS_FILE_NAME).exists());
+        clock.advance(std::time::Duration::from_secs(30));
+        wal.read(hot).unwrap();
+        let persisted = wal.read_stats();
+        drop(wal);
+
+        let wal = Wal::open(opts()).unwrap();
+        assert_eq!(wal.read_stats(), persisted);
+        assert_eq!(stats(&wal, hot.segment_id).reads, 5);
+        drop(wal);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn read_only() {
+        let dir = testing::temp_dir("wal_read_only");
+        let sealed_dir = dir.join("sealed");
+        let opts = || Options::new(&dir, 4 * BLOCK_SIZE as u64).with_sealed_dir(&sealed_dir);
+        let mut wal = Wal::open(opts()).unwrap();
+        let records = testing::write_records(&mut wal, 0, 100, 3000);
+        drop(wal);
+        let snapshot = || {
+            let mut files = Vec::new();
+            for dir in [&dir, &sealed_dir] {
+                for entry in std::fs::read_dir(dir).unwrap() {
+                    let path = entry.unwrap().path();
+                    let meta = std::fs::metadata(&path).unwrap();
+                    files.push((
+                        path,
+                        meta.len(),
+                        meta.modified().unwrap(),
+                        meta.permissions(),
+                    ));
+                }
+            }
+            files.sort_by(|a, b| a.0.cmp(&b.0));
+            files
+        };
+
+        // An archive holding only the sealed segments.
+        let active_id = records.last().unwrap().pos.segment_id;
+        let active = records
+            .iter()
+            .position(|r| r.pos.segment_id == active_id)
+            .unwrap();
+        std::fs::remove_file(segment::segment_file_path(&dir, active_id)).unwrap();
+        let before = snapshot();
+        let mut wal = Wal::open(
+            opts()
+                .with_read_only(true)
+                .with_file_permissions(Some(0o600)),
+        )
+        .unwrap();
+        testing::assert_read_back(&wal, &records[..active]);
+        assert!(matches!(wal.write(b"data"), Err(WalError::ReadOnly)));
+        assert!(matches!(wal.persist_read_stats(), Err(WalError::ReadOnly)));
+        drop(wal);
+        assert_eq!(snapshot(), before);
+
+        // A missing Wal isn't created.
+        let missing = dir.join("missing");
+        assert!(Wal::open(Options::new(&missing, 1024 * 1024).with_read_only(true)).is_err());
+        assert!(!missing.exists());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn corrupted_positions_and_chunks_are_errors() {
+        let dir = testing::temp_dir("wal_no_panics");
+        let opts = Options::new(&dir, 1024 * 1024).with_debug_assertions_as_errors(true);
+        let mut wal = Wal::open(opts).unwrap();
+        let pos = wal.write(b"record").unwrap();
+        let far = ChunkPosition {
+            block_number: u32::MAX,
+            ..pos
+        };
+        assert!(matches!(
+            wal.read(far),
+            Err(WalError::IncompleteRecord { .. })
+        ));
+        assert!(matches!(
+            wal.check_invariant(false, "test"),
+            Err(WalError::InvariantViolated("test"))
+        ));
+        drop(wal);
+
+        // A chunk type which doesn't exist, under a matching checksum.
+        let path = segment::segment_file_path(&dir, pos.segment_id);
+        let mut bytes = std::fs::read(&path).unwrap();
+        bytes[6] = 9;
+        let crc = crc32fast::hash(&bytes[4..CHUNK_HEADER_SIZE as usize + 6]);
+        bytes[..4].copy_from_slice(&crc.to_le_bytes());
+        std::fs::write(&path, bytes).unwrap();
+        let wal = Wal::open(Options::new(&dir, 1024 * 1024)).unwrap();
+        assert!(matches!(
+            wal.read(pos),
+            Err(WalError::InvalidChunkType { chunk_type: 9, .. })
+        ));
+        assert!(matches!(
+            wal.read_block(pos.block_id()),
+            Err(WalError::InvalidChunkType { .. })
+        ));
+        drop(wal);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn positions_without_a_record_are_invalid() {
+        let dir = testing::temp_dir("wal_invalid_position");
+        let mut wal = Wal::open(Options::new(&dir, 1024 * 1024)).unwrap();
+        // Leaves 3 bytes of padding at the end of block 0.
+        let first = wal.write(&[1; BLOCK_SIZE as usize - 10]).unwrap();
+        let spanning = wal.write(&[2; BLOCK_SIZE as usize + 100]).unwrap();
+        assert_eq!((spanning.block_number, spanning.chunk_offset), (1, 0));
+        let invalid = |block_number, chunk_offset| {
+            let pos = ChunkPosition {
+                segment_id: first.segment_id,
+                block_number,
+                chunk_offset,
+            };
+            matches!(wal.read(pos), Err(WalError::InvalidPosition { .. }))
+        };
+        assert!(invalid(0, BLOCK_SIZE as u64 - 3));
+        // The last chunk of the spanning record.
+        assert!(invalid(2, 0));
+
+        // Ranges step over the padding.
+        let end = wal.write(b"end").unwrap();
+        let page = wal.read_range(first..=end, ReadLimits::default()).unwrap();
+        let positions: Vec<_> = page.records.iter().map(|(pos, _)| *pos).collect();
+        assert_eq!(positions, [first, spanning, end]);
+        drop(wal);
+
+        // A zeroed header, e.g. of a hole punched into the segment.
+        let path = segment::segment_file_path(&dir, first.segment_id);
+        let mut bytes = std::fs::read(&path).unwrap();
+        bytes[..CHUNK_HEADER_SIZE as usize].fill(0);
+        std::fs::write(&path, bytes).unwrap();
+        let wal = Wal::open(Options::new(&dir, 1024 * 1024)).unwrap();
+        assert!(matches!(
+            wal.read(first),
+            Err(WalError::InvalidPosition { .. })
+        ));
+        drop(wal);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn verify() {
+        for trailers in [false, true] {
+            let dir = testing::temp_dir("wal_verify");
+            let opts = || Options::new(&dir, 64 * BLOCK_SIZE as u64).with_block_trailers(trailers);
+            let mut wal = Wal::open(opts()).unwrap();
+            let records = testing::write_records(&mut wal, 0, 300, 20_000);
+            let report = wal.verify().unwrap();
+            let last_id = records.last().unwrap().pos.segment_id;
+            assert_eq!(report.segments, last_id as u64);
+            assert!(report.chunks >= records.len() as u64);
+            let total: u64 = wal.older_segments.values().map(|seg| seg.size()).sum();
+            assert_eq!(report.bytes, total + wal.active_unchecked().size());
+            drop(wal);
+
+            // Flip a byte in the data of a record in the middle of the log.
+            let corrupted = records[150].pos;
+            let path = segment::segment_file_path(&dir, corrupted.segment_id);
+            let mut bytes = std::fs::read(&path).unwrap();
+            let offset = corrupted.block_number as usize * BLOCK_SIZE as usize
+                + corrupted.chunk_offset as usize
+                + CHUNK_HEADER_SIZE as usize;
+            bytes[offset] ^= 0xff;
+            std::fs::write(&path, bytes).unwrap();
+            let wal = Wal::open(opts()).unwrap();
+            match wal.verify() {
+                Err(WalError::InvalidCrc {
+                    segment_id,
+                    block_number,
+                    offset,
+                }) => assert_eq!(
+                    (segment_id, block_number, offset),
+                    (
+                        corrupted.segment_id,
+                        corrupted.block_number,
+                        corrupted.chunk_offset
+                    )
+                ),
+                other => panic!("expected an invalid crc, got {:?}", other),
+            }
+            drop(wal);
+            std::fs::remove_dir_all(dir).unwrap();
+        }
+    }
+
+    #[test]
+    fn open_verification() {
+        let dir = testing::temp_dir("wal_open_verification");
+        let opts = |verification| {
+            Options::new(&dir, 16 * BLOCK_SIZE as u64).with_open_verification(verification)
+        };
+        let mut wal = Wal::open(opts(OpenVerification::None)).unwrap();
+        let records = testing::write_records(&mut wal, 0, 200, 5_000);
+        assert!(wal.open_report().verified.is_none());
+        drop(wal);
+
+        let wal = Wal::open(opts(OpenVerification::Full)).unwrap();
+        let full = wal.open_report().verified.unwrap();
+        assert!(wal.open_report().warnings.is_empty());
+        assert_eq!(full, wal.verify().unwrap());
+        drop(wal);
+        assert!(!dir.join(manifest::IDENTITY_FILE_NAME).exists());
+
+        // Every open samples about a quarter of the blocks, not the same ones.
+        let sampled = |wal: &Wal| wal.open_report().verified.unwrap();
+        let mut blocks = Vec::new();
+        for _ in 0..4 {
+            let wal = Wal::open(opts(OpenVerification::Sampled { one_in: 4 })).unwrap();
+            assert_eq!(sampled(&wal).segments, full.segments);
+            assert!(sampled(&wal).blocks < full.blocks);
+            blocks.push(sampled(&wal));
+        }
+        // A single open may sample many more, four together hardly do.
+        let total: u64 = blocks.iter().map(|report| report.blocks).sum();
+        assert!(total < 2 * full.blocks);
+        assert!(blocks.windows(2).any(|w| w[0] != w[1]));
+        let (_, opens) = manifest::load_identity(&dir).unwrap().unwrap();
+        assert_eq!(opens, 4);
+        let wal = Wal::open(opts(OpenVerification::Sampled { one_in: 4 }).with_read_only(true));
+        drop(wal.unwrap());
+        assert_eq!(manifest::load_identity(&dir).unwrap().unwrap().1, 4);
+
+        // A corrupted block doesn't fail the open but is reported.
+        let corrupted = records[100].pos;
+        let path = segment::segment_file_path(&dir, corrupted.segment_id);
+        let mut bytes = std::fs::read(&path).unwrap();
+        let offset = corrupted.block_number as usize * BLOCK_SIZE as usize
+            + corrupted.chunk_offset as usize
+            + CHUNK_HEADER_SIZE as usize;
+        bytes[offset] ^= 0xff;
+        std::fs::write(&path, bytes).unwrap();
+        let wal = Wal::open(opts(OpenVerification::Full)).unwrap();
+        assert!(matches!(
+            &wal.open_report().warnings[..],
+            [WalError::InvalidCrc { segment_id, .. }] if *segment_id == corrupted.segment_id
+        ));
+        assert!(sampled(&wal).blocks < full.blocks);
+        drop(wal);
+
+        let invalid = Wal::open(opts(OpenVerification::Sampled { one_in: 0 }));
+        assert!(matches!(invalid, Err(WalError::InvalidOptions(_))));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn staged_records() {
+        let dir = testing::temp_dir("wal_staged");
+        let mut wal = Wal::open(Options::new(&dir, 4 * BLOCK_SIZE as u64)).unwrap();
+        let first = wal.write(b"before").unwrap();
+        wal.stage(b"discarded");
+        wal.discard_staged();
+        assert_eq!(wal.staged(), 0);
+        assert!(wal.commit_staged().unwrap().is_empty());
+
+        let records: Vec<Vec<u8>> = (0..20).map(|i| testing::payload(0, i, 5_000)).collect();
+        for data in &records {
+            wal.stage(data);
+        }
+        assert_eq!(wal.staged(), records.len());
+        assert_eq!(wal.reader().count(), 1);
+        let positions = wal.commit_staged().unwrap();
+        assert_eq!(wal.staged(), 0);
+        // Written as one batch, in one segment.
+        assert!(positions
+            .iter()
+            .all(|pos| pos.segment_id == first.segment_id));
+        for (pos, data) in positions.iter().zip(&records) {
+            assert_eq!(&wal.read(*pos).unwrap(), data);
+        }
+
+        // Records stay staged when committing them fails.
+        wal.stage(b"retried");
+        let guard = wal.freeze().unwrap();
+        assert!(matches!(wal.commit_staged(), Err(WalError::Frozen)));
+        assert_eq!(wal.staged(), 1);
+        drop(guard);
+        let retried = wal.commit_staged().unwrap();
+        assert_eq!(wal.read(retried[0]).unwrap(), b"retried");
+        drop(wal);
+
+        let wal = Wal::open(Options::new(&dir, 4 * BLOCK_SIZE as u64)).unwrap();
+        assert_eq!(wal.reader().count(), records.len() + 2);
+        drop(wal);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn close_and_delete() {
+        let parent = testing::temp_dir("wal_close");
+        let dir = parent.join("wal");
+        let sealed_dir = parent.join("sealed");
+        let opts = || {
+            Options::new(&dir, 4 * BLOCK_SIZE as u64)
+                .with_sealed_dir(&sealed_dir)
+                .with_entry_index(true)
+                .with_read_stats_interval(std::time::Duration::from_secs(3600))
+                .with_open_verification(OpenVerification::Sampled { one_in: 2 })
+        };
+        let mut wal = Wal::open(opts()).unwrap();
+        let records = testing::write_records(&mut wal, 0, 50, 10_000);
+        wal.truncate_before(records[1].pos).unwrap();
+        wal.read(records[20].pos).unwrap();
+        assert!(!wal.unsynced_segments().is_empty());
+        wal.close().unwrap();
+        assert!(dir.join(stats::READ_STATS_FILE_NAME).exists());
+
+        let wal = Wal::open(opts()).unwrap();
+        assert!(wal.unsynced_segments().is_empty());
+        testing::assert_read_back(&wal, &records[1..]);
+        let read_only = Wal::open(opts().with_read_only(true)).unwrap();
+        assert!(matches!(read_only.delete(), Err(WalError::ReadOnly)));
+        wal.delete().unwrap();
+        assert!(!dir.exists() && !sealed_dir.exists());
+
+        // A file the Wal doesn't know about keeps its directory.
+        let wal = Wal::open(opts()).unwrap();
+        std::fs::write(dir.join("other"), b"").unwrap();
+        assert!(matches!(wal.delete(), Err(WalError::Io { .. })));
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+        std::fs::remove_dir_all(parent).unwrap();
+    }
+
+    #[test]
+    fn min_sealed_size() {
+        let dir = testing::temp_dir("wal_min_sealed_size");
+        let segment_size = 4 * BLOCK_SIZE as u64;
+        let big: Vec<Vec<u8>> = (0..10).map(|i| testing::payload(1, i, 15_000)).collect();
+        let big: Vec<&[u8]> = big.iter().map(|data| &data[..]).collect();
+        for min_sealed_size in [0, segment_size / 2] {
+            let opts = Options::new(&dir, segment_size).with_min_sealed_size(min_sealed_size);
+            let mut wal = Wal::open(opts).unwrap();
+            let first = wal.write(&testing::payload(0, 0, 1000)).unwrap();
+            let batch = wal.write_batch(&big).unwrap();
+            let large = wal.write(&testing::payload(0, 1, 100_000)).unwrap();
+            if min_sealed_size == 0 {
+                // The first segment was sealed with a single small record.
+                assert_eq!(batch[0].segment_id, first.segment_id + 1);
+                assert_eq!(large.segment_id, first.segment_id + 2);
+            } else {
+                // It took the batch too, past the segment size, then was sealed.
+                assert!(batch.iter().all(|pos| pos.segment_id == first.segment_id));
+                assert!(wal.active_unchecked().size() > 0);
+                assert_eq!(large.segment_id, first.segment_id + 1);
+                let sealed = &wal.older_segments[&first.segment_id];
+                assert!(sealed.size() > segment_size);
+            }
+            drop(wal);
+            std::fs::remove_dir_all(&dir).unwrap();
+        }
+
+        let opts = Options::new(&dir, segment_size).with_min_sealed_size(segment_size + 1);
+        assert!(matches!(Wal::open(opts), Err(WalError::InvalidOptions(_))));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn directory_lock() {
+        let dir = testing::temp_dir("wal_lock");
+        let opts = || Options::new(&dir, 4 * BLOCK_SIZE as u64);
+        let mut wal = Wal::open(opts()).unwrap();
+        let pos = wal.write(b"locked").unwrap();
+        match Wal::open(opts()) {
+            Err(WalError::DirectoryLocked { path }) => assert_eq!(path, dir),
+            other => panic!("expected a locked directory, got {:?}", other.err()),
+        }
+        // Readers don't take the lock.
+        let reader = Wal::open(opts().with_read_only(true)).unwrap();
+        assert_eq!(reader.read(pos).unwrap(), b"locked");
+        drop(reader);
+
+        wal.close().unwrap();
+        let wal = Wal::open(opts()).unwrap();
+        drop(wal);
+        drop(Wal::open(opts()).unwrap());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn prefetch_next_segment() {
+        let dir = testing::temp_dir("wal_prefetch");
+        let segment_size = 4 * BLOCK_SIZE as u64;
+        let opts = || {
+            Options::new(&dir, segment_size)
+                .with_block_cache_bytes(1 << 20)
+                .with_prefetch_bytes(2 * BLOCK_SIZE as u64)
+        };
+        let mut wal = Wal::open(opts()).unwrap();
+        let records = testing::write_records(&mut wal, 0, 120, 5000);
+        let last_id = records.last().unwrap().pos.segment_id;
+        assert!(last_id >= 4);
+        let cached = |wal: &Wal, segment_id, block_number| {
+            let cache = wal.active_unchecked().block_cache().unwrap();
+            let block = cache.lock().unwrap().get(BlockId {
+                segment_id,
+                block_number,
+            });
+            block
+        };
+        let wait_for = |wal: &Wal, segment_id, block_number| {
+            let deadline = std::time::Instant::now() + std::time::Duration::from_secs(10);
+            while cached(wal, segment_id, block_number).is_none() {
+                assert!(std::time::Instant::now() < deadline, "not prefetched");
+                std::thread::sleep(std::time::Duration::from_millis(1));
+            }
+        };
+
+        // Reading to the end of the first segment prefetches the start of the
+        // second, not yet read by the scan.
+        let first = records[0].pos.segment_id;
+        let in_first = records.iter().filter(|r| r.pos.segment_id == first).count();
+        let mut reader = wal.reader();
+        for record in records.iter().take(in_first) {
+            assert_eq!(reader.next().unwrap().unwrap().0, record.data);
+        }
+        wait_for(&wal, first + 1, 0);
+        wait_for(&wal, first + 1, 1);
+        assert!(cached(&wal, first + 1, 2).is_none());
+        assert!(cached(&wal, first + 2, 0).is_none());
+        let rest: Vec<_> = reader.map(|record| record.unwrap().0).collect();
+        assert_eq!(rest.len(), records.len() - in_first);
+        wal.close().unwrap();
+
+        // Replays don't go through the block cache, only the prefetches do.
+        let wal = Wal::open(opts()).unwrap();
+        let mut replayed = 0;
+        wal.replay(|_, _| {
+            replayed += 1;
+            Ok(ReplayControl::Continue)
+        })
+        .unwrap();
+        assert_eq!(replayed, records.len());
+        for segment_id in first + 1..last_id {
+            wait_for(&wal, segment_id, 0);
+            assert!(cached(&wal, segment_id, 2).is_none());
+        }
+        drop(wal);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn prefetch_threads_joined_on_drop() {
+        let dir = testing::temp_dir("wal_prefetch_join");
+        let mut wal = Wal::open(
+            Options::new(&dir, 4 * BLOCK_SIZE as u64).with_prefetch_bytes(2 * BLOCK_SIZE as u64),
+        )
+        .unwrap();
+        let records = testing::write_records(&mut wal, 0, 120, 5000);
+        let first = records[0].pos.segment_id;
+        let next = wal.older_segments[&(first + 1)].clone();
+
+        // Reading to the end of the first segment prefetches the second, on a
+        // thread named after it.
+        let in_first = records.iter().filter(|r| r.pos.segment_id == first).count();
+        for record in wal.reader().take(in_first) {
+            record.unwrap();
+        }
+        let names: Vec<_> = wal
+            .prefetches
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|handle| handle.thread().name().map(str::to_owned))
+            .collect();
+        assert_eq!(names, [Some(format!("wal-prefetch-{}", first + 1))]);
+
+        // Dropping the Wal waits for the thread, which held the segment.
+        drop(wal);
+        assert_eq!(Arc::strong_count(&next), 1);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn spill_threshold() {
+        let dir = testing::temp_dir("wal_spill_fast");
+        let sealed_dir = testing::temp_dir("wal_spill_durable");
+        let segment_size = 4 * BLOCK_SIZE as u64;
+        let opts = || {
+            Options::new(&dir, segment_size)
+                .with_sealed_dir(&sealed_dir)
+                .with_spill_threshold(2 * segment_size)
+        };
+        let local_sealed_bytes = |wal: &Wal| -> u64 {
+            let active_id = wal.active_unchecked().id;
+            list_segment_ids(&dir)
+                .unwrap()
+                .into_iter()
+                .filter(|&id| id != active_id)
+                .map(|id| wal.older_segments[&id].size())
+                .sum()
+        };
+        let mut wal = Wal::open(opts()).unwrap();
+        let records = testing::write_records(&mut wal, 0, 200, 3000);
+        let local = local_sealed_bytes(&wal);
+        assert!(local > segment_size && local <= 2 * segment_size);
+        // The oldest segments were spilled.
+        let spilled = list_segment_ids(&sealed_dir).unwrap();
+        assert!(spilled.contains(&1) && !spilled.is_empty());
+        assert!(list_segment_ids(&dir)
+            .unwrap()
+            .iter()
+            .all(|id| !spilled.contains(id)));
+        testing::assert_read_back(&wal, &records);
+        drop(wal);
+
+        // Reopening leaves the recent sealed segments where they are.
+        let wal = Wal::open(opts()).unwrap();
+        assert_eq!(local_sealed_bytes(&wal), local);
+        testing::assert_read_back(&wal, &records);
+        drop(wal);
+        std::fs::remove_dir_all(dir).unwrap();
+        std::fs::remove_dir_all(sealed_dir).unwrap();
+    }
+
+    #[test]
+    fn record_versions() {
+        let dir = testing::temp_dir("wal_record_versions");
+        let opts = || Options::new(&dir, 4 * BLOCK_SIZE as u64).with_record_versions(true);
+        let mut wal = Wal::open(opts()).unwrap();
+        let mut written = Vec::new();
+        for i in 0..60 {
+            let version = (i % 3) as u8;
+            let data = testing::payload(0, i, 3000);
+            let pos = wal
+                .write_with(&data, &WriteOptions::default().with_version(version))
+                .unwrap();
+            written.push((pos, version, data));
+        }
+        let empty = wal.write(&[]).unwrap();
+        for (pos, version, data) in &written {
+            assert_eq!(
+                wal.read_with_version(*pos).unwrap(),
+                (*version, data.clone())
+            );
+            assert_eq!(wal.read(*pos).unwrap(), *data);
+        }
+        assert_eq!(wal.read_with_version(empty).unwrap(), (0, Vec::new()));
+        let mut replayed = Vec::new();
+        wal.replay(|_, data| {
+            replayed.push(data.to_vec());
+            Ok(ReplayControl::Continue)
+        })
+        .unwrap();
+        assert_eq!(replayed.len(), written.len() + 1);
+        assert!(replayed
+            .iter()
+            .zip(&written)
+            .all(|(r, (_, _, data))| r == data));
+
+        // Copies keep the versions.
+        let copy_dir = testing::temp_dir("wal_record_versions_copy");
+        let mut copy =
+            Wal::open(Options::new(&copy_dir, 1024 * 1024).with_record_versions(true)).unwrap();
+        let copied = wal
+            .copy_range(written[0].0..=written[5].0, &mut copy)
+            .unwrap();
+        for (pos, (_, version, data)) in copied.iter().zip(&written) {
+            assert_eq!(
+                copy.read_with_version(*pos).unwrap(),
+                (*version, data.clone())
+            );
+        }
+        drop((wal, copy));
+        assert_eq!(
+            Wal::open(opts())
+                .unwrap()
+                .read_with_version(written[1].0)
+                .unwrap()
+                .0,
+            1
+        );
+        assert!(matches!(
+            Wal::open(Options::new(&dir, 4 * BLOCK_SIZE as u64)),
+            Err(WalError::OptionsMismatch {
+                field: "record_versions",
+                ..
+            })
+        ));
+
+        // Without them, only version 0 can be written.
+        let mut plain = Wal::open(Options::new(copy_dir.join("plain"), 1024 * 1024)).unwrap();
+        assert!(matches!(
+            plain.write_with(b"data", &WriteOptions::default().with_version(1)),
+            Err(WalError::RecordVersionsDisabled)
+        ));
+        let pos = plain.write(b"data").unwrap();
+        assert_eq!(plain.read_with_version(pos).unwrap(), (0, b"data".to_vec()));
+        drop(plain);
+        std::fs::remove_dir_all(dir).unwrap();
+        std::fs::remove_dir_all(copy_dir).unwrap();
+    }
+
+    #[test]
+    fn read_block_records() {
+        let dir = testing::temp_dir("wal_read_block_records");
+        let opts = Options::new(&dir, 1024 * 1024).with_record_versions(true);
+        let mut wal = Wal::open(opts).unwrap();
+        let records = testing::write_records(&mut wal, 0, 20, 5_000);
+        let block = |block_number| wal.read_block_records(1, block_number).unwrap();
+        let mut read = Vec::new();
+        for block_number in 0..=records.last().unwrap().pos.block_number {
+            read.extend(block(block_number));
+        }
+        let written: Vec<_> = records.iter().map(|r| (r.pos, r.data.clone())).collect();
+        assert_eq!(read, written);
+        // Past the end.
+        assert_eq!(block(1000), []);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn sync_policy() {
+        let dir = testing::temp_dir("wal_sync_policy");
+        let clock = testing::ManualClock::new();
+        let open = |policy| {
+            let _ = std::fs::remove_dir_all(&dir);
+            let opts = Options::new(&dir, 1024 * 1024)
+                .with_clock(clock.clone())
+                .with_sync_policy(policy);
+            Wal::open(opts).unwrap()
+        };
+        let synced = |wal: &Wal| wal.unsynced_segments().is_empty();
+
+        let mut wal = open(SyncPolicy::Never);
+        wal.write(b"record").unwrap();
+        assert!(!synced(&wal));
+        wal.sync().unwrap();
+        assert!(synced(&wal));
+
+        let mut wal = open(SyncPolicy::EveryWrite);
+        wal.write(b"record").unwrap();
+        assert!(synced(&wal));
+
+        let mut wal = open(SyncPolicy::EveryNBytes(100));
+        wal.write(&[0; 60]).unwrap();
+        assert!(!synced(&wal));
+        wal.write(&[0; 60]).unwrap();
+        assert!(synced(&wal));
+        wal.write(&[0; 60]).unwrap();
+        assert!(!synced(&wal));
+
+        let mut wal = open(SyncPolicy::EveryInterval(std::time::Duration::from_secs(1)));
+        wal.write(b"record").unwrap();
+        assert!(!synced(&wal));
+        clock.advance(std::time::Duration::from_secs(1));
+        wal.write(b"record").unwrap();
+        assert!(synced(&wal));
+        wal.write(b"record").unwrap();
+        assert!(!synced(&wal));
+        drop(wal);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn write_buffer() {
+        let dir = testing::temp_dir("wal_write_buffer");
+        let opts = || Options::new(&dir, 1024 * 1024).with_write_buffer_size(64 * 1024);
+        let file_len = |wal: &Wal| wal.active_unchecked().metadata().unwrap().len();
+        let mut wal = Wal::open(opts()).unwrap();
+        let mut records = testing::write_records(&mut wal, 0, 10, 3000);
+        assert_eq!(file_len(&wal), 0);
+        assert_eq!(wal.unsynced_segments(), [1]);
+        wal.flush().unwrap();
+        assert_eq!(file_len(&wal), wal.active_unchecked().size());
+
+        // Records spanning blocks go out whole once the buffer is full.
+        records.extend(testing::write_records(
+            &mut wal,
+            1,
+            2,
+            3 * BLOCK_SIZE as usize,
+        ));
+        assert_eq!(file_len(&wal), wal.active_unchecked().size());
+        // Reads see records still held back.
+        records.extend(testing::write_records(&mut wal, 2, 3, 100));
+        assert!(file_len(&wal) < wal.active_unchecked().size());
+        testing::assert_read_back(&wal, &records);
+        assert_eq!(file_len(&wal), wal.active_unchecked().size());
+
+        // Dropping the Wal writes out the rest.
+        records.extend(testing::write_records(&mut wal, 3, 3, 100));
+        drop(wal);
+        let mut wal = Wal::open(opts().with_sync_policy(SyncPolicy::EveryWrite)).unwrap();
+        testing::assert_read_back(&wal, &records);
+        wal.write(b"record").unwrap();
+        assert_eq!(file_len(&wal), wal.active_unchecked().size());
+        drop(wal);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn write_buffer_copies() {
+        let dir = testing::temp_dir("wal_write_buffer_copies");
+        let fork_dir = testing::temp_dir("wal_write_buffer_fork");
+        let copy_dir = testing::temp_dir("wal_write_buffer_archive");
+        let opts = |dir| Options::new(dir, 1024 * 1024).with_write_buffer_size(64 * 1024);
+        let mut wal = Wal::open(opts(&dir)).unwrap();
+        let records = testing::write_records(&mut wal, 0, 10, 100);
+        assert_eq!(wal.active_unchecked().metadata().unwrap().len(), 0);
+
+        // Records held back in the write buffer are forked and exported too.
+        let mut archive = Vec::new();
+        wal.export_archive(0..=u32::MAX, &mut archive).unwrap();
+        Wal::import_archive(&archive[..], &copy_dir).unwrap();
+        testing::assert_read_back(&Wal::open(opts(&copy_dir)).unwrap(), &records);
+        let more = testing::write_records(&mut wal, 1, 10, 100);
+        let fork = wal.fork_to(&fork_dir).unwrap();
+        testing::assert_read_back(&fork, &records);
+        testing::assert_read_back(&fork, &more);
+        drop(fork);
+        drop(wal);
+        std::fs::remove_dir_all(dir).unwrap();
+        std::fs::remove_dir_all(fork_dir).unwrap();
+        std::fs::remove_dir_all(copy_dir).unwrap();
+    }
+
+    #[test]
+    fn max_batch_bytes() {
+        let dir = testing::temp_dir("wal_max_batch_bytes");
+        let records: Vec<Vec<u8>> = [100, 100, 250, 100, 100]
+            .iter()
+            .enumerate()
+            .map(|(i, len)| testing::payload(0, i, *len))
+            .collect();
+        let batch: Vec<&[u8]> = records.iter().map(|data| &data[..]).collect();
+
+        let opts =
+            Options::new(&dir, 1024 * 1024).with_max_batch_bytes(200, OversizedBatch::Reject);
+        let mut wal = Wal::open(opts).unwrap();
+        assert!(matches!(
+            wal.write_batch(&batch),
+            Err(WalError::BatchTooLarge {
+                bytes: 650,
+                max: 200
+            })
+        ));
+        // None of it was written.
+        assert_eq!(wal.reader().count(), 0);
+        assert_eq!(wal.write_batch(&batch[..2]).unwrap().len(), 2);
+        drop(wal);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let opts = Options::new(&dir, 1024 * 1024).with_max_batch_bytes(200, OversizedBatch::Split);
+        let mut wal = Wal::open(opts).unwrap();
+        // Written as [100, 100], [250] and [100, 100].
+        let positions = wal.write_batch(&batch).unwrap();
+        assert_eq!(positions.len(), records.len());
+        assert!(positions.windows(2).all(|w| w[0] < w[1]));
+        for (pos, data) in positions.iter().zip(&records) {
+            assert_eq!(&wal.read(*pos).unwrap(), data);
+        }
+        drop(wal);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn split_batch_partly_written() {
+        let dir = testing::temp_dir("wal_split_batch_partly_written");
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("log");
+        let file = std::fs::File::options()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)
+            .unwrap();
+        // A single segment, which holds the first part of the batch but not
+        // the second.
+        let opts = Options::new(&path, 4 * BLOCK_SIZE as u64)
+            .with_max_batch_bytes(100_000, OversizedBatch::Split);
+        let mut wal = Wal::open_file(file, opts).unwrap();
+        let records: Vec<Vec<u8>> = (0..3).map(|i| testing::payload(0, i, 80_000)).collect();
+        for data in &records {
+            wal.stage(data);
+        }
+        let Err(WalError::BatchPartlyWritten { written, source }) = wal.commit_staged() else {
+            panic!("the second part fits");
+        };
+        assert!(matches!(*source, WalError::LogFull));
+        assert_eq!(written.len(), 1);
+        assert_eq!(wal.read(written[0]).unwrap(), records[0]);
+        // Only what wasn't written stays staged, a retry doesn't write the
+        // first part again.
+        assert_eq!(wal.staged(), 2);
+        assert!(matches!(wal.commit_staged(), Err(WalError::LogFull)));
+        assert_eq!(wal.staged(), 2);
+        let log: Vec<Vec<u8>> = wal.reader().map(|record| record.unwrap().0).collect();
+        assert_eq!(log, records[..1]);
+        drop(wal);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn write_batch() {
+        let dir = testing::temp_dir("wal_write_batch");
+        let opts = || Options::new(&dir, 4 * BLOCK_SIZE as u64);
+        let mut wal = Wal::open(opts()).unwrap();
+        let before = wal.write(b"before").unwrap();
+        let big = vec![7; BLOCK_SIZE as usize + 100];
+        let batch: [&[u8]; 3] = [b"first", &big, b"last"];
+        let positions = wal.write_batch(&batch).unwrap();
+        for (pos, data) in positions.iter().zip(batch) {
+            assert_eq!(wal.read(*pos).unwrap(), data);
+        }
+        assert!(wal.unsynced_segments().is_empty());
+        assert_eq!(wal.write_batch(&[]).unwrap(), []);
+        // Doesn't fit into the rest of the segment, so all of it goes into
+        // the next one.
+        let large = vec![8; 2 * BLOCK_SIZE as usize];
+        let rotated = wal.write_batch(&[&large, &large]).unwrap();
+        assert!(rotated
+            .iter()
+            .all(|pos| pos.segment_id == before.segment_id + 1));
+        drop(wal);
+
+        // Complete batches survive reopening, followed by single records.
+        let mut wal = Wal::open(opts()).unwrap();
+        assert_eq!(wal.read(rotated[1]).unwrap(), large);
+        let after = wal.write(b"after").unwrap();
+        drop(wal);
+        let wal = Wal::open(opts()).unwrap();
+        assert_eq!(wal.read(after).unwrap(), b"after");
+        assert_eq!(wal.read(positions[2]).unwrap(), b"last");
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn unfinished_batch_is_discarded() {
+        let dir = testing::temp_dir("wal_unfinished_batch");
+        let opts = || Options::new(&dir, 1024 * 1024);
+        let mut wal = Wal::open(opts()).unwrap();
+        let before = wal.write(b"before").unwrap();
+        let big = vec![7; BLOCK_SIZE as usize + 100];
+        let positions = wal.write_batch(&[b"first", &big, b"last"]).unwrap();
+        let path = segment::segment_file_path(&dir, before.segment_id);
+        let len = std::fs::metadata(&path).unwrap().len();
+        drop(wal);
+        for cut in [
+            // The last record torn, or missing altogether.
+            len - 2,
+            positions[2].block_number as u64 * BLOCK_SIZE as u64 + positions[2].chunk_offset,
+            // Only the first record made it.
+            BLOCK_SIZE as u64,
+        ] {
+            let file = std::fs::File::options().write(true).open(&path).unwrap();
+            file.set_len(cut).unwrap();
+            drop(file);
+
+            let read_only = Wal::open(opts().with_read_only(true)).unwrap();
+            assert_eq!(
+                read_only.reader().map(Result::unwrap).collect::<Vec<_>>(),
+                [(b"before".to_vec(), before)]
+            );
+            drop(read_only);
+            let mut wal = Wal::open(opts()).unwrap();
+            assert_eq!(wal.read(before).unwrap(), b"before");
+            assert!(wal.read(positions[0]).is_err());
+            assert_eq!(wal.reader().count(), 1);
+            // Writes continue where the batch started.
+            assert_eq!(wal.write(b"after").unwrap(), positions[0]);
+            drop(wal);
+            // Take it off again and rewrite the batch for the next cut.
+            let len = std::fs::metadata(&path).unwrap().len();
+            std::fs::File::options()
+                .write(true)
+                .open(&path)
+                .unwrap()
+                .set_len(len - 5 - CHUNK_HEADER_SIZE as u64)
+                .unwrap();
+            let mut wal = Wal::open(opts()).unwrap();
+            let positions_again = wal.write_batch(&[b"first", &big, b"last"]).unwrap();
+            assert_eq!(positions_again, positions);
+            drop(wal);
+        }
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn shared_between_threads() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<Wal>();
+        assert_send_sync::<WalReader<'_>>();
+
+        let dir = testing::temp_dir("wal_shared");
+        let mut wal = Wal::open(Options::new(&dir, 4 * BLOCK_SIZE as u64)).unwrap();
+        let records = testing::write_records(&mut wal, 0, 40, 5_000);
+        std::thread::scope(|s| {
+            for start in 0..4 {
+                let (wal, records) = (&wal, &records);
+                s.spawn(move || {
+                    for record in records.iter().skip(start).step_by(4) {
+                        assert_eq!(wal.read(record.pos).unwrap(), record.data);
+                    }
+                });
+            }
+        });
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn reader() {
+        let dir = testing::temp_dir("wal_reader");
+        let mut wal = Wal::open(Options::new(&dir, 4 * BLOCK_SIZE as u64)).unwrap();
+        assert_eq!(wal.reader().count(), 0);
+        let records = testing::write_records(&mut wal, 0, 50, 7_000);
+        assert!(records.last().unwrap().pos.segment_id > 1);
+        let read: Vec<(Vec<u8>, ChunkPosition)> = wal.reader().map(Result::unwrap).collect();
+        assert_eq!(read.len(), records.len());
+        for ((data, pos), record) in read.iter().zip(&records) {
+            assert_eq!((data, pos), (&record.data, &record.pos));
+        }
+        drop(wal);
+
+        // A corrupted record ends the iteration with its error.
+        let corrupted = records[10].pos;
+        let path = segment::segment_file_path(&dir, corrupted.segment_id);
+        let mut bytes = std::fs::read(&path).unwrap();
+        let offset = corrupted.block_number as usize * BLOCK_SIZE as usize
+            + corrupted.chunk_offset as usize
+            + CHUNK_HEADER_SIZE as usize;
+        bytes[offset] ^= 0xff;
+        std::fs::write(&path, bytes).unwrap();
+        let wal = Wal::open(Options::new(&dir, 4 * BLOCK_SIZE as u64)).unwrap();
+        let read: Vec<_> = wal.reader().collect();
+        assert_eq!(read.len(), 11);
+        assert!(matches!(read[10], Err(WalError::InvalidCrc { .. })));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn first_position() {
+        let dir = testing::temp_dir("wal_first_position");
+        let mut wal = Wal::open(Options::new(&dir, BLOCK_SIZE as u64)).unwrap();
+        assert_eq!(wal.first_position().unwrap(), None);
+        let first = wal.write(&[1; 100]).unwrap();
+        assert_eq!(wal.first_position().unwrap(), Some(first));
+        let second = wal.write(&[2; BLOCK_SIZE as usize - 100]).unwrap();
+        assert_ne!(first.segment_id, second.segment_id);
+        drop(wal);
+
+        // The oldest segment is gone, e.g. removed by retention.
+        std::fs::remove_file(segment::segment_file_path(&dir, first.segment_id)).unwrap();
+        let wal = Wal::open(Options::new(&dir, BLOCK_SIZE as u64)).unwrap();
+        assert_eq!(wal.first_position().unwrap(), Some(second));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn archive() {
+        let dir = testing::temp_dir("wal_archive");
+        let opts = |dir| Options::new(dir, 4 * BLOCK_SIZE as u64).with_record_versions(true);
+        let mut wal = Wal::open(opts(&dir)).unwrap();
+        let records = testing::write_records(&mut wal, 0, 40, 10_000);
+        let last_id = records.last().unwrap().pos.segment_id;
+        assert!(last_id > 3);
+
+        let mut archive = Vec::new();
+        wal.export_archive(2..=3, &mut archive).unwrap();
+        let copy = testing::temp_dir("wal_archive_copy");
+        Wal::import_archive(&archive[..], &copy).unwrap();
+        let imported = Wal::open(opts(&copy)).unwrap();
+        let mut exported = 0;
+        for record in &records {
+            match record.pos.segment_id {
+                2 | 3 => {
+                    assert_eq!(
+                        imported.read(record.pos).unwrap(),
+                        wal.read(record.pos).unwrap()
+                    );
+                    exported += 1;
+                }
+                _ => assert!(imported.read(record.pos).is_err()),
+            }
+        }
+        assert!(exported > 0);
+        drop(imported);
+
+        // Into a directory which already holds a Wal, or from a damaged archive.
+        assert!(matches!(
+            Wal::import_archive(&archive[..], &copy),
+            Err(WalError::InvalidArchive(_))
+        ));
+        let other = testing::temp_dir("wal_archive_damaged");
+        assert!(Wal::import_archive(&archive[..archive.len() - 10], &other).is_err());
+        assert!(matches!(
+            Wal::import_archive(&b"not an archive"[..], &other),
+            Err(WalError::InvalidArchive(_))
+        ));
+        // The manifest travels with the segments, so the format is checked.
+        let all = testing::temp_dir("wal_archive_all");
+        let mut archive = Vec::new();
+        wal.export_archive(0..=u32::MAX, &mut archive).unwrap();
+        Wal::import_archive(&archive[..], &all).unwrap();
+        assert!(matches!(
+            Wal::open(Options::new(&all, 4 * BLOCK_SIZE as u64)),
+            Err(WalError::OptionsMismatch { .. })
+        ));
+        let all_wal = Wal::open(opts(&all)).unwrap();
+        for record in &records {
+            assert_eq!(
+                all_wal.read(record.pos).unwrap(),
+                wal.read(record.pos).unwrap()
+            );
+        }
+        drop((wal, all_wal));
+        for dir in [dir, copy, other, all] {
+            let _ = std::fs::remove_dir_all(dir);
+        }
+    }
+
+    #[test]
+    fn lag() {
+        let dir = testing::temp_dir("wal_lag");
+        let mut wal = Wal::open(Options::new(&dir, BLOCK_SIZE as u64)).unwrap();
+        let first = wal.write(&[1; 100]).unwrap();
+        let second = wal.write(&[2; BLOCK_SIZE as usize - 100]).unwrap();
+        let third = wal.write(&[3; 10]).unwrap();
+        assert_eq!((second.segment_id, third.segment_id), (2, 2));
+
+        let all = wal.lag(None).unwrap();
+        assert_eq!(all.records, 3);
+        assert_eq!(all.segments, 2);
+        assert_eq!(
+            all.bytes,
+            wal.read_stats()
+                .iter()
+                .map(|s| s.segment_id)
+                .fold(0, |bytes, id| {
+                    bytes
+                        + std::fs::metadata(segment::segment_file_path(&dir, id))
+                            .unwrap()
+                            .len()
+                })
+        );
+
+        let behind = wal.lag(Some(first)).unwrap();
+        assert_eq!(behind.records, 2);
+        assert_eq!(behind.segments, 1);
+        assert_eq!(behind.bytes, all.bytes - 100 - CHUNK_HEADER_SIZE as u64);
+        let behind = wal.lag(Some(second)).unwrap();
+        assert_eq!(behind.records, 1);
+        assert_eq!(behind.segments, 1);
+        assert_eq!(behind.bytes, 10 + CHUNK_HEADER_SIZE as u64);
+        assert_eq!(wal.lag(Some(third)).unwrap(), Lag::default());
+
+        let missing = ChunkPosition {
+            segment_id: 9,
+            ..first
+        };
+        assert!(matches!(
+            wal.lag(Some(missing)),
+            Err(WalError::SegmentFileNotFound)
+        ));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn block_cache() {
+        let dir = testing::temp_dir("wal_block_cache");
+        let opts = Options::new(&dir, 1024 * 1024).with_block_cache_bytes(BLOCK_SIZE as usize);
+        let mut wal = Wal::open(opts).unwrap();
+        let cached = wal.write(&[1; 100]).unwrap();
+        wal.write(&[2; BLOCK_SIZE as usize]).unwrap();
+        let tail = wal.write(&[3; 100]).unwrap();
+        assert_eq!(wal.read(cached).unwrap(), [1; 100]);
+        assert_eq!(wal.read(tail).unwrap(), [3; 100]);
+
+        // Only the complete block is served from memory afterwards, until
+        // the file turns out to disagree with it.
+        let path = segment::segment_file_path(&dir, cached.segment_id);
+        let mut bytes = std::fs::read(&path).unwrap();
+        for pos in [cached, tail] {
+            let offset = pos.block_number as usize * BLOCK_SIZE as usize
+                + pos.chunk_offset as usize
+                + CHUNK_HEADER_SIZE as usize;
+            bytes[offset] ^= 0xff;
+        }
+        std::fs::write(&path, bytes).unwrap();
+        assert_eq!(wal.read(cached).unwrap(), [1; 100]);
+        assert!(matches!(wal.read(tail), Err(WalError::InvalidCrc { .. })));
+        // Verifying finds the corrupt block in the file and drops the cached
+        // copy, reads then report the corruption too.
+        assert!(matches!(
+            wal.verify(),
+            Err(WalError::InvalidCrc {
+                block_number: 0,
+                ..
+            })
+        ));
+        assert_eq!(wal.block_cache_repairs(), 1);
+        assert!(matches!(wal.read(cached), Err(WalError::InvalidCrc { .. })));
+        drop(wal);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn block_cache_repair() {
+        let dir = testing::temp_dir("wal_block_cache_repair");
+        let opts = Options::new(&dir, 1024 * 1024).with_block_cache_bytes(1024 * 1024);
+        let mut wal = Wal::open(opts).unwrap();
+        let cached = wal.write(&[1; 100]).unwrap();
+        wal.write(&[2; BLOCK_SIZE as usize]).unwrap();
+        assert_eq!(wal.read(cached).unwrap(), [1; 100]);
+
+        // The cached copy is corrupted in memory, the file is intact.
+        let id = BlockId {
+            segment_id: cached.segment_id,
+            block_number: cached.block_number,
+        };
+        let cache = wal.active_unchecked().block_cache().unwrap();
+        let mut block = cache.lock().unwrap().get(id).unwrap().to_vec();
+        block[CHUNK_HEADER_SIZE as usize] ^= 0xff;
+        cache.lock().unwrap().insert(id, block.into());
+        assert_eq!(wal.read(cached).unwrap(), [1; 100]);
+        assert_eq!(wal.block_cache_repairs(), 1);
+        // Read from the file again and cached as it is there.
+        assert_eq!(
+            cache.lock().unwrap().get(id).unwrap()[CHUNK_HEADER_SIZE as usize],
+            1
+        );
+        assert_eq!(wal.read(cached).unwrap(), [1; 100]);
+        assert_eq!(wal.block_cache_repairs(), 1);
+        drop(wal);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn read_into_buf() {
+        let dir = testing::temp_dir("wal_read_into_buf");
+        let mut wal = Wal::open(Options::new(&dir, 1024 * 1024)).unwrap();
+        let records = testing::write_records(&mut wal, 0, 20, 50000);
+        let mut buf = Vec::with_capacity(60000);
+        let ptr = buf.as_ptr();
+        for record in &records {
+            wal.read_into_buf(record.pos, &mut buf).unwrap();
+            assert_eq!(buf, record.data);
+        }
+        // Every record fit, so the buffer was never reallocated.
+        assert_eq!(buf.as_ptr(), ptr);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn incomplete_record() {
+        let dir = testing::temp_dir("wal_incomplete");
+        let opts = || Options::new(&dir, 1024 * 1024);
+        let mut wal = Wal::open(opts()).unwrap();
+        let complete = wal.write(&[1; 100]).unwrap();
+        let spanning = wal.write(&[2; 40 * 1024]).unwrap();
+        drop(wal);
+        // The Last chunk of the second record has only partly reached the file.
+        let path = segment::segment_file_path(&dir, complete.segment_id);
+        std::fs::File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_len(BLOCK_SIZE as u64 + 100)
+            .unwrap();
+
+        let wal = Wal::open(opts()).unwrap();
+        assert_eq!(wal.read(complete).unwrap(), [1; 100]);
+        match wal.read(spanning) {
+            Err(WalError::IncompleteRecord {
+                segment_id,
+                block_number,
+                offset,
+            }) => {
+                assert_eq!(segment_id, spanning.segment_id);
+                assert_eq!((block_number, offset), (0, spanning.chunk_offset));
+            }
+            other => panic!(
+                "expected an incomplete record, got {:?}",
+                other.map(|d| d.len())
+            ),
+        }
+        let past_end = ChunkPosition {
+            block_number: 1,
+            chunk_offset: 200,
+            ..complete
+        };
+        assert!(matches!(
+            wal.read(past_end),
+            Err(WalError::IncompleteRecord { .. })
+        ));
+        // A scan stops cleanly before it.
+        let mut replayed = Vec::new();
+        wal.replay(|pos, _| {
+            replayed.push(pos);
+            Ok(ReplayControl::Continue)
+        })
+        .unwrap();
+        assert_eq!(replayed, [complete]);
+        // The torn record was cut off, so that is where writes continue.
+        drop(wal);
+        assert_eq!(
+            std::fs::metadata(&path).unwrap().len(),
+            spanning.chunk_offset
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn tail_check() {
+        let dir = testing::temp_dir("wal_tail_check");
+        let mut wal = Wal::open(Options::new(&dir, 1024 * 1024)).unwrap();
+        let first = wal.write(b"first").unwrap();
+        drop(wal);
+        // A chunk of a type which doesn't exist, under a matching checksum, so
+        // it isn't cut off as a torn write.
+        let path = segment::segment_file_path(&dir, first.segment_id);
+        let end = std::fs::metadata(&path).unwrap().len();
+        let mut chunk = vec![0, 0, 0, 0, 3, 0, 9, 1, 2, 3];
+        let crc = crc32fast::hash(&chunk[4..]);
+        chunk[..4].copy_from_slice(&crc.to_le_bytes());
+        let mut bytes = std::fs::read(&path).unwrap();
+        bytes.extend_from_slice(&chunk);
+        std::fs::write(&path, bytes).unwrap();
+
+        let wal = Wal::open(Options::new(&dir, 1024 * 1024)).unwrap();
+        assert!(wal.open_report().warnings.is_empty());
+        drop(wal);
+        let mut wal = Wal::open(Options::new(&dir, 1024 * 1024).with_tail_check(true)).unwrap();
+        assert!(matches!(
+            wal.open_report().warnings[..],
+            [WalError::MisalignedTail { offset, len, .. }]
+                if offset == end && len == end + chunk.len() as u64
+        ));
+        let second = wal.write(b"second").unwrap();
+        assert_eq!(second.chunk_offset, end);
+        assert_eq!(wal.read(second).unwrap(), b"second");
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn torn_writes_are_truncated() {
+        let dir = testing::temp_dir("wal_torn_writes");
+        let opts = || Options::new(&dir, 1024 * 1024);
+        let mut wal = Wal::open(opts()).unwrap();
+        let first = wal.write(&[1; 100]).unwrap();
+        let torn = wal.write(&[2; 100]).unwrap();
+        drop(wal);
+        // The file was extended, but the data of the last write never
+        // reached it.
+        let path = segment::segment_file_path(&dir, first.segment_id);
+        let mut bytes = std::fs::read(&path).unwrap();
+        bytes[torn.chunk_offset as usize + CHUNK_HEADER_SIZE as usize..].fill(0);
+        std::fs::write(&path, &bytes).unwrap();
+
+        let mut wal = Wal::open(opts()).unwrap();
+        let next = wal.write(&[3; 100]).unwrap();
+        assert_eq!(next, torn);
+        let mut replayed = Vec::new();
+        wal.replay(|_, data| {
+            replayed.push(data.to_vec());
+            Ok(ReplayControl::Continue)
+        })
+        .unwrap();
+        assert_eq!(replayed, [vec![1; 100], vec![3; 100]]);
+        drop(wal);
+
+        // Anything but zeros after a bad checksum is left for reads to report.
+        let mut bytes = std::fs::read(&path).unwrap();
+        bytes[first.chunk_offset as usize + CHUNK_HEADER_SIZE as usize] ^= 0xff;
+        std::fs::write(&path, &bytes).unwrap();
+        let wal = Wal::open(opts()).unwrap();
+        assert!(matches!(wal.read(first), Err(WalError::InvalidCrc { .. })));
+        assert_eq!(wal.read(next).unwrap(), [3; 100]);
+        drop(wal);
+        assert_eq!(std::fs::read(&path).unwrap(), bytes);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn block_trailers() {
+        let dir = testing::temp_dir("wal_block_trailers");
+        let opts = || Options::new(&dir, 4 * BLOCK_SIZE as u64).with_block_trailers(true);
+        let mut wal = Wal::open(opts()).unwrap();
+        let records = testing::write_records(&mut wal, 0, 100, 3000);
+        testing::assert_read_back(&wal, &records);
+        let first = records[0].pos.block_id();
+        let trailer = wal.block_trailer(first).unwrap().unwrap();
+        let block = wal.read_block(first).unwrap();
+        assert_eq!(trailer.chunks as usize, block.chunks.len());
+        drop(wal);
+
+        let wal = Wal::open(opts()).unwrap();
+        testing::assert_read_back(&wal, &records);
+        drop(wal);
+        assert!(matches!(
+            Wal::open(Options::new(&dir, 4 * BLOCK_SIZE as u64)),
+            Err(WalError::OptionsMismatch {
+                field: "block_trailers",
+                ..
+            })
+        ));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn rotate_segments() {
+        let dir = testing::temp_dir("wal_rotate");
+        let opts = Options::new(&dir, 4 * BLOCK_SIZE as u64);
+        let mut wal = Wal::open(opts).unwrap();
+        let records = testing::write_records(&mut wal, 0, 64, 4096);
+        assert!(records.last().unwrap().pos.segment_id > INITIAL_SEGMENT_FILE_ID);
+        testing::assert_read_back(&wal, &records);
+        testing::assert_no_interleaving(&records);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn direct_reads_and_page_cache_hints() {
+        let dir = testing::temp_dir("wal_direct_reads");
+        let opts = || {
+            Options::new(&dir, 4 * BLOCK_SIZE as u64)
+                .with_direct_reads(true)
+                .with_page_cache_hints(true)
+        };
+        if cfg!(not(all(feature = "direct-io", target_os = "linux"))) {
+            assert!(matches!(
+                Wal::open(opts()),
+                Err(WalError::InvalidOptions(_))
+            ));
+            std::fs::remove_dir_all(dir).unwrap();
+            return;
+        }
+        let mut wal = Wal::open(opts()).unwrap();
+        // Sizes which leave records at offsets and lengths of every alignment.
+        let mut records = testing::write_records(&mut wal, 0, 100, 4999);
+        assert!(wal.older_segments.len() > 2);
+        #[cfg(all(feature = "direct-io", target_os = "linux"))]
+        assert!(wal.older_segments.values().all(|seg| seg.reads_directly()));
+        testing::assert_read_back(&wal, &records);
+        wal.sync().unwrap();
+        drop(wal);
+
+        let mut wal = Wal::open(opts()).unwrap();
+        let replayed: Vec<_> = wal.reader().map(|record| record.unwrap().0).collect();
+        let expected: Vec<_> = records.iter().map(|record| record.data.clone()).collect();
+        assert_eq!(replayed, expected);
+        records.extend(testing::write_records(&mut wal, 1, 20, 3001));
+        testing::assert_read_back(&wal, &records);
+        drop(wal);
+
+        #[cfg(feature = "mmap")]
+        assert!(matches!(
+            Wal::open(opts().with_mmap_reads(true)),
+            Err(WalError::InvalidOptions(_))
+        ));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[cfg(feature = "mmap")]
+    #[test]
+    fn mmap_reads() {
+        let dir = testing::temp_dir("wal_mmap_reads");
+        let sealed_dir = testing::temp_dir("wal_mmap_reads_sealed");
+        let opts = || {
+            Options::new(&dir, 4 * BLOCK_SIZE as u64)
+                .with_sealed_dir(&sealed_dir)
+                .with_mmap_reads(true)
+        };
+        let mut wal = Wal::open(opts()).unwrap();
+        let mut records = testing::write_records(&mut wal, 0, 100, 5000);
+        assert!(wal.older_segments.len() > 2);
+        assert!(wal
+            .older_segments
+            .values()
+            .all(|seg| seg.is_mapped_for_reads()));
+        assert!(!wal.active_unchecked().is_mapped_for_reads());
+        testing::assert_read_back(&wal, &records);
+        // Block reads and verification go through the mapping too.
+        let block = wal.read_block(records[0].pos.block_id()).unwrap();
+        assert_eq!(block.chunks[0].data, records[0].data);
+        wal.verify().unwrap();
+        drop(wal);
+
+        let mut wal = Wal::open(opts()).unwrap();
+        assert!(wal
+            .older_segments
+            .values()
+            .all(|seg| seg.is_mapped_for_reads()));
+        let mut replayed = Vec::new();
+        wal.replay(|_, data| {
+            replayed.push(data.to_vec());
+            Ok(ReplayControl::Continue)
+        })
+        .unwrap();
+        let expected: Vec<_> = records.iter().map(|record| record.data.clone()).collect();
+        assert_eq!(replayed, expected);
+
+        // A sealed segment cut short is mapped again, only up to its new end.
+        let cut = records.iter().position(|r| r.pos.segment_id == 2).unwrap() + 1;
+        wal.truncate_after(records[cut].pos).unwrap();
+        records.truncate(cut + 1);
+        assert!(wal.older_segments[&2].is_mapped_for_reads());
+        testing::assert_read_back(&wal, &records);
+        records.extend(testing::write_records(&mut wal, 1, 20, 5000));
+        testing::assert_read_back(&wal, &records);
+        drop(wal);
+        std::fs::remove_dir_all(dir).unwrap();
+        std::fs::remove_dir_all(sealed_dir).unwrap();
+    }
+
+    #[cfg(feature = "mmap")]
+    #[test]
+    fn mmap_appends() {
+        let dir = testing::temp_dir("wal_mmap");
+        let opts = || Options::new(&dir, 4 * BLOCK_SIZE as u64).with_mmap_appends(true);
+        let mut wal = Wal::open(opts()).unwrap();
+        let mut records = testing::write_records(&mut wal, 0, 40, 3000);
+        // A record larger than the preallocated segment grows the mapping.
+        records.push(testing::WrittenRecord {
+            writer: 1,
+            seq: 0,
+            pos: wal
+                .write(&testing::payload(1, 0, 5 * BLOCK_SIZE as usize))
+                .unwrap(),
+            data: testing::payload(1, 0, 5 * BLOCK_SIZE as usize),
+        });
+        records.extend(testing::write_records(&mut wal, 2, 3, 100));
+        wal.sync_segment(records.last().unwrap().pos.segment_id)
+            .unwrap();
+        testing::assert_read_back(&wal, &records);
+        for seg in wal.older_segments.values() {
+            assert_eq!(seg.metadata().unwrap().len(), seg.size());
+        }
+        let active_len = wal.active_unchecked().metadata().unwrap().len();
+        assert_eq!(active_len, 4 * BLOCK_SIZE as u64);
+        drop(wal);
+
+        // The active segment is still preallocated, writing continues after its data.
+        let mut wal = Wal::open(opts()).unwrap();
+        testing::assert_read_back(&wal, &records);
+        records.extend(testing::write_records(&mut wal, 3, 20, 3000));
+        testing::assert_read_back(&wal, &records);
+        testing::assert_no_interleaving(&records);
+        std::fs::remove_dir_all(dir).unwrap();
     }
 }
@@ -0,0 +1,180 @@
+//! An async front end of [`crate::wal::Wal`] for tokio, async-std or smol.
+//!
+//! Every call runs on a blocking thread pool, so the file I/O never blocks
+//! the executor: tokio's with the `tokio` feature, otherwise that of the
+//! `blocking` crate, which async-std and smol use themselves.
+
+use std::sync::{Arc, RwLock};
+
+use crate::{error::WalError, options::Options, wal::ChunkPosition};
+
+/// A [`crate::wal::Wal`] shared between tasks: reads run concurrently,
+/// writes one at a time. Clones share the same Wal, and once one of them
+/// closes it every call fails with `WalError::Closed`.
+#[derive(Clone)]
+pub struct Wal {
+    /// `None` once closed.
+    inner: Arc<RwLock<Option<crate::wal::Wal>>>,
+}
+
+impl From<crate::wal::Wal> for Wal {
+    fn from(wal: crate::wal::Wal) -> Self {
+        Self {
+            inner: Arc::new(RwLock::new(Some(wal))),
+        }
+    }
+}
+
+impl Wal {
+    pub async fn open(options: Options) -> Result<Self, WalError> {
+        let wal = blocking(move || crate::wal::Wal::open(options)).await?;
+        Ok(wal.into())
+    }
+
+    /// Append a record and return its position, see [`crate::wal::Wal::write`].
+    pub async fn write(&self, data: Vec<u8>) -> Result<ChunkPosition, WalError> {
+        self.with_blocking(move |wal| wal.write(&data)).await
+    }
+
+    /// Append the records as one batch, see [`crate::wal::Wal::write_batch`].
+    pub async fn write_batch(&self, records: Vec<Vec<u8>>) -> Result<Vec<ChunkPosition>, WalError> {
+        self.with_blocking(move |wal| {
+            let records: Vec<&[u8]> = records.iter().map(|data| &data[..]).collect();
+            wal.write_batch(&records)
+        })
+        .await
+    }
+
+    pub async fn read(&self, pos: ChunkPosition) -> Result<Vec<u8>, WalError> {
+        self.with_shared(move |wal| wal.read(pos)).await
+    }
+
+    /// Sync every segment with data that hasn't been synced yet, see
+    /// [`crate::wal::Wal::sync`].
+    pub async fn sync(&self) -> Result<(), WalError> {
+        self.with_shared(|wal| wal.sync()).await
+    }
+
+    /// Close the Wal for every clone once the calls in progress are done,
+    /// see [`crate::wal::Wal::close`]. Closing it again fails with
+    /// `WalError::Closed`, like every other call.
+    pub async fn close(&self) -> Result<(), WalError> {
+        let inner = self.inner.clone();
+        blocking(move || {
+            let wal = inner.write().map_err(|_| WalError::Poisoned)?.take();
+            wal.ok_or(WalError::Closed)?.close()
+        })
+        .await
+    }
+
+    /// Run `f` with the blocking Wal on the blocking thread pool, e.g. for
+    /// calls this front end doesn't wrap.
+    pub async fn with_blocking<T, F>(&self, f: F) -> Result<T, WalError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut crate::wal::Wal) -> Result<T, WalError> + Send + 'static,
+    {
+        let inner = self.inner.clone();
+        blocking(move || {
+            let mut wal = inner.write().map_err(|_| WalError::Poisoned)?;
+            f(wal.as_mut().ok_or(WalError::Closed)?)
+        })
+        .await
+    }
+
+    /// Like [`Wal::with_blocking`], concurrently with other reads.
+    async fn with_shared<T, F>(&self, f: F) -> Result<T, WalError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&crate::wal::Wal) -> Result<T, WalError> + Send + 'static,
+    {
+        let inner = self.inner.clone();
+        blocking(move || {
+            let wal = inner.read().map_err(|_| WalError::Poisoned)?;
+            f(wal.as_ref().ok_or(WalError::Closed)?)
+        })
+        .await
+    }
+}
+
+/// Run `f` on the blocking thread pool, passing a panic in it on.
+#[cfg(feature = "tokio")]
+async fn blocking<T, F>(f: F) -> Result<T, WalError>
+where
+    T: Send + 'static,
+    F: FnOnce() -> Result<T, WalError> + Send + 'static,
+{
+    match tokio::task::spawn_blocking(f).await {
+        Ok(result) => result,
+        Err(e) => std::panic::resume_unwind(e.into_panic()),
+    }
+}
+
+/// Run `f` on the blocking thread pool, passing a panic in it on.
+#[cfg(not(feature = "tokio"))]
+async fn blocking<T, F>(f: F) -> Result<T, WalError>
+where
+    T: Send + 'static,
+    F: FnOnce() -> Result<T, WalError> + Send + 'static,
+{
+    ::blocking::unblock(f).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    #[cfg(feature = "tokio")]
+    fn block_on<T>(future: impl std::future::Future<Output = T>) -> T {
+        tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap()
+            .block_on(future)
+    }
+
+    #[cfg(not(feature = "tokio"))]
+    use futures_lite::future::block_on;
+
+    #[test]
+    fn round_trip() {
+        let dir = testing::temp_dir("wal_async");
+        block_on(async {
+            let wal = Wal::open(Options::new(&dir, 1024 * 1024)).await.unwrap();
+            let pos = wal.write(b"record".to_vec()).await.unwrap();
+            let batch = wal
+                .write_batch(vec![b"one".to_vec(), b"two".to_vec()])
+                .await
+                .unwrap();
+            wal.sync().await.unwrap();
+            assert_eq!(wal.read(pos).await.unwrap(), b"record");
+            assert_eq!(wal.read(batch[1]).await.unwrap(), b"two");
+            let unsynced = wal
+                .with_blocking(|wal| Ok(wal.unsynced_segments()))
+                .await
+                .unwrap();
+            assert!(unsynced.is_empty());
+        });
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn closed() {
+        let dir = testing::temp_dir("wal_async_closed");
+        block_on(async {
+            let wal = Wal::open(Options::new(&dir, 1024 * 1024)).await.unwrap();
+            let pos = wal.write(b"record".to_vec()).await.unwrap();
+            let clone = wal.clone();
+            clone.close().await.unwrap();
+            assert!(matches!(wal.read(pos).await, Err(WalError::Closed)));
+            assert!(matches!(wal.write(vec![]).await, Err(WalError::Closed)));
+            assert!(matches!(wal.sync().await, Err(WalError::Closed)));
+            assert!(matches!(wal.close().await, Err(WalError::Closed)));
+
+            // Closing released the directory.
+            let wal = Wal::open(Options::new(&dir, 1024 * 1024)).await.unwrap();
+            assert_eq!(wal.read(pos).await.unwrap(), b"record");
+        });
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}